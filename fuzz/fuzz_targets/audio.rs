@@ -0,0 +1,25 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tsproto_packets::packets::{AudioData, Direction, PacketType};
+
+// The first byte selects the packet type, direction and newprotocol flag.
+fuzz_target!(|data: &[u8]| {
+	if data.is_empty() {
+		return;
+	}
+	let p_type = if data[0] & 1 == 0 {
+		PacketType::Voice
+	} else {
+		PacketType::VoiceWhisper
+	};
+	let dir = if data[0] & 2 == 0 {
+		Direction::S2C
+	} else {
+		Direction::C2S
+	};
+	let newprotocol = data[0] & 4 != 0;
+	if let Ok(audio) = AudioData::parse(p_type, newprotocol, dir, &data[1..]) {
+		format!("{:?}", audio);
+		audio.direction();
+	}
+});
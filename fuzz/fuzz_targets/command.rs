@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tsproto_packets::commands::parse_command;
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(s) = std::str::from_utf8(data) {
+		if let Ok(cmd) = parse_command(s) {
+			for c in cmd.iter() {
+				for (k, _) in &c.0 {
+					c.get(k);
+				}
+			}
+		}
+	}
+});
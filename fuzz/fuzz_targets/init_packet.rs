@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tsproto_packets::packets::{Direction, InPacket};
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(packet) = InPacket::try_new(data.to_vec().into(), Direction::S2C)
+	{
+		if let Ok(init) = packet.into_s2cinit() {
+			init.with_data(|d| format!("{:?}", d));
+		}
+	}
+	if let Ok(packet) = InPacket::try_new(data.to_vec().into(), Direction::C2S)
+	{
+		if let Ok(init) = packet.into_c2sinit() {
+			init.with_data(|d| format!("{:?}", d));
+		}
+	}
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tsproto::license::Licenses;
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(licenses) = Licenses::parse(data) {
+		let _ = licenses.derive_public_key();
+	}
+});
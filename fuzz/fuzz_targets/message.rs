@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ts_bookkeeping::messages::s2c::InMessage;
+use tsproto_packets::packets::{Direction, InCommand, PacketType};
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(cmd) = InCommand::new(
+		data.to_vec(),
+		PacketType::Command,
+		false,
+		Direction::S2C,
+	) {
+		let _ = InMessage::new(cmd);
+	}
+});
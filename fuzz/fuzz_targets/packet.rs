@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tsproto_packets::packets::{Direction, InPacket, InUdpPacket};
+
+fuzz_target!(|data: &[u8]| {
+	for &dir in &[Direction::S2C, Direction::C2S] {
+		if let Ok(packet) = InPacket::try_new(data.to_vec().into(), dir) {
+			let header = packet.header();
+			header.packet_type();
+			header.flags();
+			header.get_meta();
+			packet.ack_packet();
+			format!("{:?} {:?}", packet, InUdpPacket::new(&packet));
+			if header.packet_type().is_voice() {
+				let _ = packet.into_audio();
+			}
+		}
+	}
+});
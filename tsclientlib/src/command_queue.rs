@@ -0,0 +1,375 @@
+//! Send commands without triggering the flood protection of the server.
+//!
+//! The server adds points to a client for every command it sends and removes
+//! a fixed amount of points every second. If a client reaches a certain
+//! number of points, its commands are blocked and later it gets banned.
+//!
+//! The command queue models this as a token bucket and delays commands until
+//! they can be sent without reaching the limit. Commands with a normal
+//! priority are sent before low priority commands.
+//!
+//! Only commands which are sent with `Connection::send_packet` or
+//! `Connection::send_packet_with_priority` go through the queue. Packets which
+//! are sent through `Connection::get_packet_sink` and the disconnect command
+//! are sent immediately, but their points are not counted.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Stream};
+use parking_lot::RwLock;
+use slog::{warn, Logger};
+use tokio::timer::Delay;
+use ts_bookkeeping::data;
+
+/// Only use this fraction of the points which are needed to get blocked.
+///
+/// The server counts points in ticks, so we stay a bit below the limit.
+const MARGIN: f64 = 0.9;
+
+/// The settings of the flood protection of a server.
+///
+/// The points are taken from the `ConnectionServerData` if the server sent
+/// them, otherwise the values of this config are used.
+#[derive(Clone, Debug)]
+pub struct AntifloodConfig {
+	/// Points which are removed every second.
+	pub points_tick_reduce: u32,
+	/// Commands are blocked when a client reaches this number of points.
+	pub points_needed_command_block: u32,
+	/// The cost of a command which is not in `command_costs`.
+	pub default_cost: u32,
+	/// The costs of single commands, indexed by the command name.
+	pub command_costs: HashMap<String, u32>,
+}
+
+/// The priority of an outgoing command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandPriority {
+	/// Sent as `Command` packet.
+	Normal,
+	/// Sent as `CommandLow` packet after all pending commands with a normal
+	/// priority. This should be used for bulk commands like mass messages.
+	Low,
+}
+
+struct QueuedCommand {
+	name: String,
+	/// Send the command when this is fulfilled.
+	permit: oneshot::Sender<()>,
+}
+
+/// Enqueue commands into the command queue of a connection.
+#[derive(Clone)]
+pub(crate) struct CommandQueueHandle(
+	mpsc::UnboundedSender<(CommandPriority, QueuedCommand)>,
+);
+
+/// Tracks the antiflood points which the server counted for this client.
+#[derive(Debug)]
+struct Bucket {
+	points: f64,
+	last_update: Instant,
+}
+
+/// Returns the `points_tick_reduce` and `points_needed_command_block` of the
+/// server if they are known.
+type ServerLimits = Box<Fn() -> Option<(u32, u32)> + Send>;
+
+/// Hands out permits to send commands, so that the points of the flood
+/// protection stay below the limit.
+///
+/// The future finishes when all handles are dropped. If the timer fails, all
+/// waiting commands are cancelled and the future returns an error.
+pub(crate) struct CommandQueue {
+	logger: Logger,
+	config: AntifloodConfig,
+	server_limits: ServerLimits,
+	recv: Option<mpsc::UnboundedReceiver<(CommandPriority, QueuedCommand)>>,
+	normal: VecDeque<QueuedCommand>,
+	low: VecDeque<QueuedCommand>,
+	bucket: Bucket,
+	timer: Option<Delay>,
+}
+
+impl Default for AntifloodConfig {
+	fn default() -> Self {
+		// The costs are estimates, the server does not publish them.
+		let command_costs = [("clientpoke", 25), ("sendtextmessage", 15)]
+			.iter()
+			.map(|(n, c)| (n.to_string(), *c))
+			.collect();
+		Self {
+			points_tick_reduce: 5,
+			points_needed_command_block: 150,
+			default_cost: 5,
+			command_costs,
+		}
+	}
+}
+
+impl AntifloodConfig {
+	/// The points which the server adds for this command.
+	pub fn cost(&self, command: &str) -> u32 {
+		self.command_costs
+			.get(command)
+			.cloned()
+			.unwrap_or(self.default_cost)
+	}
+}
+
+impl CommandQueueHandle {
+	/// Enqueue a command.
+	///
+	/// The returned receiver is fulfilled when the command can be sent.
+	pub(crate) fn enqueue(
+		&self,
+		name: String,
+		priority: CommandPriority,
+	) -> oneshot::Receiver<()>
+	{
+		let (permit, recv) = oneshot::channel();
+		// If the queue is gone, the receiver gets cancelled
+		let _ = self
+			.0
+			.unbounded_send((priority, QueuedCommand { name, permit }));
+		recv
+	}
+}
+
+impl Bucket {
+	fn new(now: Instant) -> Self { Self { points: 0.0, last_update: now } }
+
+	/// Remove the points which the server removed since the last update.
+	fn update(&mut self, now: Instant, tick_reduce: u32) {
+		if now <= self.last_update {
+			return;
+		}
+		let elapsed = now - self.last_update;
+		let elapsed =
+			elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+		self.points = (self.points - elapsed * f64::from(tick_reduce)).max(0.0);
+		self.last_update = now;
+	}
+
+	/// Add the cost of a command if it can be sent now.
+	///
+	/// Otherwise returns the time when there are enough points.
+	fn try_take(
+		&mut self,
+		now: Instant,
+		tick_reduce: u32,
+		limit: u32,
+		cost: u32,
+	) -> Result<(), Instant>
+	{
+		self.update(now, tick_reduce);
+		let limit = f64::from(limit) * MARGIN;
+		let cost = f64::from(cost);
+		// Commands which cost more than the limit are sent when no points are
+		// left, they would never be sent otherwise.
+		if self.points + cost <= limit || self.points == 0.0 {
+			self.points += cost;
+			return Ok(());
+		}
+		if tick_reduce == 0 {
+			// Never happens on a sane server, try again later
+			return Err(now + Duration::from_secs(1));
+		}
+		let missing = self.points + cost.min(limit) - limit;
+		let wait = missing / f64::from(tick_reduce);
+		Err(now
+			+ Duration::from_secs(wait as u64)
+			+ Duration::from_nanos((wait.fract() * 1e9) as u64 + 1))
+	}
+
+	/// Give back the cost of a command which was not sent.
+	fn refund(&mut self, cost: u32) {
+		self.points = (self.points - f64::from(cost)).max(0.0);
+	}
+}
+
+impl CommandQueue {
+	pub(crate) fn new(
+		logger: Logger,
+		config: AntifloodConfig,
+		connection: Arc<RwLock<data::Connection>>,
+	) -> (Self, CommandQueueHandle)
+	{
+		let server_limits = Box::new(move || {
+			let con = connection.read();
+			con.server.connection_data.as_ref().map(|data| {
+				(
+					u32::from(data.antiflood_points_tick_reduce),
+					u32::from(data.antiflood_points_needed_command_block),
+				)
+			})
+		});
+		Self::with_server_limits(logger, config, server_limits)
+	}
+
+	fn with_server_limits(
+		logger: Logger,
+		config: AntifloodConfig,
+		server_limits: ServerLimits,
+	) -> (Self, CommandQueueHandle)
+	{
+		let (send, recv) = mpsc::unbounded();
+		let queue = Self {
+			logger,
+			config,
+			server_limits,
+			recv: Some(recv),
+			normal: VecDeque::new(),
+			low: VecDeque::new(),
+			bucket: Bucket::new(tokio::clock::now()),
+			timer: None,
+		};
+		(queue, CommandQueueHandle(send))
+	}
+
+	/// The antiflood points of the server, if it sent them, otherwise the
+	/// points from the config.
+	fn limits(&self) -> (u32, u32) {
+		(self.server_limits)().unwrap_or((
+			self.config.points_tick_reduce,
+			self.config.points_needed_command_block,
+		))
+	}
+}
+
+impl Future for CommandQueue {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		// Receive new commands
+		while let Some(recv) = &mut self.recv {
+			match recv.poll()? {
+				Async::Ready(Some((CommandPriority::Normal, c))) => {
+					self.normal.push_back(c)
+				}
+				Async::Ready(Some((CommandPriority::Low, c))) => {
+					self.low.push_back(c)
+				}
+				Async::Ready(None) => self.recv = None,
+				Async::NotReady => break,
+			}
+		}
+
+		loop {
+			let (tick_reduce, limit) = self.limits();
+			let cmd = if let Some(c) =
+				self.normal.front().or_else(|| self.low.front())
+			{
+				c
+			} else if self.recv.is_none() {
+				return Ok(Async::Ready(()));
+			} else {
+				return Ok(Async::NotReady);
+			};
+
+			let cost = self.config.cost(&cmd.name);
+			let now = tokio::clock::now();
+			match self.bucket.try_take(now, tick_reduce, limit, cost) {
+				Ok(()) => {
+					let cmd = if self.normal.is_empty() {
+						self.low.pop_front()
+					} else {
+						self.normal.pop_front()
+					}
+					.unwrap();
+					if cmd.permit.send(()).is_err() {
+						// The command was cancelled
+						self.bucket.refund(cost);
+					}
+				}
+				Err(at) => {
+					let mut timer = Delay::new(at);
+					match timer.poll() {
+						Ok(Async::NotReady) => {
+							self.timer = Some(timer);
+							return Ok(Async::NotReady);
+						}
+						Ok(Async::Ready(())) => {}
+						Err(e) => {
+							warn!(self.logger, "Command queue timer failed";
+								"error" => ?e);
+							// Dropping the permits cancels all waiting commands
+							self.timer = None;
+							self.normal.clear();
+							self.low.clear();
+							self.recv = None;
+							return Err(());
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::future;
+	use tokio::runtime::current_thread::Runtime;
+
+	use super::*;
+
+	#[test]
+	fn antiflood_bucket() {
+		let start = Instant::now();
+		let mut bucket = Bucket::new(start);
+		// The limit is 100 * MARGIN = 90 points
+		for _ in 0..9 {
+			bucket.try_take(start, 5, 100, 10).unwrap();
+		}
+		let at = bucket.try_take(start, 5, 100, 10).unwrap_err();
+		assert!(at > start + Duration::from_secs(2));
+		assert!(at < start + Duration::from_millis(2100));
+		bucket.try_take(at, 5, 100, 10).unwrap();
+
+		// Commands above the limit are sent when no points are left
+		let later = at + Duration::from_secs(60);
+		bucket.try_take(later, 5, 100, 200).unwrap();
+		assert!(bucket.try_take(later, 5, 100, 1).is_err());
+		bucket.refund(200);
+		bucket.try_take(later, 5, 100, 1).unwrap();
+	}
+
+	#[test]
+	fn antiflood_server_limits() {
+		let logger = Logger::root(slog::Discard, slog::o!());
+		// The config would allow all commands at once, the server only allows
+		// one command of 5 points and removes 100 points per second.
+		let (queue, handle) = CommandQueue::with_server_limits(
+			logger,
+			AntifloodConfig::default(),
+			Box::new(|| Some((100, 10))),
+		);
+		let mut rt = Runtime::new().unwrap();
+		let (queue, permits) = rt
+			.block_on(future::lazy(move || {
+				let mut queue = queue;
+				let mut permits = (0..3)
+					.map(|_| {
+						handle.enqueue("test".into(), CommandPriority::Normal)
+					})
+					.collect::<Vec<_>>();
+				assert!(queue.poll().unwrap().is_not_ready());
+				assert!(permits[0].poll().unwrap().is_ready());
+				assert!(permits[1].poll().unwrap().is_not_ready());
+				assert!(permits[2].poll().unwrap().is_not_ready());
+				Ok::<_, ()>((queue, permits))
+			}))
+			.unwrap();
+
+		// The queue finishes when the handle is dropped and all commands got
+		// their permit.
+		rt.block_on(queue).unwrap();
+		for p in permits.into_iter().skip(1) {
+			rt.block_on(p).unwrap();
+		}
+	}
+}
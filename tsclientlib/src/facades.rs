@@ -158,4 +158,26 @@ impl ClientMut<'_> {
 	pub fn poke(&self, message: &str) -> impl Future<Item = (), Error = Error> {
 		self.connection.send_packet(self.inner.poke(message))
 	}
+
+	/// Request the connection information of this client from the server.
+	///
+	/// When the server answers, the `connection_data` of this client is
+	/// filled and the corresponding events are raised.
+	///
+	/// # Examples
+	/// ```rust,no_run
+	/// # use futures::Future;
+	/// # let connection: tsclientlib::Connection = panic!();
+	/// let con_lock = connection.lock();
+	/// let con_mut = con_lock.to_mut();
+	/// // Get our own client in mutable form
+	/// let client = con_mut.get_server().get_client(&con_lock.own_client).unwrap();
+	/// // Request the connection info
+	/// tokio::spawn(client.get_connection_info()
+	///	    .map_err(|e| println!("Failed to request connection info ({:?})", e)));
+	/// ```
+	#[must_use = "futures do nothing unless polled"]
+	pub fn get_connection_info(&self) -> impl Future<Item = (), Error = Error> {
+		self.connection.send_packet(self.inner.get_connection_info())
+	}
 }
@@ -0,0 +1,559 @@
+//! Race connection attempts to multiple addresses of a server.
+//!
+//! This is similar to the happy eyeballs algorithm (RFC 8305). The first
+//! address is tried immediately, every following address is tried after a
+//! short delay or as soon as a previous attempt failed. IPv6 and IPv4
+//! addresses are tried alternately, starting with IPv6. Once an attempt
+//! succeeds, all other attempts are cancelled.
+//!
+//! Several attempts can succeed, so an attempt should not have visible side
+//! effects on the server. The connection only races the low-level handshake
+//! and the winner joins the server afterwards. If joining fails, the next
+//! successful attempt joins. Attempts which succeeded at the same time as the
+//! winner are handed to a cancel function, which disconnects them.
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use failure::format_err;
+use futures::{Async, Future, Poll, Stream};
+use slog::{debug, info, Logger};
+use tokio::timer::Delay;
+
+use crate::{BoxFuture, Error};
+
+/// A future which resolves to the address and result of the first connection
+/// attempt which succeeded and joined the server.
+pub(crate) struct Race<S, F, J, C, T, R> {
+	/// The resolved addresses, `None` when the stream ended or failed.
+	addresses: Option<S>,
+	connect: F,
+	/// Called with a successful attempt to join the server.
+	join: J,
+	/// Called with the attempts which succeeded but lost the race.
+	cancel: C,
+	/// The time between starting two attempts.
+	delay: Duration,
+	logger: Logger,
+
+	ipv6: VecDeque<SocketAddr>,
+	ipv4: VecDeque<SocketAddr>,
+	/// The address family which should be used for the next attempt.
+	prefer_ipv6: bool,
+	attempts: Vec<(SocketAddr, BoxFuture<T>)>,
+	/// Successful attempts which wait until the current join failed.
+	connected: VecDeque<(SocketAddr, T)>,
+	/// The attempt which currently joins the server.
+	joining: Option<(SocketAddr, BoxFuture<R>)>,
+	/// Start the next attempt when this timer fires.
+	///
+	/// If it is `None`, the next attempt can be started immediately.
+	next_attempt: Option<Delay>,
+}
+
+impl<S, F, J, C, T, R> Race<S, F, J, C, T, R>
+where
+	S: Stream<Item = SocketAddr, Error = Error>,
+	F: FnMut(SocketAddr) -> BoxFuture<T>,
+	J: FnMut(SocketAddr, T) -> BoxFuture<R>,
+	C: FnMut(SocketAddr, T),
+{
+	/// Start connecting with `connect` to the addresses of the stream.
+	///
+	/// The first successful attempt is passed to `join`. If joining fails, the
+	/// race continues with the other attempts. Attempts which succeeded but
+	/// lost the race are passed to `cancel`.
+	pub(crate) fn new(
+		addresses: S,
+		delay: Duration,
+		logger: Logger,
+		connect: F,
+		join: J,
+		cancel: C,
+	) -> Self
+	{
+		Self {
+			addresses: Some(addresses),
+			connect,
+			join,
+			cancel,
+			delay,
+			logger,
+			ipv6: VecDeque::new(),
+			ipv4: VecDeque::new(),
+			prefer_ipv6: true,
+			attempts: Vec::new(),
+			connected: VecDeque::new(),
+			joining: None,
+			next_attempt: None,
+		}
+	}
+
+	/// Take the next address and alternate the address family.
+	fn next_address(&mut self) -> Option<SocketAddr> {
+		let (first, second) = if self.prefer_ipv6 {
+			(&mut self.ipv6, &mut self.ipv4)
+		} else {
+			(&mut self.ipv4, &mut self.ipv6)
+		};
+		let res = first.pop_front().or_else(|| second.pop_front())?;
+		self.prefer_ipv6 = res.is_ipv4();
+		Some(res)
+	}
+
+	/// Add all addresses which were resolved until now.
+	fn poll_addresses(&mut self) {
+		while let Some(addresses) = &mut self.addresses {
+			match addresses.poll() {
+				Ok(Async::Ready(Some(addr))) => {
+					if addr.is_ipv6() {
+						self.ipv6.push_back(addr);
+					} else {
+						self.ipv4.push_back(addr);
+					}
+				}
+				Ok(Async::Ready(None)) => self.addresses = None,
+				Ok(Async::NotReady) => break,
+				Err(e) => {
+					// Use the addresses which were resolved until now
+					debug!(self.logger, "Failed to resolve address";
+						"error" => ?e);
+					self.addresses = None;
+				}
+			}
+		}
+	}
+
+	/// Cancel all attempts except the winner.
+	///
+	/// Attempts which finished already are passed to the cancel function,
+	/// all others are dropped.
+	fn cancel_attempts(&mut self) {
+		for (addr, mut attempt) in self.attempts.drain(..) {
+			if let Ok(Async::Ready(r)) = attempt.poll() {
+				self.connected.push_back((addr, r));
+			}
+		}
+		for (addr, r) in self.connected.drain(..) {
+			debug!(self.logger, "Cancel successful connection attempt";
+				"address" => %addr);
+			(self.cancel)(addr, r);
+		}
+	}
+
+	/// Poll the attempt which joins the server.
+	///
+	/// Returns `None` if no attempt is joining or joining failed.
+	fn poll_join(&mut self) -> Option<Poll<(SocketAddr, R), Error>> {
+		let (addr, join) = self.joining.as_mut()?;
+		let addr = *addr;
+		match join.poll() {
+			Ok(Async::NotReady) => Some(Ok(Async::NotReady)),
+			Ok(Async::Ready(r)) => {
+				self.joining = None;
+				info!(self.logger, "Connected"; "address" => %addr);
+				self.cancel_attempts();
+				Some(Ok(Async::Ready((addr, r))))
+			}
+			// Other addresses belong to the same server, they will not
+			// accept the identity either.
+			Err(Error::IdentityLevelTooLow(level)) => {
+				self.joining = None;
+				self.cancel_attempts();
+				Some(Err(Error::IdentityLevelTooLow(level)))
+			}
+			Err(e) => {
+				self.joining = None;
+				debug!(self.logger, "Joining failed, trying next address";
+					"address" => %addr, "error" => ?e);
+				// Start the next attempt immediately
+				self.next_attempt = None;
+				None
+			}
+		}
+	}
+
+	/// Start new attempts if the delay passed.
+	fn start_attempts(&mut self) -> Result<(), Error> {
+		loop {
+			if let Some(timer) = &mut self.next_attempt {
+				let ready = timer
+					.poll()
+					.map_err(|e| format_err!("Timer failed ({:?})", e))?
+					.is_ready();
+				if !ready {
+					return Ok(());
+				}
+			}
+			let addr = if let Some(addr) = self.next_address() {
+				addr
+			} else {
+				// Start the next attempt as soon as an address is resolved
+				self.next_attempt = None;
+				return Ok(());
+			};
+			let attempt = (self.connect)(addr);
+			self.attempts.push((addr, attempt));
+			self.next_attempt =
+				Some(Delay::new(tokio::clock::now() + self.delay));
+		}
+	}
+}
+
+impl<S, F, J, C, T, R> Future for Race<S, F, J, C, T, R>
+where
+	S: Stream<Item = SocketAddr, Error = Error>,
+	F: FnMut(SocketAddr) -> BoxFuture<T>,
+	J: FnMut(SocketAddr, T) -> BoxFuture<R>,
+	C: FnMut(SocketAddr, T),
+{
+	type Item = (SocketAddr, R);
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		loop {
+			if let Some(r) = self.poll_join() {
+				return r;
+			}
+			self.poll_addresses();
+			self.start_attempts()?;
+
+			let mut failed = false;
+			let mut i = 0;
+			while i < self.attempts.len() {
+				match self.attempts[i].1.poll() {
+					Ok(Async::NotReady) => i += 1,
+					Ok(Async::Ready(r)) => {
+						let (addr, _) = self.attempts.remove(i);
+						self.connected.push_back((addr, r));
+					}
+					Err(e) => {
+						let (addr, _) = self.attempts.remove(i);
+						debug!(self.logger, "Connecting failed, trying next \
+							address"; "address" => %addr, "error" => ?e);
+						failed = true;
+					}
+				}
+			}
+
+			if let Some((addr, r)) = self.connected.pop_front() {
+				debug!(self.logger, "Joining"; "address" => %addr);
+				self.joining = Some((addr, (self.join)(addr, r)));
+				continue;
+			}
+			if failed {
+				// Start the next attempt immediately
+				self.next_attempt = None;
+				continue;
+			}
+			if self.attempts.is_empty()
+				&& self.addresses.is_none()
+				&& self.ipv6.is_empty()
+				&& self.ipv4.is_empty()
+			{
+				return Err(format_err!("Failed to connect to server").into());
+			}
+			return Ok(Async::NotReady);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+	use std::time::Instant;
+
+	use futures::{future, stream, task};
+	use parking_lot::Mutex;
+	use slog::{o, Discard};
+	use tokio::runtime::current_thread::Runtime;
+	use tsproto::clock::MockClock;
+
+	use super::*;
+
+	/// Connecting to `1.1.1.1` never finishes, `::1` fails and `2.2.2.2`
+	/// succeeds after a delay.
+	fn connect(addr: SocketAddr) -> BoxFuture<u16> {
+		match addr.ip().to_string().as_str() {
+			"1.1.1.1" => Box::new(future::empty::<u16, Error>()),
+			"::1" => Box::new(future::err::<u16, Error>(
+				format_err!("Failed").into(),
+			)),
+			_ => Box::new(
+				Delay::new(Instant::now() + Duration::from_millis(50))
+					.map_err(|e| {
+						Error::from(format_err!("Timer failed ({:?})", e))
+					})
+					.map(move |_| addr.port()),
+			),
+		}
+	}
+
+	/// Joining always succeeds.
+	fn join(_: SocketAddr, port: u16) -> BoxFuture<u16> {
+		Box::new(future::ok(port))
+	}
+
+	/// Poll the race and advance the clock by one second after every turn of
+	/// the runtime.
+	fn run_with_clock<S, F, J, C, T, R>(
+		clock: &MockClock,
+		mut race: Race<S, F, J, C, T, R>,
+	) -> Result<(SocketAddr, R), Error>
+	where
+		S: Stream<Item = SocketAddr, Error = Error>,
+		F: FnMut(SocketAddr) -> BoxFuture<T>,
+		J: FnMut(SocketAddr, T) -> BoxFuture<R>,
+		C: FnMut(SocketAddr, T),
+	{
+		let mut rt = clock.runtime().unwrap();
+		let clock = clock.clone();
+		rt.block_on(future::poll_fn(move || {
+			if let Async::Ready(r) = race.poll()? {
+				return Ok(Async::Ready(r));
+			}
+			clock.advance(Duration::from_secs(1));
+			task::current().notify();
+			Ok(Async::NotReady)
+		}))
+	}
+
+	#[test]
+	fn race_addresses() {
+		let clock = MockClock::new();
+		let addresses = stream::iter_ok::<_, Error>(vec![
+			"1.1.1.1:1".parse().unwrap(),
+			"2.2.2.2:2".parse().unwrap(),
+			"[::1]:3".parse().unwrap(),
+			"[::2]:4".parse().unwrap(),
+		]);
+		let logger = Logger::root(Discard, o!());
+		let started = Arc::new(Mutex::new(Vec::new()));
+		// Held by all pending attempts
+		let pending = Arc::new(());
+
+		// `::1` fails, `1.1.1.1` and `::2` never finish and `2.2.2.2`
+		// succeeds immediately.
+		let started2 = started.clone();
+		let pending2 = pending.clone();
+		let connect = move |addr: SocketAddr| -> BoxFuture<u16> {
+			started2.lock().push((addr, tokio::clock::now()));
+			match addr.port() {
+				3 => Box::new(future::err::<u16, Error>(
+					format_err!("Failed").into(),
+				)),
+				2 => Box::new(future::ok::<_, Error>(addr.port())),
+				_ => {
+					let pending = pending2.clone();
+					Box::new(future::empty::<(), Error>().map(move |()| {
+						drop(pending);
+						0
+					}))
+				}
+			}
+		};
+		let race = Race::new(
+			addresses,
+			Duration::from_secs(10),
+			logger,
+			connect,
+			join,
+			|_, _| panic!("No other attempt succeeded"),
+		);
+		let (addr, port) = run_with_clock(&clock, race).unwrap();
+		assert_eq!(addr, "2.2.2.2:2".parse().unwrap());
+		assert_eq!(port, 2);
+
+		// IPv6 is tried first, then the address families alternate
+		let started = started.lock();
+		let order = started.iter().map(|(a, _)| a.port()).collect::<Vec<_>>();
+		assert_eq!(order, vec![3, 1, 4, 2]);
+
+		// `1.1.1.1` starts immediately after `::1` failed, the others have
+		// to wait for the delay.
+		let times = started.iter().map(|(_, t)| *t).collect::<Vec<_>>();
+		assert!(times[1] - times[0] < Duration::from_secs(1));
+		for w in times[1..].windows(2) {
+			let diff = w[1] - w[0];
+			assert!(diff >= Duration::from_secs(10), "Started after {:?}", diff);
+			assert!(diff <= Duration::from_secs(12), "Started after {:?}", diff);
+		}
+
+		// The pending attempts were cancelled
+		assert_eq!(Arc::strong_count(&pending), 1);
+	}
+
+	/// Both attempts succeed, but only the winner joins the server.
+	#[test]
+	fn race_two_succeeded() {
+		let mut rt = Runtime::new().unwrap();
+		let addresses = stream::iter_ok::<_, Error>(vec![
+			"[::1]:1".parse().unwrap(),
+			"127.0.0.1:2".parse().unwrap(),
+		]);
+		let logger = Logger::root(Discard, o!());
+		let attempts = Arc::new(AtomicUsize::new(0));
+		let joined = Arc::new(AtomicUsize::new(0));
+		let cancelled = Arc::new(Mutex::new(Vec::new()));
+
+		let attempts2 = attempts.clone();
+		let joined2 = joined.clone();
+		let connect = move |addr: SocketAddr| -> BoxFuture<u16> {
+			attempts2.fetch_add(1, Ordering::SeqCst);
+			Box::new(future::ok(addr.port()))
+		};
+		let join = move |_: SocketAddr, port: u16| -> BoxFuture<u16> {
+			joined2.fetch_add(1, Ordering::SeqCst);
+			Box::new(future::ok(port))
+		};
+		let cancelled2 = cancelled.clone();
+		let cancel =
+			move |addr: SocketAddr, _: u16| cancelled2.lock().push(addr);
+		let (_, port) = rt
+			.block_on(future::lazy(move || {
+				Race::new(
+					addresses,
+					Duration::from_millis(0),
+					logger,
+					connect,
+					join,
+					cancel,
+				)
+			}))
+			.unwrap();
+		assert_eq!(port, 1);
+		assert_eq!(attempts.load(Ordering::SeqCst), 2);
+		assert_eq!(joined.load(Ordering::SeqCst), 1);
+		// The loser gets disconnected
+		assert_eq!(*cancelled.lock(), vec!["127.0.0.1:2".parse().unwrap()]);
+	}
+
+	#[test]
+	fn race_all_failed() {
+		let mut rt = Runtime::new().unwrap();
+		let addresses = stream::iter_ok::<_, Error>(vec![
+			"[::1]:1".parse().unwrap(),
+			"[::1]:2".parse().unwrap(),
+		]);
+		let logger = Logger::root(Discard, o!());
+
+		let res = rt.block_on(future::lazy(move || {
+			Race::new(
+				addresses,
+				Duration::from_secs(10),
+				logger,
+				connect,
+				join,
+				|_, _| {},
+			)
+		}));
+		assert!(res.is_err());
+	}
+
+	/// A resolver which keeps returning errors does not block the race.
+	#[test]
+	fn race_resolve_error() {
+		let mut rt = Runtime::new().unwrap();
+		let addresses = stream::once::<SocketAddr, Error>(Ok("2.2.2.2:2"
+			.parse()
+			.unwrap()))
+		.chain(stream::poll_fn(|| -> Poll<Option<SocketAddr>, Error> {
+			Err(format_err!("Failed").into())
+		}));
+		let logger = Logger::root(Discard, o!());
+
+		let (addr, _) = rt
+			.block_on(future::lazy(move || {
+				Race::new(
+					addresses,
+					Duration::from_secs(10),
+					logger,
+					connect,
+					join,
+					|_, _| {},
+				)
+			}))
+			.unwrap();
+		assert_eq!(addr, "2.2.2.2:2".parse().unwrap());
+	}
+
+	/// If the winner fails to join, the next successful attempt joins.
+	#[test]
+	fn race_join_failed() {
+		let mut rt = Runtime::new().unwrap();
+		let addresses = stream::iter_ok::<_, Error>(vec![
+			"[::1]:1".parse().unwrap(),
+			"127.0.0.1:2".parse().unwrap(),
+			"[::1]:3".parse().unwrap(),
+		]);
+		let logger = Logger::root(Discard, o!());
+		let joined = Arc::new(Mutex::new(Vec::new()));
+
+		let joined2 = joined.clone();
+		let join = move |addr: SocketAddr, port: u16| -> BoxFuture<u16> {
+			joined2.lock().push(addr);
+			if port == 1 {
+				Box::new(future::err(format_err!("No initserver").into()))
+			} else {
+				Box::new(future::ok(port))
+			}
+		};
+		let (addr, port) = rt
+			.block_on(future::lazy(move || {
+				Race::new(
+					addresses,
+					Duration::from_secs(10),
+					logger,
+					|addr: SocketAddr| -> BoxFuture<u16> {
+						Box::new(future::ok(addr.port()))
+					},
+					join,
+					|_, _| panic!("No other attempt succeeded"),
+				)
+			}))
+			.unwrap();
+		assert_eq!(addr, "127.0.0.1:2".parse().unwrap());
+		assert_eq!(port, 2);
+		assert_eq!(*joined.lock(), vec![
+			"[::1]:1".parse::<SocketAddr>().unwrap(),
+			"127.0.0.1:2".parse().unwrap(),
+		]);
+	}
+
+	/// A too low identity level is not retried on other addresses.
+	#[test]
+	fn race_join_identity_level() {
+		let mut rt = Runtime::new().unwrap();
+		let addresses = stream::iter_ok::<_, Error>(vec![
+			"[::1]:1".parse().unwrap(),
+			"127.0.0.1:2".parse().unwrap(),
+		]);
+		let logger = Logger::root(Discard, o!());
+		let cancelled = Arc::new(AtomicUsize::new(0));
+
+		let cancelled2 = cancelled.clone();
+		let res = rt.block_on(future::lazy(move || {
+			Race::new(
+				addresses,
+				Duration::from_millis(0),
+				logger,
+				|addr: SocketAddr| -> BoxFuture<u16> {
+					Box::new(future::ok(addr.port()))
+				},
+				|_, _| -> BoxFuture<u16> {
+					Box::new(future::err(Error::IdentityLevelTooLow(8)))
+				},
+				move |_, _| {
+					cancelled2.fetch_add(1, Ordering::SeqCst);
+				},
+			)
+		}));
+		match res {
+			Err(Error::IdentityLevelTooLow(8)) => {}
+			r => panic!("Expected identity level error, got {:?}", r),
+		}
+		// The other attempt gets disconnected
+		assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+	}
+}
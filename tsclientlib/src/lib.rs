@@ -14,31 +14,41 @@
 
 use std::collections::HashMap;
 use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
 use std::net::SocketAddr;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::Utc;
 use derive_more::From;
 use failure::{format_err, Fail, ResultExt};
 use futures::sync::oneshot;
 use futures::{future, stream, Future, Sink, Stream};
+use num_traits::FromPrimitive;
 use parking_lot::{RwLock, RwLockReadGuard};
 use slog::{debug, info, o, warn, Drain, Logger};
-use tsproto::algorithms as algs;
+use tsproto::algorithms::{HashCashHandle, HashCashProgress, HashCashSearch};
+use tsproto::connection::ProtocolConfig;
 use tsproto::connectionmanager::ConnectionManager;
 use tsproto::connectionmanager::Resender;
 use tsproto::handler_data::{ConnectionListener, ConnectionValue};
 use tsproto_packets::packets::{
 	Direction, InAudio, InCommand, OutCommand, OutPacket, PacketType,
 };
-use tsproto::{client, crypto, log};
+use tsproto::{client, crypto, license, log, pcap};
 #[cfg(feature = "audio")]
 use tsproto_audio::ts_to_audio::AudioPacketHandler;
 use ts_bookkeeping::messages::s2c::{InMessage, InMessages};
 
+use crate::command_queue::{CommandQueue, CommandQueueHandle};
 use crate::packet_handler::{ReturnCodeHandler, SimplePacketHandler};
 
+mod command_queue;
 mod facades;
+mod happy_eyeballs;
 mod packet_handler;
 pub mod resolver;
 
@@ -53,6 +63,14 @@ mod tests;
 
 // Reexports
 pub use ts_bookkeeping::*;
+pub use tsproto::connection::Identity;
+
+pub use crate::command_queue::{AntifloodConfig, CommandPriority};
+
+/// The minimum identity level, which is needed to connect.
+const MIN_IDENTITY_LEVEL: u8 = 8;
+/// How often the progress of an identity upgrade is reported.
+const UPGRADE_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
 type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
 type Result<T> = std::result::Result<T, Error>;
@@ -88,6 +106,10 @@ pub enum Error {
 
 	#[fail(display = "Connection failed ({})", _0)]
 	ConnectionFailed(String),
+	/// The server rejected the identity because its level is too low. The
+	/// required level is stored.
+	#[fail(display = "The server requires an identity level of {}", _0)]
+	IdentityLevelTooLow(u8),
 
 	#[doc(hidden)]
 	#[fail(display = "Not an error – non exhaustive enum")]
@@ -132,7 +154,11 @@ struct InnerConnection {
 	connection: Arc<RwLock<data::Connection>>,
 	client_data: client::ClientDataM<SimplePacketHandler>,
 	client_connection: client::ClientConVal,
+	/// The address which won the race of connection attempts.
+	server_address: SocketAddr,
+	identity: Identity,
 	return_code_handler: Arc<ReturnCodeHandler>,
+	command_queue: CommandQueueHandle,
 	event_listeners: Arc<RwLock<HashMap<String, EventListener>>>,
 }
 
@@ -224,23 +250,47 @@ impl Connection {
 
 		let logger = logger.new(o!("addr" => options.address.to_string()));
 
-		// Try all addresses
-		let addr: Box<Stream<Item = _, Error = _> + Send> =
-			options.address.resolve(&logger);
-		let private_key =
-			match options.private_key.take().map(Ok).unwrap_or_else(|| {
+		let identity =
+			match options.identity.take().map(Ok).unwrap_or_else(|| {
 				// Create new ECDH key
-				crypto::EccKeyPrivP256::create()
+				crypto::EccKeyPrivP256::create().map(|k| Identity::new(k, 0))
 			}) {
-				Ok(key) => key,
+				Ok(identity) => identity,
 				Err(e) => return Box::new(future::err(e.into())),
 			};
 
+		// Open the packet captures once for all connection attempts
+		let captures = match PacketCaptures::new(&options) {
+			Ok(captures) => captures,
+			Err(e) => return Box::new(future::err(e)),
+		};
+
 		// Make options clonable
-		let options = Arc::new(options);
+		Self::connect(Arc::new(options), identity, captures, logger)
+	}
+
+	/// Connect with the given identity and upgrade it, if the server requires
+	/// a higher level.
+	fn connect(
+		options: Arc<ConnectOptions>,
+		identity: Identity,
+		captures: PacketCaptures,
+		logger: Logger,
+	) -> BoxFuture<Connection>
+	{
+		// Try all addresses
+		let addr: Box<Stream<Item = _, Error = _> + Send> =
+			options.address.resolve(&logger);
+		let options2 = options.clone();
+		let identity2 = identity.clone();
+		let captures2 = captures.clone();
 		let logger2 = logger.clone();
+		let logger3 = logger.clone();
 		Box::new(
-			addr.and_then(
+			happy_eyeballs::Race::new(
+				addr,
+				options.connection_attempt_delay,
+				logger2,
 				move |addr| -> Box<Future<Item = _, Error = _> + Send> {
 					let (initserver_send, initserver_recv) = oneshot::channel();
 					let (connection_send, connection_recv) = oneshot::channel();
@@ -264,8 +314,9 @@ impl Connection {
 								"[::]:0".parse().unwrap()
 							}
 						}),
-						private_key.clone(),
+						identity.key().clone(),
 						packet_handler,
+						options.protocol_config.clone(),
 						logger.clone(),
 					) {
 						Ok(client) => client,
@@ -287,16 +338,23 @@ impl Connection {
 						if options.log_udp_packets {
 							log::add_udp_packet_logger(c);
 						}
+
+						// Packet captures
+						if let Some(writer) = &captures.udp_packets {
+							pcap::add_shared_udp_pcap_writer(c, writer.clone());
+						}
+						if let Some(writer) = &captures.commands {
+							pcap::add_shared_command_pcap_writer(
+								c,
+								writer.clone(),
+							);
+						}
 					}
 
 					if let Some(prepare_client) = &options.prepare_client {
 						prepare_client(&client);
 					}
 
-					let client = client.clone();
-					let client2 = client.clone();
-					let options = options.clone();
-
 					// Create a connection
 					debug!(logger, "Connecting"; "address" => %addr);
 					let connect_fut = client::connect(
@@ -306,167 +364,111 @@ impl Connection {
 					)
 					.from_err();
 
-					let initserver_poll = initserver_recv
-						.map_err(|e| {
-							format_err!(
-								"Error while waiting for initserver ({:?})",
-								e
-							)
-							.into()
-						})
-						.and_then(move |cmd| {
-							let msg =
-								InMessage::new(cmd).map_err(|(_, e)| e)?;
-							if let InMessages::InitServer(_) = msg.msg() {
-								Ok(msg)
-							} else {
-								Err(Error::ConnectionFailed(
-									format!("Got no initserver but {:?}", msg)
-								))
-							}
-						});
-
-					Box::new(
-						connect_fut
-				.and_then(move |con| -> Box<Future<Item=_, Error=_> + Send> {
-					let logger = {
-						let mutex = match con.upgrade().ok_or_else(||
-							format_err!("Connection does not exist anymore")) {
-							Ok(r) => r.mutex,
-							Err(e) => return Box::new(future::err(e.into())),
-						};
-						let con = mutex.lock();
-						con.1.logger.clone()
-					};
-					// TODO Add possibility to specify offset and level in ConnectOptions
-					// Compute hash cash
-					let mut time_reporter = slog_perf::TimeReporter::new_with_level(
-						"Compute public key hash cash level", logger.clone(),
-						slog::Level::Info);
-					time_reporter.start("Compute public key hash cash level");
-					let pub_k = {
-						let c = client.lock();
-						c.private_key.to_pub()
-					};
-
-					let (send, recv) = oneshot::channel();
-					std::thread::spawn(move || {
-						let _ = send.send((
-							con.clone(),
-							algs::hash_cash(&pub_k, 8).unwrap(),
-							pub_k.to_ts().unwrap(),
-							logger.clone(),
-						));
-					});
-
-					Box::new(recv.map(|r| { time_reporter.finish(); r })
-						.map_err(|e| format_err!("Failed to compute \
-							identity level ({:?})", e).into()))
-				})
-				.and_then(move |(con, offset, omega, logger)| {
-					info!(logger, "Computed hash cash level";
-						"level" => algs::get_hash_cash_level(&omega, offset),
-						"offset" => offset);
-
-					// Create clientinit packet
-					let version_string = options.version.get_version_string();
-					let version_platform = options.version.get_platform();
-					let version_sign = base64::encode(options.version.get_signature());
-					let offset = offset.to_string();
-
-					let mut args = vec![
-						("client_nickname", options.name.as_str()),
-						("client_version", &version_string),
-						("client_platform", &version_platform),
-						("client_input_hardware", "1"),
-						("client_output_hardware", "1"),
-						("client_server_password", ""),
-						("client_meta_data", ""),
-						("client_version_sign", &version_sign),
-						("client_nickname_phonetic", ""),
-						("client_key_offset", &offset),
-						("client_default_token", ""),
-						("hwid", "923f136fb1e22ae6ce95e60255529c00,d13231b1bc33edfecfb9169cc7a63bcc"),
-					];
-
-					if let Some(channel) = &options.channel {
-						args.push(("client_default_channel", channel));
-					}
-					
-					args.push(("client_default_channel_password", "xxxxxxx"));
-
-					let packet = OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
-						Direction::C2S,
-						PacketType::Command,
-						"clientinit",
-						args.into_iter(),
-						std::iter::empty(),
-					);
-
-					let sink = con.as_packet_sink();
-					sink.send(packet).map(move |_| con)
-				})
-				.from_err()
-				// Wait until we sent the clientinit packet and afterwards received
-				// the initserver packet.
-				.and_then(move |con| initserver_poll.map(|r| (con, r)))
-				.and_then(move |(con, initserver)| {
-					// Get uid of server
-					let uid = {
-						let mutex = con.upgrade().ok_or_else(||
-							format_err!("Connection does not exist anymore"))?
-							.mutex;
-						let con = mutex.lock();
-						con.1.params.as_ref().ok_or_else(||
-							format_err!("Connection params do not exist"))?
-							.public_key.get_uid()?
-					};
-
-					// Create connection
-					let data = data::Connection::new(Uid(uid), &initserver);
-					let con = InnerConnection {
-						connection: Arc::new(RwLock::new(data)),
-						client_data: client2,
-						client_connection: con,
+					// Only the low-level handshake is raced. The clientinit is
+					// sent only for the winner, otherwise every successful
+					// attempt would join the server as a client.
+					let options = options.clone();
+					let identity = identity.clone();
+					let logger = logger.clone();
+					Box::new(connect_fut.map(move |con| ConnectedAttempt {
+						options,
+						identity,
+						address: addr,
+						client,
+						con,
+						initserver_recv,
+						connection_send,
 						return_code_handler,
-						event_listeners: Arc::new(RwLock::new(HashMap::new())),
-					};
-
-					// Send connection to packet handler
-					let con = Connection { inner: con };
-					connection_send.send(con.clone()).map_err(|_|
-						format_err!("Failed to send connection to packet \
-							handler"))?;
-
-					Ok(con)
-				}),
-					)
+						logger,
+					}))
 				},
+				|_, attempt: ConnectedAttempt| attempt.join(),
+				|_, attempt: ConnectedAttempt| attempt.disconnect(),
 			)
-			.then(move |r| -> Result<_> {
-				if let Err(e) = &r {
-					debug!(logger2, "Connecting failed, trying next address";
-					"error" => ?e);
+			.map(|(_, con)| con)
+			.or_else(move |e| -> BoxFuture<_> {
+				let level = match e {
+					Error::IdentityLevelTooLow(level)
+						if options2.upgrade_identity =>
+					{
+						level
+					}
+					e => return Box::new(future::err(e)),
+				};
+				let mut identity = identity2;
+				match identity.level() {
+					// Do not try again if the level does not change
+					Ok(cur) if cur >= level => {
+						return Box::new(future::err(
+							Error::IdentityLevelTooLow(level),
+						));
+					}
+					Ok(_) => {}
+					Err(e) => return Box::new(future::err(e.into())),
 				}
-				Ok(r.ok())
-			})
-			.filter_map(|r| r)
-			.into_future()
-			.map_err(|_| {
-				Error::from(format_err!("Failed to connect to server"))
-			})
-			.and_then(|(r, _)| {
-				r.ok_or_else(|| {
-					format_err!("Failed to connect to server").into()
-				})
+				info!(logger3, "Upgrading identity"; "level" => level);
+				let search = match identity.upgrade_level_parallel(level) {
+					Ok(r) => r,
+					Err(e) => return Box::new(future::err(e.into())),
+				};
+				let search = Self::report_upgrade_progress(&options2, search);
+				Box::new(search.and_then(move |offset| {
+					identity.set_counter(offset);
+					Self::connect(options2, identity, captures2, logger3)
+				}))
 			}),
 		)
 	}
 
+	/// Call the [`ConnectOptions::identity_upgrade_progress`] callback
+	/// regularly until the search finished.
+	///
+	/// [`ConnectOptions::identity_upgrade_progress`]: struct.ConnectOptions.html#method.identity_upgrade_progress
+	fn report_upgrade_progress(
+		options: &Arc<ConnectOptions>,
+		search: HashCashSearch,
+	) -> BoxFuture<u64>
+	{
+		if options.identity_upgrade_progress.is_none() {
+			return Box::new(search.from_err());
+		}
+		let handle = search.handle();
+		let options = options.clone();
+		let progress = search
+			.handle()
+			.progress_stream(UPGRADE_PROGRESS_INTERVAL)
+			.for_each(move |progress| {
+				if let Some(f) = &options.identity_upgrade_progress {
+					f(&handle, progress);
+				}
+				Ok(())
+			});
+
+		Box::new(search.select2(progress).then(|r| -> BoxFuture<u64> {
+			match r {
+				Ok(future::Either::A((offset, _))) => {
+					Box::new(future::ok(offset))
+				}
+				Err(future::Either::A((e, _))) => {
+					Box::new(future::err(e.into()))
+				}
+				// The progress stream ends when the search stopped, it only
+				// fails if the timer fails. Wait for the search in both cases.
+				Ok(future::Either::B((_, search)))
+				| Err(future::Either::B((_, search))) => {
+					Box::new(search.from_err())
+				}
+			}
+		}))
+	}
+
 	/// **This is part of the unstable interface.**
 	///
 	/// You can use it if you need access to lower level functions, but this
 	/// interface may change on any version changes.
+	///
+	/// Packets which are sent through this sink bypass the command queue, so
+	/// they are not delayed by the flood protection.
 	pub fn get_packet_sink(
 		&self,
 	) -> impl Sink<SinkItem = OutPacket, SinkError = Error> {
@@ -490,6 +492,41 @@ impl Connection {
 			.sink_map_err(|e| e.into())
 	}
 
+	/// The identity which is used for this connection.
+	///
+	/// If the identity was upgraded while connecting, it contains the new
+	/// counter and should be stored.
+	pub fn get_identity(&self) -> &Identity { &self.inner.identity }
+
+	/// The address of the server which we are connected to.
+	///
+	/// If the server address resolved to multiple addresses, this is the one
+	/// where the connection attempt succeeded first.
+	pub fn get_server_address(&self) -> SocketAddr {
+		self.inner.server_address
+	}
+
+	/// Use a new local socket for this connection.
+	///
+	/// This is useful if the local network changed, e.g. when switching to a
+	/// different network. Commands which were not acknowledged by the server
+	/// yet are sent again over the new socket, so the connection stays alive.
+	///
+	/// # Default
+	///
+	/// If no local address is given, a random port on all interfaces is used.
+	pub fn rebind(&self, local_address: Option<SocketAddr>) -> Result<()> {
+		let local_address = match local_address {
+			Some(a) => a,
+			None if self.get_server_address().is_ipv4() => {
+				"0.0.0.0:0".parse().unwrap()
+			}
+			None => "[::]:0".parse().unwrap(),
+		};
+		self.inner.client_data.lock().rebind(local_address)?;
+		Ok(())
+	}
+
 	/// **This is part of the unstable interface.**
 	///
 	/// You can use it if you need access to lower level functions, but this
@@ -505,10 +542,37 @@ impl Connection {
 	///
 	/// Adds a `return_code` to the command and returns if the corresponding
 	/// answer is received. If an error occurs, the future will return an error.
+	///
+	/// The command is sent with a normal priority, see
+	/// [`send_packet_with_priority`].
+	///
+	/// [`send_packet_with_priority`]: #method.send_packet_with_priority
 	#[must_use = "futures do nothing unless polled"]
 	pub fn send_packet(
+		&self,
+		packet: OutPacket,
+	) -> impl Future<Item = (), Error = Error> + Send + 'static
+	{
+		self.send_packet_with_priority(packet, CommandPriority::Normal)
+	}
+
+	/// **This is part of the unstable interface.**
+	///
+	/// You can use it if you need access to lower level functions, but this
+	/// interface may change on any version changes.
+	///
+	/// Like [`send_packet`], but the command is put into the command queue
+	/// with the given priority. The queue delays commands, so the flood
+	/// protection of the server is not triggered. Commands with a low
+	/// priority are sent as `CommandLow` packets after all waiting commands
+	/// with a normal priority.
+	///
+	/// [`send_packet`]: #method.send_packet
+	#[must_use = "futures do nothing unless polled"]
+	pub fn send_packet_with_priority(
 		&self,
 		mut packet: OutPacket,
+		priority: CommandPriority,
 	) -> impl Future<Item = (), Error = Error> + Send + 'static
 	{
 		// Store waiting in HashMap<usize (return code), oneshot::Sender>
@@ -523,10 +587,24 @@ impl Connection {
 		packet
 			.data_mut()
 			.extend_from_slice(code.to_string().as_bytes());
+		if priority == CommandPriority::Low {
+			packet.packet_type(PacketType::CommandLow);
+		}
+
+		// Wait until the command queue allows to send the command
+		let name = packet
+			.content()
+			.split(|b| *b == b' ')
+			.next()
+			.map(|n| String::from_utf8_lossy(n).into_owned())
+			.unwrap_or_default();
+		let permit = self.inner.command_queue.enqueue(name, priority);
+		let sink = self.get_packet_sink();
 
 		// Send a message and wait until we get an answer for the return code
-		self.get_packet_sink()
-			.send(packet)
+		permit
+			.map_err(|_| Error::from(format_err!("The command queue is gone")))
+			.and_then(move |_| sink.send(packet))
 			.and_then(|_| recv.from_err())
 			.and_then(|r| {
 				if r == TsError::Ok {
@@ -537,12 +615,43 @@ impl Connection {
 			})
 	}
 
+	/// The current round trip time statistics of the connection.
+	///
+	/// The client sends a ping every second. This returns `None` if no ping
+	/// was answered so far.
+	pub fn get_ping_stats(&self) -> Option<tsproto::connection::PingStats> {
+		self.inner
+			.client_connection
+			.upgrade()
+			.and_then(|con| con.ping_stats())
+	}
+
+	/// A snapshot of the traffic and packet loss statistics of the
+	/// connection.
+	///
+	/// This returns `None` if the connection is already gone.
+	pub fn get_stats(&self) -> Option<tsproto::connection::ConnectionStats> {
+		self.inner.client_connection.upgrade().map(|con| con.stats())
+	}
+
+	/// If voice packets in the given channel have to be encrypted.
+	///
+	/// This depends on the codec encryption mode of the server and the
+	/// settings of the channel. Voice packets which are sent by this client
+	/// are encrypted, if this is `true` for the current channel of the client.
+	pub fn is_voice_encrypted(&self, channel: ChannelId) -> bool {
+		packet_handler::is_voice_encrypted(&self.lock(), channel)
+	}
+
 	pub fn lock(&self) -> ConnectionLock {
 		ConnectionLock::new(self.clone(), self.inner.connection.read())
 	}
 
 	/// Disconnect from the server.
 	///
+	/// The disconnect command is sent immediately, it does not wait in the
+	/// command queue.
+	///
 	/// # Arguments
 	/// - `options`: Either `None` or `DisconnectOptions`.
 	///
@@ -743,6 +852,338 @@ impl<'a> ConnectionLock<'a> {
 	}
 }
 
+/// The packet capture files of a connection.
+///
+/// The files are opened once and shared by all connection attempts, so they
+/// contain the packets of all attempts.
+#[derive(Clone, Default)]
+struct PacketCaptures {
+	udp_packets: Option<pcap::SharedWriter>,
+	commands: Option<pcap::SharedWriter>,
+}
+
+impl PacketCaptures {
+	fn new(options: &ConnectOptions) -> Result<Self> {
+		let mut res = Self::default();
+		if let Some(path) = &options.pcap_udp_packets {
+			let file = BufWriter::new(File::create(path)?);
+			res.udp_packets = Some(pcap::new_udp_writer(file)?);
+		}
+		if let Some(path) = &options.pcap_commands {
+			let file = BufWriter::new(File::create(path)?);
+			res.commands = Some(pcap::new_command_writer(file)?);
+		}
+		Ok(res)
+	}
+}
+
+/// A connection attempt which finished the low-level handshake.
+///
+/// The client did not join the server yet, this happens in [`join`] for the
+/// attempt which won the race.
+///
+/// [`join`]: #method.join
+struct ConnectedAttempt {
+	options: Arc<ConnectOptions>,
+	identity: Identity,
+	address: SocketAddr,
+	client: client::ClientDataM<SimplePacketHandler>,
+	con: client::ClientConVal,
+	initserver_recv: oneshot::Receiver<InCommand>,
+	connection_send: oneshot::Sender<Connection>,
+	return_code_handler: Arc<ReturnCodeHandler>,
+	logger: Logger,
+}
+
+impl ConnectedAttempt {
+	/// Join the server by sending `clientinit`.
+	///
+	/// The identity level is increased to the minimum level first.
+	fn join(self) -> BoxFuture<Connection> {
+		let ConnectedAttempt {
+			options,
+			identity,
+			address,
+			client,
+			con,
+			initserver_recv,
+			connection_send,
+			return_code_handler,
+			logger,
+		} = self;
+
+		// Wait until we sent the clientinit packet and afterwards received
+		// the initserver packet.
+		let initserver_poll = initserver_recv
+			.map_err(|e| {
+				format_err!("Error while waiting for initserver ({:?})", e)
+					.into()
+			})
+			.and_then(move |cmd| {
+				check_clientinit_error(&cmd)?;
+				let msg = InMessage::new(cmd).map_err(|(_, e)| e)?;
+				if let InMessages::InitServer(_) = msg.msg() {
+					Ok(msg)
+				} else {
+					Err(Error::ConnectionFailed(format!(
+						"Got no initserver but {:?}",
+						msg
+					)))
+				}
+			});
+
+		let logger2 = logger.clone();
+		Box::new(
+			Self::upgrade_identity(identity, logger.clone())
+				.and_then(move |identity| {
+					let packet = Self::create_clientinit(&options, &identity);
+					let sink = con.as_packet_sink();
+					sink.send(packet)
+						.map(move |_| (con, identity, options))
+						.from_err()
+				})
+				.and_then(move |(con, identity, options)| {
+					initserver_poll
+						.map(move |initserver| (con, identity, options, initserver))
+				})
+				.and_then(move |(con, identity, options, initserver)| {
+					// Get uid and license of server
+					let (uid, license) = {
+						let mutex = con
+							.upgrade()
+							.ok_or_else(|| {
+								format_err!("Connection does not exist anymore")
+							})?
+							.mutex;
+						let con = mutex.lock();
+						let params = con.1.params.as_ref().ok_or_else(|| {
+							format_err!("Connection params do not exist")
+						})?;
+						let license = params
+							.licenses
+							.as_ref()
+							.and_then(|l| {
+								let res = l.verify(Utc::now());
+								if res.is_valid() {
+									res.license_type
+								} else {
+									warn!(con.1.logger, "Invalid server license";
+										"errors" => ?res.errors);
+									None
+								}
+							})
+							.map(convert_license_type);
+						(params.public_key.get_uid()?, license)
+					};
+
+					// Create connection
+					let data =
+						data::Connection::new(Uid(uid), license, &initserver);
+					let connection = Arc::new(RwLock::new(data));
+					let (queue, command_queue) = CommandQueue::new(
+						logger.clone(),
+						options.antiflood.clone(),
+						connection.clone(),
+					);
+					tokio::spawn(queue);
+					let con = InnerConnection {
+						connection,
+						client_data: client,
+						client_connection: con,
+						server_address: address,
+						identity,
+						return_code_handler,
+						command_queue,
+						event_listeners: Arc::new(RwLock::new(HashMap::new())),
+					};
+
+					// Send connection to packet handler
+					let con = Connection { inner: con };
+					connection_send.send(con.clone()).map_err(|_| {
+						format_err!("Failed to send connection to packet handler")
+					})?;
+
+					// The command queue uses the antiflood settings from the
+					// connection info of the server
+					let packet =
+						con.inner.connection.read().server.get_connection_info();
+					tokio::spawn(con.send_packet(packet).map_err(move |e| {
+						warn!(logger2, "Failed to request server connection \
+							info"; "error" => ?e)
+					}));
+
+					Ok(con)
+				}),
+		)
+	}
+
+	/// Compute the minimum identity level if the identity has a lower level.
+	fn upgrade_identity(
+		mut identity: Identity,
+		logger: Logger,
+	) -> BoxFuture<Identity>
+	{
+		match identity.level() {
+			Ok(level) if level >= MIN_IDENTITY_LEVEL => {
+				return Box::new(future::ok(identity));
+			}
+			Ok(_) => {}
+			Err(e) => return Box::new(future::err(e.into())),
+		}
+
+		// Compute hash cash
+		let mut time_reporter = slog_perf::TimeReporter::new_with_level(
+			"Compute public key hash cash level",
+			logger.clone(),
+			slog::Level::Info,
+		);
+		time_reporter.start("Compute public key hash cash level");
+		let search = match identity.upgrade_level_parallel(MIN_IDENTITY_LEVEL)
+		{
+			Ok(r) => r,
+			Err(e) => return Box::new(future::err(e.into())),
+		};
+
+		Box::new(
+			search
+				.map(move |offset| {
+					time_reporter.finish();
+					identity.set_counter(offset);
+					info!(logger, "Computed hash cash level";
+						"level" => MIN_IDENTITY_LEVEL, "offset" => offset);
+					identity
+				})
+				.map_err(|e| {
+					format_err!("Failed to compute identity level ({:?})", e)
+						.into()
+				}),
+		)
+	}
+
+	/// Create the `clientinit` packet.
+	fn create_clientinit(
+		options: &ConnectOptions,
+		identity: &Identity,
+	) -> OutPacket
+	{
+		let version_string = options.version.get_version_string();
+		let version_platform = options.version.get_platform();
+		let version_sign = base64::encode(options.version.get_signature());
+		let offset = identity.counter().to_string();
+
+		let mut args = vec![
+			("client_nickname", options.name.as_str()),
+			("client_version", &version_string),
+			("client_platform", &version_platform),
+			("client_input_hardware", "1"),
+			("client_output_hardware", "1"),
+			("client_server_password", ""),
+			("client_meta_data", ""),
+			("client_version_sign", &version_sign),
+			("client_nickname_phonetic", ""),
+			("client_key_offset", &offset),
+			("client_default_token", ""),
+			("hwid", "923f136fb1e22ae6ce95e60255529c00,d13231b1bc33edfecfb9169cc7a63bcc"),
+		];
+
+		if let Some(channel) = &options.channel {
+			args.push(("client_default_channel", channel));
+		}
+
+		args.push(("client_default_channel_password", "xxxxxxx"));
+
+		OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"clientinit",
+			args.into_iter(),
+			std::iter::empty(),
+		)
+	}
+
+	/// Leave the server with an attempt which lost the race.
+	///
+	/// The attempt finished the handshake, so the server would keep the
+	/// connection until it times out.
+	fn disconnect(self) {
+		let reason = (Reason::Clientdisconnect as u8).to_string();
+		let packet =
+			OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+				Direction::C2S,
+				PacketType::Command,
+				"clientdisconnect",
+				std::iter::once(("reasonid", reason.as_str())),
+				std::iter::empty(),
+			);
+		let wait = self.client.lock().wait_for_disconnect(self.address);
+		let client = self.client;
+		let logger = self.logger;
+		let address = self.address;
+		debug!(logger, "Disconnecting lost connection attempt";
+			"address" => %address);
+		tokio::spawn(
+			self.con
+				.as_packet_sink()
+				.send(packet)
+				.and_then(|_| wait)
+				// Keep the client alive until the server acknowledged it
+				.map(move |_| drop(client))
+				.map_err(move |e| {
+					debug!(logger, "Failed to disconnect lost connection \
+						attempt"; "address" => %address, "error" => ?e)
+				}),
+		);
+	}
+}
+
+/// Check if the server answered `clientinit` with an error.
+///
+/// If the identity level is too low, the required level is returned in an
+/// [`Error::IdentityLevelTooLow`].
+///
+/// [`Error::IdentityLevelTooLow`]: enum.Error.html#variant.IdentityLevelTooLow
+fn check_clientinit_error(cmd: &InCommand) -> Result<()> {
+	if cmd.name() != "error" {
+		return Ok(());
+	}
+	let cmd = cmd
+		.iter()
+		.next()
+		.ok_or_else(|| format_err!("Empty error command"))?;
+	let error = cmd
+		.get("id")
+		.and_then(|id| id.parse().ok())
+		.and_then(TsError::from_u32)
+		.ok_or_else(|| format_err!("Got an invalid error as answer"))?;
+	match error {
+		TsError::Ok => Ok(()),
+		TsError::ClientCouldNotValidateIdentity => {
+			let level = cmd
+				.get("extra_msg")
+				.and_then(|l| l.parse().ok())
+				.ok_or_else(|| format_err!("Got no required identity level"))?;
+			Err(Error::IdentityLevelTooLow(level))
+		}
+		e => Err(e.into()),
+	}
+}
+
+/// Convert the type of a server license block to the license type which is
+/// shown to users.
+fn convert_license_type(license_type: license::LicenseType) -> LicenseType {
+	match license_type {
+		license::LicenseType::None | license::LicenseType::Default => {
+			LicenseType::NoLicense
+		}
+		license::LicenseType::Offline => LicenseType::Lan,
+		license::LicenseType::Npl => LicenseType::Npl,
+		license::LicenseType::Athp => LicenseType::Athp,
+		license::LicenseType::Sdk
+		| license::LicenseType::SdkOffline
+		| license::LicenseType::Aal => LicenseType::Unknown,
+	}
+}
+
 trait ServerAddressExt {
 	fn resolve(&self, logger: &Logger) -> Box<Stream<Item = SocketAddr, Error = Error> + Send>;
 }
@@ -784,7 +1225,11 @@ impl ServerAddressExt for ServerAddress {
 pub struct ConnectOptions {
 	address: ServerAddress,
 	local_address: Option<SocketAddr>,
-	private_key: Option<crypto::EccKeyPrivP256>,
+	connection_attempt_delay: Duration,
+	identity: Option<Identity>,
+	upgrade_identity: bool,
+	identity_upgrade_progress:
+		Option<Box<Fn(&HashCashHandle, HashCashProgress) + Send + Sync>>,
 	name: String,
 	version: Version,
 	channel: Option<String>,
@@ -793,6 +1238,10 @@ pub struct ConnectOptions {
 	log_commands: bool,
 	log_packets: bool,
 	log_udp_packets: bool,
+	pcap_udp_packets: Option<PathBuf>,
+	pcap_commands: Option<PathBuf>,
+	protocol_config: ProtocolConfig,
+	antiflood: AntifloodConfig,
 	#[cfg(feature = "audio")]
 	audio_packet_handler: Option<AudioPacketHandler>,
 	handle_packets: Option<PHBox>,
@@ -818,7 +1267,10 @@ impl ConnectOptions {
 		Self {
 			address: address.into(),
 			local_address: None,
-			private_key: None,
+			connection_attempt_delay: Duration::from_millis(250),
+			identity: None,
+			upgrade_identity: false,
+			identity_upgrade_progress: None,
 			name: String::from("TeamSpeakUser"),
 			version: Version::Linux_3_3_0__3,
 			channel: None,
@@ -827,6 +1279,10 @@ impl ConnectOptions {
 			log_commands: false,
 			log_packets: false,
 			log_udp_packets: false,
+			pcap_udp_packets: None,
+			pcap_commands: None,
+			protocol_config: ProtocolConfig::default(),
+			antiflood: AntifloodConfig::default(),
 			#[cfg(feature = "audio")]
 			audio_packet_handler: None,
 			handle_packets: None,
@@ -845,13 +1301,90 @@ impl ConnectOptions {
 		self
 	}
 
+	/// The time to wait before trying the next address of the server.
+	///
+	/// If the server address resolves to multiple addresses, they are tried
+	/// alternating between IPv6 and IPv4. A new attempt is started after this
+	/// delay or as soon as the previous attempt failed. The first successful
+	/// connection wins and all other attempts are cancelled.
+	///
+	/// # Default
+	/// 250 ms
+	#[inline]
+	pub fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+		self.connection_attempt_delay = delay;
+		self
+	}
+
 	/// Set the private key of the user.
 	///
+	/// The key offset for the identity level is computed when connecting.
+	///
 	/// # Default
 	/// A new identity is generated when connecting.
 	#[inline]
 	pub fn private_key(mut self, private_key: crypto::EccKeyPrivP256) -> Self {
-		self.private_key = Some(private_key);
+		self.identity = Some(Identity::new(private_key, 0));
+		self
+	}
+
+	/// Set the identity of the user, which contains the private key and the
+	/// key offset.
+	///
+	/// If the level of the identity is lower than 8, it is upgraded to level
+	/// 8 when connecting. The used identity can be retrieved with
+	/// [`Connection::get_identity`].
+	///
+	/// # Default
+	/// A new identity is generated when connecting.
+	///
+	/// [`Connection::get_identity`]: struct.Connection.html#method.get_identity
+	#[inline]
+	pub fn identity(mut self, identity: Identity) -> Self {
+		self.identity = Some(identity);
+		self
+	}
+
+	/// Upgrade the identity and reconnect, if the server rejects it because
+	/// the identity level is too low.
+	///
+	/// The upgraded identity can be retrieved with
+	/// [`Connection::get_identity`] and should be stored, so the next
+	/// connection does not need to upgrade it again. This can take a long
+	/// time for high levels.
+	///
+	/// # Default
+	/// `false`, the connection fails with
+	/// [`Error::IdentityLevelTooLow`].
+	///
+	/// [`Connection::get_identity`]: struct.Connection.html#method.get_identity
+	/// [`Error::IdentityLevelTooLow`]: enum.Error.html#variant.IdentityLevelTooLow
+	#[inline]
+	pub fn upgrade_identity(mut self, upgrade_identity: bool) -> Self {
+		self.upgrade_identity = upgrade_identity;
+		self
+	}
+
+	/// Get notified about the progress of an identity upgrade, which is
+	/// started because of [`upgrade_identity`].
+	///
+	/// The given function is called about once per second while the upgrade
+	/// runs. The handle can be used to cancel the upgrade, the connection
+	/// then fails with an error.
+	///
+	/// # Default
+	/// The progress is not reported.
+	///
+	/// [`upgrade_identity`]: #method.upgrade_identity
+	#[inline]
+	pub fn identity_upgrade_progress(
+		mut self,
+		identity_upgrade_progress: Box<
+			Fn(&HashCashHandle, HashCashProgress) + Send + Sync,
+		>,
+	) -> Self
+	{
+		self.identity_upgrade_progress = Some(identity_upgrade_progress);
 		self
 	}
 
@@ -865,8 +1398,8 @@ impl ConnectOptions {
 	/// An error is returned if the string cannot be decoded.
 	#[inline]
 	pub fn private_key_str(mut self, private_key: &str) -> Result<Self> {
-		self.private_key =
-			Some(crypto::EccKeyPrivP256::import_str(private_key)?);
+		let key = crypto::EccKeyPrivP256::import_str(private_key)?;
+		self.identity = Some(Identity::new(key, 0));
 		Ok(self)
 	}
 
@@ -880,7 +1413,8 @@ impl ConnectOptions {
 	/// An error is returned if the byte slice cannot be decoded.
 	#[inline]
 	pub fn private_key_bytes(mut self, private_key: &[u8]) -> Result<Self> {
-		self.private_key = Some(crypto::EccKeyPrivP256::import(private_key)?);
+		let key = crypto::EccKeyPrivP256::import(private_key)?;
+		self.identity = Some(Identity::new(key, 0));
 		Ok(self)
 	}
 
@@ -974,6 +1508,60 @@ impl ConnectOptions {
 		self
 	}
 
+	/// Write all udp packets into a pcapng file at the given path.
+	///
+	/// The file can be opened with Wireshark. If the server address resolves
+	/// to multiple addresses, the packets of all connection attempts are
+	/// written into this file.
+	///
+	/// # Default
+	/// No packets are captured.
+	#[inline]
+	pub fn pcap_udp_packets<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.pcap_udp_packets = Some(path.into());
+		self
+	}
+
+	/// Write the content of all commands into a pcapng file at the given
+	/// path.
+	///
+	/// The commands are written after they are decrypted, decompressed and
+	/// reassembled.
+	///
+	/// # Default
+	/// No commands are captured.
+	#[inline]
+	pub fn pcap_commands<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.pcap_commands = Some(path.into());
+		self
+	}
+
+	/// Set the limits for buffers and queues of the connection, e.g. the
+	/// maximum size of a reassembled command.
+	///
+	/// # Default
+	/// The limits of the TeamSpeak client, see [`ProtocolConfig`].
+	///
+	/// [`ProtocolConfig`]: ../tsproto/connection/struct.ProtocolConfig.html
+	#[inline]
+	pub fn protocol_config(mut self, protocol_config: ProtocolConfig) -> Self {
+		self.protocol_config = protocol_config;
+		self
+	}
+
+	/// The flood protection settings which are used for the command queue,
+	/// if the server did not send its own settings.
+	///
+	/// # Default
+	/// The default settings of a TeamSpeak server, see [`AntifloodConfig`].
+	///
+	/// [`AntifloodConfig`]: struct.AntifloodConfig.html
+	#[inline]
+	pub fn antiflood(mut self, antiflood: AntifloodConfig) -> Self {
+		self.antiflood = antiflood;
+		self
+	}
+
 	/// If the client should.
 	///
 	/// # Default
@@ -1046,7 +1634,10 @@ impl fmt::Debug for ConnectOptions {
 		let ConnectOptions {
 			address,
 			local_address,
-			private_key,
+			connection_attempt_delay,
+			identity,
+			upgrade_identity,
+			identity_upgrade_progress: _,
 			name,
 			version,
 			channel,
@@ -1055,6 +1646,10 @@ impl fmt::Debug for ConnectOptions {
 			log_commands,
 			log_packets,
 			log_udp_packets,
+			pcap_udp_packets,
+			pcap_commands,
+			protocol_config,
+			antiflood,
 			// TODO This cannot be parsed by syn
 			//#[cfg(feature = "audio")]
 			//audio_packet_handler,
@@ -1064,12 +1659,17 @@ impl fmt::Debug for ConnectOptions {
 		write!(
 			f,
 			"ConnectOptions {{ address: {:?}, local_address: {:?}, \
-			 private_key: {:?}, name: {}, version: {}, channel: {:?}, channel_pass: {:?}, \
+			 connection_attempt_delay: {:?}, identity: {:?}, \
+			 upgrade_identity: {}, name: {}, version: {}, \
+			 channel: {:?}, channel_pass: {:?}, \
 			 logger: {:?}, log_commands: {}, log_packets: {}, \
-			 log_udp_packets: {},",
+			 log_udp_packets: {}, pcap_udp_packets: {:?}, \
+			 pcap_commands: {:?}, protocol_config: {:?}, antiflood: {:?}",
 			address,
 			local_address,
-			private_key,
+			connection_attempt_delay,
+			identity,
+			upgrade_identity,
 			name,
 			version,
 			channel,
@@ -1078,6 +1678,10 @@ impl fmt::Debug for ConnectOptions {
 			log_commands,
 			log_packets,
 			log_udp_packets,
+			pcap_udp_packets,
+			pcap_commands,
+			protocol_config,
+			antiflood,
 		)?;
 		#[cfg(feature = "audio")]
 		write!(f, ", audio_packet_handler: {:?}", audio_packet_handler)?;
@@ -4,14 +4,16 @@ use std::sync::Arc;
 use chashmap::CHashMap;
 use failure::format_err;
 use futures::sync::oneshot;
-use futures::{task, try_ready, Async, Future, Poll, Stream};
+use futures::{task, try_ready, Async, Future, Poll, Sink, Stream};
 use num_traits::FromPrimitive;
-use slog::{error, warn, Logger};
+use slog::{debug, error, warn, Logger};
+use tsproto::connection::{ConnectionStats, PingStats, TrafficKind};
 use tsproto::handler_data::ConnectionValue;
 #[cfg(feature = "audio")]
 use tsproto_audio::ts_to_audio::AudioPacketHandler;
 use tsproto_packets::packets::*;
 use ts_bookkeeping::messages::CommandExt;
+use ts_bookkeeping::{data, ChannelId, CodecEncryptionMode};
 
 use crate::{Connection, PHBox, TsError};
 
@@ -193,11 +195,38 @@ impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>> Stream
 				return Ok(Async::Ready(None));
 			};
 
+			if cmd.name() == "notifyconnectioninforequest" {
+				// The server wants to know our connection statistics
+				if let Some(con_val) =
+					connection.inner.client_connection.upgrade()
+				{
+					let packet = connection_info_packet(
+						&con_val.stats(),
+						con_val.ping_stats(),
+					);
+					let logger = self.logger.clone();
+					tokio::spawn(
+						connection
+							.get_packet_sink()
+							.send(packet)
+							.map(|_| ())
+							.map_err(move |e| {
+								warn!(logger, "Failed to send connection info";
+									"error" => ?e);
+							}),
+					);
+				}
+				return Ok(Async::Ready(Some(cmd)));
+			}
+
 			// 3.
 			let mut con = connection.inner.connection.write();
 			let name = cmd.name().to_string();
 			if cmd.name() == "error" {
-				let cmd = cmd.iter().next().unwrap();
+				let cmd = cmd
+					.iter()
+					.next()
+					.ok_or_else(|| format_err!("Empty error command"))?;
 				// 3.1
 				if let Some(code) =
 					cmd.get("return_code").and_then(|c| c.parse().ok())
@@ -235,9 +264,17 @@ impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>> Stream
 					None
 				}
 			};
+			let voice_encryption = con
+				.server
+				.clients
+				.get(&con.own_client)
+				.map(|c| is_voice_encrypted(&con, c.channel));
 
 			// Call event handler
 			drop(con);
+			if let Some(encrypt) = voice_encryption {
+				self.set_voice_encryption(connection, encrypt);
+			}
 			if let Some(events) = events {
 				let con = connection.lock();
 				let listeners = connection.inner.event_listeners.read();
@@ -287,3 +324,116 @@ impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>> Stream
 		return Ok(Async::NotReady);
 	}
 }
+
+impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>>
+	SimplePacketStreamHandler<Inner>
+{
+	/// Encrypt outgoing voice packets and reject incoming unencrypted voice
+	/// packets, if `encrypt` is set.
+	fn set_voice_encryption(&self, connection: &Connection, encrypt: bool) {
+		if let Some(con_val) = connection.inner.client_connection.upgrade() {
+			let mut con = con_val.mutex.lock();
+			if let Some(params) = &mut con.1.params {
+				if params.voice_encryption != encrypt {
+					debug!(self.logger, "Voice encryption changed";
+						"encrypted" => encrypt);
+					params.voice_encryption = encrypt;
+				}
+			}
+		}
+	}
+}
+
+/// If voice packets in `channel` have to be encrypted.
+///
+/// The server can enable or disable the encryption globally, otherwise it is
+/// configured per channel. Unknown channels are treated as encrypted.
+pub(crate) fn is_voice_encrypted(
+	con: &data::Connection,
+	channel: ChannelId,
+) -> bool
+{
+	match con.server.codec_encryption_mode {
+		CodecEncryptionMode::ForcedOn => true,
+		CodecEncryptionMode::ForcedOff => false,
+		CodecEncryptionMode::PerChannel => con
+			.server
+			.channels
+			.get(&channel)
+			.map_or(true, |c| c.is_unencrypted != Some(true)),
+	}
+}
+
+/// Create the `setconnectioninfo` answer for a `notifyconnectioninforequest`.
+fn connection_info_packet(
+	stats: &ConnectionStats,
+	ping: Option<PingStats>,
+) -> OutPacket
+{
+	let ms = |d: chrono::Duration| {
+		d.num_microseconds().unwrap_or(0) as f64 / 1000.0
+	};
+	let mut args = Vec::new();
+	if let Some(ping) = ping {
+		args.push((
+			"connection_ping".to_string(),
+			format!("{:.4}", ms(ping.average)),
+		));
+		args.push((
+			"connection_ping_deviation".to_string(),
+			format!("{:.4}", ms(ping.jitter)),
+		));
+	}
+
+	let kinds = [
+		(TrafficKind::Speech, "speech"),
+		(TrafficKind::Keepalive, "keepalive"),
+		(TrafficKind::Control, "control"),
+	];
+	let mut received_total = 0;
+	let mut lost_total = 0;
+	for &(kind, name) in &kinds {
+		let sent = stats.sent_kind(kind);
+		let received = stats.received_kind(kind);
+		let last_second = stats.bandwidth_last_second(kind);
+		let last_minute = stats.bandwidth_last_minute(kind);
+		received_total += received.packets;
+		lost_total += stats.lost_kind(kind);
+
+		let values = [
+			("packets_sent", sent.packets.to_string()),
+			("bytes_sent", sent.bytes.to_string()),
+			("packets_received", received.packets.to_string()),
+			("bytes_received", received.bytes.to_string()),
+			(
+				"server2client_packetloss",
+				format!("{:.4}", stats.incoming_loss(kind)),
+			),
+			("bandwidth_sent_last_second", last_second.0.to_string()),
+			("bandwidth_sent_last_minute", last_minute.0.to_string()),
+			("bandwidth_received_last_second", last_second.1.to_string()),
+			("bandwidth_received_last_minute", last_minute.1.to_string()),
+		];
+		for (key, value) in values.iter() {
+			args.push((format!("connection_{}_{}", key, name), value.clone()));
+		}
+	}
+
+	let loss_total = if received_total + lost_total == 0 {
+		0.0
+	} else {
+		lost_total as f32 / (received_total + lost_total) as f32
+	};
+	args.push((
+		"connection_server2client_packetloss_total".to_string(),
+		format!("{:.4}", loss_total),
+	));
+
+	OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+		Direction::C2S,
+		PacketType::Command,
+		"setconnectioninfo",
+		args.into_iter(),
+		std::iter::empty(),
+	)
+}
@@ -1,5 +1,9 @@
 use tsproto_packets::packets::{Direction, InCommand, PacketType};
 use ts_bookkeeping::messages::s2c::{InMessage, InMessages};
+use ts_bookkeeping::{data, ChannelId, ClientId, LicenseType, Uid};
+
+use crate::packet_handler::is_voice_encrypted;
+use crate::{check_clientinit_error, Error, TsError};
 
 fn parse_msg(msg: &str) -> InMessage {
 	let cmd = InCommand::new(
@@ -31,3 +35,108 @@ fn negative_iconid() { test_iconid("-96136942", 4198830354); }
 
 #[test]
 fn big_iconid() { test_iconid("18446744073225738240", 3811153920); }
+
+fn parse_cmd(msg: &str) -> InCommand {
+	InCommand::new(
+		msg.as_bytes().to_vec(),
+		PacketType::Command,
+		false,
+		Direction::S2C,
+	)
+	.unwrap()
+}
+
+fn initserver(codec_encryption_mode: u8) -> InMessage {
+	parse_msg(&format!(
+		r#"initserver virtualserver_welcomemessage virtualserver_platform virtualserver_version virtualserver_maxclients=0 virtualserver_created=0 virtualserver_hostmessage virtualserver_hostmessage_mode=0 virtualserver_id=0 virtualserver_ip virtualserver_ask_for_privilegekey=0 acn aclid=0 pv=0 client_talk_power=0 client_needed_serverquery_view_power=0 virtualserver_name virtualserver_codec_encryption_mode={} virtualserver_default_server_group=0 virtualserver_default_channel_group=0 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=0 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_hostbanner_mode=0 virtualserver_channel_temp_delete_delay_default=0 virtualserver_icon_id=0"#,
+		codec_encryption_mode
+	))
+}
+
+fn voice_encryption(codec_encryption_mode: u8) -> Vec<bool> {
+	let mut con = data::Connection::new(
+		Uid("test".into()),
+		None,
+		&initserver(codec_encryption_mode),
+	);
+	let channellist = parse_cmd(&format!(
+		"channellist {}|{}",
+		r#"cid=1 cpid=0 channel_name=Unencrypted channel_topic channel_codec=4 channel_codec_quality=7 channel_maxclients=-1 channel_maxfamilyclients=-1 channel_order=0 channel_flag_permanent=1 channel_flag_semi_permanent=0 channel_flag_default=0 channel_flag_password=0 channel_codec_latency_factor=1 channel_codec_is_unencrypted=1 channel_delete_delay=0 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power=0 channel_forced_silence=0 channel_name_phonetic channel_icon_id=0 channel_flag_private=0"#,
+		r#"cid=2 cpid=0 channel_name=Encrypted channel_topic channel_codec=4 channel_codec_quality=7 channel_maxclients=-1 channel_maxfamilyclients=-1 channel_order=1 channel_flag_permanent=1 channel_flag_semi_permanent=0 channel_flag_default=0 channel_flag_password=0 channel_codec_latency_factor=1 channel_codec_is_unencrypted=0 channel_delete_delay=0 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power=0 channel_forced_silence=0 channel_name_phonetic channel_icon_id=0 channel_flag_private=0"#,
+	));
+	con.handle_command(&channellist).unwrap();
+	(1..4)
+		.map(|i| is_voice_encrypted(&con, ChannelId(i)))
+		.collect()
+}
+
+#[test]
+fn voice_encryption_per_channel() {
+	assert_eq!(voice_encryption(0), vec![false, true, true]);
+}
+
+#[test]
+fn voice_encryption_forced() {
+	assert_eq!(voice_encryption(1), vec![false, false, false]);
+	assert_eq!(voice_encryption(2), vec![true, true, true]);
+}
+
+#[test]
+fn clientinit_identity_error() {
+	let cmd = parse_cmd(&format!(
+		"error id={} msg=client\\scould\\snot\\svalidate\\sidentity \
+		 extra_msg=25",
+		TsError::ClientCouldNotValidateIdentity as u32
+	));
+	match check_clientinit_error(&cmd) {
+		Err(Error::IdentityLevelTooLow(25)) => {}
+		r => panic!("Expected identity level error, got {:?}", r),
+	}
+}
+
+#[test]
+fn clientinit_other_error() {
+	let cmd = parse_cmd(&format!(
+		"error id={} msg=invalid",
+		TsError::ClientNicknameInuse as u32
+	));
+	match check_clientinit_error(&cmd) {
+		Err(Error::Ts(TsError::ClientNicknameInuse)) => {}
+		r => panic!("Expected nickname error, got {:?}", r),
+	}
+}
+
+#[test]
+fn license_from_chain() {
+	let con = data::Connection::new(
+		Uid("test".into()),
+		Some(LicenseType::Npl),
+		&initserver(0),
+	);
+	assert_eq!(con.server.license, LicenseType::Npl);
+
+	let con = data::Connection::new(Uid("test".into()), None, &initserver(0));
+	assert_eq!(con.server.license, LicenseType::NoLicense);
+}
+
+#[test]
+fn client_connection_info() {
+	let mut con = data::Connection::new(Uid("test".into()), None, &initserver(0));
+	let enterview = parse_cmd(
+		r#"notifycliententerview cfid=0 ctid=1 reasonid=0 clid=1 client_unique_identifier=abc= client_nickname=Test client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_meta_data client_is_recording=0 client_database_id=1 client_channel_group_id=8 client_servergroups=8 client_away=0 client_away_message client_type=0 client_flag_avatar client_talk_power=0 client_talk_request=0 client_talk_request_msg client_description client_is_talker=0 client_is_priority_speaker=0 client_unread_messages=0 client_nickname_phonetic client_needed_serverquery_view_power=0 client_icon_id=0 client_is_channel_commander=0 client_country client_channel_group_inherited_channel_id=1 client_badges client_myteamspeak_id client_integrations client_myteamspeak_avatar client_signed_badges"#,
+	);
+	con.handle_command(&enterview).unwrap();
+
+	// Request
+	let client = &con.server.clients[&ClientId(1)];
+	assert!(client.connection_data.is_none());
+	let packet = client.get_connection_info();
+	assert_eq!(packet.content(), &b"getconnectioninfo clid=1"[..]);
+
+	// Answer
+	let info = parse_cmd(
+		r#"notifyconnectioninfo clid=1 connection_ping=12.5000 connection_ping_deviation=1.2500 connection_connected_time=1000 connection_client_ip=127.0.0.1 connection_client_port=9987 connection_packets_sent_speech=1 connection_packets_sent_keepalive=2 connection_packets_sent_control=3 connection_bytes_sent_speech=4 connection_bytes_sent_keepalive=5 connection_bytes_sent_control=6 connection_packets_received_speech=7 connection_packets_received_keepalive=8 connection_packets_received_control=9 connection_bytes_received_speech=10 connection_bytes_received_keepalive=11 connection_bytes_received_control=12 connection_server2client_packetloss_speech=0.0000 connection_server2client_packetloss_keepalive=0.0000 connection_server2client_packetloss_control=0.0000 connection_server2client_packetloss_total=0.0000 connection_client2server_packetloss_speech=0.0000 connection_client2server_packetloss_keepalive=0.0000 connection_client2server_packetloss_control=0.0000 connection_client2server_packetloss_total=0.0000 connection_bandwidth_sent_last_second_speech=0 connection_bandwidth_sent_last_second_keepalive=0 connection_bandwidth_sent_last_second_control=0 connection_bandwidth_sent_last_minute_speech=0 connection_bandwidth_sent_last_minute_keepalive=0 connection_bandwidth_sent_last_minute_control=0 connection_bandwidth_received_last_second_speech=0 connection_bandwidth_received_last_second_keepalive=0 connection_bandwidth_received_last_second_control=0 connection_bandwidth_received_last_minute_speech=0 connection_bandwidth_received_last_minute_keepalive=0 connection_bandwidth_received_last_minute_control=0 connection_filetransfer_bandwidth_sent=0 connection_filetransfer_bandwidth_received=0 connection_idle_time=0"#,
+	);
+	con.handle_command(&info).unwrap();
+	assert!(con.server.clients[&ClientId(1)].connection_data.is_some());
+}
@@ -6,13 +6,44 @@ extern crate slog;
 extern crate tokio;
 extern crate tsproto;
 
+use std::net::SocketAddr;
+
 use criterion::{Bencher, Benchmark, Criterion};
 use futures::{future, Future};
+use tsproto::client;
 
 mod utils;
 use crate::utils::*;
 
-fn connect(b: &mut Bencher) {
+type CreateClient = fn(
+	SocketAddr,
+	slog::Logger,
+	SimplePacketHandler,
+	u8,
+) -> client::ClientDataM<SimplePacketHandler>;
+
+/// The number of clients which connect at the same time in the `connect-many`
+/// benchmarks.
+const MANY_CLIENTS: usize = 8;
+
+fn connect(b: &mut Bencher) { connect_with(b, create_client, 1) }
+
+fn connect_many(b: &mut Bencher) {
+	connect_with(b, create_client, MANY_CLIENTS)
+}
+
+/// Connect with the batched udp socket.
+#[cfg(target_os = "linux")]
+fn connect_mmsg(b: &mut Bencher) { connect_with(b, create_client_mmsg, 1) }
+
+/// Connect many clients with the batched udp socket.
+#[cfg(target_os = "linux")]
+fn connect_many_mmsg(b: &mut Bencher) {
+	connect_with(b, create_client_mmsg, MANY_CLIENTS)
+}
+
+/// Connect `count` clients concurrently and disconnect them again.
+fn connect_with(b: &mut Bencher, create_client: CreateClient, count: usize) {
 	let local_address = "127.0.0.1:0".parse().unwrap();
 	let address = "127.0.0.1:9987".parse().unwrap();
 
@@ -30,31 +61,35 @@ fn connect(b: &mut Bencher) {
 	b.iter(|| {
 		let logger = logger.clone();
 		rt.block_on(future::lazy(move || {
-			// The TS server does not accept the 3rd reconnect from the same port
-			// so we create a new client for every connection.
-			let c = create_client(
-				local_address,
-				logger.clone(),
-				SimplePacketHandler,
-				0,
-			);
-
-			info!(logger, "Connecting");
-			let logger2 = logger.clone();
-			let c2 = c.clone();
-			utils::connect(logger.clone(), c.clone(), address)
-				.map_err(|e| panic!("Failed to connect ({:?})", e))
-				.and_then(move |con| {
-					info!(logger, "Disconnecting");
-					disconnect(&c2, con)
-						.map_err(|e| panic!("Failed to disconnect ({:?})", e))
-				})
-				.and_then(move |_| {
-					info!(logger2, "Disconnected");
-					// Quit client
-					drop(c);
-					Ok(())
-				})
+			future::join_all((0..count).map(move |_| {
+				// The TS server does not accept the 3rd reconnect from the
+				// same port so we create a new client for every connection.
+				let c = create_client(
+					local_address,
+					logger.clone(),
+					SimplePacketHandler,
+					0,
+				);
+
+				info!(logger, "Connecting");
+				let logger = logger.clone();
+				let logger2 = logger.clone();
+				let c2 = c.clone();
+				utils::connect(logger.clone(), c.clone(), address)
+					.map_err(|e| panic!("Failed to connect ({:?})", e))
+					.and_then(move |con| {
+						info!(logger, "Disconnecting");
+						disconnect(&c2, con).map_err(|e| {
+							panic!("Failed to disconnect ({:?})", e)
+						})
+					})
+					.and_then(move |_| {
+						info!(logger2, "Disconnected");
+						// Quit client
+						drop(c);
+						Ok(())
+					})
+			}))
 		}))
 		.unwrap();
 	});
@@ -62,10 +97,14 @@ fn connect(b: &mut Bencher) {
 }
 
 fn bench_connect(c: &mut Criterion) {
-	c.bench(
-		"connect",
-		Benchmark::new("connect", connect).sample_size(20),
-	);
+	let bench = Benchmark::new("connect", connect)
+		.with_function("connect-many", connect_many)
+		.sample_size(20);
+	#[cfg(target_os = "linux")]
+	let bench = bench
+		.with_function("connect-mmsg", connect_mmsg)
+		.with_function("connect-many-mmsg", connect_many_mmsg);
+	c.bench("connect", bench);
 }
 
 criterion_group!(benches, bench_connect);
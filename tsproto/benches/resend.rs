@@ -0,0 +1,76 @@
+#[macro_use]
+extern crate criterion;
+extern crate futures;
+extern crate tokio;
+extern crate tsproto;
+
+use std::time::{Duration, Instant};
+
+use criterion::{Bencher, Criterion, ParameterizedBenchmark};
+use futures::{future, Future};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Delay;
+use tsproto::timer_wheel::TimerWheel;
+
+/// How often the timeout of every connection is changed.
+///
+/// The resender reschedules its timeout whenever it sends a packet or the
+/// state changes.
+const ROUNDS: u64 = 10;
+
+/// The timeout of a connection in a round, spread over a second.
+fn timeout(now: Instant, round: u64, con: usize) -> Instant {
+	now + Duration::from_millis(round * 10 + 500 + con as u64 % 1000)
+}
+
+/// One timer wheel for all connections, like the `ResendScheduler`.
+fn timer_wheel(b: &mut Bencher, count: &usize) {
+	b.iter(|| {
+		let now = Instant::now();
+		let mut wheel = TimerWheel::new(now, Duration::from_millis(1));
+		let mut expired = Vec::new();
+		for round in 0..ROUNDS {
+			for con in 0..*count {
+				wheel.insert(timeout(now, round, con), con);
+			}
+			let round_start = now + Duration::from_millis(round * 10);
+			wheel.poll_expired(round_start, &mut expired);
+		}
+		wheel.len()
+	});
+}
+
+/// A tokio timer per connection.
+fn delays(b: &mut Bencher, count: &usize) {
+	let mut rt = Runtime::new().unwrap();
+	b.iter(|| {
+		rt.block_on(future::lazy(|| {
+			let now = tokio::clock::now();
+			let mut delays: Vec<_> =
+				(0..*count).map(|_| Delay::new(now)).collect();
+			for round in 0..ROUNDS {
+				for (con, delay) in delays.iter_mut().enumerate() {
+					delay.reset(timeout(now, round, con));
+					delay.poll().unwrap();
+				}
+			}
+			Ok::<_, ()>(delays.len())
+		}))
+		.unwrap()
+	});
+}
+
+fn bench_resend_timers(c: &mut Criterion) {
+	c.bench(
+		"resend-timers",
+		ParameterizedBenchmark::new(
+			"timer-wheel",
+			timer_wheel,
+			vec![10, 100, 1000, 10_000],
+		)
+		.with_function("delay", delays),
+	);
+}
+
+criterion_group!(benches, bench_resend_timers);
+criterion_main!(benches);
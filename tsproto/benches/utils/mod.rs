@@ -51,31 +51,69 @@ pub fn create_client<PH: PacketHandler<ServerConnectionData>>(
 	verbose: u8,
 ) -> client::ClientDataM<PH>
 {
+	let c = client::new(
+		local_address,
+		private_key(),
+		packet_handler,
+		Default::default(),
+		logger,
+	)
+	.unwrap();
+	add_loggers(&c, verbose);
+	c
+}
+
+/// Create a client which uses the batched udp socket.
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+pub fn create_client_mmsg<PH: PacketHandler<ServerConnectionData>>(
+	local_address: SocketAddr,
+	logger: slog::Logger,
+	packet_handler: PH,
+	verbose: u8,
+) -> client::ClientDataM<PH>
+{
+	let (bound_address, sink, stream) = mmsg::bind(&local_address).unwrap();
+	let c = client::new_with_socket(
+		bound_address,
+		private_key(),
+		packet_handler,
+		Default::default(),
+		sink,
+		stream,
+		logger,
+	)
+	.unwrap();
+	// Keep the batched socket when rebinding
+	c.lock().set_bind_socket(local_address, Box::new(mmsg::bind_socket));
+	add_loggers(&c, verbose);
+	c
+}
+
+fn private_key() -> EccKeyPrivP256 {
 	// Get P-256 ECDH key
-	let private_key = EccKeyPrivP256::import_str(
+	EccKeyPrivP256::import_str(
 		"MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
 		k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
-		DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
-
-	let c = client::new(local_address, private_key, packet_handler, logger)
-		.unwrap();
+		DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap()
+}
 
-	{
-		let mut c = c.lock();
-		let c = &mut *c;
-		// Logging
-		if verbose > 0 {
-			log::add_command_logger(c);
-		}
-		if verbose > 1 {
-			log::add_packet_logger(c);
-		}
-		if verbose > 2 {
-			log::add_udp_packet_logger(c);
-		}
+fn add_loggers<PH: PacketHandler<ServerConnectionData>>(
+	c: &client::ClientDataM<PH>,
+	verbose: u8,
+) {
+	let mut c = c.lock();
+	let c = &mut *c;
+	// Logging
+	if verbose > 0 {
+		log::add_command_logger(c);
+	}
+	if verbose > 1 {
+		log::add_packet_logger(c);
+	}
+	if verbose > 2 {
+		log::add_udp_packet_logger(c);
 	}
-
-	c
 }
 
 pub fn connect<PH: PacketHandler<ServerConnectionData>>(
@@ -0,0 +1,61 @@
+extern crate base64;
+extern crate chrono;
+extern crate structopt;
+extern crate tsproto;
+
+use chrono::{Duration, Utc};
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+use tsproto::crypto::EccKeyPrivEd25519;
+use tsproto::license::*;
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp, \
+	AppSettings::VersionlessSubcommands]"))]
+struct Args {
+	#[structopt(
+		long = "root",
+		help = "The private root key (base64), a new one is created if it is \
+		        not set"
+	)]
+	root: Option<String>,
+	#[structopt(
+		long = "issuer",
+		default_value = "tsproto",
+		help = "The issuer of the license"
+	)]
+	issuer: String,
+	#[structopt(
+		long = "days",
+		default_value = "365",
+		help = "The number of days for which the license is valid"
+	)]
+	days: i64,
+}
+
+fn main() {
+	tsproto::init().unwrap();
+
+	// Parse command line options
+	let args = Args::from_args();
+
+	let root = if let Some(root) = &args.root {
+		EccKeyPrivEd25519::from_base64(root).unwrap()
+	} else {
+		EccKeyPrivEd25519::create().unwrap()
+	};
+	let now = Utc::now();
+	let (licenses, key) = LicenseBuilder::new(root.clone())
+		.validity(now, now + Duration::days(args.days))
+		.intermediate(args.issuer.as_str())
+		.server(args.issuer.as_str(), LicenseType::Npl)
+		.build()
+		.unwrap();
+
+	let mut l = Vec::new();
+	licenses.write(&mut l).unwrap();
+	println!("License: {}", base64::encode(&l));
+	println!("Root private key: {}", root.to_base64());
+	println!("Root public key: {}", root.to_pub().to_base64());
+	println!("Derived private key: {}", key.to_base64());
+}
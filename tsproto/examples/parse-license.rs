@@ -1,4 +1,5 @@
 extern crate base64;
+extern crate chrono;
 extern crate structopt;
 extern crate tsproto;
 
@@ -22,4 +23,5 @@ fn main() {
 
 	let l = Licenses::parse(&base64::decode(&args.license).unwrap()).unwrap();
 	println!("{:#?}", l);
+	println!("{:#?}", l.verify(chrono::Utc::now()));
 }
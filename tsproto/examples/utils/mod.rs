@@ -57,8 +57,14 @@ pub fn create_client<PH: PacketHandler<ServerConnectionData>>(
 		k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
 		DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
 
-	let c = client::new(local_address, private_key, packet_handler, logger)
-		.unwrap();
+	let c = client::new(
+		local_address,
+		private_key,
+		packet_handler,
+		Default::default(),
+		logger,
+	)
+	.unwrap();
 
 	{
 		let mut c = c.lock();
@@ -1,14 +1,23 @@
 //! Handle packet splitting and cryptography
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::u64;
 
 use aes::block_cipher_trait::generic_array::typenum::consts::U16;
 use aes::block_cipher_trait::generic_array::GenericArray;
 use byteorder::{NetworkEndian, WriteBytesExt};
+use bytes::BytesMut;
 use curve25519_dalek::edwards::EdwardsPoint;
+use failure::format_err;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Stream};
 use num_bigint::BigUint;
 use num_traits::ToPrimitive;
+use parking_lot::Mutex;
 use quicklz::CompressionLevel;
 use ring::digest;
+use tokio::timer::Interval;
 
 use crate::connection::{CachedKey, SharedIv};
 use crate::crypto::{EccKeyPrivEd25519, EccKeyPrivP256, EccKeyPubP256};
@@ -47,6 +56,33 @@ pub fn compress_and_split(
 	is_client: bool,
 	packet: OutPacket,
 ) -> Vec<OutPacket>
+{
+	let mut buf = BytesMut::new();
+	let len = match compress_and_split_into(is_client, &packet, &mut buf) {
+		Some(len) => len,
+		None => return vec![packet],
+	};
+	let dir = packet.direction();
+	buf.chunks(len)
+		.map(|d| OutPacket::new_from_data(dir, d.to_vec()))
+		.collect()
+}
+
+/// Like [`compress_and_split`], but writes all packets into one buffer.
+///
+/// The packets are appended to `buf`. Every packet except the last one has
+/// the returned length, so `buf` can be split into packets without copying
+/// them.
+///
+/// Returns `None` and leaves `buf` untouched if the packet is small enough to
+/// be sent as it is.
+///
+/// [`compress_and_split`]: fn.compress_and_split.html
+pub fn compress_and_split_into(
+	is_client: bool,
+	packet: &OutPacket,
+	buf: &mut BytesMut,
+) -> Option<usize>
 {
 	// Everything except whisper packets has to be less than 500 bytes
 	let header_size = if is_client {
@@ -54,71 +90,54 @@ pub fn compress_and_split(
 	} else {
 		tsproto_packets::S2C_HEADER_LEN
 	};
-	let data = packet.content();
 	// The maximum packet size (including header) is 500 bytes.
 	let max_size = 500 - header_size;
-	// Split the data if it is necessary.
-	let compressed;
-	let datas = if data.len() > max_size {
-		// Compress with QuickLZ
-		let cdata = ::quicklz::compress(&data, CompressionLevel::Lvl1);
-		// Use only if it is efficient
-		let mut data = if cdata.len() > data.len() {
-			compressed = false;
-			data
-		} else {
-			compressed = true;
-			&cdata
-		};
+	if packet.content().len() <= max_size {
+		return None;
+	}
 
-		// Ignore size limit for whisper packets
-		if data.len() <= max_size
-			|| packet.header().packet_type() == PacketType::VoiceWhisper
-		{
-			let mut v = vec![0; header_size + data.len()];
-			v[header_size..].copy_from_slice(data);
-			vec![v]
-		} else {
-			// Split
-			let count = (data.len() + max_size - 1) / max_size;
-			let mut splitted = Vec::with_capacity(count);
-			while data.len() > max_size {
-				let (first, last) = data.split_at(max_size);
-				let mut v = vec![0; header_size + max_size];
-				v[header_size..].copy_from_slice(first);
-				splitted.push(v);
-				data = last;
-			}
-			// Rest
-			let mut v = vec![0; header_size + data.len()];
-			v[header_size..].copy_from_slice(data);
-			splitted.push(v);
-			splitted
-		}
+	// Compress with QuickLZ
+	let cdata = ::quicklz::compress(packet.content(), CompressionLevel::Lvl1);
+	// Use only if it is efficient
+	let compressed = cdata.len() <= packet.content().len();
+	let data = if compressed {
+		&cdata[..]
 	} else {
-		return vec![packet];
+		packet.content()
 	};
 
-	let len = datas.len();
-	let fragmented = len > 1;
+	// Split the data if it is necessary, ignore the size limit for whisper
+	// packets.
+	let part_size = if data.len() <= max_size
+		|| packet.header().packet_type() == PacketType::VoiceWhisper
+	{
+		data.len()
+	} else {
+		max_size
+	};
+	let count = (data.len() + part_size - 1) / part_size;
+
 	let orig_header = packet.header_bytes();
-	let dir = packet.direction();
-	let mut packets = Vec::with_capacity(datas.len());
-	for (i, mut d) in datas.into_iter().enumerate() {
-		d[..header_size].copy_from_slice(orig_header);
-		let mut packet = OutPacket::new_from_data(dir, d);
+	// The packet type and flags are stored in the last byte of the header
+	let flags_off = header_size - 1;
+	buf.reserve(count * header_size + data.len());
+	for (i, part) in data.chunks(part_size).enumerate() {
+		let start = buf.len();
+		buf.extend_from_slice(orig_header);
+		buf.extend_from_slice(part);
+
+		let mut flags = Flags::empty();
 		// Only set flags on first fragment
 		if i == 0 && compressed {
-			packet.flags(packet.header().flags() | Flags::COMPRESSED);
+			flags |= Flags::COMPRESSED;
 		}
-
 		// Set fragmented flag on first and last part
-		if fragmented && (i == 0 || i == len - 1) {
-			packet.flags(packet.header().flags() | Flags::FRAGMENTED);
+		if count > 1 && (i == 0 || i == count - 1) {
+			flags |= Flags::FRAGMENTED;
 		}
-		packets.push(packet);
+		buf[start + flags_off] |= flags.bits();
 	}
-	packets
+	Some(header_size + part_size)
 }
 
 fn create_key_nonce(
@@ -178,14 +197,32 @@ pub fn encrypt_key_nonce(
 	nonce: &GenericArray<u8, U16>,
 ) -> Result<()>
 {
-	let meta = packet.header().get_meta();
-	let mac = eax::Eax::<aes::Aes128>::encrypt(
-		key,
-		nonce,
-		&meta,
-		packet.content_mut(),
-	);
-	packet.mac().copy_from_slice(&mac[..8]);
+	// Encrypt in place, the meta data is part of the header
+	let (mac, meta, content) = packet.split_mac_meta_content_mut();
+	let tag = eax::Eax::<aes::Aes128>::encrypt(key, nonce, meta, content);
+	mac.copy_from_slice(&tag[..8]);
+	Ok(())
+}
+
+/// Like [`encrypt_key_nonce`], but for a whole udp packet in `data`.
+///
+/// [`encrypt_key_nonce`]: fn.encrypt_key_nonce.html
+pub fn encrypt_key_nonce_udp(
+	data: &mut [u8],
+	dir: Direction,
+	key: &GenericArray<u8, U16>,
+	nonce: &GenericArray<u8, U16>,
+) -> Result<()>
+{
+	let header_len = if dir == Direction::S2C {
+		tsproto_packets::S2C_HEADER_LEN
+	} else {
+		tsproto_packets::C2S_HEADER_LEN
+	};
+	let (header, content) = data.split_at_mut(header_len);
+	let (mac, meta) = header.split_at_mut(8);
+	let tag = eax::Eax::<aes::Aes128>::encrypt(key, nonce, meta, content);
+	mac.copy_from_slice(&tag[..8]);
 	Ok(())
 }
 
@@ -197,6 +234,18 @@ pub fn encrypt_fake(packet: &mut OutPacket) -> Result<()> {
 	)
 }
 
+/// Like [`encrypt_fake`], but for a whole udp packet in `data`.
+///
+/// [`encrypt_fake`]: fn.encrypt_fake.html
+pub fn encrypt_fake_udp(data: &mut [u8], dir: Direction) -> Result<()> {
+	encrypt_key_nonce_udp(
+		data,
+		dir,
+		&crate::FAKE_KEY.into(),
+		&crate::FAKE_NONCE.into(),
+	)
+}
+
 pub fn encrypt(
 	packet: &mut OutPacket,
 	generation_id: u32,
@@ -204,16 +253,33 @@ pub fn encrypt(
 	cache: &mut [[CachedKey; 2]; 8],
 ) -> Result<()>
 {
-	let header = packet.header();
-	let (key, nonce) = create_key_nonce(
-		header.packet_type(),
-		header.client_id(),
-		header.packet_id(),
-		generation_id,
-		iv,
-		cache,
-	);
-	encrypt_key_nonce(packet, &key, &nonce)
+	let dir = packet.direction();
+	encrypt_udp(packet.data_mut(), dir, generation_id, iv, cache)
+}
+
+/// Like [`encrypt`], but for a whole udp packet in `data`.
+///
+/// [`encrypt`]: fn.encrypt.html
+pub fn encrypt_udp(
+	data: &mut [u8],
+	dir: Direction,
+	generation_id: u32,
+	iv: &SharedIv,
+	cache: &mut [[CachedKey; 2]; 8],
+) -> Result<()>
+{
+	let (key, nonce) = {
+		let header = InHeader::new(data, dir);
+		create_key_nonce(
+			header.packet_type(),
+			header.client_id(),
+			header.packet_id(),
+			generation_id,
+			iv,
+			cache,
+		)
+	};
+	encrypt_key_nonce_udp(data, dir, &key, &nonce)
 }
 
 pub fn decrypt_key_nonce(
@@ -223,13 +289,13 @@ pub fn decrypt_key_nonce(
 ) -> Result<Vec<u8>>
 {
 	let header = packet.header();
-	let meta = header.get_meta();
+	let meta = header.meta();
 	// TODO decrypt in-place
 	let mut content = packet.content().to_vec();
 	eax::Eax::<aes::Aes128>::decrypt(
 		key,
 		nonce,
-		&meta,
+		meta,
 		&mut content,
 		header.mac(),
 	)
@@ -348,6 +414,280 @@ pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
 	res
 }
 
+/// The number of offsets which a thread checks at once in a parallel hash
+/// cash search.
+const HASH_CASH_CHUNK: u64 = 100_000;
+/// Check for cancellation after this many offsets.
+const HASH_CASH_CANCEL_CHECK: u64 = 1024;
+
+/// Configures a parallel search for a hash cash offset.
+#[derive(Clone, Debug)]
+pub struct HashCashConfig {
+	/// The first offset which is checked.
+	///
+	/// A cancelled search can be resumed by setting this to the `counter` of
+	/// its last [`HashCashProgress`].
+	///
+	/// [`HashCashProgress`]: struct.HashCashProgress.html
+	pub start: u64,
+	/// The number of threads which are used for the search.
+	pub threads: usize,
+}
+
+impl Default for HashCashConfig {
+	fn default() -> Self {
+		Self {
+			start: 0,
+			threads: num_cpus::get(),
+		}
+	}
+}
+
+/// The progress of a parallel hash cash search.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HashCashProgress {
+	/// All offsets below this counter were checked.
+	pub counter: u64,
+	/// The number of offsets which were checked by this search.
+	pub checked: u64,
+	/// The highest level, which was found so far.
+	pub best_level: u8,
+	/// The offset for `best_level`.
+	pub best_offset: u64,
+}
+
+struct HashCashState {
+	progress: HashCashProgress,
+	/// The start of the next chunk.
+	next: u64,
+	/// The start of all chunks which are currently checked.
+	running: BTreeSet<u64>,
+	/// The lowest offset of a chunk, which was not checked completely
+	/// because the search got cancelled.
+	unfinished: Option<u64>,
+	/// The lowest offset which reaches the level.
+	found: Option<u64>,
+	/// The number of running threads.
+	workers: usize,
+	result: Option<oneshot::Sender<Option<u64>>>,
+}
+
+struct HashCashShared {
+	omega: String,
+	level: u8,
+	cancelled: AtomicBool,
+	state: Mutex<HashCashState>,
+}
+
+/// Cancel a running hash cash search or ask for its progress.
+#[derive(Clone)]
+pub struct HashCashHandle {
+	shared: Arc<HashCashShared>,
+}
+
+/// A search for a hash cash offset, which runs on multiple threads.
+///
+/// The future resolves to the lowest offset (starting from
+/// [`HashCashConfig::start`]), which reaches the requested level. This is the
+/// same offset as [`hash_cash`] returns. Dropping the future cancels the
+/// search.
+///
+/// [`HashCashConfig::start`]: struct.HashCashConfig.html#structfield.start
+/// [`hash_cash`]: fn.hash_cash.html
+pub struct HashCashSearch {
+	handle: HashCashHandle,
+	recv: oneshot::Receiver<Option<u64>>,
+}
+
+/// Search the offset for a hash cash level on multiple threads.
+pub fn hash_cash_parallel(
+	key: &EccKeyPubP256,
+	level: u8,
+	config: HashCashConfig,
+) -> Result<HashCashSearch>
+{
+	let (send, recv) = oneshot::channel();
+	let threads = config.threads.max(1);
+	let shared = Arc::new(HashCashShared {
+		omega: key.to_ts()?,
+		level,
+		cancelled: AtomicBool::new(false),
+		state: Mutex::new(HashCashState {
+			progress: HashCashProgress {
+				counter: config.start,
+				checked: 0,
+				best_level: 0,
+				best_offset: config.start,
+			},
+			next: config.start,
+			running: BTreeSet::new(),
+			unfinished: None,
+			found: None,
+			workers: threads,
+			result: Some(send),
+		}),
+	});
+
+	for i in 0..threads {
+		let shared = shared.clone();
+		if let Err(e) = std::thread::Builder::new()
+			.name(format!("hash cash {}", i))
+			.spawn(move || hash_cash_worker(&shared))
+		{
+			shared.cancelled.store(true, Ordering::Relaxed);
+			return Err(e.into());
+		}
+	}
+
+	Ok(HashCashSearch {
+		handle: HashCashHandle { shared },
+		recv,
+	})
+}
+
+fn hash_cash_worker(shared: &HashCashShared) {
+	loop {
+		// Take the next chunk
+		let start = {
+			let mut state = shared.state.lock();
+			let start = state.next;
+			if shared.cancelled.load(Ordering::Relaxed)
+				|| start == u64::MAX
+				|| state.found.map_or(false, |f| f <= start)
+			{
+				break;
+			}
+			state.next = start.saturating_add(HASH_CASH_CHUNK);
+			state.running.insert(start);
+			start
+		};
+
+		let end = start.saturating_add(HASH_CASH_CHUNK);
+		let mut offset = start;
+		let mut best = (0, start);
+		let mut found = None;
+		while offset < end {
+			if (offset - start) % HASH_CASH_CANCEL_CHECK == 0
+				&& shared.cancelled.load(Ordering::Relaxed)
+			{
+				break;
+			}
+			let level = get_hash_cash_level(&shared.omega, offset);
+			if level > best.0 {
+				best = (level, offset);
+			}
+			if level >= shared.level {
+				found = Some(offset);
+				break;
+			}
+			offset += 1;
+		}
+
+		let mut state = shared.state.lock();
+		state.running.remove(&start);
+		state.progress.checked += offset - start;
+		if best.0 > state.progress.best_level {
+			state.progress.best_level = best.0;
+			state.progress.best_offset = best.1;
+		}
+		if let Some(f) = found {
+			state.progress.checked += 1;
+			state.found = Some(state.found.map_or(f, |o| o.min(f)));
+		} else if offset < end {
+			// Cancelled
+			state.unfinished =
+				Some(state.unfinished.map_or(offset, |o| o.min(offset)));
+		}
+	}
+
+	let mut state = shared.state.lock();
+	state.workers -= 1;
+	if state.workers == 0 {
+		if let Some(send) = state.result.take() {
+			let _ = send.send(state.found);
+		}
+	}
+}
+
+impl HashCashHandle {
+	/// Stop the search.
+	///
+	/// The search resolves to an error, unless an offset was already found.
+	pub fn cancel(&self) {
+		self.shared.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	/// If the search is cancelled.
+	pub fn is_cancelled(&self) -> bool {
+		self.shared.cancelled.load(Ordering::Relaxed)
+	}
+
+	/// If all threads of the search stopped.
+	pub fn is_finished(&self) -> bool { self.shared.state.lock().workers == 0 }
+
+	/// A snapshot of the current progress.
+	pub fn progress(&self) -> HashCashProgress {
+		let state = self.shared.state.lock();
+		let mut counter = state.next;
+		for o in state
+			.running
+			.iter()
+			.next()
+			.into_iter()
+			.chain(state.unfinished.iter())
+			.chain(state.found.iter())
+		{
+			counter = counter.min(*o);
+		}
+		HashCashProgress {
+			counter,
+			..state.progress
+		}
+	}
+
+	/// Report the progress in a regular interval until the search stops.
+	pub fn progress_stream(
+		&self,
+		interval: std::time::Duration,
+	) -> impl Stream<Item = HashCashProgress, Error = Error>
+	{
+		let handle = self.clone();
+		let handle2 = self.clone();
+		Interval::new(tokio::clock::now() + interval, interval)
+			.from_err()
+			.take_while(move |_| Ok(!handle.is_finished()))
+			.map(move |_| handle2.progress())
+	}
+}
+
+impl HashCashSearch {
+	/// Get a handle to cancel the search or ask for its progress.
+	pub fn handle(&self) -> HashCashHandle { self.handle.clone() }
+}
+
+impl Future for HashCashSearch {
+	type Item = u64;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		match self.recv.poll()? {
+			Async::Ready(Some(offset)) => Ok(Async::Ready(offset)),
+			Async::Ready(None) if self.handle.is_cancelled() => {
+				Err(format_err!("Hash cash search was cancelled").into())
+			}
+			Async::Ready(None) => {
+				Err(format_err!("Found no offset for the hash cash level")
+					.into())
+			}
+			Async::NotReady => Ok(Async::NotReady),
+		}
+	}
+}
+
+impl Drop for HashCashSearch {
+	fn drop(&mut self) { self.handle.cancel(); }
+}
+
 pub fn biguint_to_array(i: &BigUint) -> [u8; 64] {
 	let mut v = i.to_bytes_le();
 
@@ -390,6 +730,66 @@ mod tests {
 		assert_eq!(&data, &dec_data);
 	}
 
+	#[test]
+	fn split_command() {
+		// Pseudo random data, which cannot be compressed
+		let mut x = 1u32;
+		let data = (0..2000)
+			.map(|_| {
+				x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+				b'a' + (x >> 16) as u8 % 26
+			})
+			.collect::<Vec<_>>();
+		let mut packet = OutPacket::new_with_dir(
+			Direction::C2S,
+			Flags::empty(),
+			PacketType::Command,
+		);
+		packet.data_mut().extend_from_slice(&data);
+
+		let packets = compress_and_split(true, packet);
+		assert_eq!(packets.len(), 5);
+		let mut content = Vec::new();
+		for (i, p) in packets.iter().enumerate() {
+			let flags = p.header().flags();
+			assert!(p.header_bytes().len() + p.content().len() <= 500);
+			assert!(!flags.contains(Flags::COMPRESSED));
+			assert_eq!(
+				flags.contains(Flags::FRAGMENTED),
+				i == 0 || i == packets.len() - 1
+			);
+			assert_eq!(p.header().packet_type(), PacketType::Command);
+			content.extend_from_slice(p.content());
+		}
+		assert_eq!(content, data);
+
+		// All fragments are written into one buffer
+		let mut packet = OutPacket::new_with_dir(
+			Direction::C2S,
+			Flags::empty(),
+			PacketType::Command,
+		);
+		packet.data_mut().extend_from_slice(&data);
+		let mut buf = BytesMut::new();
+		let len = compress_and_split_into(true, &packet, &mut buf).unwrap();
+		assert_eq!(len, 500);
+		let expected = packets
+			.into_iter()
+			.flat_map(OutPacket::into_vec)
+			.collect::<Vec<_>>();
+		assert_eq!(&buf[..], &expected[..]);
+
+		// Small packets are not copied
+		let packet = OutPacket::new_with_dir(
+			Direction::C2S,
+			Flags::empty(),
+			PacketType::Command,
+		);
+		let mut buf = BytesMut::new();
+		assert!(compress_and_split_into(true, &packet, &mut buf).is_none());
+		assert!(buf.is_empty());
+	}
+
 	#[test]
 	fn test_fake_encrypt() {
 		let mut packet = OutAck::new(Direction::C2S, PacketType::Command, 0);
@@ -403,7 +803,6 @@ mod tests {
 	}
 
 	#[test]
-	#[should_panic]
 	fn shared_iv31() {
 		let licenses = Licenses::parse(&base64::decode("AQA1hUFJiiSs\
 			0wFXkYuPUJVcDa6XCrZTcsvkB0Ffzz4CmwIITRXgCqeTYAcAAAAgQW5vbnltb3VzAAC\
@@ -488,4 +887,44 @@ mod tests {
 
 		assert!(keynonce.as_ref() == &expected_keynonce as &[u8]);
 	}
+
+	#[test]
+	fn hash_cash_parallel_finds_lowest_offset() {
+		let key = EccKeyPrivP256::create().unwrap().to_pub();
+		let expected = hash_cash(&key, 12).unwrap();
+		let config = HashCashConfig {
+			start: 0,
+			threads: 4,
+		};
+		let search = hash_cash_parallel(&key, 12, config).unwrap();
+		let handle = search.handle();
+		assert_eq!(search.wait().unwrap(), expected);
+
+		let progress = handle.progress();
+		assert_eq!(progress.counter, expected);
+		assert!(progress.best_level >= 12);
+		assert!(handle.is_finished());
+	}
+
+	#[test]
+	fn hash_cash_parallel_cancel() {
+		let key = EccKeyPrivP256::create().unwrap().to_pub();
+		let config = HashCashConfig {
+			start: 1000,
+			threads: 2,
+		};
+		let search = hash_cash_parallel(&key, 200, config).unwrap();
+		let handle = search.handle();
+		// Wait until the first chunk is checked
+		while handle.progress().checked == 0 {
+			std::thread::yield_now();
+		}
+		handle.cancel();
+		assert!(search.wait().is_err());
+
+		// The search can be resumed from the counter
+		let progress = handle.progress();
+		assert!(progress.counter > 1000);
+		assert!(progress.checked >= progress.counter - 1000);
+	}
 }
@@ -1,6 +1,8 @@
+use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::sync::{Arc, Weak};
 
+use bytes::{Bytes, BytesMut};
 use chrono::Utc;
 use failure::format_err;
 use futures::sync::{mpsc, oneshot};
@@ -16,6 +18,7 @@ use rug::integer::Order;
 #[cfg(feature = "rug")]
 use rug::Integer;
 use slog::{debug, error, info, Logger};
+use tokio::timer::Interval;
 
 use crate::algorithms as algs;
 use crate::built_info;
@@ -133,6 +136,7 @@ pub fn new<
 	local_addr: SocketAddr,
 	private_key: EccKeyPrivP256,
 	packet_handler: PH,
+	protocol_config: ProtocolConfig,
 	logger: L,
 ) -> Result<Arc<Mutex<ClientData<PH>>>>
 {
@@ -143,26 +147,70 @@ pub fn new<
 		None,
 		DefaultPacketHandler::new(packet_handler),
 		SocketConnectionManager::new(),
+		protocol_config,
 		logger,
 	)?;
+	init(&c);
+	Ok(c)
+}
 
-	let c2 = Arc::downgrade(&c);
-	{
-		let mut c = c.lock();
-		let c = &mut *c;
-		// Set the data reference
-		c.packet_handler.complete(c2.clone());
-
-		// Change state on disconnect
-		c.add_out_packet_observer(
-			"tsproto::client".into(),
-			Box::new(ClientOutPacketObserver),
-		);
-	}
-
+/// Create a new client without creating a socket.
+///
+/// The stream and sink of the socket have to be provided, see
+/// [`Data::new_with_socket`].
+///
+/// [`Data::new_with_socket`]: ../handler_data/struct.Data.html#method.new_with_socket
+pub fn new_with_socket<
+	PH: PacketHandler<ServerConnectionData> + 'static,
+	E1: Debug,
+	E2: Debug,
+>(
+	local_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+	packet_handler: PH,
+	protocol_config: ProtocolConfig,
+	sink: impl Sink<SinkItem = (Bytes, SocketAddr), SinkError = E1>
+		+ Send
+		+ 'static,
+	stream: impl Stream<Item = (BytesMut, SocketAddr), Error = E2>
+		+ Send
+		+ 'static,
+	logger: slog::Logger,
+) -> Result<Arc<Mutex<ClientData<PH>>>>
+{
+	let c = ClientData::new_with_socket(
+		local_addr,
+		private_key,
+		true,
+		None,
+		DefaultPacketHandler::new(packet_handler),
+		SocketConnectionManager::new(),
+		protocol_config,
+		sink,
+		stream,
+		logger,
+	)?;
+	init(&c);
 	Ok(c)
 }
 
+/// Set the data reference and add the observers of the client.
+fn init<PH: PacketHandler<ServerConnectionData> + 'static>(
+	c: &Arc<Mutex<ClientData<PH>>>,
+) {
+	let c2 = Arc::downgrade(c);
+	let mut c = c.lock();
+	let c = &mut *c;
+	// Set the data reference
+	c.packet_handler.complete(c2);
+
+	// Change state on disconnect
+	c.add_out_packet_observer(
+		"tsproto::client".into(),
+		Box::new(ClientOutPacketObserver),
+	);
+}
+
 /// Connect to a server.
 ///
 /// This function returns, when the client reached the
@@ -193,6 +241,7 @@ pub fn connect<PH: PacketHandler<ServerConnectionData>>(
 	// Add the connection to the connection list
 	let key = data.add_connection(datam, state, server_addr);
 	let con = data.get_connection(&key).unwrap().downgrade();
+	tokio::spawn(send_pings(con.clone(), data.logger.clone()));
 
 	let packet = OutC2SInit0::new(timestamp, timestamp, random0);
 	let con2 = con.clone();
@@ -206,6 +255,43 @@ pub fn connect<PH: PacketHandler<ServerConnectionData>>(
 		.and_then(move |_| Ok(con2))
 }
 
+/// Send a ping to the server in a regular interval while the client is
+/// connected.
+///
+/// The answers are used to measure the round trip time of the connection. The
+/// returned future ends when the connection is gone.
+fn send_pings(
+	con: ClientConVal,
+	logger: Logger,
+) -> impl Future<Item = (), Error = ()>
+{
+	let con2 = con.clone();
+	let start = tokio::clock::now() + crate::PING_INTERVAL;
+	Interval::new(start, crate::PING_INTERVAL)
+		.map_err(move |e| error!(logger, "Ping timer failed"; "error" => ?e))
+		.take_while(move |_| Ok(con2.mutex.upgrade().is_some()))
+		.for_each(move |_| {
+			let connected = if let Some(mutex) = con.mutex.upgrade() {
+				mutex.lock().0.state == ServerConnectionState::Connected
+			} else {
+				false
+			};
+			if !connected {
+				return future::Either::A(future::ok(()));
+			}
+
+			let packet = OutPacket::new_with_dir(
+				Direction::C2S,
+				Flags::UNENCRYPTED,
+				PacketType::Ping,
+			);
+			// Ignore errors, the resender handles timeouts
+			future::Either::B(
+				con.as_packet_sink().send(packet).then(|_| Ok(())),
+			)
+		})
+}
+
 struct ClientOutPacketObserver;
 impl OutPacketObserver<ServerConnectionData> for ClientOutPacketObserver {
 	fn observe(
@@ -717,9 +803,13 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 		let res = match state.state {
 			ServerConnectionState::ClientInitIv { ref alpha } => {
 				let resender = &mut con.resender;
+				let license_root_key = &con.protocol_config.license_root_key;
 				let res =
 					(|con_params: &mut Option<ConnectedParams>| -> Result<_> {
-						let cmd = command.iter().next().unwrap();
+						let cmd = command
+							.iter()
+							.next()
+							.ok_or_else(|| format_err!("Empty command"))?;
 						if command.name() == "initivexpand"
 							&& cmd.has("alpha") && cmd.has("beta")
 							&& cmd.has("omega") && base64::decode(cmd.0["alpha"])
@@ -781,8 +871,11 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 
 							// Parse license argument
 							let licenses = Licenses::parse(&l)?;
+							// Invalid licenses are not an error here, the
+							// chain can be checked with `Licenses::verify`.
 							// Ephemeral key of server
-							let server_ek = licenses.derive_public_key()?;
+							let server_ek = licenses
+								.derive_public_key_with_root(license_root_key)?;
 
 							// Create own ephemeral key
 							let ek = EccKeyPrivEd25519::create()?;
@@ -790,11 +883,12 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 							let (iv, mac) = algs::compute_iv_mac31(
 								alpha, &beta, &ek, &server_ek,
 							)?;
-							let params = ConnectedParams::new(
+							let mut params = ConnectedParams::new(
 								server_key,
 								SharedIv::Protocol31(iv),
 								mac,
 							);
+							params.licenses = Some(licenses);
 							*con_params = Some(params);
 
 							// Send clientek
@@ -841,7 +935,10 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 				}
 			}
 			ServerConnectionState::Connecting => {
-				let cmd = command.iter().next().unwrap();
+				let cmd = command
+					.iter()
+					.next()
+					.ok_or_else(|| format_err!("Empty command"))?;
 				if command.name() == "initserver" {
 					// Handle an initserver
 					if let Some(params) = &mut con.params {
@@ -871,7 +968,10 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 			}
 			ServerConnectionState::Connected => {
 				*ignore_packet = false;
-				let cmd = command.iter().next().unwrap();
+				let cmd = command
+					.iter()
+					.next()
+					.ok_or_else(|| format_err!("Empty command"))?;
 				if command.name() == "notifyclientleftview" {
 					// Handle a disconnect
 					if let Some(ref mut params) = con.params {
@@ -998,13 +1098,25 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use crate::handler_data::InPacketObserver;
+	use crate::clock::MockClock;
+	use crate::handler_data::{
+		BindSocket, InCommandObserver, InPacketObserver, OutPacketObserver,
+		UdpSink, UdpStream,
+	};
+	use crate::impairment::{Impaired, ImpairmentConfig};
+	use crate::middleware::{
+		CommandAction, InCommandMiddleware, OutCommandMiddleware,
+	};
 
-	use std::time::Duration;
+	use std::collections::VecDeque;
+	use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+	use std::time::{Duration, Instant};
 
-	use bytes::{Bytes, BytesMut};
-	use futures::stream;
+	use futures::{stream, task, Async};
 	use num_traits::ToPrimitive;
+	use rand::distributions::Alphanumeric;
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
 	use slog::{o, Drain};
 	use tokio::runtime::current_thread::Runtime;
 	use tokio::util::FutureExt;
@@ -1053,8 +1165,22 @@ mod tests {
 
 	impl TestConnection {
 		pub fn new() -> (Runtime, Self) {
-			let mut runtime = Runtime::new().unwrap();
+			Self::new_impaired(ImpairmentConfig::default())
+		}
+
+		/// Create a connection where the packets in both directions are sent
+		/// over an impaired link.
+		pub fn new_impaired(config: ImpairmentConfig) -> (Runtime, Self) {
+			Self::with_runtime(Runtime::new().unwrap(), config)
+		}
 
+		/// Create the connection in the given runtime, e.g. to use a mocked
+		/// clock.
+		pub fn with_runtime(
+			mut runtime: Runtime,
+			config: ImpairmentConfig,
+		) -> (Runtime, Self)
+		{
 			let (send_sink, send_stream) =
 				mpsc::unbounded::<(Bytes, SocketAddr)>();
 			let (recv_sink, recv_stream) =
@@ -1082,8 +1208,9 @@ mod tests {
 						None,
 						DefaultPacketHandler::new(TestPacketHandler),
 						SocketConnectionManager::new(),
+						Default::default(),
 						send_sink,
-						recv_stream,
+						Impaired::new(recv_stream, config.clone()),
 						logger.clone(),
 					)
 					.expect("Failed to create client");
@@ -1111,6 +1238,7 @@ mod tests {
 						None,
 						DefaultPacketHandler::new(TestPacketHandler),
 						SocketConnectionManager::new(),
+						Default::default(),
 						recv_sink
 							.sink_map_err(|e| {
 								format_err!(
@@ -1121,7 +1249,11 @@ mod tests {
 							.with(|(b, a): (Bytes, SocketAddr)| -> Result<_> {
 								Ok((b.into(), a))
 							}),
-						send_stream.map(|(b, a)| (b.into(), a)),
+						Impaired::new(send_stream, ImpairmentConfig {
+							seed: config.seed.wrapping_add(1),
+							..config.clone()
+						})
+						.map(|(b, a)| (b.into(), a)),
 						logger.new(o!("server" => true)),
 					)
 					.expect("Failed to create \"server\" mock");
@@ -1155,6 +1287,15 @@ mod tests {
 		fn set_connected(
 			data: &Arc<Mutex<ClientData<TestPacketHandler>>>,
 		) -> ClientConVal {
+			Self::set_connected_at(data, "127.0.0.1:1".parse().unwrap())
+		}
+
+		/// Add a connected connection to `address`.
+		fn set_connected_at(
+			data: &Arc<Mutex<ClientData<TestPacketHandler>>>,
+			address: SocketAddr,
+		) -> ClientConVal
+		{
 			let mut client = data.lock();
 			let con_key = client.add_connection(
 				Arc::downgrade(&data),
@@ -1162,7 +1303,7 @@ mod tests {
 					state_change_listener: Vec::new(),
 					state: ServerConnectionState::Connected,
 				},
-				"127.0.0.1:1".parse().unwrap(),
+				address,
 			);
 			let connection = client.get_connection(&con_key).unwrap();
 			let mut con = connection.mutex.lock();
@@ -1245,6 +1386,49 @@ mod tests {
 		runtime.run().unwrap();
 	}
 
+	/// Skip the connect timeout with a mocked clock.
+	#[test]
+	fn test_connect_timeout_mock_clock() {
+		let clock = MockClock::new();
+		let (mut runtime, con) = TestConnection::with_runtime(
+			clock.runtime().unwrap(),
+			Default::default(),
+		);
+		let start = Instant::now();
+
+		runtime
+			.block_on(future::lazy(move || {
+				let cw = Arc::downgrade(&con.client);
+				let (send, recv) = mpsc::unbounded();
+				con.server.lock().add_in_packet_observer(
+					"tsproto::test".into(),
+					Box::new(InitObserver(send)),
+				);
+
+				// Jump over the timeout after the first init packet arrived
+				tokio::spawn(
+					recv.into_future()
+						.map(move |_| clock.advance(Duration::from_secs(60)))
+						.map_err(|_| panic!("Failed to receive init packet")),
+				);
+
+				connect(
+					cw,
+					&mut *con.client.lock(),
+					"127.0.0.1:1".parse().unwrap(),
+				)
+				.then(move |r| {
+					drop(con);
+					match r {
+						Ok(_) => panic!("Should not connect"),
+						Err(_) => Ok::<_, ()>(()),
+					}
+				})
+			}))
+			.unwrap();
+		assert!(start.elapsed() < Duration::from_secs(4));
+	}
+
 	struct PongObserver(mpsc::UnboundedSender<()>);
 	impl<T> InPacketObserver<T> for PongObserver {
 		fn observe(&self, _: &mut (T, Connection), packet: &InPacket) {
@@ -1298,6 +1482,170 @@ mod tests {
 			.unwrap();
 	}
 
+	/// Advance the clock when a ping arrives, so the pong seems to be late.
+	struct SlowPingObserver(MockClock, Mutex<VecDeque<Duration>>);
+	impl<T> InPacketObserver<T> for SlowPingObserver {
+		fn observe(&self, _: &mut (T, Connection), packet: &InPacket) {
+			if packet.header().packet_type() == PacketType::Ping {
+				if let Some(delay) = self.1.lock().pop_front() {
+					self.0.advance(delay);
+				}
+			}
+		}
+	}
+
+	struct AnyPongObserver(mpsc::UnboundedSender<()>);
+	impl<T> InPacketObserver<T> for AnyPongObserver {
+		fn observe(&self, _: &mut (T, Connection), packet: &InPacket) {
+			if packet.header().packet_type() == PacketType::Pong {
+				self.0.unbounded_send(()).unwrap();
+			}
+		}
+	}
+
+	/// Answer pings of the client and check the round trip time statistics.
+	#[test]
+	fn test_ping_stats() {
+		let clock = MockClock::new();
+		let (mut runtime, con) = TestConnection::with_runtime(
+			clock.runtime().unwrap(),
+			Default::default(),
+		);
+
+		let delays = vec![Duration::from_secs(1), Duration::from_secs(3)];
+		con.server.lock().add_in_packet_observer(
+			"tsproto::test".into(),
+			Box::new(SlowPingObserver(clock, Mutex::new(delays.into()))),
+		);
+		let (send, recv) = mpsc::unbounded();
+		con.client.lock().add_in_packet_observer(
+			"tsproto::test".into(),
+			Box::new(AnyPongObserver(send)),
+		);
+		let client_con = con.client_con.upgrade().unwrap();
+		assert!(client_con.ping_stats().is_none());
+
+		let ping = || {
+			OutPacket::new_with_dir(
+				Direction::C2S,
+				Flags::UNENCRYPTED,
+				PacketType::Ping,
+			)
+		};
+		// The real time which passes additionally to the mocked delays
+		let tolerance = chrono::Duration::milliseconds(500);
+		let is_close = |a: chrono::Duration, b: chrono::Duration| {
+			a >= b && a - b < tolerance
+		};
+
+		let recv = runtime
+			.block_on(future::lazy(|| {
+				con.client_con
+					.as_packet_sink()
+					.send(ping())
+					.map_err(|e| panic!("Failed to send ping: {:?}", e))
+					.and_then(|_| recv.into_future().map_err(|_| ()))
+					.map(|(_, recv)| recv)
+			}))
+			.unwrap();
+
+		let stats = client_con.ping_stats().expect("No ping stats");
+		assert!(is_close(stats.current, chrono::Duration::seconds(1)));
+		assert_eq!(stats.average, stats.current);
+		assert_eq!(stats.jitter, chrono::Duration::zero());
+
+		runtime
+			.block_on(future::lazy(|| {
+				con.client_con
+					.as_packet_sink()
+					.send(ping())
+					.map_err(|e| panic!("Failed to send ping: {:?}", e))
+					.and_then(|_| recv.into_future().map_err(|_| ()))
+			}))
+			.unwrap();
+
+		let stats = client_con.ping_stats().expect("No ping stats");
+		assert!(is_close(stats.current, chrono::Duration::seconds(3)));
+		// 1 s * 7/8 + 3 s / 8
+		assert!(is_close(stats.average, chrono::Duration::milliseconds(1250)));
+		// |3 s - 1 s| / 4
+		assert!(is_close(stats.jitter, chrono::Duration::milliseconds(500)));
+		drop(con);
+	}
+
+	struct PingIdObserver(mpsc::UnboundedSender<u16>);
+	impl<T> InPacketObserver<T> for PingIdObserver {
+		fn observe(&self, _: &mut (T, Connection), packet: &InPacket) {
+			let header = packet.header();
+			if header.packet_type() == PacketType::Ping {
+				self.0.unbounded_send(header.packet_id()).unwrap();
+			}
+		}
+	}
+
+	/// Send pings with wrapping, duplicated and reordered ids and check the
+	/// traffic and loss statistics.
+	#[test]
+	fn test_stats() {
+		let (mut runtime, con) = TestConnection::new();
+		let type_i = PacketType::Ping.to_usize().unwrap();
+		con.client_con.upgrade().unwrap().mutex.lock().1.incoming_p_ids
+			[type_i] = (0, 65_533);
+
+		let (send, recv) = mpsc::unbounded();
+		con.client.lock().add_in_packet_observer(
+			"tsproto::test".into(),
+			Box::new(PingIdObserver(send)),
+		);
+
+		// 65 534, 0 and 1 get lost, the second 65 535 is a duplicate and 1
+		// arrives too late.
+		let ids = [65_533, 65_535, 65_535, 2, 1, 3];
+		for &id in &ids {
+			{
+				let server_con = con.server_con.upgrade().unwrap();
+				let mut server_con = server_con.mutex.lock();
+				let gen = if id < 65_533 { 1 } else { 0 };
+				server_con.1.outgoing_p_ids[type_i] = (gen, id);
+			}
+			let packet = OutPacket::new_with_dir(
+				Direction::S2C,
+				Flags::UNENCRYPTED,
+				PacketType::Ping,
+			);
+			runtime.block_on(con.send_packet(packet)).unwrap();
+		}
+
+		// Wait until the last ping arrived
+		let received = runtime
+			.block_on(
+				recv.take_while(|id| Ok(*id != 3))
+					.collect()
+					.timeout(Duration::from_secs(5)),
+			)
+			.expect("Failed to receive pings");
+		assert_eq!(received, vec![65_533, 65_535, 2]);
+
+		let header_len = tsproto_packets::S2C_HEADER_LEN as u64;
+		let stats = con.server_con.upgrade().unwrap().stats();
+		assert_eq!(stats.sent[type_i], TrafficCounter {
+			packets: 6,
+			bytes: 6 * header_len,
+		});
+
+		let stats = con.client_con.upgrade().unwrap().stats();
+		assert_eq!(stats.received[type_i], TrafficCounter {
+			packets: 4,
+			bytes: 4 * header_len,
+		});
+		assert_eq!(stats.lost[type_i], 3);
+		assert_eq!(stats.received_kind(TrafficKind::Keepalive).packets, 4);
+		assert_eq!(stats.lost_kind(TrafficKind::Keepalive), 3);
+		assert_eq!(stats.incoming_loss(TrafficKind::Keepalive), 3.0 / 7.0);
+		assert_eq!(stats.lost_kind(TrafficKind::Speech), 0);
+		drop(con);
+	}
+
 	struct CounterObserver(mpsc::UnboundedSender<()>, Mutex<usize>);
 	impl<T> InPacketObserver<T> for CounterObserver {
 		fn observe(&self, _: &mut (T, Connection), packet: &InPacket) {
@@ -1377,4 +1725,545 @@ mod tests {
 		}));
 		runtime.run().unwrap();
 	}
+
+	struct MessageObserver(mpsc::UnboundedSender<String>);
+	impl<T> InCommandObserver<T> for MessageObserver {
+		fn observe(&self, _: &mut (T, Connection), cmd: &InCommand) {
+			let msg = cmd.iter().next().and_then(|c| c.get("msg")).unwrap();
+			self.0.unbounded_send(msg.to_string()).unwrap();
+		}
+	}
+
+	fn text_message(dir: Direction, msg: String) -> OutPacket {
+		let name = if dir == Direction::S2C {
+			"notifytextmessage"
+		} else {
+			"sendtextmessage"
+		};
+		OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+			dir,
+			PacketType::Command,
+			name,
+			vec![("msg", msg)].into_iter(),
+			std::iter::empty(),
+		)
+	}
+
+	/// Send messages from the server and return the messages which arrived
+	/// at the client.
+	fn send_messages(
+		config: ImpairmentConfig,
+		msgs: Vec<String>,
+	) -> Vec<String>
+	{
+		let count = msgs.len();
+		send_messages_with(config, msgs, count, |_| {})
+	}
+
+	/// Call `setup` on the connection, send messages from the server and
+	/// return the first `count` messages which arrived at the client.
+	fn send_messages_with(
+		config: ImpairmentConfig,
+		msgs: Vec<String>,
+		count: usize,
+		setup: impl FnOnce(&TestConnection),
+	) -> Vec<String>
+	{
+		let (mut runtime, con) = TestConnection::new_impaired(config);
+
+		runtime
+			.block_on(future::lazy(move || {
+				setup(&con);
+				let (send, recv) = mpsc::unbounded();
+				con.client.lock().add_in_command_observer(
+					"tsproto::test".into(),
+					Box::new(MessageObserver(send)),
+				);
+
+				let packets = msgs
+					.into_iter()
+					.map(|msg| {
+						con.send_packet(text_message(Direction::S2C, msg))
+					})
+					.collect::<Vec<_>>();
+				tokio::spawn(
+					stream::futures_ordered(packets)
+						.for_each(|_| Ok(()))
+						.map_err(|e| panic!("Failed to send packet: {:?}", e)),
+				);
+
+				recv.take(count as u64)
+					.collect()
+					.timeout(Duration::from_secs(30))
+					.map(|r| {
+						drop(con);
+						r
+					})
+					.map_err(|_| panic!("Failed to receive all messages"))
+			}))
+			.unwrap()
+	}
+
+	/// Commands arrive in order and without duplicates over a bad link.
+	#[test]
+	fn test_impaired_resend() {
+		let config = ImpairmentConfig {
+			seed: 5,
+			loss: 0.05,
+			duplicate: 0.05,
+			reorder: 0.1,
+			delay: Duration::from_millis(5),
+			jitter: Duration::from_millis(5),
+			..Default::default()
+		};
+		let msgs =
+			(0..20).map(|i| format!("message {}", i)).collect::<Vec<_>>();
+		assert_eq!(send_messages(config, msgs.clone()), msgs);
+	}
+
+	/// A large command gets fragmented and reassembled over a bad link.
+	#[test]
+	fn test_impaired_fragments() {
+		let config = ImpairmentConfig {
+			seed: 7,
+			loss: 0.05,
+			reorder: 0.2,
+			jitter: Duration::from_millis(5),
+			..Default::default()
+		};
+		// Derive the message from the seed to make runs reproducible
+		let msg = StdRng::seed_from_u64(config.seed)
+			.sample_iter(&Alphanumeric)
+			.take(5000)
+			.collect::<String>();
+		assert_eq!(send_messages(config, vec![msg.clone()]), vec![msg]);
+	}
+
+	/// Drops `secret` messages.
+	struct DropSecret;
+	impl<T> InCommandMiddleware<T> for DropSecret {
+		fn handle(
+			&self,
+			_: &mut (T, Connection),
+			cmd: InCommand,
+		) -> CommandAction<InCommand>
+		{
+			let msg = cmd.iter().next().and_then(|c| c.get("msg"));
+			if msg == Some("secret") {
+				CommandAction::Drop
+			} else {
+				CommandAction::Continue(cmd)
+			}
+		}
+	}
+
+	/// Drops `drop` messages and rewrites `hello` messages.
+	struct RewriteHello;
+	impl<T> OutCommandMiddleware<T> for RewriteHello {
+		fn handle(
+			&self,
+			_: &mut (T, Connection),
+			packet: OutPacket,
+		) -> CommandAction<OutPacket>
+		{
+			let content =
+				String::from_utf8_lossy(packet.content()).into_owned();
+			if content.ends_with("msg=drop") {
+				CommandAction::Drop
+			} else if content.ends_with("msg=hello") {
+				CommandAction::Continue(text_message(
+					Direction::S2C,
+					"rewritten".into(),
+				))
+			} else {
+				CommandAction::Continue(packet)
+			}
+		}
+	}
+
+	/// Records the content of outgoing commands.
+	struct CommandRecorder(Arc<Mutex<Vec<String>>>);
+	impl<T> OutPacketObserver<T> for CommandRecorder {
+		fn observe(&self, _: &mut (T, Connection), packet: &mut OutPacket) {
+			if packet.header().packet_type() == PacketType::Command {
+				let content = String::from_utf8_lossy(packet.content());
+				self.0.lock().push(content.into_owned());
+			}
+		}
+	}
+
+	/// Commands pass the middlewares of the sender and the receiver and the
+	/// observers only see commands which passed the middlewares.
+	#[test]
+	fn test_middleware() {
+		let sent = Arc::new(Mutex::new(Vec::new()));
+		let sent2 = sent.clone();
+		let msgs = ["hello", "drop", "secret", "last"]
+			.iter()
+			.map(|s| s.to_string())
+			.collect();
+		let received =
+			send_messages_with(Default::default(), msgs, 2, move |con| {
+				let mut server = con.server.lock();
+				server.add_out_command_middleware(
+					"tsproto::test".into(),
+					0,
+					Box::new(RewriteHello),
+				);
+				server.add_out_packet_observer(
+					"tsproto::test".into(),
+					Box::new(CommandRecorder(sent2)),
+				);
+				con.client.lock().add_in_command_middleware(
+					"tsproto::test".into(),
+					0,
+					Box::new(DropSecret),
+				);
+			});
+		assert_eq!(received, ["rewritten", "last"]);
+		assert_eq!(
+			*sent.lock(),
+			[
+				"notifytextmessage msg=rewritten",
+				"notifytextmessage msg=secret",
+				"notifytextmessage msg=last",
+			]
+		);
+	}
+
+	struct AckObserver(mpsc::UnboundedSender<()>);
+	impl<T> InPacketObserver<T> for AckObserver {
+		fn observe(&self, _: &mut (T, Connection), packet: &InPacket) {
+			if packet.header().packet_type() == PacketType::Ack {
+				let _ = self.0.unbounded_send(());
+			}
+		}
+	}
+
+	/// Send a command from a client to a "server" and wait until the client
+	/// received the ack.
+	///
+	/// The client starts with `sink` and `stream` as its socket. The server
+	/// sends to `server_send` and receives from `server_recv`. `setup` is
+	/// called on the client before the command is sent and `received` after
+	/// the server got the command.
+	fn send_acked_command<E1: Debug, E2: Debug>(
+		config: ProtocolConfig,
+		sink: impl Sink<SinkItem = (Bytes, SocketAddr), SinkError = E1>
+			+ Send
+			+ 'static,
+		stream: impl Stream<Item = (BytesMut, SocketAddr), Error = E2>
+			+ Send
+			+ 'static,
+		server_send: mpsc::UnboundedSender<(Bytes, SocketAddr)>,
+		server_recv: mpsc::UnboundedReceiver<(Bytes, SocketAddr)>,
+		setup: impl FnOnce(&mut ClientData<TestPacketHandler>) + Send + 'static,
+		received: impl FnOnce(&mut ClientData<TestPacketHandler>)
+			+ Send
+			+ 'static,
+	)
+	{
+		let mut runtime = Runtime::new().unwrap();
+		let logger = slog::Logger::root(slog::Discard, o!());
+
+		runtime
+			.block_on(future::lazy(move || {
+				let client = ClientData::new_with_socket(
+					"127.0.0.1:1".parse().unwrap(),
+					EccKeyPrivP256::create().unwrap(),
+					true,
+					None,
+					DefaultPacketHandler::new(TestPacketHandler),
+					SocketConnectionManager::new(),
+					config,
+					sink,
+					stream,
+					logger.clone(),
+				)
+				.unwrap();
+				{
+					let mut c = client.lock();
+					c.packet_handler.complete(Arc::downgrade(&client));
+					setup(&mut c);
+				}
+				let server = ClientData::new_with_socket(
+					"127.0.0.1:2".parse().unwrap(),
+					EccKeyPrivP256::create().unwrap(),
+					false,
+					None,
+					DefaultPacketHandler::new(TestPacketHandler),
+					SocketConnectionManager::new(),
+					Default::default(),
+					server_send,
+					server_recv.map(|(b, a)| (b.into(), a)),
+					logger,
+				)
+				.unwrap();
+				server
+					.lock()
+					.packet_handler
+					.complete(Arc::downgrade(&server));
+				let client_con = TestConnection::set_connected(&client);
+				TestConnection::set_connected(&server);
+
+				let (msg_send, msg_recv) = mpsc::unbounded();
+				server.lock().add_in_command_observer(
+					"tsproto::test".into(),
+					Box::new(MessageObserver(msg_send)),
+				);
+				let (ack_send, ack_recv) = mpsc::unbounded();
+				client.lock().add_in_packet_observer(
+					"tsproto::test".into(),
+					Box::new(AckObserver(ack_send)),
+				);
+
+				client_con
+					.as_packet_sink()
+					.send(text_message(Direction::C2S, "rebind".into()))
+					.and_then(|_| {
+						msg_recv.into_future().map_err(|_| {
+							format_err!("Failed to receive message").into()
+						})
+					})
+					.and_then(move |(msg, msg_recv)| {
+						assert_eq!(
+							msg.as_ref().map(String::as_str),
+							Some("rebind")
+						);
+						received(&mut client.lock());
+						ack_recv
+							.into_future()
+							.map(move |_| (client, server, msg_recv))
+							.map_err(|_| {
+								format_err!("Failed to receive ack").into()
+							})
+					})
+					.timeout(Duration::from_secs(10))
+					.map(move |(client, server, _)| {
+						let con = client_con.upgrade().unwrap();
+						assert!(con.mutex.lock().1.resender.is_empty());
+						drop(client);
+						drop(server);
+					})
+					.map_err(|e: tokio::timer::timeout::Error<Error>| {
+						panic!("Rebinding failed: {:?}", e)
+					})
+			}))
+			.unwrap();
+	}
+
+	/// A command, which was not acknowledged, is resent after the client
+	/// switched to a new socket and the connection continues over it.
+	#[test]
+	fn test_rebind() {
+		let (to_server, server_recv) = mpsc::unbounded::<(Bytes, SocketAddr)>();
+		let (server_send, from_server) =
+			mpsc::unbounded::<(Bytes, SocketAddr)>();
+		// Packets from the server arrive at the current socket of the client,
+		// until the client rebinds they are lost.
+		let route = Arc::new(Mutex::new(mpsc::unbounded().0));
+		let (_old_send, old_recv) = mpsc::unbounded::<(BytesMut, SocketAddr)>();
+		let (new_send, new_recv) = mpsc::unbounded::<(BytesMut, SocketAddr)>();
+
+		let route2 = route.clone();
+		let to_server2 = to_server.clone();
+		send_acked_command(
+			Default::default(),
+			to_server,
+			old_recv,
+			server_send,
+			server_recv,
+			move |_| {
+				tokio::spawn(from_server.for_each(move |(b, a)| {
+					let _ = route2.lock().unbounded_send((b.into(), a));
+					Ok(())
+				}));
+			},
+			move |client| {
+				// The ack got lost, switch to a new socket
+				*route.lock() = new_send;
+				client.rebind_with_socket(
+					"127.0.0.1:3".parse().unwrap(),
+					to_server2,
+					new_recv,
+				);
+			},
+		);
+	}
+
+	/// A failed send binds a new socket at most once per `rebind_interval`
+	/// and the unacknowledged command is resent over the new socket.
+	#[test]
+	fn test_rebind_on_send_error() {
+		let (to_server, server_recv) = mpsc::unbounded::<(Bytes, SocketAddr)>();
+		let (server_send, from_server) =
+			mpsc::unbounded::<(Bytes, SocketAddr)>();
+		// Sending over the old socket fails
+		let (broken, _) = mpsc::unbounded::<(Bytes, SocketAddr)>();
+		let (_old_send, old_recv) = mpsc::unbounded::<(BytesMut, SocketAddr)>();
+		let (new_send, new_recv) = mpsc::unbounded::<(BytesMut, SocketAddr)>();
+		let new_recv = Mutex::new(Some(new_recv));
+		let binds = Arc::new(AtomicUsize::new(0));
+
+		// The new socket fails to send the first packet, this must not bind
+		// another socket.
+		let binds2 = binds.clone();
+		let fail_first = Arc::new(AtomicBool::new(true));
+		let bind_socket: BindSocket = Box::new(move |_| {
+			binds2.fetch_add(1, Ordering::SeqCst);
+			let fail_first = fail_first.clone();
+			let sink: UdpSink = Box::new(
+				to_server
+					.clone()
+					.sink_map_err(|e| {
+						Error::from(format_err!("Failed to send ({:?})", e))
+					})
+					.with(move |p| {
+						if fail_first.swap(false, Ordering::SeqCst) {
+							Err(Error::from(format_err!("Network is unreachable")))
+						} else {
+							Ok(p)
+						}
+					}),
+			);
+			let stream: UdpStream = match new_recv.lock().take() {
+				Some(recv) => Box::new(recv.map_err(|_| {
+					Error::from(format_err!("Failed to receive"))
+				})),
+				None => {
+					Box::new(stream::empty::<(BytesMut, SocketAddr), Error>())
+				}
+			};
+			Ok(("127.0.0.1:3".parse().unwrap(), sink, stream))
+		});
+
+		let config = ProtocolConfig {
+			rebind_on_send_error: true,
+			rebind_interval: Duration::from_secs(60),
+			..Default::default()
+		};
+		send_acked_command(
+			config,
+			broken,
+			old_recv,
+			server_send,
+			server_recv,
+			move |client| {
+				tokio::spawn(from_server.for_each(move |(b, a)| {
+					let _ = new_send.unbounded_send((b.into(), a));
+					Ok(())
+				}));
+				client.set_bind_socket(
+					"127.0.0.1:0".parse().unwrap(),
+					bind_socket,
+				);
+			},
+			|_| {},
+		);
+		assert_eq!(binds.load(Ordering::SeqCst), 1);
+	}
+
+	/// The resend scheduler of a `Data` object polls each connection at its
+	/// own deadline.
+	#[test]
+	fn test_resend_scheduler() {
+		let clock = MockClock::new();
+		let mut runtime = clock.runtime().unwrap();
+		let logger = slog::Logger::root(slog::Discard, o!());
+		let (udp_send, mut udp_recv) = mpsc::unbounded::<(Bytes, SocketAddr)>();
+		let (_in_send, in_recv) = mpsc::unbounded::<(BytesMut, SocketAddr)>();
+		let addr_a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+		let addr_b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+		let step = Duration::from_millis(100);
+		let b_delay = Duration::from_millis(300);
+
+		let sent = runtime
+			.block_on(future::lazy(move || {
+				let client = ClientData::new_with_socket(
+					"127.0.0.1:3".parse().unwrap(),
+					EccKeyPrivP256::create().unwrap(),
+					true,
+					None,
+					DefaultPacketHandler::new(TestPacketHandler),
+					SocketConnectionManager::new(),
+					Default::default(),
+					udp_send,
+					in_recv,
+					logger,
+				)
+				.unwrap();
+				client
+					.lock()
+					.packet_handler
+					.complete(Arc::downgrade(&client));
+				let con_a = TestConnection::set_connected_at(&client, addr_a);
+				let con_b = TestConnection::set_connected_at(&client, addr_b);
+
+				// Nobody acknowledges the commands, so they get resent
+				let send = |con: &ClientConVal| {
+					let packet =
+						text_message(Direction::C2S, "resend".into());
+					tokio::spawn(
+						con.as_packet_sink()
+							.send(packet)
+							.map(|_| ())
+							.map_err(|e| panic!("Failed to send: {:?}", e)),
+					);
+				};
+				send(&con_a);
+
+				let start = tokio::clock::now();
+				let mut b_sent = false;
+				let mut sent = Vec::new();
+				future::poll_fn(move || {
+					// Keep the client alive
+					let _ = &client;
+					while let Async::Ready(Some((_, addr))) = udp_recv.poll()? {
+						sent.push((addr, tokio::clock::now()));
+					}
+					let count = |a: SocketAddr| {
+						sent.iter().filter(|(s, _)| *s == a).count()
+					};
+					if count(addr_a) >= 2 && count(addr_b) >= 2 {
+						return Ok(Async::Ready(sent.clone()));
+					}
+
+					let elapsed = tokio::clock::now() - start;
+					if !b_sent && elapsed >= b_delay {
+						send(&con_b);
+						b_sent = true;
+					}
+					assert!(elapsed < Duration::from_secs(60), "No resend");
+					clock.advance(step);
+					task::current().notify();
+					Ok(Async::NotReady)
+				})
+			}))
+			.unwrap();
+
+		let times = |a: SocketAddr| {
+			sent.iter()
+				.filter(|(s, _)| *s == a)
+				.map(|(_, t)| *t)
+				.collect::<Vec<_>>()
+		};
+		let (a, b) = (times(addr_a), times(addr_b));
+		// Both connections are resent after the same timeout
+		let timeout_a = a[1] - a[0];
+		let timeout_b = b[1] - b[0];
+		assert!(timeout_a > step && timeout_b > step);
+		let diff = if timeout_a > timeout_b {
+			timeout_a - timeout_b
+		} else {
+			timeout_b - timeout_a
+		};
+		assert!(
+			diff <= step * 2,
+			"Resent after {:?} and {:?}",
+			timeout_a,
+			timeout_b
+		);
+		// The second connection was resent later
+		assert!(b[1] - a[1] >= b_delay - step * 2);
+	}
 }
@@ -0,0 +1,85 @@
+//! Time as seen by the protocol implementation.
+//!
+//! All timeouts of tsproto use the clock of the current tokio runtime. A
+//! runtime with a [`MockClock`] can be used to skip time in tests, e.g. to
+//! check that a connection times out without waiting for it.
+//!
+//! [`MockClock`]: struct.MockClock.html
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use tokio::clock::{Clock, Now};
+use tokio::runtime::current_thread;
+
+/// The current time of the tokio clock.
+///
+/// Without a mocked clock, this is the same as `Utc::now()`.
+pub fn now() -> DateTime<Utc> {
+	let clock = tokio::clock::now();
+	let real = Instant::now();
+	let now = Utc::now();
+	if clock >= real {
+		now + chrono::Duration::from_std(clock - real).unwrap()
+	} else {
+		now - chrono::Duration::from_std(real - clock).unwrap()
+	}
+}
+
+/// A clock which runs with the real time, but can be advanced manually.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock {
+	offset: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+	pub fn new() -> Self { Self::default() }
+
+	/// Move the clock forward.
+	///
+	/// Timers, which expire because of this, fire on the next turn of the
+	/// runtime.
+	pub fn advance(&self, duration: Duration) {
+		*self.offset.lock() += duration;
+	}
+
+	/// Create a tokio clock, which can be passed to a runtime builder.
+	pub fn clock(&self) -> Clock { Clock::new_with_now(self.clone()) }
+
+	/// Create a single threaded runtime, which uses this clock.
+	pub fn runtime(&self) -> std::io::Result<current_thread::Runtime> {
+		current_thread::Builder::new().clock(self.clock()).build()
+	}
+}
+
+impl Now for MockClock {
+	fn now(&self) -> Instant { Instant::now() + *self.offset.lock() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use futures::{future, Future};
+	use tokio::timer::Delay;
+
+	#[test]
+	fn advance_fires_timer() {
+		let clock = MockClock::new();
+		let mut runtime = clock.runtime().unwrap();
+		let start = Instant::now();
+
+		runtime
+			.block_on(future::lazy(|| {
+				let real_start = Utc::now();
+				let delay =
+					Delay::new(tokio::clock::now() + Duration::from_secs(60));
+				clock.advance(Duration::from_secs(60));
+				assert!(now() - real_start >= chrono::Duration::seconds(60));
+				delay
+			}))
+			.unwrap();
+		assert!(start.elapsed() < Duration::from_secs(30));
+	}
+}
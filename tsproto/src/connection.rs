@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::fmt;
 use std::net::SocketAddr;
 use std::{u16, u64};
@@ -5,10 +6,11 @@ use std::{u16, u64};
 use aes::block_cipher_trait::generic_array::typenum::consts::U16;
 use aes::block_cipher_trait::generic_array::GenericArray;
 use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
 use failure::format_err;
 use futures::sync::mpsc;
 use futures::{self, AsyncSink, Sink};
-use num_traits::ToPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Unexpected, Visitor};
 use slog;
@@ -16,11 +18,19 @@ use tsproto_packets::HexSlice;
 use tsproto_packets::packets::*;
 
 use crate::algorithms as algs;
-use crate::crypto::{EccKeyPubP256, EccKeyPrivP256};
+use crate::clock;
+use crate::crypto::{EccKeyPrivP256, EccKeyPubEd25519, EccKeyPubP256};
 use crate::handler_data::{ConnectionValue, ConnectionValueWeak};
+use crate::license::Licenses;
 use crate::resend::DefaultResender;
+use crate::send_queue::SendQueueSink;
 use crate::{Error, Result};
 
+/// The maximum number of unanswered pings which are remembered.
+const MAX_OUTSTANDING_PINGS: usize = 8;
+/// The number of seconds for which the bandwidth history is kept.
+const STATS_HISTORY_SECS: i64 = 60;
+
 /// A cache for the key and nonce for a generation id.
 /// This has to be stored for each packet type.
 #[derive(Debug)]
@@ -83,7 +93,11 @@ pub struct Identity {
 pub struct ConnectedParams {
 	/// The client id of this connection.
 	pub c_id: u16,
-	/// If voice packets should be encrypted
+	/// If voice packets should be encrypted.
+	///
+	/// Incoming unencrypted `Voice` packets are rejected if this is set.
+	/// `VoiceWhisper` packets are accepted anyway, because their encryption
+	/// depends on the channel of the sender.
 	pub voice_encryption: bool,
 
 	/// The public key of the other side.
@@ -92,6 +106,11 @@ pub struct ConnectedParams {
 	pub shared_iv: SharedIv,
 	/// The mac used for unencrypted packets.
 	pub shared_mac: [u8; 8],
+	/// The license chain of the server.
+	///
+	/// This is only set for clients which connected with the protocol of
+	/// TeamSpeak 3.1 or newer (`initivexpand2`).
+	pub licenses: Option<Licenses>,
 	/// Cached key and nonce per packet type and for server to client (without
 	/// client id inside the packet) and client to server communication.
 	pub key_cache: [[CachedKey; 2]; 8],
@@ -111,11 +130,336 @@ impl ConnectedParams {
 			public_key,
 			shared_iv,
 			shared_mac,
+			licenses: None,
 			key_cache: Default::default(),
 		}
 	}
 }
 
+/// Round trip time statistics of a connection, measured with `Ping` packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PingStats {
+	/// The round trip time of the last answered ping.
+	pub current: Duration,
+	/// The smoothed average of the round trip time.
+	pub average: Duration,
+	/// The smoothed deviation of the round trip time.
+	pub jitter: Duration,
+}
+
+impl PingStats {
+	fn new(rtt: Duration) -> Self {
+		Self { current: rtt, average: rtt, jitter: Duration::zero() }
+	}
+
+	/// Add a new round trip time sample.
+	fn update(&mut self, rtt: Duration) {
+		let diff = if rtt > self.average {
+			rtt - self.average
+		} else {
+			self.average - rtt
+		};
+		self.jitter = self.jitter * 3 / 4 + diff / 4;
+		self.average = self.average * 7 / 8 + rtt / 8;
+		self.current = rtt;
+	}
+}
+
+/// The categories of packets, as they are shown in the connection info of the
+/// TeamSpeak client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrafficKind {
+	/// `Voice` and `VoiceWhisper` packets.
+	Speech,
+	/// `Ping` and `Pong` packets.
+	Keepalive,
+	/// Commands, acks and init packets.
+	Control,
+}
+
+impl From<PacketType> for TrafficKind {
+	fn from(p_type: PacketType) -> Self {
+		match p_type {
+			PacketType::Voice | PacketType::VoiceWhisper => TrafficKind::Speech,
+			PacketType::Ping | PacketType::Pong => TrafficKind::Keepalive,
+			PacketType::Command
+			| PacketType::CommandLow
+			| PacketType::Ack
+			| PacketType::AckLow
+			| PacketType::Init => TrafficKind::Control,
+		}
+	}
+}
+
+/// A number of packets and their summed up size in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficCounter {
+	pub packets: u64,
+	pub bytes: u64,
+}
+
+impl TrafficCounter {
+	fn record(&mut self, len: usize) {
+		self.packets += 1;
+		self.bytes += len as u64;
+	}
+}
+
+impl std::ops::Add for TrafficCounter {
+	type Output = Self;
+	fn add(self, other: Self) -> Self {
+		Self {
+			packets: self.packets + other.packets,
+			bytes: self.bytes + other.bytes,
+		}
+	}
+}
+
+/// The traffic of a single second in bytes, indexed by the [`TrafficKind`].
+///
+/// [`TrafficKind`]: enum.TrafficKind.html
+#[derive(Clone, Copy, Debug)]
+struct TrafficSecond {
+	/// The unix timestamp of this second.
+	second: i64,
+	sent: [u64; 3],
+	received: [u64; 3],
+}
+
+/// Traffic and packet loss statistics of a connection.
+///
+/// The sizes are the sizes of the udp packets, so they include the packet
+/// header.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+	/// Sent packets, indexed by the [`PacketType`].
+	///
+	/// This includes resent packets.
+	///
+	/// [`PacketType`]: ../../tsproto_packets/packets/enum.PacketType.html
+	pub sent: [TrafficCounter; 9],
+	/// Received packets, indexed by the [`PacketType`].
+	///
+	/// [`PacketType`]: ../../tsproto_packets/packets/enum.PacketType.html
+	pub received: [TrafficCounter; 9],
+	/// Packets which had to be sent again because no ack was received.
+	pub resent: TrafficCounter,
+	/// The number of incoming packets which got lost, indexed by the
+	/// [`PacketType`].
+	///
+	/// This is detected by gaps in the packet ids of voice, ping and pong
+	/// packets, so it is always zero for other packet types.
+	///
+	/// [`PacketType`]: ../../tsproto_packets/packets/enum.PacketType.html
+	pub lost: [u64; 9],
+	history: VecDeque<TrafficSecond>,
+}
+
+impl ConnectionStats {
+	/// The sum of sent packets of a certain kind.
+	pub fn sent_kind(&self, kind: TrafficKind) -> TrafficCounter {
+		Self::sum_kind(&self.sent, kind)
+	}
+
+	/// The sum of received packets of a certain kind.
+	pub fn received_kind(&self, kind: TrafficKind) -> TrafficCounter {
+		Self::sum_kind(&self.received, kind)
+	}
+
+	/// The sum of lost incoming packets of a certain kind.
+	pub fn lost_kind(&self, kind: TrafficKind) -> u64 {
+		self.lost
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| Self::is_kind(*i, kind))
+			.map(|(_, l)| *l)
+			.sum()
+	}
+
+	fn is_kind(p_type: usize, kind: TrafficKind) -> bool {
+		PacketType::from_usize(p_type).map(TrafficKind::from) == Some(kind)
+	}
+
+	fn sum_kind(
+		counters: &[TrafficCounter; 9],
+		kind: TrafficKind,
+	) -> TrafficCounter
+	{
+		counters
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| Self::is_kind(*i, kind))
+			.fold(TrafficCounter::default(), |a, (_, c)| a + *c)
+	}
+
+	/// The estimated ratio of lost incoming packets of a certain kind, between
+	/// 0 and 1.
+	pub fn incoming_loss(&self, kind: TrafficKind) -> f32 {
+		let received = self.received_kind(kind).packets;
+		let lost = self.lost_kind(kind);
+		if received + lost == 0 {
+			0.0
+		} else {
+			lost as f32 / (received + lost) as f32
+		}
+	}
+
+	/// The estimated ratio of lost outgoing packets, between 0 and 1.
+	///
+	/// This is the ratio of resent command and init packets.
+	pub fn outgoing_loss(&self) -> f32 {
+		let sent = self.sent[PacketType::Command.to_usize().unwrap()].packets
+			+ self.sent[PacketType::CommandLow.to_usize().unwrap()].packets
+			+ self.sent[PacketType::Init.to_usize().unwrap()].packets;
+		if sent == 0 {
+			0.0
+		} else {
+			self.resent.packets as f32 / sent as f32
+		}
+	}
+
+	/// The sent and received bytes of a certain kind in the last full second.
+	pub fn bandwidth_last_second(&self, kind: TrafficKind) -> (u64, u64) {
+		self.bandwidth(kind, 1)
+	}
+
+	/// The average sent and received bytes per second of a certain kind in the
+	/// last minute.
+	pub fn bandwidth_last_minute(&self, kind: TrafficKind) -> (u64, u64) {
+		self.bandwidth(kind, STATS_HISTORY_SECS)
+	}
+
+	/// The average sent and received bytes per second in the last `secs`
+	/// full seconds.
+	fn bandwidth(&self, kind: TrafficKind, secs: i64) -> (u64, u64) {
+		let now = clock::now().timestamp();
+		let i = kind as usize;
+		let (sent, received) = self
+			.history
+			.iter()
+			.filter(|s| s.second >= now - secs && s.second < now)
+			.fold((0, 0), |(sent, received), s| {
+				(sent + s.sent[i], received + s.received[i])
+			});
+		(sent / secs as u64, received / secs as u64)
+	}
+
+	pub(crate) fn add_sent(&mut self, p_type: PacketType, len: usize) {
+		self.sent[p_type.to_usize().unwrap()].record(len);
+		self.add_history().sent[TrafficKind::from(p_type) as usize] +=
+			len as u64;
+	}
+
+	pub(crate) fn add_resent(&mut self, p_type: PacketType, len: usize) {
+		self.resent.record(len);
+		self.add_sent(p_type, len);
+	}
+
+	pub(crate) fn add_received(&mut self, p_type: PacketType, len: usize) {
+		self.received[p_type.to_usize().unwrap()].record(len);
+		self.add_history().received[TrafficKind::from(p_type) as usize] +=
+			len as u64;
+	}
+
+	pub(crate) fn add_lost(&mut self, p_type: PacketType, count: u64) {
+		self.lost[p_type.to_usize().unwrap()] += count;
+	}
+
+	/// Get the history entry for the current second and remove old entries.
+	fn add_history(&mut self) -> &mut TrafficSecond {
+		let now = clock::now().timestamp();
+		// Remove old entries
+		while self
+			.history
+			.front()
+			.map(|s| s.second < now - STATS_HISTORY_SECS)
+			.unwrap_or(false)
+		{
+			self.history.pop_front();
+		}
+
+		if self.history.back().map(|s| s.second != now).unwrap_or(true) {
+			self.history.push_back(TrafficSecond {
+				second: now,
+				sent: [0; 3],
+				received: [0; 3],
+			});
+		}
+		self.history.back_mut().unwrap()
+	}
+}
+
+/// Limits for buffers and queues and keys of the protocol.
+///
+/// The default values are the ones of the TeamSpeak client.
+#[derive(Clone, Debug)]
+pub struct ProtocolConfig {
+	/// The maximum number of bytes for a fragmented packet.
+	pub max_fragments_length: usize,
+	/// The maximum number of packets which are stored, if they are received
+	/// out-of-order.
+	pub max_queue_len: u16,
+	/// The maximum decompressed size of a packet.
+	pub max_decompressed_size: u32,
+	/// The number of outgoing udp packets of each priority which can be
+	/// buffered before sending blocks.
+	///
+	/// This is only used when a [`Data`] object is created.
+	///
+	/// [`Data`]: ../handler_data/struct.Data.html
+	pub udp_sink_capacity: usize,
+	/// Outgoing voice packets which wait longer than this in the send queue
+	/// are dropped.
+	///
+	/// This is only used when a [`Data`] object is created.
+	///
+	/// [`Data`]: ../handler_data/struct.Data.html
+	pub voice_deadline: std::time::Duration,
+	/// Bind a new socket when sending a packet fails.
+	///
+	/// This is only used if the socket was created by [`Data::new`] or a bind
+	/// function was set with [`Data::set_bind_socket`]. It is only used when a
+	/// [`Data`] object is created.
+	///
+	/// [`Data`]: ../handler_data/struct.Data.html
+	/// [`Data::new`]: ../handler_data/struct.Data.html#method.new
+	/// [`Data::set_bind_socket`]: ../handler_data/struct.Data.html#method.set_bind_socket
+	pub rebind_on_send_error: bool,
+	/// The minimum time between two sockets which are bound because sending
+	/// failed.
+	///
+	/// Send errors in between drop the failed packet and keep the socket.
+	///
+	/// This is only used when a [`Data`] object is created.
+	///
+	/// [`Data`]: ../handler_data/struct.Data.html
+	pub rebind_interval: std::time::Duration,
+	/// The root key of license chains.
+	///
+	/// Clients derive the ephemeral key of a server from this key and the
+	/// license chain of the server. A license chain for a different root key
+	/// can be created with a [`LicenseBuilder`].
+	///
+	/// [`LicenseBuilder`]: ../license/struct.LicenseBuilder.html
+	pub license_root_key: EccKeyPubEd25519,
+}
+
+impl Default for ProtocolConfig {
+	#[allow(clippy::unreadable_literal)]
+	fn default() -> Self {
+		Self {
+			max_fragments_length: 40960,
+			max_queue_len: 50,
+			max_decompressed_size: 40960,
+			udp_sink_capacity: 20,
+			voice_deadline: std::time::Duration::from_millis(100),
+			rebind_on_send_error: false,
+			rebind_interval: std::time::Duration::from_secs(5),
+			license_root_key: EccKeyPubEd25519::from_bytes(crate::ROOT_KEY),
+		}
+	}
+}
+
 /// Represents a currently alive connection.
 #[derive(Debug)]
 pub struct Connection {
@@ -129,7 +473,9 @@ pub struct Connection {
 	pub address: SocketAddr,
 
 	pub resender: DefaultResender,
-	udp_packet_sink: mpsc::Sender<(SocketAddr, Bytes)>,
+	/// The limits of this connection.
+	pub protocol_config: ProtocolConfig,
+	udp_packet_sink: SendQueueSink,
 	pub s2c_init_sink: mpsc::UnboundedSender<InS2CInit>,
 	pub c2s_init_sink: mpsc::UnboundedSender<InC2SInit>,
 	pub command_sink: mpsc::UnboundedSender<InCommand>,
@@ -155,6 +501,15 @@ pub struct Connection {
 	///
 	/// Works like the `outgoing_p_ids`.
 	pub incoming_p_ids: [(u32, u16); 8],
+
+	/// The round trip time statistics, `None` until the first pong arrived.
+	pub ping_stats: Option<PingStats>,
+	/// Pings which are not yet answered with their packet id and send time.
+	sent_pings: VecDeque<(u16, DateTime<Utc>)>,
+	/// Traffic and packet loss statistics.
+	pub stats: ConnectionStats,
+	/// Reused buffer for encoded udp packets.
+	pub(crate) send_buffer: Vec<(u32, u16, Bytes)>,
 }
 
 impl Connection {
@@ -162,8 +517,9 @@ impl Connection {
 	pub fn new(
 		address: SocketAddr,
 		resender: DefaultResender,
+		protocol_config: ProtocolConfig,
 		logger: slog::Logger,
-		udp_packet_sink: mpsc::Sender<(SocketAddr, Bytes)>,
+		udp_packet_sink: SendQueueSink,
 		is_client: bool,
 		s2c_init_sink: mpsc::UnboundedSender<InS2CInit>,
 		c2s_init_sink: mpsc::UnboundedSender<InC2SInit>,
@@ -177,6 +533,7 @@ impl Connection {
 			params: None,
 			address,
 			resender,
+			protocol_config,
 			udp_packet_sink,
 			s2c_init_sink,
 			c2s_init_sink,
@@ -187,6 +544,11 @@ impl Connection {
 			receive_queue: Default::default(),
 			fragmented_queue: Default::default(),
 			incoming_p_ids: Default::default(),
+
+			ping_stats: None,
+			sent_pings: VecDeque::new(),
+			stats: ConnectionStats::default(),
+			send_buffer: Vec::new(),
 		};
 		if is_client {
 			// The first command is sent as part of the C2SInit::Init4 packet
@@ -200,6 +562,31 @@ impl Connection {
 		res
 	}
 
+	/// Remember the send time of a ping packet.
+	pub(crate) fn ping_sent(&mut self, p_id: u16) {
+		if self.sent_pings.len() >= MAX_OUTSTANDING_PINGS {
+			// The oldest ping was probably lost
+			self.sent_pings.pop_front();
+		}
+		self.sent_pings.push_back((p_id, clock::now()));
+	}
+
+	/// Match a pong to its ping and update the round trip time.
+	pub(crate) fn pong_received(&mut self, p_id: u16) {
+		let i = match self.sent_pings.iter().position(|(id, _)| *id == p_id) {
+			Some(i) => i,
+			None => return,
+		};
+		// All older pings were lost
+		let (_, sent) = self.sent_pings.drain(..=i).last().unwrap();
+		let rtt = clock::now().signed_duration_since(sent);
+		self.resender.update_srtt(rtt);
+		match &mut self.ping_stats {
+			Some(stats) => stats.update(rtt),
+			None => self.ping_stats = Some(PingStats::new(rtt)),
+		}
+	}
+
 	/// Check if a given id is in the receive window.
 	///
 	/// Returns
@@ -284,6 +671,30 @@ impl Identity {
 	#[inline]
 	pub fn set_counter(&mut self, counter: u64) { self.counter = counter; }
 
+	/// Parse an identity in the format of the TeamSpeak client settings.
+	///
+	/// The format is `<counter>V<obfuscated key>`, the key is obfuscated with
+	/// `EccKeyPrivP256::to_ts_obfuscated`.
+	pub fn from_ts_obfuscated(s: &str) -> Result<Self> {
+		let pos = s.find('V').ok_or_else(|| {
+			format_err!("Identity contains no counter separator")
+		})?;
+		let counter = s[..pos]
+			.parse()
+			.map_err(|e| format_err!("Invalid identity counter ({})", e))?;
+		let key = EccKeyPrivP256::from_ts_obfuscated(&s[pos + 1..])?;
+		Ok(Self { key, counter })
+	}
+
+	/// Write the identity in the format of the TeamSpeak client settings.
+	///
+	/// This is the inverse of [`from_ts_obfuscated`].
+	///
+	/// [`from_ts_obfuscated`]: #method.from_ts_obfuscated
+	pub fn to_ts_obfuscated(&self) -> Result<String> {
+		Ok(format!("{}V{}", self.counter, self.key.to_ts_obfuscated()?))
+	}
+
 	/// Compute the current hash cash level.
 	#[inline]
 	pub fn level(&self) -> Result<u8> {
@@ -303,12 +714,33 @@ impl Identity {
 		self.counter = offset;
 		Ok(())
 	}
+
+	/// Compute a better hash cash level on multiple threads.
+	///
+	/// The search uses one thread per cpu and starts at the current counter.
+	/// The resulting offset has to be stored with [`set_counter`].
+	///
+	/// [`set_counter`]: #method.set_counter
+	pub fn upgrade_level_parallel(
+		&self,
+		target: u8,
+	) -> Result<algs::HashCashSearch>
+	{
+		algs::hash_cash_parallel(
+			&self.key.to_pub(),
+			target,
+			algs::HashCashConfig {
+				start: self.counter,
+				..Default::default()
+			},
+		)
+	}
 }
 
 pub struct ConnectionUdpPacketSink<T: Send + 'static> {
 	con: ConnectionValueWeak<T>,
 	address: SocketAddr,
-	udp_packet_sink: mpsc::Sender<(SocketAddr, Bytes)>,
+	udp_packet_sink: SendQueueSink,
 }
 
 impl<T: Send + 'static> ConnectionUdpPacketSink<T> {
@@ -350,12 +782,12 @@ impl<T: Send + 'static> Sink for ConnectionUdpPacketSink<T> {
 			_ => Ok(
 				match self
 					.udp_packet_sink
-					.start_send((self.address, udp_packet))
+					.start_send((p_type, self.address, udp_packet))
 					.map_err(|e| {
 						format_err!("Failed to send udp packet ({:?})", e)
 					})? {
 					AsyncSink::Ready => AsyncSink::Ready,
-					AsyncSink::NotReady((_, p)) => {
+					AsyncSink::NotReady((_, _, p)) => {
 						AsyncSink::NotReady((p_type, p_gen, p_id, p))
 					}
 				},
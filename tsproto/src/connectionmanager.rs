@@ -85,6 +85,15 @@ pub trait Resender:
 
 	/// Return `true` if the connection is trying to disconnect.
 	fn is_disconnecting(&self) -> bool;
+
+	/// The local socket changed, resend all packets which were not
+	/// acknowledged immediately.
+	///
+	/// The state of the connection should not change, otherwise a connection
+	/// which rebinds because of send errors never times out.
+	///
+	/// The default implementation does nothing.
+	fn rebound(&mut self) {}
 }
 
 /// An implementation of a connectionmanager, that identifies a connection its
@@ -3,27 +3,55 @@ use std::fmt::Debug;
 use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Weak};
+use std::time::Instant;
 
 use bytes::{Bytes, BytesMut};
 use failure::format_err;
 use futures::sync::{mpsc, oneshot};
-use futures::{future, stream, Future, Sink, Stream};
+use futures::{
+	future, stream, try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend,
+	Stream,
+};
 use parking_lot::{Mutex, RwLock};
 use slog::{debug, error, o, warn, Drain};
-use tokio::codec::BytesCodec;
-use tokio::net::{UdpFramed, UdpSocket};
+use tokio::net::UdpSocket;
 use {slog, slog_async, slog_term, tokio};
 
 use crate::connection::*;
 use crate::connectionmanager::ConnectionManager;
 use crate::crypto::EccKeyPrivP256;
+use crate::middleware::{
+	ChainPosition, ChainResult, InCommandMiddleware, LockedMiddlewareChain,
+	MiddlewareChain, OutCommandMiddleware,
+};
 use crate::packet_codec::{PacketCodecReceiver, PacketCodecSender};
 use tsproto_packets::packets::*;
-use crate::resend::DefaultResender;
+use crate::resend::{
+	DefaultResender, ResendConfig, ResendFuture, ResendScheduler,
+	ResendSchedulerHandle,
+};
+use crate::send_queue::{
+	self, SendQueueSink, SendQueueStats, SendQueueStream,
+};
 use crate::{Error, LockedHashMap, Result};
 
 pub type DataM<CM> = Arc<Mutex<Data<CM>>>;
 
+/// The sending half of a socket.
+pub type UdpSink =
+	Box<Sink<SinkItem = (Bytes, SocketAddr), SinkError = Error> + Send>;
+/// The receiving half of a socket.
+pub type UdpStream =
+	Box<Stream<Item = (BytesMut, SocketAddr), Error = Error> + Send>;
+/// Binds a new socket to an address.
+///
+/// Returns the local address of the socket, its sink and its stream.
+pub type BindSocket =
+	Box<Fn(&SocketAddr) -> Result<(SocketAddr, UdpSink, UdpStream)> + Send>;
+
+/// The receive buffer of a socket has to fit the largest udp datagram.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
 /// A listener for added and removed connections.
 pub trait ConnectionListener<CM: ConnectionManager>: Send {
 	/// Called when a new connection is created.
@@ -98,6 +126,10 @@ pub trait InPacketObserver<T>: Send + Sync {
 }
 
 /// The `observe` method is called on every outgoing packet.
+///
+/// Commands are observed after they passed all [`OutCommandMiddleware`]s.
+///
+/// [`OutCommandMiddleware`]: ../middleware/trait.OutCommandMiddleware.html
 pub trait OutPacketObserver<T>: Send + Sync {
 	/// The `observe` method should not take too long, because it holds a lock.
 	/// It is possible to modify the given object, but that should only be done
@@ -105,7 +137,11 @@ pub trait OutPacketObserver<T>: Send + Sync {
 	fn observe(&self, connection: &mut (T, Connection), packet: &mut OutPacket);
 }
 
-/// The `observe` method is called on every incoming packet.
+/// The `observe` method is called on every incoming command.
+///
+/// Commands are observed after they passed all [`InCommandMiddleware`]s.
+///
+/// [`InCommandMiddleware`]: ../middleware/trait.InCommandMiddleware.html
 pub trait InCommandObserver<T>: Send + Sync {
 	/// The `observe` method should not take too long, because it holds a lock.
 	fn observe(&self, connection: &mut (T, Connection), cmd: &InCommand);
@@ -115,6 +151,8 @@ pub struct ConnectionValue<T: 'static> {
 	pub mutex: Arc<Mutex<(T, Connection)>>,
 	pub(crate) out_packet_observer:
 		LockedHashMap<String, Box<OutPacketObserver<T>>>,
+	pub(crate) out_command_middleware:
+		LockedMiddlewareChain<OutCommandMiddleware<T>>,
 }
 
 impl<T: Send + 'static> ConnectionValue<T> {
@@ -122,21 +160,67 @@ impl<T: Send + 'static> ConnectionValue<T> {
 		data: T,
 		con: Connection,
 		out_packet_observer: LockedHashMap<String, Box<OutPacketObserver<T>>>,
+		out_command_middleware: LockedMiddlewareChain<OutCommandMiddleware<T>>,
 	) -> Self
 	{
 		Self {
 			mutex: Arc::new(Mutex::new((data, con))),
 			out_packet_observer,
+			out_command_middleware,
 		}
 	}
 
 	fn encode_packet(
 		&self,
-		mut packet: OutPacket,
+		packet: OutPacket,
 	) -> Box<Stream<Item = (PacketType, u32, u16, Bytes), Error = Error> + Send>
 	{
 		let mut con = self.mutex.lock();
-		let con = &mut *con;
+		self.encode_command(&mut *con, packet, None)
+	}
+
+	/// Pass command packets through the middlewares after `start`, call the
+	/// observers and encode the packet.
+	///
+	/// The observers see the packet only after all middlewares passed it.
+	fn encode_command(
+		&self,
+		con: &mut (T, Connection),
+		mut packet: OutPacket,
+		start: Option<ChainPosition>,
+	) -> Box<Stream<Item = (PacketType, u32, u16, Bytes), Error = Error> + Send>
+	{
+		let is_command = match packet.header().packet_type() {
+			PacketType::Command | PacketType::CommandLow => true,
+			_ => false,
+		};
+		if is_command {
+			let res = self.out_command_middleware.read().run(
+				start,
+				packet,
+				|m, p| m.handle(con, p),
+			);
+			match res {
+				ChainResult::Finished(p) => packet = p,
+				ChainResult::Dropped => return Box::new(stream::empty()),
+				ChainResult::Delayed(packet, position) => {
+					// Continue when the packet is ready
+					let cv = self.downgrade();
+					let packets = packet.map(move |p| match cv.upgrade() {
+						Some(cv) => {
+							let mut con = cv.mutex.lock();
+							cv.encode_command(&mut *con, p, Some(position))
+						}
+						None => Box::new(stream::once(Err(format_err!(
+							"Connection is gone"
+						)
+						.into()))),
+					});
+					return Box::new(packets.flatten_stream());
+				}
+			}
+		}
+
 		// Call observer
 		for o in self.out_packet_observer.read().values() {
 			o.observe(con, &mut packet);
@@ -145,22 +229,44 @@ impl<T: Send + 'static> ConnectionValue<T> {
 		let codec = PacketCodecSender::new(con.1.is_client);
 		let p_type = packet.header().packet_type();
 
-		let udp_packets = match codec.encode_packet(&mut con.1, packet) {
-			Ok(r) => r,
-			Err(e) => return Box::new(stream::once(Err(e))),
-		};
+		let mut udp_packets = mem::replace(&mut con.1.send_buffer, Vec::new());
+		udp_packets.clear();
+		if let Err(e) =
+			codec.encode_packet_into(&mut con.1, packet, &mut udp_packets)
+		{
+			con.1.send_buffer = udp_packets;
+			return Box::new(stream::once(Err(e)));
+		}
 
-		let udp_packets = udp_packets
-			.into_iter()
-			.map(|(p_gen, p_id, p)| (p_type, p_gen, p_id, p))
-			.collect::<Vec<_>>();
-		Box::new(stream::iter_ok(udp_packets))
+		if udp_packets.len() == 1 {
+			// Keep the buffer for the next packet
+			let (p_gen, p_id, p) = udp_packets.pop().unwrap();
+			con.1.send_buffer = udp_packets;
+			Box::new(stream::once(Ok((p_type, p_gen, p_id, p))))
+		} else {
+			Box::new(stream::iter_ok(
+				udp_packets
+					.into_iter()
+					.map(move |(p_gen, p_id, p)| (p_type, p_gen, p_id, p)),
+			))
+		}
 	}
 
+	/// The round trip time statistics of this connection.
+	///
+	/// Returns `None` if no ping was answered yet.
+	pub fn ping_stats(&self) -> Option<PingStats> {
+		self.mutex.lock().1.ping_stats
+	}
+
+	/// A snapshot of the traffic statistics of this connection.
+	pub fn stats(&self) -> ConnectionStats { self.mutex.lock().1.stats.clone() }
+
 	pub fn downgrade(&self) -> ConnectionValueWeak<T> {
 		ConnectionValueWeak {
 			mutex: Arc::downgrade(&self.mutex),
 			out_packet_observer: self.out_packet_observer.clone(),
+			out_command_middleware: self.out_command_middleware.clone(),
 		}
 	}
 }
@@ -177,6 +283,7 @@ impl<T: 'static> Clone for ConnectionValue<T> {
 		Self {
 			mutex: self.mutex.clone(),
 			out_packet_observer: self.out_packet_observer.clone(),
+			out_command_middleware: self.out_command_middleware.clone(),
 		}
 	}
 }
@@ -185,6 +292,8 @@ pub struct ConnectionValueWeak<T: Send + 'static> {
 	pub mutex: Weak<Mutex<(T, Connection)>>,
 	pub(crate) out_packet_observer:
 		LockedHashMap<String, Box<OutPacketObserver<T>>>,
+	pub(crate) out_command_middleware:
+		LockedMiddlewareChain<OutCommandMiddleware<T>>,
 }
 
 impl<T: Send + 'static> ConnectionValueWeak<T> {
@@ -192,6 +301,7 @@ impl<T: Send + 'static> ConnectionValueWeak<T> {
 		self.mutex.upgrade().map(|mutex| ConnectionValue {
 			mutex,
 			out_packet_observer: self.out_packet_observer.clone(),
+			out_command_middleware: self.out_command_middleware.clone(),
 		})
 	}
 
@@ -236,6 +346,7 @@ impl<T: Send + 'static> Clone for ConnectionValueWeak<T> {
 		Self {
 			mutex: self.mutex.clone(),
 			out_packet_observer: self.out_packet_observer.clone(),
+			out_command_middleware: self.out_command_middleware.clone(),
 		}
 	}
 }
@@ -258,11 +369,26 @@ pub struct Data<CM: ConnectionManager + 'static> {
 	pub logger: slog::Logger,
 
 	/// The sink of udp packets.
-	pub udp_packet_sink: mpsc::Sender<(SocketAddr, Bytes)>,
+	pub udp_packet_sink: SendQueueSink,
+	/// Stops receiving packets from the current socket.
 	exit_send: oneshot::Sender<()>,
+	/// Replaces the socket which is used to send packets.
+	socket_sinks: mpsc::UnboundedSender<UdpSink>,
+	/// The address which was used to bind the socket.
+	///
+	/// This is `None` if the socket was not created by `Data` and no bind
+	/// function was set. It is used to bind a new socket when sending fails.
+	bind_addr: Option<SocketAddr>,
+	/// Creates new sockets in `rebind`.
+	bind_socket: BindSocket,
+	unknown_udp_packet_sink: Option<mpsc::Sender<(SocketAddr, InPacket)>>,
+	/// Drives the resend futures of all connections.
+	resend_scheduler: ResendSchedulerHandle<CM>,
 
 	/// The default resend config. It gets copied for each new connection.
-	resend_config: crate::resend::ResendConfig,
+	pub resend_config: ResendConfig,
+	/// The default protocol limits. They get copied for each new connection.
+	pub protocol_config: ProtocolConfig,
 
 	pub connections:
 		LockedHashMap<CM::Key, ConnectionValue<CM::AssociatedData>>,
@@ -285,7 +411,12 @@ pub struct Data<CM: ConnectionManager + 'static> {
 	/// Observe incoming `Commands`s.
 	pub(crate) in_command_observer:
 		LockedHashMap<String, Box<InCommandObserver<CM::AssociatedData>>>,
-	/// Observe outgoing `Commands`s.
+	/// Inspect, modify, delay or drop incoming `Command`s.
+	pub(crate) in_command_middleware:
+		LockedMiddlewareChain<InCommandMiddleware<CM::AssociatedData>>,
+	/// Inspect, modify, delay or drop outgoing `Command`s.
+	pub(crate) out_command_middleware:
+		LockedMiddlewareChain<OutCommandMiddleware<CM::AssociatedData>>,
 
 	/// A list of all connected clients or servers
 	///
@@ -330,6 +461,7 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		unknown_udp_packet_sink: Option<mpsc::Sender<(SocketAddr, InPacket)>>,
 		packet_handler: CM::PacketHandler,
 		connection_manager: CM,
+		protocol_config: ProtocolConfig,
 		logger: L,
 	) -> Result<Arc<Mutex<Self>>>
 	{
@@ -342,27 +474,31 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		});
 
 		// Create the socket
-		let socket = UdpSocket::bind(&local_addr)?;
-		let local_addr = socket.local_addr().unwrap_or(local_addr);
+		let bind_addr = local_addr;
+		let (local_addr, sink, stream) = bind_udp_socket(&local_addr)?;
 		debug!(logger, "Listening"; "local_addr" => %local_addr);
-		let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
 
-		Self::new_with_socket(
+		let data = Self::new_with_socket(
 			local_addr,
 			private_key,
 			is_client,
 			unknown_udp_packet_sink,
 			packet_handler,
 			connection_manager,
+			protocol_config,
 			sink,
 			stream,
 			logger,
-		)
+		)?;
+		data.lock().bind_addr = Some(bind_addr);
+		Ok(data)
 	}
 
 	/// Creates a new connection without creating the socket.
 	///
-	/// The stream and sink of the socket have to be provided.
+	/// The stream and sink of the socket have to be provided. If sending a
+	/// packet fails, the sink should drop this packet and continue with the
+	/// next one.
 	pub fn new_with_socket<E1: Debug, E2: Debug>(
 		local_addr: SocketAddr,
 		private_key: EccKeyPrivP256,
@@ -370,6 +506,7 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		unknown_udp_packet_sink: Option<mpsc::Sender<(SocketAddr, InPacket)>>,
 		packet_handler: CM::PacketHandler,
 		connection_manager: CM,
+		protocol_config: ProtocolConfig,
 		sink: impl Sink<SinkItem = (Bytes, SocketAddr), SinkError = E1>
 			+ Send
 			+ 'static,
@@ -380,9 +517,16 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 	) -> Result<Arc<Mutex<Self>>>
 	{
 		let (exit_send, exit_recv) = oneshot::channel();
-		let (udp_packet_sink, udp_packet_sink_sender) =
-			mpsc::channel(crate::UDP_SINK_CAPACITY);
-		let logger2 = logger.clone();
+		let (udp_packet_sink, udp_packet_sink_sender) = send_queue::new(
+			protocol_config.udp_sink_capacity,
+			protocol_config.voice_deadline,
+		);
+		let (scheduler, resend_scheduler) =
+			ResendScheduler::new(logger.clone());
+		tokio::spawn(scheduler);
+		let (socket_sinks, new_sinks) = mpsc::unbounded();
+		let rebind_on_send_error = protocol_config.rebind_on_send_error;
+		let rebind_interval = protocol_config.rebind_interval;
 
 		let connections = Arc::new(RwLock::new(HashMap::new()));
 		let in_udp_packet_observer = Arc::new(RwLock::new(HashMap::new()));
@@ -390,6 +534,10 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		let in_packet_observer = Arc::new(RwLock::new(HashMap::new()));
 		let out_packet_observer = Arc::new(RwLock::new(HashMap::new()));
 		let in_command_observer = Arc::new(RwLock::new(HashMap::new()));
+		let in_command_middleware =
+			Arc::new(RwLock::new(MiddlewareChain::new()));
+		let out_command_middleware =
+			Arc::new(RwLock::new(MiddlewareChain::new()));
 
 		let data = Self {
 			is_client,
@@ -398,7 +546,13 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 			logger,
 			udp_packet_sink,
 			exit_send,
+			socket_sinks,
+			bind_addr: None,
+			bind_socket: Box::new(bind_udp_socket),
+			unknown_udp_packet_sink,
+			resend_scheduler,
 			resend_config: Default::default(),
+			protocol_config,
 			connections,
 
 			in_udp_packet_observer,
@@ -406,33 +560,146 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 			in_packet_observer,
 			out_packet_observer,
 			in_command_observer,
+			in_command_middleware,
+			out_command_middleware,
 
 			packet_handler,
 			connection_manager,
 			connection_listeners: Vec::new(),
 		};
 
+		data.spawn_receiver(stream, exit_recv);
+		let logger = data.logger.clone();
 		let out_udp_packet_observer = data.out_udp_packet_observer.clone();
-		tokio::spawn(
-			udp_packet_sink_sender
-				.map(move |(addr, p)| {
-					for o in out_udp_packet_observer.read().values() {
-						o.observe(addr, &p);
-					}
-					(p, addr)
-				})
-				.forward(sink.sink_map_err(
-					move |e| error!(logger2, "Failed to send udp packet"; "error" => ?e),
-				))
-				.map(|_| ()),
-		);
+		let data = Arc::new(Mutex::new(data));
 
+		// Bind a new socket if sending fails
+		let data_weak = Arc::downgrade(&data);
+		let mut last_rebind: Option<Instant> = None;
+		let on_error = move || {
+			if !rebind_on_send_error {
+				return;
+			}
+			// Do not bind a new socket for every failed packet. This is
+			// checked first, so the data is not locked for every error.
+			let now = tokio::clock::now();
+			if let Some(last) = last_rebind {
+				if now < last + rebind_interval {
+					return;
+				}
+			}
+			if let Some(data) = data_weak.upgrade() {
+				let mut data = data.lock();
+				let addr = match data.bind_addr {
+					Some(addr) => addr,
+					None => return,
+				};
+				last_rebind = Some(now);
+				if let Err(e) = data.rebind(addr) {
+					error!(data.logger, "Failed to bind new socket";
+						"error" => ?e);
+				}
+			}
+		};
+		let sender = SocketSender {
+			logger,
+			queue: udp_packet_sink_sender,
+			sink: udp_sink(sink),
+			new_sinks: Some(new_sinks),
+			pending: None,
+			out_udp_packet_observer,
+			on_error: Box::new(on_error),
+		};
+		tokio::spawn(sender);
+		Ok(data)
+	}
+
+	/// Bind a new udp socket and use it for all connections.
+	///
+	/// The old socket is closed. Packets which were not acknowledged yet are
+	/// resent over the new socket, so the connections survive if the local
+	/// address changes, e.g. when a laptop switches to a different Wi-Fi.
+	///
+	/// The socket is created by the function which was set with
+	/// [`set_bind_socket`], a tokio udp socket is used per default.
+	///
+	/// [`set_bind_socket`]: #method.set_bind_socket
+	pub fn rebind(&mut self, local_addr: SocketAddr) -> Result<()> {
+		let (new_addr, sink, stream) = (self.bind_socket)(&local_addr)?;
+		self.bind_addr = Some(local_addr);
+		debug!(self.logger, "Rebinding"; "local_addr" => %new_addr);
+		self.rebind_with_socket(new_addr, sink, stream);
+		Ok(())
+	}
+
+	/// Set the function which creates new sockets in [`rebind`].
+	///
+	/// This should be used if the socket was passed to [`new_with_socket`],
+	/// e.g. to keep a batched socket from [`mmsg`]. `bind_addr` is used
+	/// when a new socket is bound because sending failed, see
+	/// `ProtocolConfig::rebind_on_send_error`.
+	///
+	/// [`rebind`]: #method.rebind
+	/// [`new_with_socket`]: #method.new_with_socket
+	/// [`mmsg`]: ../mmsg/index.html
+	pub fn set_bind_socket(
+		&mut self,
+		bind_addr: SocketAddr,
+		bind_socket: BindSocket,
+	)
+	{
+		self.bind_addr = Some(bind_addr);
+		self.bind_socket = bind_socket;
+	}
+
+	/// Use a new socket for all connections without creating the socket.
+	///
+	/// See [`rebind`] and [`new_with_socket`].
+	///
+	/// [`rebind`]: #method.rebind
+	/// [`new_with_socket`]: #method.new_with_socket
+	pub fn rebind_with_socket<E1: Debug, E2: Debug>(
+		&mut self,
+		local_addr: SocketAddr,
+		sink: impl Sink<SinkItem = (Bytes, SocketAddr), SinkError = E1>
+			+ Send
+			+ 'static,
+		stream: impl Stream<Item = (BytesMut, SocketAddr), Error = E2>
+			+ Send
+			+ 'static,
+	)
+	{
+		// Stop receiving from the old socket
+		let (exit_send, exit_recv) = oneshot::channel();
+		let _ = mem::replace(&mut self.exit_send, exit_send).send(());
+		self.local_addr = local_addr;
+		let _ = self.socket_sinks.unbounded_send(udp_sink(sink));
+		self.spawn_receiver(stream, exit_recv);
+
+		// Resend all packets which were not acknowledged
+		for con in self.connections.read().values() {
+			con.mutex.lock().1.resender.rebound();
+		}
+	}
+
+	/// Handle incoming packets from `stream` until `exit` is fulfilled.
+	fn spawn_receiver<E: Debug>(
+		&self,
+		stream: impl Stream<Item = (BytesMut, SocketAddr), Error = E>
+			+ Send
+			+ 'static,
+		exit: oneshot::Receiver<()>,
+	)
+	{
 		// Handle incoming packets
-		let logger = data.logger.clone();
+		let is_client = self.is_client;
+		let logger = self.logger.clone();
 		let logger2 = logger.clone();
-		let in_udp_packet_observer = data.in_udp_packet_observer.clone();
-		let mut codec =
-			PacketCodecReceiver::new(&data, unknown_udp_packet_sink);
+		let in_udp_packet_observer = self.in_udp_packet_observer.clone();
+		let mut codec = PacketCodecReceiver::new(
+			self,
+			self.unknown_udp_packet_sink.clone(),
+		);
 		tokio::spawn(
 			stream
 				.map_err(move |e| {
@@ -475,11 +742,10 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 						}
 					},
 				)
-				.select2(exit_recv)
+				.select2(exit)
 				.map_err(|_| ())
 				.map(|_| ()),
 		);
-		Ok(Arc::new(Mutex::new(data)))
 	}
 
 	/// Add a new connection to this socket.
@@ -502,7 +768,8 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		let mut con = Connection::new(
 			addr,
 			resender,
-			logger.clone(),
+			self.protocol_config.clone(),
+			logger,
 			self.udp_packet_sink.clone(),
 			self.is_client,
 			s2c_init_send,
@@ -526,8 +793,12 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 			}
 		}
 
-		let con_val =
-			ConnectionValue::new(data, con, self.out_packet_observer.clone());
+		let con_val = ConnectionValue::new(
+			data,
+			con,
+			self.out_packet_observer.clone(),
+			self.out_command_middleware.clone(),
+		);
 		self.packet_handler.new_connection(
 			&con_val,
 			s2c_init_recv.map_err(|_| format_err!("Failed to receive").into()),
@@ -540,11 +811,8 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		self.connections.write().insert(key.clone(), con_val);
 
 		// Start resender
-		let resend_fut =
-			crate::resend::ResendFuture::new(&self, data_mut, key.clone());
-		::tokio::spawn(resend_fut.map_err(move |e| {
-			error!(logger, "Resend future failed"; "error" => ?e);
-		}));
+		self.resend_scheduler
+			.add(ResendFuture::new(&self, data_mut, key.clone()));
 
 		key
 	}
@@ -582,6 +850,11 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		self.connections.read().get(key).cloned()
 	}
 
+	/// The number of outgoing packets which wait in the send queue.
+	pub fn send_queue_stats(&self) -> SendQueueStats {
+		self.udp_packet_sink.stats()
+	}
+
 	pub fn wait_for_disconnect(
 		&mut self,
 		key: CM::Key,
@@ -654,6 +927,57 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 	pub fn remove_in_command_observer(&mut self, key: &str) {
 		self.in_command_observer.write().remove(key);
 	}
+
+	/// Add a middleware for incoming commands.
+	///
+	/// Middlewares with a lower `order` run first. An existing middleware with
+	/// the same key is replaced.
+	pub fn add_in_command_middleware(
+		&mut self,
+		key: String,
+		order: i32,
+		m: Box<InCommandMiddleware<CM::AssociatedData>>,
+	)
+	{
+		self.in_command_middleware.write().insert(key, order, m);
+	}
+	/// Add a middleware for outgoing commands.
+	///
+	/// Middlewares with a lower `order` run first. An existing middleware with
+	/// the same key is replaced.
+	pub fn add_out_command_middleware(
+		&mut self,
+		key: String,
+		order: i32,
+		m: Box<OutCommandMiddleware<CM::AssociatedData>>,
+	)
+	{
+		self.out_command_middleware.write().insert(key, order, m);
+	}
+	pub fn remove_in_command_middleware(&mut self, key: &str) {
+		self.in_command_middleware.write().remove(key);
+	}
+	pub fn remove_out_command_middleware(&mut self, key: &str) {
+		self.out_command_middleware.write().remove(key);
+	}
+	/// The keys of the middlewares for incoming commands in the order in
+	/// which they run.
+	pub fn in_command_middleware_keys(&self) -> Vec<String> {
+		self.in_command_middleware
+			.read()
+			.keys()
+			.map(String::from)
+			.collect()
+	}
+	/// The keys of the middlewares for outgoing commands in the order in
+	/// which they run.
+	pub fn out_command_middleware_keys(&self) -> Vec<String> {
+		self.out_command_middleware
+			.read()
+			.keys()
+			.map(String::from)
+			.collect()
+	}
 }
 
 struct DisconnectListener<T: Eq> {
@@ -688,3 +1012,186 @@ impl<CM: ConnectionManager> ConnectionListener<CM>
 		}
 	}
 }
+
+/// Sends the packets of the send queue over the current socket.
+///
+/// The socket can be replaced while connections are running.
+struct SocketSender {
+	logger: slog::Logger,
+	queue: SendQueueStream,
+	sink: UdpSink,
+	new_sinks: Option<mpsc::UnboundedReceiver<UdpSink>>,
+	/// The packet which is currently sent.
+	pending: Option<(Bytes, SocketAddr)>,
+	out_udp_packet_observer: LockedHashMap<String, Box<OutUdpPacketObserver>>,
+	/// Called when sending a packet failed.
+	on_error: Box<FnMut() + Send>,
+}
+
+fn udp_sink<E: Debug>(
+	sink: impl Sink<SinkItem = (Bytes, SocketAddr), SinkError = E>
+		+ Send
+		+ 'static,
+) -> UdpSink
+{
+	Box::new(sink.sink_map_err(|e| {
+		format_err!("Failed to send udp packet ({:?})", e).into()
+	}))
+}
+
+/// Bind a tokio udp socket.
+fn bind_udp_socket(
+	addr: &SocketAddr,
+) -> Result<(SocketAddr, UdpSink, UdpStream)>
+{
+	let socket = UdpSocket::bind(addr)?;
+	let local_addr = socket.local_addr().unwrap_or(*addr);
+	let socket = Arc::new(Mutex::new(socket));
+	let sink = UdpSocketSink {
+		socket: socket.clone(),
+		pending: None,
+	};
+	let stream = UdpSocketStream {
+		socket,
+		buffer: vec![0; MAX_DATAGRAM_SIZE],
+	};
+	Ok((local_addr, Box::new(sink), Box::new(stream)))
+}
+
+/// The sending half of a tokio udp socket.
+///
+/// In contrast to `UdpFramed`, a packet which cannot be sent is dropped
+/// instead of trying it again.
+struct UdpSocketSink {
+	socket: Arc<Mutex<UdpSocket>>,
+	pending: Option<(Bytes, SocketAddr)>,
+}
+
+/// The receiving half of a tokio udp socket.
+struct UdpSocketStream {
+	socket: Arc<Mutex<UdpSocket>>,
+	buffer: Vec<u8>,
+}
+
+impl Sink for UdpSocketSink {
+	type SinkItem = (Bytes, SocketAddr);
+	type SinkError = Error;
+
+	fn start_send(
+		&mut self,
+		item: Self::SinkItem,
+	) -> StartSend<Self::SinkItem, Self::SinkError>
+	{
+		if self.pending.is_some() {
+			if let Err(e) = self.poll_complete() {
+				// Only the pending packet failed, keep the new one
+				self.pending = Some(item);
+				return Err(e);
+			}
+			if self.pending.is_some() {
+				return Ok(AsyncSink::NotReady(item));
+			}
+		}
+		self.pending = Some(item);
+		Ok(AsyncSink::Ready)
+	}
+
+	fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+		if let Some((data, addr)) = &self.pending {
+			let res = self.socket.lock().poll_send_to(data, addr);
+			match res {
+				Ok(Async::NotReady) => return Ok(Async::NotReady),
+				Ok(Async::Ready(_)) => self.pending = None,
+				Err(e) => {
+					// Drop the packet which failed
+					self.pending = None;
+					return Err(e.into());
+				}
+			}
+		}
+		Ok(Async::Ready(()))
+	}
+}
+
+impl Stream for UdpSocketStream {
+	type Item = (BytesMut, SocketAddr);
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		let (len, addr) =
+			try_ready!(self.socket.lock().poll_recv_from(&mut self.buffer));
+		Ok(Async::Ready(Some((BytesMut::from(&self.buffer[..len]), addr))))
+	}
+}
+
+impl SocketSender {
+	/// Send packets from the queue until the socket or the queue is not ready.
+	///
+	/// Returns `Ready` when the queue is finished.
+	fn poll_send(&mut self) -> Result<Async<()>> {
+		let sink = &mut self.sink;
+		loop {
+			if let Some(packet) = self.pending.take() {
+				if let AsyncSink::NotReady(packet) = sink.start_send(packet)? {
+					self.pending = Some(packet);
+					sink.poll_complete()?;
+					return Ok(Async::NotReady);
+				}
+			}
+
+			match self.queue.poll() {
+				Ok(Async::Ready(Some((addr, p)))) => {
+					for o in self.out_udp_packet_observer.read().values() {
+						o.observe(addr, &p);
+					}
+					self.pending = Some((p, addr));
+				}
+				Ok(Async::NotReady) => {
+					sink.poll_complete()?;
+					return Ok(Async::NotReady);
+				}
+				Ok(Async::Ready(None)) | Err(()) => {
+					return match sink.close() {
+						Err(e) => {
+							// Do not retry or rebind while shutting down
+							error!(self.logger, "Failed to close udp socket";
+								"error" => ?e);
+							Ok(Async::Ready(()))
+						}
+						r => r,
+					};
+				}
+			}
+		}
+	}
+}
+
+impl Future for SocketSender {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		loop {
+			// Switch to a new socket
+			while let Some(new_sinks) = &mut self.new_sinks {
+				match new_sinks.poll()? {
+					Async::Ready(Some(sink)) => self.sink = sink,
+					Async::Ready(None) => self.new_sinks = None,
+					Async::NotReady => break,
+				}
+			}
+
+			match self.poll_send() {
+				Ok(r) => return Ok(r),
+				Err(e) => {
+					error!(self.logger, "Failed to send udp packet";
+						"error" => ?e);
+					// The sink dropped the failed packet and the next packets
+					// are sent over the same socket, unless it gets replaced.
+					// Commands will be resent, other packets are lost.
+					(self.on_error)();
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,256 @@
+//! Import and export identities in the format of the TeamSpeak client.
+//!
+//! The TeamSpeak client exports identities as an ini file with an
+//! `[Identity]` section:
+//!
+//! ```text
+//! [Identity]
+//! id=Default
+//! identity="47V<obfuscated key>"
+//! nickname=TeamSpeakUser
+//! phonetic_nickname=
+//! ```
+use std::fmt::Write as _;
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Identity;
+use crate::Result;
+
+/// The name of the section, which contains the identity.
+const SECTION: &str = "Identity";
+
+/// An identity with the metadata of a TeamSpeak identity file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IdentityFile {
+	/// The name of the identity in the client.
+	#[serde(default)]
+	pub id: String,
+	pub identity: Identity,
+	#[serde(default)]
+	pub nickname: Option<String>,
+	#[serde(default)]
+	pub phonetic_nickname: Option<String>,
+	/// Other keys of the identity section, they are kept in order when
+	/// writing the file.
+	#[serde(default)]
+	pub other: Vec<(String, String)>,
+}
+
+impl IdentityFile {
+	pub fn new(id: String, identity: Identity) -> Self {
+		Self {
+			id,
+			identity,
+			nickname: None,
+			phonetic_nickname: None,
+			other: Vec::new(),
+		}
+	}
+
+	/// Parse an exported identity file.
+	///
+	/// Only the `[Identity]` section is read. If the file contains no
+	/// sections, all keys are read.
+	pub fn parse(s: &str) -> Result<Self> {
+		let mut in_section = true;
+		let mut id = None;
+		let mut identity = None;
+		let mut nickname = None;
+		let mut phonetic_nickname = None;
+		let mut other = Vec::new();
+
+		for (i, line) in s.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with(';') || line.starts_with('#')
+			{
+				continue;
+			}
+			if line.starts_with('[') {
+				if !line.ends_with(']') {
+					return Err(format_err!(
+						"Invalid section header in line {}",
+						i + 1
+					)
+					.into());
+				}
+				in_section = line[1..line.len() - 1].trim() == SECTION;
+				continue;
+			}
+			if !in_section {
+				continue;
+			}
+
+			let pos = line.find('=').ok_or_else(|| {
+				format_err!("Expected key=value in line {}", i + 1)
+			})?;
+			let key = line[..pos].trim();
+			let value = unquote(line[pos + 1..].trim()).map_err(|e| {
+				format_err!("Invalid value in line {} ({})", i + 1, e)
+			})?;
+			match key {
+				"id" => id = Some(value),
+				"identity" => {
+					identity = Some(Identity::from_ts_obfuscated(&value)?)
+				}
+				"nickname" => nickname = Some(value),
+				"phonetic_nickname" => phonetic_nickname = Some(value),
+				_ => other.push((key.to_string(), value)),
+			}
+		}
+
+		let identity = identity
+			.ok_or_else(|| format_err!("The file contains no identity"))?;
+		Ok(Self {
+			id: id.unwrap_or_default(),
+			identity,
+			nickname,
+			phonetic_nickname,
+			other,
+		})
+	}
+
+	/// Write the identity file in the format of the TeamSpeak client.
+	pub fn write(&self) -> Result<String> {
+		let mut res = format!("[{}]\n", SECTION);
+		// Writing to a string cannot fail
+		writeln!(res, "id={}", quote(&self.id)).unwrap();
+		writeln!(res, "identity=\"{}\"", self.identity.to_ts_obfuscated()?)
+			.unwrap();
+		if let Some(nickname) = &self.nickname {
+			writeln!(res, "nickname={}", quote(nickname)).unwrap();
+		}
+		if let Some(phonetic_nickname) = &self.phonetic_nickname {
+			writeln!(res, "phonetic_nickname={}", quote(phonetic_nickname))
+				.unwrap();
+		}
+		for (k, v) in &self.other {
+			writeln!(res, "{}={}", k, quote(v)).unwrap();
+		}
+		Ok(res)
+	}
+}
+
+/// Remove surrounding quotes and escape sequences from a value.
+fn unquote(s: &str) -> Result<String> {
+	if !s.starts_with('"') {
+		return Ok(s.to_string());
+	}
+	if s.len() < 2 || !s.ends_with('"') {
+		return Err(format_err!("Unterminated quote").into());
+	}
+
+	let mut res = String::with_capacity(s.len() - 2);
+	let mut chars = s[1..s.len() - 1].chars();
+	while let Some(c) = chars.next() {
+		if c == '\\' {
+			match chars.next() {
+				Some(c @ '\\') | Some(c @ '"') => res.push(c),
+				Some(c) => {
+					return Err(
+						format_err!("Unknown escape sequence \\{}", c).into()
+					);
+				}
+				None => return Err(format_err!("Unterminated escape").into()),
+			}
+		} else {
+			res.push(c);
+		}
+	}
+	Ok(res)
+}
+
+/// Quote a value if it contains characters which have a special meaning.
+fn quote(s: &str) -> String {
+	let needs_quotes = s.trim() != s
+		|| s.contains(|c| ['"', '\\', ';', ',', '=', '#'].contains(&c));
+	if !needs_quotes {
+		return s.to_string();
+	}
+	let mut res = String::with_capacity(s.len() + 2);
+	res.push('"');
+	for c in s.chars() {
+		if c == '"' || c == '\\' {
+			res.push('\\');
+		}
+		res.push(c);
+	}
+	res.push('"');
+	res
+}
+
+/// Use the format of the TeamSpeak client to store an [`Identity`] with serde.
+///
+/// Use it with `#[serde(with = "tsproto::identity_file::ts_format")]`.
+///
+/// [`Identity`]: ../../connection/struct.Identity.html
+pub mod ts_format {
+	use serde::de::Error as _;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	use crate::connection::Identity;
+
+	pub fn serialize<S: Serializer>(
+		identity: &Identity,
+		s: S,
+	) -> std::result::Result<S::Ok, S::Error>
+	{
+		let data = identity
+			.to_ts_obfuscated()
+			.map_err(serde::ser::Error::custom)?;
+		s.serialize_str(&data)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(
+		d: D,
+	) -> std::result::Result<Identity, D::Error> {
+		let data = String::deserialize(d)?;
+		Identity::from_ts_obfuscated(&data).map_err(D::Error::custom)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TEST_UID: &str = "lks7QL5OVMKo4pZ79cEOI5r5oEA=";
+	const TEST_FILE: &str = "[Identity]\n\
+		id=Test identity\n\
+		identity=\"1337VOfydZwzLwCCWm3smUImF+mI+6W1fV1lhW1UpASZABXEQe1h0Xn\
+		oPAA8ZEABhJXoDSF4Id2FYdj82Un0GfFZ5X1Nce18DCUoFDCFJBlcSCgN6AmNCT0AG\
+		VlYBcUUBLGMBLGRFcC81MENJQThNNW5tREJubURNL2daLy80QUFBQUFBQUFBQUFBQU\
+		FBQUFBQUFaUnpPSQ==\"\n\
+		nickname=\"Some; \\\"User\\\"\"\n\
+		phonetic_nickname=\n\
+		group=1\n";
+
+	#[test]
+	fn parse_identity_file() {
+		let file = IdentityFile::parse(TEST_FILE).unwrap();
+		assert_eq!(file.id, "Test identity");
+		assert_eq!(file.identity.counter(), 1337);
+		assert_eq!(
+			file.identity.key().to_pub().get_uid().unwrap(),
+			TEST_UID
+		);
+		assert_eq!(file.nickname.as_ref().unwrap(), "Some; \"User\"");
+		assert_eq!(file.phonetic_nickname.as_ref().unwrap(), "");
+		assert_eq!(file.other, [("group".to_string(), "1".to_string())]);
+	}
+
+	#[test]
+	fn identity_file_round_trip() {
+		let file = IdentityFile::parse(TEST_FILE).unwrap();
+		let file2 = IdentityFile::parse(&file.write().unwrap()).unwrap();
+		assert_eq!(file.id, file2.id);
+		assert_eq!(file.identity.counter(), file2.identity.counter());
+		assert_eq!(
+			file.identity.key().to_short(),
+			file2.identity.key().to_short()
+		);
+		assert_eq!(file.nickname, file2.nickname);
+		assert_eq!(file.phonetic_nickname, file2.phonetic_nickname);
+		assert_eq!(file.other, file2.other);
+	}
+}
@@ -0,0 +1,287 @@
+//! Simulate a bad network link.
+//!
+//! The sink and stream, which are passed to [`Data::new_with_socket`], can be
+//! wrapped to drop, duplicate, reorder and delay packets. All random decisions
+//! are taken from a seeded random number generator, so a failing test can be
+//! reproduced.
+//!
+//! [`Data::new_with_socket`]: ../handler_data/struct.Data.html#method.new_with_socket
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::sync::mpsc;
+use futures::{Async, Future, Poll, Sink, Stream};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::timer::Delay;
+
+/// Configures the impairments of a simulated link.
+///
+/// All probabilities are between `0` and `1`. The default configuration
+/// passes all packets through without changing them.
+#[derive(Clone, Debug)]
+pub struct ImpairmentConfig {
+	/// The seed for the random number generator.
+	pub seed: u64,
+	/// The probability that a packet gets dropped.
+	pub loss: f64,
+	/// The probability that a packet gets sent twice.
+	pub duplicate: f64,
+	/// The probability that a packet gets delayed by an additional
+	/// `reorder_delay`, so it is overtaken by following packets.
+	pub reorder: f64,
+	pub reorder_delay: Duration,
+	/// The constant delay of every packet.
+	pub delay: Duration,
+	/// A random delay between zero and `jitter`, which is added to every
+	/// packet.
+	pub jitter: Duration,
+	/// The maximum number of bytes per second.
+	pub bandwidth: Option<u64>,
+}
+
+impl Default for ImpairmentConfig {
+	fn default() -> Self {
+		Self {
+			seed: 0,
+			loss: 0.0,
+			duplicate: 0.0,
+			reorder: 0.0,
+			reorder_delay: Duration::from_millis(20),
+			delay: Duration::from_millis(0),
+			jitter: Duration::from_millis(0),
+			bandwidth: None,
+		}
+	}
+}
+
+/// An item that can be sent over an impaired link.
+pub trait Datagram: Clone {
+	/// The size of the packet, which is used for the bandwidth limit.
+	fn datagram_len(&self) -> usize;
+}
+
+impl Datagram for (Bytes, SocketAddr) {
+	fn datagram_len(&self) -> usize { self.0.len() }
+}
+
+impl Datagram for (BytesMut, SocketAddr) {
+	fn datagram_len(&self) -> usize { self.0.len() }
+}
+
+/// A stream which outputs the items of an inner stream after applying the
+/// configured impairments.
+pub struct Impaired<S: Stream> {
+	/// The inner stream, this is `None` when the stream ended.
+	inner: Option<S>,
+	config: ImpairmentConfig,
+	rng: StdRng,
+	/// Packets which wait for their release, sorted by the release time and
+	/// a counter.
+	queue: BTreeMap<(Instant, u64), S::Item>,
+	counter: u64,
+	/// The time when the simulated link is free again.
+	link_free: Instant,
+	timer: Delay,
+}
+
+impl<S: Stream> Impaired<S>
+where S::Item: Datagram
+{
+	pub fn new(inner: S, config: ImpairmentConfig) -> Self {
+		Self {
+			inner: Some(inner),
+			rng: StdRng::seed_from_u64(config.seed),
+			config,
+			queue: BTreeMap::new(),
+			counter: 0,
+			link_free: tokio::clock::now(),
+			timer: Delay::new(tokio::clock::now()),
+		}
+	}
+
+	fn chance(&mut self, probability: f64) -> bool {
+		probability > 0.0 && self.rng.gen::<f64>() < probability
+	}
+
+	fn enqueue(&mut self, item: S::Item) {
+		if self.chance(self.config.loss) {
+			return;
+		}
+		if self.chance(self.config.duplicate) {
+			self.schedule(item.clone());
+		}
+		self.schedule(item);
+	}
+
+	/// Compute the release time of a packet and put it into the queue.
+	fn schedule(&mut self, item: S::Item) {
+		let now = tokio::clock::now();
+		let mut time = now;
+		if let Some(bandwidth) = self.config.bandwidth {
+			let start = std::cmp::max(now, self.link_free);
+			let nanos =
+				item.datagram_len() as u64 * 1_000_000_000 / bandwidth.max(1);
+			self.link_free = start + Duration::from_nanos(nanos);
+			time = self.link_free;
+		}
+
+		time += self.config.delay;
+		let jitter = self.config.jitter.as_secs() * 1_000_000_000
+			+ u64::from(self.config.jitter.subsec_nanos());
+		if jitter > 0 {
+			time += Duration::from_nanos(self.rng.gen_range(0, jitter + 1));
+		}
+		if self.chance(self.config.reorder) {
+			time += self.config.reorder_delay;
+		}
+
+		self.queue.insert((time, self.counter), item);
+		self.counter += 1;
+	}
+}
+
+impl<S: Stream> Stream for Impaired<S>
+where S::Item: Datagram
+{
+	type Item = S::Item;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		// Read all available packets
+		while let Some(inner) = &mut self.inner {
+			match inner.poll()? {
+				Async::Ready(Some(item)) => self.enqueue(item),
+				Async::Ready(None) => self.inner = None,
+				Async::NotReady => break,
+			}
+		}
+
+		// Release the next packet
+		let key = match self.queue.keys().next() {
+			Some(k) => *k,
+			None if self.inner.is_none() => return Ok(Async::Ready(None)),
+			None => return Ok(Async::NotReady),
+		};
+		if key.0 > tokio::clock::now() {
+			self.timer.reset(key.0);
+			// Release the packet if the timer fails
+			if let Ok(Async::NotReady) = self.timer.poll() {
+				return Ok(Async::NotReady);
+			}
+		}
+		Ok(Async::Ready(self.queue.remove(&key)))
+	}
+}
+
+/// Wrap a sink, so all packets which are sent into it get impaired.
+///
+/// The returned sender forwards the packets into the given sink. This spawns
+/// a future, so it has to be called from within a tokio runtime.
+pub fn impair_sink<T, S>(
+	sink: S,
+	config: ImpairmentConfig,
+) -> mpsc::UnboundedSender<T>
+where
+	T: Datagram + Send + 'static,
+	S: Sink<SinkItem = T> + Send + 'static,
+{
+	let (send, recv) = mpsc::unbounded();
+	tokio::spawn(
+		Impaired::new(recv, config)
+			.forward(sink.sink_map_err(|_| ()))
+			.map(|_| ()),
+	);
+	send
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use futures::stream;
+	use tokio::runtime::current_thread::Runtime;
+
+	fn packets(count: u8) -> Vec<(Bytes, SocketAddr)> {
+		let addr = "127.0.0.1:9987".parse().unwrap();
+		(0..count).map(|i| (vec![i; 100].into(), addr)).collect()
+	}
+
+	fn run(
+		input: Vec<(Bytes, SocketAddr)>,
+		config: ImpairmentConfig,
+	) -> Vec<u8>
+	{
+		let mut runtime = Runtime::new().unwrap();
+		let stream = Impaired::new(stream::iter_ok::<_, ()>(input), config);
+		runtime
+			.block_on(stream.collect())
+			.unwrap()
+			.into_iter()
+			.map(|(b, _)| b[0])
+			.collect()
+	}
+
+	#[test]
+	fn pass_through() {
+		let res = run(packets(50), ImpairmentConfig::default());
+		assert_eq!(res, (0..50).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn seeded_loss() {
+		let config = ImpairmentConfig {
+			seed: 42,
+			loss: 0.5,
+			..Default::default()
+		};
+		let res = run(packets(100), config.clone());
+		assert!(res.len() > 10 && res.len() < 90);
+		// The order is preserved
+		assert!(res.windows(2).all(|w| w[0] < w[1]));
+		// The same seed gives the same result
+		assert_eq!(res, run(packets(100), config));
+	}
+
+	#[test]
+	fn duplicate() {
+		let config = ImpairmentConfig {
+			duplicate: 1.0,
+			..Default::default()
+		};
+		let res = run(packets(10), config);
+		let expected =
+			(0..10).flat_map(|i| vec![i, i].into_iter()).collect::<Vec<_>>();
+		assert_eq!(res, expected);
+	}
+
+	#[test]
+	fn reorder() {
+		let config = ImpairmentConfig {
+			seed: 1,
+			reorder: 0.3,
+			reorder_delay: Duration::from_millis(5),
+			..Default::default()
+		};
+		let mut res = run(packets(100), config);
+		assert!(res.windows(2).any(|w| w[0] > w[1]));
+		// No packet is lost
+		res.sort();
+		assert_eq!(res, (0..100).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn bandwidth() {
+		let config = ImpairmentConfig {
+			// 10 packets per second
+			bandwidth: Some(1000),
+			..Default::default()
+		};
+		let start = Instant::now();
+		let res = run(packets(5), config);
+		assert_eq!(res, (0..5).collect::<Vec<_>>());
+		assert!(start.elapsed() >= Duration::from_millis(450));
+	}
+}
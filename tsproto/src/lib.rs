@@ -17,14 +17,25 @@ use tsproto_packets::packets;
 
 pub mod algorithms;
 pub mod client;
+pub mod clock;
 pub mod connection;
 pub mod connectionmanager;
 pub mod crypto;
 pub mod handler_data;
+pub mod identity_file;
+pub mod impairment;
 pub mod license;
 pub mod log;
+pub mod memory;
+pub mod middleware;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
 pub mod packet_codec;
+pub mod pcap;
 pub mod resend;
+pub mod send_queue;
+pub mod server;
+pub mod timer_wheel;
 pub mod utils;
 
 /// Access the build environment of tsproto.
@@ -36,15 +47,6 @@ type Result<T> = std::result::Result<T, Error>;
 type LockedHashMap<K, V> =
 	std::sync::Arc<parking_lot::RwLock<std::collections::HashMap<K, V>>>;
 
-/// The maximum number of bytes for a fragmented packet.
-#[allow(clippy::unreadable_literal)]
-const MAX_FRAGMENTS_LENGTH: usize = 40960;
-/// The maximum number of packets which are stored, if they are received
-/// out-of-order.
-const MAX_QUEUE_LEN: u16 = 50;
-/// The maximum decompressed size of a packet.
-#[allow(clippy::unreadable_literal)]
-const MAX_DECOMPRESSED_SIZE: u32 = 40960;
 const FAKE_KEY: [u8; 16] = *b"c:\\windows\\syste";
 const FAKE_NONCE: [u8; 16] = *b"m\\firewall32.cpl";
 /// The root key in the TeamSpeak license system.
@@ -57,7 +59,8 @@ const ROOT_KEY: [u8; 32] = [
 const IDENTITY_OBFUSCATION: [u8; 128] = *b"b9dfaa7bee6ac57ac7b65f1094a1c155\
 	e747327bc2fe5d51c512023fe54a280201004e90ad1daaae1075d53b7d571c30e063b5a\
 	62a4a017bb394833aa0983e6e";
-const UDP_SINK_CAPACITY: usize = 20;
+/// The interval in which clients send pings to the server.
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[derive(Fail, Debug, From)]
 pub enum Error {
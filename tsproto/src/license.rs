@@ -3,11 +3,11 @@ use std::io::prelude::*;
 use std::str;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use curve25519_dalek::constants;
 use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::scalar::Scalar;
-use failure::format_err;
+use failure::{format_err, Fail};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive as _, ToPrimitive as _};
 use ring::digest;
@@ -79,6 +79,52 @@ pub struct Licenses {
 	pub blocks: Vec<License>,
 }
 
+/// A problem in a license chain, found by [`Licenses::verify`].
+///
+/// The contained numbers are the index of the faulty block.
+///
+/// [`Licenses::verify`]: struct.Licenses.html#method.verify
+#[derive(Clone, Debug, Eq, Fail, PartialEq)]
+pub enum LicenseError {
+	#[fail(display = "The license chain contains no blocks")]
+	Empty,
+	#[fail(display = "License block {} is not yet valid", _0)]
+	NotYetValid(usize),
+	#[fail(display = "License block {} expired", _0)]
+	Expired(usize),
+	#[fail(display = "License block {} is valid longer than its parent", _0)]
+	InvalidTimeBounds(usize),
+	#[fail(display = "License block {} has the disallowed type {}", _0, _1)]
+	InvalidBlockType(usize, u8),
+	#[fail(display = "License block {} contains an invalid key", _0)]
+	InvalidKey(usize),
+	#[fail(display = "The license chain does not derive the expected key")]
+	KeyMismatch,
+}
+
+/// The result of [`Licenses::verify`].
+///
+/// [`Licenses::verify`]: struct.Licenses.html#method.verify
+#[derive(Clone, Debug)]
+pub struct LicenseVerification {
+	/// The type of the server license, if the chain contains a server block.
+	pub license_type: Option<LicenseType>,
+	/// The issuer of the last block which has an issuer.
+	pub issuer: Option<String>,
+	/// The start of the time span in which all blocks are valid.
+	pub not_valid_before: Option<DateTime<Utc>>,
+	/// The end of the time span in which all blocks are valid.
+	pub not_valid_after: Option<DateTime<Utc>>,
+	/// All problems which were found in the chain.
+	pub errors: Vec<LicenseError>,
+}
+
+impl LicenseVerification {
+	/// If no problems were found in the license chain.
+	#[inline]
+	pub fn is_valid(&self) -> bool { self.errors.is_empty() }
+}
+
 impl LicenseKey {
 	pub fn get_pub_bytes(&self) -> Cow<[u8; 32]> {
 		match *self {
@@ -109,12 +155,59 @@ impl InnerLicense {
 			InnerLicense::Ephemeral { .. } => 32,
 		}
 	}
+
+	pub fn issuer(&self) -> Option<&str> {
+		match self {
+			InnerLicense::Intermediate { issuer, .. }
+			| InnerLicense::Website { issuer }
+			| InnerLicense::Server { issuer, .. }
+			| InnerLicense::Code { issuer } => Some(issuer),
+			InnerLicense::Ephemeral => None,
+		}
+	}
+
+	/// If a block of type `next` is allowed to be the first block after the
+	/// root key.
+	///
+	/// The root signs all blocks except ephemeral blocks, e.g. the default
+	/// license of a server is a server block directly after the root.
+	fn root_allows_child(next: &InnerLicense) -> bool {
+		if let InnerLicense::Ephemeral = next {
+			false
+		} else {
+			true
+		}
+	}
+
+	/// If a block of type `next` is allowed to follow this block.
+	///
+	/// Intermediate blocks can be followed by any block. Website, server and
+	/// code licenses can only be followed by an ephemeral block and nothing
+	/// can follow an ephemeral block.
+	fn allows_child(&self, next: &InnerLicense) -> bool {
+		match self {
+			InnerLicense::Intermediate { .. } => true,
+			InnerLicense::Website { .. }
+			| InnerLicense::Server { .. }
+			| InnerLicense::Code { .. } => {
+				if let InnerLicense::Ephemeral = next {
+					true
+				} else {
+					false
+				}
+			}
+			InnerLicense::Ephemeral => false,
+		}
+	}
 }
 
 impl Licenses {
 	pub fn new() -> Self { Self::default() }
 
 	pub fn parse(mut data: &[u8]) -> Result<Self> {
+		if data.is_empty() {
+			return Err(format_err!("License too short").into());
+		}
 		let version = data[0];
 		if version != 1 {
 			return Err(format_err!("Unsupported version").into());
@@ -123,8 +216,6 @@ impl Licenses {
 		let mut res = Licenses { blocks: Vec::new() };
 		data = &data[1..];
 
-		let now = Utc::now();
-		let mut bounds = None;
 		while !data.is_empty() {
 			if res.blocks.len() >= 8 {
 				// Accept only 8 blocks
@@ -133,30 +224,111 @@ impl Licenses {
 
 			// Read next license
 			let (license, len) = License::parse(data)?;
+			res.blocks.push(license);
+			data = &data[len..];
+		}
+		Ok(res)
+	}
 
-			// Check if the certificate is valid
-			if license.not_valid_before > now {
-				return Err(format_err!("License is not yet valid").into());
+	/// Check if this license chain is valid at the time `now`.
+	///
+	/// The chain starts at the root key. Each block has to be valid at `now`,
+	/// must not be valid longer than its parent and has to be of a type which
+	/// is allowed after its parent.
+	///
+	/// The signature of the chain is not checked here, it is signed by the
+	/// identity of the server in the handshake.
+	///
+	/// The root key (`ROOT_KEY` or `ProtocolConfig::license_root_key`) is not
+	/// checked either, so a valid result does not mean that the chain belongs
+	/// to a root. Use [`verify_with_root`] to check the derived key.
+	///
+	/// [`verify_with_root`]: #method.verify_with_root
+	pub fn verify(&self, now: DateTime<Utc>) -> LicenseVerification {
+		let mut res = LicenseVerification {
+			license_type: None,
+			issuer: None,
+			not_valid_before: None,
+			not_valid_after: None,
+			errors: Vec::new(),
+		};
+		if self.blocks.is_empty() {
+			res.errors.push(LicenseError::Empty);
+			return res;
+		}
+
+		let mut parent: Option<&License> = None;
+		for (i, l) in self.blocks.iter().enumerate() {
+			if l.key.get_pub().is_err() {
+				res.errors.push(LicenseError::InvalidKey(i));
 			}
-			if license.not_valid_after < now {
-				return Err(format_err!("License expired").into());
+			if l.not_valid_before > now {
+				res.errors.push(LicenseError::NotYetValid(i));
 			}
-			if let Some((start, end)) = bounds {
+			if l.not_valid_after < now {
+				res.errors.push(LicenseError::Expired(i));
+			}
+			let allowed = if let Some(parent) = parent {
 				// The inner license must not have wider bounds
-				if license.not_valid_before < start
-					|| license.not_valid_after > end
+				if l.not_valid_before < parent.not_valid_before
+					|| l.not_valid_after > parent.not_valid_after
 				{
-					return Err(
-						format_err!("Invalid license time bounds").into()
-					);
+					res.errors.push(LicenseError::InvalidTimeBounds(i));
 				}
+				parent.inner.allows_child(&l.inner)
+			} else {
+				InnerLicense::root_allows_child(&l.inner)
+			};
+			if !allowed {
+				res.errors
+					.push(LicenseError::InvalidBlockType(i, l.inner.type_id()));
 			}
-			bounds = Some((license.not_valid_before, license.not_valid_after));
 
-			res.blocks.push(license);
-			data = &data[len..];
+			if let InnerLicense::Server { license_type, .. } = l.inner {
+				res.license_type = Some(license_type);
+			}
+			if let Some(issuer) = l.inner.issuer() {
+				res.issuer = Some(issuer.to_string());
+			}
+			res.not_valid_before = Some(
+				res.not_valid_before
+					.map_or(l.not_valid_before, |t| t.max(l.not_valid_before)),
+			);
+			res.not_valid_after = Some(
+				res.not_valid_after
+					.map_or(l.not_valid_after, |t| t.min(l.not_valid_after)),
+			);
+			parent = Some(l);
 		}
-		Ok(res)
+		res
+	}
+
+	/// Check if this license chain is valid at the time `now` and derives
+	/// `server_key`, starting with the given `root` key.
+	///
+	/// This does the same checks as [`verify`] and additionally reports
+	/// [`LicenseError::KeyMismatch`] if the key of the chain, computed with
+	/// [`derive_public_key_with_root`], is not the public key of the server.
+	///
+	/// [`verify`]: #method.verify
+	/// [`LicenseError::KeyMismatch`]: enum.LicenseError.html#variant.KeyMismatch
+	/// [`derive_public_key_with_root`]: #method.derive_public_key_with_root
+	pub fn verify_with_root(
+		&self,
+		now: DateTime<Utc>,
+		root: &EccKeyPubEd25519,
+		server_key: &EccKeyPubEd25519,
+	) -> LicenseVerification
+	{
+		let mut res = self.verify(now);
+		if res.errors.contains(&LicenseError::Empty) {
+			return res;
+		}
+		match self.derive_public_key_with_root(root) {
+			Ok(key) if key.compress() == server_key.0 => {}
+			_ => res.errors.push(LicenseError::KeyMismatch),
+		}
+		res
 	}
 
 	pub fn write(&self, w: &mut Write) -> Result<()> {
@@ -170,9 +342,24 @@ impl Licenses {
 		Ok(())
 	}
 
+	/// Derive the public key of this license, starting with the TeamSpeak
+	/// root key.
 	pub fn derive_public_key(&self) -> Result<EdwardsPoint> {
-		let mut last_round =
-			CompressedEdwardsY(crate::ROOT_KEY).decompress().unwrap();
+		self.derive_public_key_with_root(&EccKeyPubEd25519(
+			CompressedEdwardsY(crate::ROOT_KEY),
+		))
+	}
+
+	/// Derive the public key of this license, starting with a custom root key.
+	pub fn derive_public_key_with_root(
+		&self,
+		root: &EccKeyPubEd25519,
+	) -> Result<EdwardsPoint>
+	{
+		let mut last_round = root
+			.0
+			.decompress()
+			.ok_or_else(|| format_err!("Cannot uncompress root key"))?;
 		for l in &self.blocks {
 			//let derived_key = last_round.compress().0;
 			//println!("Got key: {:?}", ::utils::HexSlice((&derived_key) as &[u8]));
@@ -211,6 +398,120 @@ impl Licenses {
 	}
 }
 
+/// Create license chains with own keys, e.g. for test servers.
+///
+/// A new private key is generated for each block. The chain starts at a
+/// custom root key, clients need the public part of it in
+/// `ProtocolConfig::license_root_key`.
+///
+/// Servers append an ephemeral block to their license for each connection,
+/// so the license of a server should not contain one.
+///
+/// # Example
+///
+/// ```
+/// # use chrono::{Duration, Utc};
+/// # use tsproto::crypto::EccKeyPrivEd25519;
+/// # use tsproto::license::*;
+/// let root = EccKeyPrivEd25519::create().unwrap();
+/// let now = Utc::now();
+/// let (licenses, key) = LicenseBuilder::new(root)
+///     .validity(now - Duration::days(1), now + Duration::days(30))
+///     .intermediate("Test issuer")
+///     .server("Test server", LicenseType::Npl)
+///     .build()
+///     .unwrap();
+/// assert!(licenses.verify(now).is_valid());
+/// ```
+#[derive(Clone, Debug)]
+pub struct LicenseBuilder {
+	root_key: EccKeyPrivEd25519,
+	not_valid_before: DateTime<Utc>,
+	not_valid_after: DateTime<Utc>,
+	blocks: Vec<(InnerLicense, DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl LicenseBuilder {
+	/// Start a license chain at the private `root_key`.
+	///
+	/// The blocks are valid from now on for one year, if no other
+	/// [`validity`] is set.
+	///
+	/// [`validity`]: #method.validity
+	pub fn new(root_key: EccKeyPrivEd25519) -> Self {
+		let now = Utc::now();
+		Self {
+			root_key,
+			not_valid_before: now,
+			not_valid_after: now + Duration::days(365),
+			blocks: Vec::new(),
+		}
+	}
+
+	/// Set the validity for all following blocks.
+	///
+	/// Inner blocks must not be valid longer than their parents.
+	pub fn validity(
+		mut self,
+		not_valid_before: DateTime<Utc>,
+		not_valid_after: DateTime<Utc>,
+	) -> Self
+	{
+		self.not_valid_before = not_valid_before;
+		self.not_valid_after = not_valid_after;
+		self
+	}
+
+	pub fn intermediate<S: Into<String>>(self, issuer: S) -> Self {
+		self.block(InnerLicense::Intermediate {
+			issuer: issuer.into(),
+			data: 0,
+		})
+	}
+
+	pub fn server<S: Into<String>>(
+		self,
+		issuer: S,
+		license_type: LicenseType,
+	) -> Self
+	{
+		self.block(InnerLicense::Server {
+			issuer: issuer.into(),
+			license_type,
+			data: 0,
+		})
+	}
+
+	pub fn ephemeral(self) -> Self { self.block(InnerLicense::Ephemeral) }
+
+	fn block(mut self, inner: InnerLicense) -> Self {
+		self.blocks
+			.push((inner, self.not_valid_before, self.not_valid_after));
+		self
+	}
+
+	/// Create the license chain.
+	///
+	/// Returns the licenses and the derived private key of the whole chain,
+	/// like it is needed for `server::HandshakeConfig::license`.
+	pub fn build(self) -> Result<(Licenses, EccKeyPrivEd25519)> {
+		let mut licenses = Licenses::new();
+		for (inner, not_valid_before, not_valid_after) in self.blocks {
+			let mut license = License {
+				key: LicenseKey::Private(EccKeyPrivEd25519::create()?),
+				not_valid_before,
+				not_valid_after,
+				hash: [0; 32],
+				inner,
+			};
+			license.fill_hash();
+			licenses.blocks.push(license);
+		}
+		let key = licenses.derive_private_key(0, self.root_key)?;
+		Ok((licenses, key))
+	}
+}
+
 impl License {
 	/// Compute the hash of this license and store it in the hash field.
 	///
@@ -432,26 +733,111 @@ mod tests {
 	use base64;
 
 	#[test]
-	#[should_panic]
+	fn parse_empty() { assert!(Licenses::parse(&[]).is_err()); }
+
+	fn timestamp(secs: i64) -> DateTime<Utc> {
+		DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc)
+	}
+
+	#[test]
 	fn parse_standard_license() {
-		Licenses::parse(&base64::decode("AQA1hUFJiiSs0wFXkYuPUJVcDa6XCrZTcsvkB0\
-			Ffzz4CmwIITRXgCqeTYAcAAAAgQW5vbnltb3VzAACiIBip9hQaK6P3QhwOJs/BkPn0i\
-			oyIDPaNgzJ6M8x0kiAJf4hxCYAxMQ==").unwrap()).unwrap();
+		let licenses = Licenses::parse(&base64::decode("AQA1hUFJiiSs0wFXkYuP\
+			UJVcDa6XCrZTcsvkB0Ffzz4CmwIITRXgCqeTYAcAAAAgQW5vbnltb3VzAACiIBip9hQ\
+			aK6P3QhwOJs/BkPn0ioyIDPaNgzJ6M8x0kiAJf4hxCYAxMQ==").unwrap())
+			.unwrap();
+
+		// 2018-01-19 12:00:00
+		let res = licenses.verify(timestamp(1_516_363_200));
+		assert!(res.is_valid(), "{:?}", res.errors);
+		match res.license_type {
+			Some(LicenseType::Default) => {}
+			t => panic!("Wrong license type {:?}", t),
+		}
+		assert_eq!(res.issuer.as_ref().unwrap(), "Anonymous");
+
+		// 2019-01-01 00:00:00
+		let res = licenses.verify(timestamp(1_546_300_800));
+		assert_eq!(res.errors, [
+			LicenseError::Expired(0),
+			LicenseError::Expired(1),
+		]);
 	}
 
 	#[test]
-	#[should_panic]
 	fn parse_aal_license() {
-		Licenses::parse(&base64::decode("AQCvbHFTQDY/terPeilrp/ECU9xCH5U3xC92lY\
-			TNaY/0KQAJFueAazbsgAAAACVUZWFtU3BlYWsgU3lzdGVtcyBHbWJIAABhl9gwla/UJ\
-			p2Eszst9TRVXO/PeE6a6d+CTI6Pg7OEVgAJc5CrL4Nh8gAAACRUZWFtU3BlYWsgc3lz\
-			dGVtcyBHbWJIAACvTQIgpv6zmLZq3znh7ygmOSokGFkFjz4bTigrOnetrgIJdIIACdS\
-			/gAYAAAAAU29zc2VuU3lzdGVtcy5iaWQAADY7+uV1CQ1niOvYSdGzsu83kPTNWijovr\
-			3B78eHGeePIAm98vQJvpu0").unwrap()).unwrap();
+		let licenses = Licenses::parse(&base64::decode("AQCvbHFTQDY/terPeilr\
+			p/ECU9xCH5U3xC92lYTNaY/0KQAJFueAazbsgAAAACVUZWFtU3BlYWsgU3lzdGVtcyB\
+			HbWJIAABhl9gwla/UJp2Eszst9TRVXO/PeE6a6d+CTI6Pg7OEVgAJc5CrL4Nh8gAAAC\
+			RUZWFtU3BlYWsgc3lzdGVtcyBHbWJIAACvTQIgpv6zmLZq3znh7ygmOSokGFkFjz4bT\
+			igrOnetrgIJdIIACdS/gAYAAAAAU29zc2VuU3lzdGVtcy5iaWQAADY7+uV1CQ1niOvY\
+			SdGzsu83kPTNWijovr3B78eHGeePIAm98vQJvpu0").unwrap()).unwrap();
+
+		// 2018-03-07 18:00:00
+		let res = licenses.verify(timestamp(1_520_445_600));
+		assert!(res.is_valid(), "{:?}", res.errors);
+		match res.license_type {
+			Some(LicenseType::Aal) => {}
+			t => panic!("Wrong license type {:?}", t),
+		}
+		assert_eq!(res.issuer.as_ref().unwrap(), "SossenSystems.bid");
+	}
+
+	#[test]
+	fn verify_block_order() {
+		let mut licenses = Licenses::parse(&base64::decode("AQA1hUFJiiSs0wF\
+			XkYuPUJVcDa6XCrZTcsvkB0Ffzz4CmwIITRXgCqeTYAcAAAAgQW5vbnltb3VzAACiIB\
+			ip9hQaK6P3QhwOJs/BkPn0ioyIDPaNgzJ6M8x0kiAJf4hxCYAxMQ==").unwrap())
+			.unwrap();
+		licenses.blocks.reverse();
+
+		let res = licenses.verify(timestamp(1_516_363_200));
+		assert_eq!(res.errors, [
+			LicenseError::InvalidBlockType(0, 32),
+			LicenseError::InvalidTimeBounds(1),
+			LicenseError::InvalidBlockType(1, 2),
+		]);
+		let res = Licenses::new().verify(Utc::now());
+		assert_eq!(res.errors, [LicenseError::Empty]);
+
+		// The chain has to start at the root
+		licenses.blocks.truncate(1);
+		let res = licenses.verify(timestamp(1_516_363_200));
+		assert_eq!(res.errors, [LicenseError::InvalidBlockType(0, 32)]);
+	}
+
+	#[test]
+	fn build_license() {
+		let root = EccKeyPrivEd25519::create().unwrap();
+		let root_pub = root.to_pub();
+		let now = Utc::now();
+		let (licenses, key) = LicenseBuilder::new(root)
+			.validity(now - Duration::days(1), now + Duration::days(2))
+			.intermediate("Intermediate")
+			.validity(now - Duration::hours(1), now + Duration::days(1))
+			.server("Server", LicenseType::Npl)
+			.build()
+			.unwrap();
+
+		let mut data = Vec::new();
+		licenses.write(&mut data).unwrap();
+		let licenses = Licenses::parse(&data).unwrap();
+		let res = licenses.verify(now);
+		assert!(res.is_valid(), "{:?}", res.errors);
+		assert_eq!(res.issuer.as_ref().unwrap(), "Server");
+
+		let derived = licenses.derive_public_key_with_root(&root_pub).unwrap();
+		assert_eq!(derived.compress(), key.to_pub().0);
+
+		let res = licenses.verify_with_root(now, &root_pub, &key.to_pub());
+		assert!(res.is_valid(), "{:?}", res.errors);
+
+		// A chain from another root derives a different key
+		let other_root = EccKeyPrivEd25519::create().unwrap().to_pub();
+		let res = licenses.verify_with_root(now, &other_root, &key.to_pub());
+		assert_eq!(res.errors, [LicenseError::KeyMismatch]);
 	}
 
 	#[test]
-	#[should_panic]
 	fn derive_public_key() {
 		let licenses = Licenses::parse(&base64::decode("AQA1hUFJiiSs0wFXkYuPUJVcDa6XCrZTcsvkB0Ffzz4CmwIITRXgCqeTYAcAAAAgQW5vbnltb3VzAAC4R+5mos+UQ/KCbkpQLMI5WRp4wkQu8e5PZY4zU+/FlyAJwaE8CcJJ/A==").unwrap()).unwrap();
 		let derived_key = licenses.derive_public_key().unwrap();
@@ -0,0 +1,91 @@
+//! An in-memory transport to connect two [`Data`] objects without sockets.
+//!
+//! The endpoints can be passed to [`Data::new_with_socket`], so they work with
+//! every [`ConnectionManager`]. For the client and server, there are
+//! [`client::new_with_socket`] and [`server::new_with_socket`].
+//!
+//! [`Data`]: ../handler_data/struct.Data.html
+//! [`Data::new_with_socket`]: ../handler_data/struct.Data.html#method.new_with_socket
+//! [`ConnectionManager`]: ../connectionmanager/trait.ConnectionManager.html
+//! [`client::new_with_socket`]: ../client/fn.new_with_socket.html
+//! [`server::new_with_socket`]: ../server/fn.new_with_socket.html
+use std::net::SocketAddr;
+
+use bytes::{Bytes, BytesMut};
+use failure::format_err;
+use futures::sync::mpsc;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend};
+
+use crate::Error;
+
+/// The sending half of an endpoint.
+///
+/// All packets arrive at the other endpoint, regardless of the destination
+/// address.
+#[derive(Clone, Debug)]
+pub struct MemorySink {
+	addr: SocketAddr,
+	inner: mpsc::UnboundedSender<(BytesMut, SocketAddr)>,
+}
+
+/// The receiving half of an endpoint.
+pub type MemoryStream = mpsc::UnboundedReceiver<(BytesMut, SocketAddr)>;
+
+/// One side of an in-memory connection.
+#[derive(Debug)]
+pub struct MemoryEndpoint {
+	/// The address, which is seen as the source address by the other side.
+	pub addr: SocketAddr,
+	pub sink: MemorySink,
+	pub stream: MemoryStream,
+}
+
+impl Sink for MemorySink {
+	type SinkItem = (Bytes, SocketAddr);
+	type SinkError = Error;
+
+	fn start_send(
+		&mut self,
+		(packet, _): Self::SinkItem,
+	) -> StartSend<Self::SinkItem, Self::SinkError>
+	{
+		self.inner
+			.unbounded_send((packet.into(), self.addr))
+			.map_err(|e| format_err!("Failed to send packet ({:?})", e))?;
+		Ok(AsyncSink::Ready)
+	}
+
+	fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+		Ok(Async::Ready(()))
+	}
+}
+
+/// Create two endpoints, which are connected to each other.
+///
+/// `a` and `b` are the addresses of the two endpoints.
+pub fn pair(
+	a: SocketAddr,
+	b: SocketAddr,
+) -> (MemoryEndpoint, MemoryEndpoint)
+{
+	let (a_send, a_recv) = mpsc::unbounded();
+	let (b_send, b_recv) = mpsc::unbounded();
+	(
+		MemoryEndpoint {
+			addr: a,
+			sink: MemorySink {
+				addr: a,
+				inner: b_send,
+			},
+			stream: a_recv,
+		},
+		MemoryEndpoint {
+			addr: b,
+			sink: MemorySink {
+				addr: b,
+				inner: a_send,
+			},
+			stream: b_recv,
+		},
+	)
+}
@@ -0,0 +1,272 @@
+//! Middlewares inspect, modify, delay or drop commands.
+//!
+//! Incoming commands pass the [`InCommandMiddleware`]s of a [`Data`] object
+//! after they were decrypted and reassembled and before the
+//! `InCommandObserver`s see them and they are given to the packet handler.
+//! Outgoing command packets pass the [`OutCommandMiddleware`]s before the
+//! `OutPacketObserver`s see them and before they get a packet id and are put
+//! into the resender.
+//!
+//! Middlewares run sorted by their order, lower values first. Middlewares with
+//! the same order run in the order in which they were added.
+//!
+//! A delayed incoming command can be overtaken by later commands. A delayed
+//! outgoing command blocks all following packets of the sink which sent it.
+//!
+//! [`InCommandMiddleware`]: trait.InCommandMiddleware.html
+//! [`OutCommandMiddleware`]: trait.OutCommandMiddleware.html
+//! [`Data`]: ../handler_data/struct.Data.html
+use std::sync::Arc;
+
+use futures::Future;
+use parking_lot::RwLock;
+use tsproto_packets::packets::{InCommand, OutPacket};
+
+use crate::connection::Connection;
+use crate::Error;
+
+pub(crate) type LockedMiddlewareChain<M> = Arc<RwLock<MiddlewareChain<M>>>;
+
+/// What should happen with a command after a middleware handled it.
+pub enum CommandAction<C> {
+	/// Pass the command, which may be modified, to the next middleware.
+	Continue(C),
+	/// Discard the command.
+	Drop,
+	/// Pass the command to the next middleware when the future resolves.
+	///
+	/// If the future returns an error, the command is discarded.
+	Delay(Box<Future<Item = C, Error = Error> + Send>),
+}
+
+/// The `handle` method is called on every incoming command.
+pub trait InCommandMiddleware<T>: Send + Sync {
+	/// The `handle` method should not take too long, because it holds a lock.
+	fn handle(
+		&self,
+		connection: &mut (T, Connection),
+		cmd: InCommand,
+	) -> CommandAction<InCommand>;
+}
+
+/// The `handle` method is called on every outgoing `Command` and `CommandLow`
+/// packet.
+pub trait OutCommandMiddleware<T>: Send + Sync {
+	/// The `handle` method should not take too long, because it holds a lock.
+	fn handle(
+		&self,
+		connection: &mut (T, Connection),
+		packet: OutPacket,
+	) -> CommandAction<OutPacket>;
+}
+
+/// The position of a middleware in a chain.
+///
+/// This is used to continue with the next middleware after a command was
+/// delayed, even if the chain changed in the meantime.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) struct ChainPosition {
+	order: i32,
+	/// Increases with every added middleware.
+	id: u64,
+}
+
+pub(crate) enum ChainResult<C> {
+	/// All middlewares passed the command.
+	Finished(C),
+	Dropped,
+	/// The middleware at the position delayed the command.
+	Delayed(Box<Future<Item = C, Error = Error> + Send>, ChainPosition),
+}
+
+struct Entry<M: ?Sized> {
+	key: String,
+	position: ChainPosition,
+	middleware: Box<M>,
+}
+
+/// A list of middlewares, sorted by the order in which they run.
+pub struct MiddlewareChain<M: ?Sized> {
+	entries: Vec<Entry<M>>,
+	next_id: u64,
+}
+
+impl<M: ?Sized> Default for MiddlewareChain<M> {
+	fn default() -> Self {
+		Self {
+			entries: Vec::new(),
+			next_id: 0,
+		}
+	}
+}
+
+impl<M: ?Sized> MiddlewareChain<M> {
+	pub fn new() -> Self { Self::default() }
+
+	pub fn len(&self) -> usize { self.entries.len() }
+
+	pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+	pub fn contains_key(&self, key: &str) -> bool {
+		self.entries.iter().any(|e| e.key == key)
+	}
+
+	/// The keys of all middlewares in the order in which they run.
+	pub fn keys(&self) -> impl Iterator<Item = &str> {
+		self.entries.iter().map(|e| e.key.as_str())
+	}
+
+	/// Add a middleware, middlewares with a lower `order` run first.
+	///
+	/// If a middleware with the same key exists, it is replaced and returned.
+	pub fn insert(
+		&mut self,
+		key: String,
+		order: i32,
+		middleware: Box<M>,
+	) -> Option<Box<M>>
+	{
+		let old = self.remove(&key);
+		let position = ChainPosition {
+			order,
+			id: self.next_id,
+		};
+		self.next_id += 1;
+		let i = self
+			.entries
+			.iter()
+			.position(|e| e.position > position)
+			.unwrap_or_else(|| self.entries.len());
+		self.entries.insert(
+			i,
+			Entry {
+				key,
+				position,
+				middleware,
+			},
+		);
+		old
+	}
+
+	/// Remove the middleware with the given key.
+	pub fn remove(&mut self, key: &str) -> Option<Box<M>> {
+		let i = self.entries.iter().position(|e| e.key == key)?;
+		Some(self.entries.remove(i).middleware)
+	}
+
+	/// Pass `cmd` through all middlewares which come after `start`.
+	///
+	/// If `start` is `None`, all middlewares are used.
+	pub(crate) fn run<C>(
+		&self,
+		start: Option<ChainPosition>,
+		mut cmd: C,
+		mut handle: impl FnMut(&M, C) -> CommandAction<C>,
+	) -> ChainResult<C>
+	{
+		for e in &self.entries {
+			if start.map(|s| e.position <= s).unwrap_or(false) {
+				continue;
+			}
+			match handle(&e.middleware, cmd) {
+				CommandAction::Continue(c) => cmd = c,
+				CommandAction::Drop => return ChainResult::Dropped,
+				CommandAction::Delay(f) => {
+					return ChainResult::Delayed(f, e.position);
+				}
+			}
+		}
+		ChainResult::Finished(cmd)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::future;
+
+	use super::*;
+
+	type TestChain = MiddlewareChain<Fn(u32) -> CommandAction<u32>>;
+
+	fn run(
+		chain: &TestChain,
+		start: Option<ChainPosition>,
+		cmd: u32,
+	) -> ChainResult<u32>
+	{
+		chain.run(start, cmd, |m, c| m(c))
+	}
+
+	#[test]
+	fn middleware_order() {
+		let mut chain = TestChain::new();
+		chain.insert(
+			"double".into(),
+			0,
+			Box::new(|c: u32| CommandAction::Continue(c * 2)),
+		);
+		chain.insert(
+			"add".into(),
+			-1,
+			Box::new(|c: u32| CommandAction::Continue(c + 1)),
+		);
+		chain.insert(
+			"drop".into(),
+			0,
+			Box::new(|c: u32| {
+				if c > 10 {
+					CommandAction::Drop
+				} else {
+					CommandAction::Continue(c)
+				}
+			}),
+		);
+		assert_eq!(chain.keys().collect::<Vec<_>>(), ["add", "double", "drop"]);
+
+		match run(&chain, None, 2) {
+			ChainResult::Finished(c) => assert_eq!(c, 6),
+			_ => panic!("Command should pass the chain"),
+		}
+		match run(&chain, None, 5) {
+			ChainResult::Dropped => {}
+			_ => panic!("Command should be dropped"),
+		}
+
+		// Replace a middleware
+		assert!(chain
+			.insert("add".into(), 1, Box::new(CommandAction::<u32>::Continue))
+			.is_some());
+		assert_eq!(chain.keys().collect::<Vec<_>>(), ["double", "drop", "add"]);
+		assert!(chain.remove("double").is_some());
+		assert!(!chain.contains_key("double"));
+		assert_eq!(chain.len(), 2);
+	}
+
+	#[test]
+	fn middleware_delay() {
+		let mut chain = TestChain::new();
+		chain.insert(
+			"delay".into(),
+			0,
+			Box::new(|c: u32| CommandAction::Delay(Box::new(future::ok(c)))),
+		);
+		chain.insert(
+			"add".into(),
+			1,
+			Box::new(|c: u32| CommandAction::Continue(c + 1)),
+		);
+
+		let pos = match run(&chain, None, 1) {
+			ChainResult::Delayed(f, pos) => {
+				assert_eq!(f.wait().unwrap(), 1);
+				pos
+			}
+			_ => panic!("Command should be delayed"),
+		};
+		// Continue after the delaying middleware
+		match run(&chain, Some(pos), 1) {
+			ChainResult::Finished(c) => assert_eq!(c, 2),
+			_ => panic!("Command should pass the chain"),
+		}
+	}
+}
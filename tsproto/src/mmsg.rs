@@ -0,0 +1,360 @@
+//! A batched udp socket for Linux, which uses `recvmmsg` and `sendmmsg`.
+//!
+//! The default tokio socket needs one system call per datagram. When many
+//! connections run in one process, this socket moves up to [`BATCH_SIZE`]
+//! datagrams with a single system call.
+//!
+//! The sink and stream can be passed to [`Data::new_with_socket`], for clients
+//! there is [`client::new_with_socket`]. Set [`bind_socket`] with
+//! [`Data::set_bind_socket`] so a rebound `Data` object keeps using batched
+//! sockets.
+//!
+//! Generic segmentation offload is not used, it only helps if many datagrams
+//! of the same size are sent to the same address, which is rare for voice
+//! and command packets.
+//!
+//! [`BATCH_SIZE`]: constant.BATCH_SIZE.html
+//! [`Data::new_with_socket`]: ../handler_data/struct.Data.html#method.new_with_socket
+//! [`client::new_with_socket`]: ../client/fn.new_with_socket.html
+//! [`bind_socket`]: fn.bind_socket.html
+//! [`Data::set_bind_socket`]: ../handler_data/struct.Data.html#method.set_bind_socket
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::{try_ready, Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use mio::Ready;
+use tokio::reactor::PollEvented2;
+
+use crate::handler_data::{UdpSink, UdpStream};
+use crate::{Error, Result};
+
+/// The maximum number of datagrams which are sent or received with one
+/// system call.
+pub const BATCH_SIZE: usize = 32;
+/// Larger datagrams are dropped.
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+type Socket = Arc<PollEvented2<mio::net::UdpSocket>>;
+
+/// The sending half of a batched socket.
+///
+/// Datagrams are collected until the sink is flushed or a batch is full.
+pub struct MmsgSink {
+	socket: Socket,
+	queue: Vec<(Bytes, SocketAddr)>,
+}
+
+/// The receiving half of a batched socket.
+pub struct MmsgStream {
+	socket: Socket,
+	/// The kernel writes received datagrams into this buffer, it is reused for
+	/// every batch.
+	buffer: Vec<u8>,
+	received: VecDeque<(BytesMut, SocketAddr)>,
+}
+
+/// Create a batched udp socket which is bound to the given address.
+///
+/// Returns the local address of the socket, its sink and its stream.
+pub fn bind(addr: &SocketAddr) -> Result<(SocketAddr, MmsgSink, MmsgStream)> {
+	let socket = mio::net::UdpSocket::bind(addr)?;
+	let local_addr = socket.local_addr()?;
+	let socket = Arc::new(PollEvented2::new(socket));
+	Ok((
+		local_addr,
+		MmsgSink {
+			socket: socket.clone(),
+			queue: Vec::with_capacity(BATCH_SIZE),
+		},
+		MmsgStream {
+			socket,
+			buffer: vec![0; BATCH_SIZE * MAX_DATAGRAM_SIZE],
+			received: VecDeque::with_capacity(BATCH_SIZE),
+		},
+	))
+}
+
+/// Like [`bind`], but returns the sink and stream as trait objects.
+///
+/// This can be used as the bind function of a [`Data`] object.
+///
+/// [`bind`]: fn.bind.html
+/// [`Data`]: ../handler_data/struct.Data.html
+pub fn bind_socket(
+	addr: &SocketAddr,
+) -> Result<(SocketAddr, UdpSink, UdpStream)>
+{
+	let (local_addr, sink, stream) = bind(addr)?;
+	Ok((local_addr, Box::new(sink), Box::new(stream)))
+}
+
+impl Sink for MmsgSink {
+	type SinkItem = (Bytes, SocketAddr);
+	type SinkError = Error;
+
+	fn start_send(
+		&mut self,
+		item: Self::SinkItem,
+	) -> StartSend<Self::SinkItem, Self::SinkError>
+	{
+		if self.queue.len() >= BATCH_SIZE {
+			self.poll_complete()?;
+			if self.queue.len() >= BATCH_SIZE {
+				return Ok(AsyncSink::NotReady(item));
+			}
+		}
+		self.queue.push(item);
+		Ok(AsyncSink::Ready)
+	}
+
+	fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+		while !self.queue.is_empty() {
+			try_ready!(self.socket.poll_write_ready());
+			match send_batch(self.socket.get_ref().as_raw_fd(), &self.queue) {
+				Ok(n) => {
+					self.queue.drain(..n);
+				}
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+					self.socket.clear_write_ready()?;
+					return Ok(Async::NotReady);
+				}
+				Err(e) => {
+					// Drop the datagram which failed
+					self.queue.remove(0);
+					return Err(e.into());
+				}
+			}
+		}
+		Ok(Async::Ready(()))
+	}
+}
+
+impl Stream for MmsgStream {
+	type Item = (BytesMut, SocketAddr);
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		while self.received.is_empty() {
+			try_ready!(self.socket.poll_read_ready(Ready::readable()));
+			let fd = self.socket.get_ref().as_raw_fd();
+			match recv_batch(fd, &mut self.buffer, &mut self.received) {
+				Ok(()) => {}
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+					self.socket.clear_read_ready(Ready::readable())?;
+					return Ok(Async::NotReady);
+				}
+				Err(e) => return Err(e.into()),
+			}
+		}
+		Ok(Async::Ready(self.received.pop_front()))
+	}
+}
+
+/// Send up to [`BATCH_SIZE`] datagrams and return how many were sent.
+fn send_batch(fd: RawFd, items: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+	let count = items.len().min(BATCH_SIZE);
+	// Zero is a valid value for these structs
+	let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] =
+		unsafe { mem::zeroed() };
+	let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+	let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+	for (((msg, iov), storage), (data, addr)) in msgs
+		.iter_mut()
+		.zip(iovecs.iter_mut())
+		.zip(addrs.iter_mut())
+		.zip(items)
+	{
+		// The kernel does not write to the buffer
+		iov.iov_base = data.as_ptr() as *mut libc::c_void;
+		iov.iov_len = data.len();
+		msg.msg_hdr.msg_name = storage as *mut _ as *mut libc::c_void;
+		msg.msg_hdr.msg_namelen = addr_to_sockaddr(addr, storage);
+		msg.msg_hdr.msg_iov = iov;
+		msg.msg_hdr.msg_iovlen = 1;
+	}
+
+	let res = unsafe {
+		libc::sendmmsg(
+			fd,
+			msgs.as_mut_ptr(),
+			count as libc::c_uint,
+			libc::MSG_DONTWAIT as _,
+		)
+	};
+	if res < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(res as usize)
+}
+
+/// Receive up to [`BATCH_SIZE`] datagrams.
+///
+/// The datagrams are received into `buf`, which has to hold [`BATCH_SIZE`]
+/// datagrams of the maximum size. Every datagram is copied out into its own
+/// buffer, so `buf` can be reused for the next batch.
+fn recv_batch(
+	fd: RawFd,
+	buf: &mut [u8],
+	received: &mut VecDeque<(BytesMut, SocketAddr)>,
+) -> io::Result<()>
+{
+	assert!(buf.len() >= BATCH_SIZE * MAX_DATAGRAM_SIZE);
+	let base = buf.as_mut_ptr();
+	let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] =
+		unsafe { mem::zeroed() };
+	let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+	let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+	for (i, ((msg, iov), storage)) in msgs
+		.iter_mut()
+		.zip(iovecs.iter_mut())
+		.zip(addrs.iter_mut())
+		.enumerate()
+	{
+		iov.iov_base =
+			unsafe { base.add(i * MAX_DATAGRAM_SIZE) } as *mut libc::c_void;
+		iov.iov_len = MAX_DATAGRAM_SIZE;
+		msg.msg_hdr.msg_name = storage as *mut _ as *mut libc::c_void;
+		msg.msg_hdr.msg_namelen =
+			mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+		msg.msg_hdr.msg_iov = iov;
+		msg.msg_hdr.msg_iovlen = 1;
+	}
+
+	let res = unsafe {
+		libc::recvmmsg(
+			fd,
+			msgs.as_mut_ptr(),
+			BATCH_SIZE as libc::c_uint,
+			libc::MSG_DONTWAIT as _,
+			ptr::null_mut(),
+		)
+	};
+	if res < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	for (i, (msg, storage)) in
+		msgs.iter().zip(addrs.iter()).take(res as usize).enumerate()
+	{
+		if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+			continue;
+		}
+		if let Some(addr) = sockaddr_to_addr(storage, msg.msg_hdr.msg_namelen) {
+			let start = i * MAX_DATAGRAM_SIZE;
+			let data = &buf[start..start + msg.msg_len as usize];
+			received.push_back((BytesMut::from(data), addr));
+		}
+	}
+	Ok(())
+}
+
+/// Write the address into `storage` and return the used length.
+fn addr_to_sockaddr(
+	addr: &SocketAddr,
+	storage: &mut libc::sockaddr_storage,
+) -> libc::socklen_t
+{
+	match addr {
+		SocketAddr::V4(addr) => {
+			let s =
+				unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+			s.sin_family = libc::AF_INET as libc::sa_family_t;
+			s.sin_port = addr.port().to_be();
+			s.sin_addr = libc::in_addr {
+				s_addr: u32::from(*addr.ip()).to_be(),
+			};
+			mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+		}
+		SocketAddr::V6(addr) => {
+			let s =
+				unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+			s.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+			s.sin6_port = addr.port().to_be();
+			s.sin6_flowinfo = addr.flowinfo();
+			s.sin6_addr.s6_addr = addr.ip().octets();
+			s.sin6_scope_id = addr.scope_id();
+			mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+		}
+	}
+}
+
+/// Read an address which was written by the kernel.
+fn sockaddr_to_addr(
+	storage: &libc::sockaddr_storage,
+	len: libc::socklen_t,
+) -> Option<SocketAddr>
+{
+	let len = len as usize;
+	match libc::c_int::from(storage.ss_family) {
+		libc::AF_INET if len >= mem::size_of::<libc::sockaddr_in>() => {
+			let s =
+				unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+			let ip = Ipv4Addr::from(u32::from_be(s.sin_addr.s_addr));
+			Some(SocketAddr::V4(SocketAddrV4::new(
+				ip,
+				u16::from_be(s.sin_port),
+			)))
+		}
+		libc::AF_INET6 if len >= mem::size_of::<libc::sockaddr_in6>() => {
+			let s =
+				unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+			let ip = Ipv6Addr::from(s.sin6_addr.s6_addr);
+			Some(SocketAddr::V6(SocketAddrV6::new(
+				ip,
+				u16::from_be(s.sin6_port),
+				s.sin6_flowinfo,
+				s.sin6_scope_id,
+			)))
+		}
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::{stream, Future};
+	use tokio::runtime::current_thread::Runtime;
+
+	use super::*;
+
+	#[test]
+	fn sockaddr_round_trip() {
+		for addr in &["127.0.0.1:9987", "[::1]:9987", "[fe80::1%2]:1"] {
+			let addr = addr.parse().unwrap();
+			let mut storage = unsafe { mem::zeroed() };
+			let len = addr_to_sockaddr(&addr, &mut storage);
+			assert_eq!(sockaddr_to_addr(&storage, len), Some(addr));
+		}
+	}
+
+	#[test]
+	fn send_and_receive_batch() {
+		let mut rt = Runtime::new().unwrap();
+		let local = "127.0.0.1:0".parse().unwrap();
+		let (a_addr, a_sink, _) = bind(&local).unwrap();
+		let (b_addr, _, b_stream) = bind(&local).unwrap();
+
+		// More packets than fit into one batch
+		let count = BATCH_SIZE + 8;
+		let packets = (0..count)
+			.map(|i| (Bytes::from(vec![i as u8; i + 1]), b_addr))
+			.collect::<Vec<_>>();
+		let send = a_sink.send_all(stream::iter_ok(packets));
+		let recv = b_stream.take(count as u64).collect();
+		let (_, received) = rt.block_on(send.join(recv)).unwrap();
+
+		assert_eq!(received.len(), count);
+		for (i, (data, addr)) in received.iter().enumerate() {
+			assert_eq!(*addr, a_addr);
+			assert_eq!(&data[..], &vec![i as u8; i + 1][..]);
+		}
+	}
+}
@@ -1,9 +1,10 @@
+use std::cmp;
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::u16;
 
-use byteorder::{NetworkEndian, WriteBytesExt};
-use bytes::Bytes;
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian, WriteBytesExt};
+use bytes::{Bytes, BytesMut};
 use futures::sync::mpsc;
 use futures::{future, Future, IntoFuture, Sink};
 use num_traits::ToPrimitive;
@@ -12,14 +13,16 @@ use tokio;
 use tsproto_packets::packets::*;
 
 use crate::algorithms as algs;
-use crate::connection::Connection;
+use crate::connection::{Connection, ProtocolConfig};
 use crate::connectionmanager::{ConnectionManager, Resender};
 use crate::handler_data::{
-	ConnectionValue, Data, InCommandObserver, InPacketObserver,
+	ConnectionValue, ConnectionValueWeak, Data, InCommandObserver,
+	InPacketObserver,
 };
-use crate::{
-	Error, LockedHashMap, Result, MAX_FRAGMENTS_LENGTH, MAX_QUEUE_LEN,
+use crate::middleware::{
+	ChainPosition, ChainResult, InCommandMiddleware, LockedMiddlewareChain,
 };
+use crate::{Error, LockedHashMap, Result};
 
 /// Decodes incoming udp packets.
 ///
@@ -32,6 +35,8 @@ pub struct PacketCodecReceiver<CM: ConnectionManager + 'static> {
 		LockedHashMap<String, Box<InPacketObserver<CM::AssociatedData>>>,
 	in_command_observer:
 		LockedHashMap<String, Box<InCommandObserver<CM::AssociatedData>>>,
+	in_command_middleware:
+		LockedMiddlewareChain<InCommandMiddleware<CM::AssociatedData>>,
 
 	/// The sink for `UdpPacket`s with no known connection.
 	///
@@ -51,6 +56,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 			logger: data.logger.clone(),
 			in_packet_observer: data.in_packet_observer.clone(),
 			in_command_observer: data.in_command_observer.clone(),
+			in_command_middleware: data.in_command_middleware.clone(),
 			unknown_udp_packet_sink,
 		}
 	}
@@ -70,6 +76,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 			let logger = self.logger.new(o!("addr" => addr));
 			let in_packet_observer = self.in_packet_observer.clone();
 			let in_command_observer = self.in_command_observer.clone();
+			let in_command_middleware = self.in_command_middleware.clone();
 			// TODO Testing, command packets get handled in the wrong order if
 			// we spawn a new future for each packet.
 			// Send it to a channel per connection.
@@ -79,6 +86,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 					&logger,
 					in_packet_observer,
 					in_command_observer,
+					in_command_middleware,
 					self.is_client,
 					&con,
 					addr,
@@ -93,6 +101,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 						&logger,
 						in_packet_observer,
 						in_command_observer,
+						in_command_middleware,
 						is_client,
 						&con,
 						addr,
@@ -127,6 +136,94 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 		}
 	}
 
+	/// Pass a command through the middlewares after `start`, call the
+	/// observers and send it to the packet handler.
+	///
+	/// The observers see the command only after all middlewares passed it.
+	fn handle_command(
+		logger: &Logger,
+		in_command_observer: &LockedHashMap<
+			String,
+			Box<InCommandObserver<CM::AssociatedData>>,
+		>,
+		in_command_middleware: &LockedMiddlewareChain<
+			InCommandMiddleware<CM::AssociatedData>,
+		>,
+		connection: &ConnectionValueWeak<CM::AssociatedData>,
+		con: &mut (CM::AssociatedData, Connection),
+		cmd: InCommand,
+		start: Option<ChainPosition>,
+	)
+	{
+		let res = in_command_middleware
+			.read()
+			.run(start, cmd, |m, c| m.handle(con, c));
+		match res {
+			ChainResult::Finished(c) => {
+				for o in in_command_observer.read().values() {
+					o.observe(con, &c);
+				}
+
+				// Send to packet handler
+				if let Err(e) = con.1.command_sink.unbounded_send(c) {
+					error!(logger, "Failed to send command packet to \
+						handler"; "error" => ?e);
+				}
+			}
+			ChainResult::Dropped => {}
+			ChainResult::Delayed(c, position) => {
+				Self::delay_in_command(
+					logger.clone(),
+					in_command_observer.clone(),
+					in_command_middleware.clone(),
+					connection.clone(),
+					c,
+					position,
+				);
+			}
+		}
+	}
+
+	/// Pass a delayed command through the remaining middlewares when it is
+	/// ready and send it to the packet handler.
+	fn delay_in_command(
+		logger: Logger,
+		in_command_observer: LockedHashMap<
+			String,
+			Box<InCommandObserver<CM::AssociatedData>>,
+		>,
+		in_command_middleware: LockedMiddlewareChain<
+			InCommandMiddleware<CM::AssociatedData>,
+		>,
+		connection: ConnectionValueWeak<CM::AssociatedData>,
+		cmd: Box<Future<Item = InCommand, Error = Error> + Send>,
+		position: ChainPosition,
+	)
+	{
+		let logger2 = logger.clone();
+		tokio::spawn(
+			cmd.map(move |c| {
+				let con = match connection.upgrade() {
+					Some(c) => c,
+					None => return,
+				};
+				let mut con = con.mutex.lock();
+				Self::handle_command(
+					&logger,
+					&in_command_observer,
+					&in_command_middleware,
+					&connection,
+					&mut *con,
+					c,
+					Some(position),
+				);
+			})
+			.map_err(move |e| {
+				warn!(logger2, "Delaying command failed"; "error" => ?e)
+			}),
+		);
+	}
+
 	/// Handle a packet for a specific connection.
 	///
 	/// This part does the defragmentation, decryption and decompression.
@@ -140,6 +237,9 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 			String,
 			Box<InCommandObserver<CM::AssociatedData>>,
 		>,
+		in_command_middleware: LockedMiddlewareChain<
+			InCommandMiddleware<CM::AssociatedData>,
+		>,
 		is_client: bool,
 		connection: &ConnectionValue<CM::AssociatedData>,
 		_: SocketAddr,
@@ -160,6 +260,13 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 		let (in_recv_win, gen_id, cur_next, limit) =
 			con.1.in_receive_window(p_type, id);
 
+		let header_len = if dir == Direction::S2C {
+			tsproto_packets::S2C_HEADER_LEN
+		} else {
+			tsproto_packets::C2S_HEADER_LEN
+		};
+		let packet_len = header_len + packet.content().len();
+
 		if con.1.params.is_some() && p_type == PacketType::Init {
 			return Err(Error::UnexpectedInitPacket);
 		}
@@ -169,11 +276,15 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 			|| p_type == PacketType::AckLow
 			|| in_recv_win
 		{
+			let voice_encryption =
+				con.1.params.as_ref().map_or(false, |p| p.voice_encryption);
 			if !packet.header().flags().contains(Flags::UNENCRYPTED) {
 				// If it is the first ack packet of a client, try to fake
-				// decrypt it.
+				// decrypt it. A server has to fake decrypt the first ack of
+				// the client, which is the response for the initivexpand.
 				let decrypted = if (p_type == PacketType::Ack
 					&& id <= 1 && is_client)
+					|| (p_type == PacketType::Ack && id == 0 && !is_client)
 					|| con.1.params.is_none()
 				{
 					if let Ok(dec) = algs::decrypt_fake(&packet) {
@@ -209,10 +320,14 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 						return Err(Error::WrongMac(p_type, gen_id, id));
 					}
 				}
-			} else if algs::must_encrypt(p_type) {
+			} else if algs::must_encrypt(p_type)
+				|| (p_type == PacketType::Voice && voice_encryption)
+			{
 				// Check if it is ok for the packet to be unencrypted
 				return Err(Error::UnallowedUnencryptedPacket);
 			}
+			// Only count packets which passed the checks
+			con.1.stats.add_received(p_type, packet_len);
 
 			match p_type {
 				PacketType::Command | PacketType::CommandLow => {
@@ -225,25 +340,25 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 
 					let r_queue = &mut con.1.receive_queue;
 					let frag_queue = &mut con.1.fragmented_queue;
+					let config = &con.1.protocol_config;
 
 					let commands = Self::handle_command_packet(
-						logger, r_queue, frag_queue, in_ids, packet,
+						logger, config, r_queue, frag_queue, in_ids, packet,
 					)?;
 
 					// Be careful with command packets, they are
 					// guaranteed to be in the right order now, because
 					// we hold a lock on the connection.
-					let observer = in_command_observer.read();
 					for c in commands {
-						for o in observer.values() {
-							o.observe(con, &c);
-						}
-
-						// Send to packet handler
-						if let Err(e) = con.1.command_sink.unbounded_send(c) {
-							error!(logger, "Failed to send command packet to \
-								handler"; "error" => ?e);
-						}
+						Self::handle_command(
+							logger,
+							&in_command_observer,
+							&in_command_middleware,
+							&con2,
+							con,
+							c,
+							None,
+						);
 					}
 
 					// Dummy value
@@ -253,6 +368,14 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 					if p_type == PacketType::Ping {
 						ack = true;
 					}
+					// Count skipped packet ids as lost
+					if p_type.is_voice()
+						|| p_type == PacketType::Ping
+						|| p_type == PacketType::Pong
+					{
+						let lost = id.wrapping_sub(cur_next);
+						con.1.stats.add_lost(p_type, u64::from(lost));
+					}
 					// Update packet ids
 					let in_ids = &mut con.1.incoming_p_ids;
 					let (id, next_gen) = id.overflowing_add(1);
@@ -269,6 +392,13 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 							PacketType::CommandLow
 						};
 						con.1.resender.ack_packet(p_type, ack_id);
+					} else if p_type == PacketType::Pong {
+						// The content of a pong is the id of the ping
+						let content = packet.content();
+						if content.len() >= 2 {
+							let ping_id = NetworkEndian::read_u16(content);
+							con.1.pong_received(ping_id);
+						}
 					} else if p_type.is_voice() {
 						// Seems to work better without assembling the first 3 voice packets
 						// Use handle_voice_packet to assemble fragmented voice packets
@@ -345,6 +475,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 	/// They have to be handled in the right order.
 	fn handle_command_packet(
 		logger: &Logger,
+		config: &ProtocolConfig,
 		r_queue: &mut [Vec<InPacket>; 2],
 		frag_queue: &mut [Option<(InPacket, Vec<u8>)>; 2],
 		in_ids: &mut [(u32, u16); 8],
@@ -384,10 +515,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 							.contains(Flags::COMPRESSED)
 						{
 							//debug!(logger, "Compressed"; "data" => ?::utils::HexSlice(&frag_queue));
-							::quicklz::decompress(
-								&mut Cursor::new(frag_queue),
-								crate::MAX_DECOMPRESSED_SIZE,
-							)?
+							decompress(&frag_queue, config.max_decompressed_size)?
 						} else {
 							frag_queue
 						};
@@ -409,7 +537,7 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 					}
 				} else if let Some((_, ref mut frag_queue)) = *frag_queue {
 					// The packet is fragmented
-					if frag_queue.len() < MAX_FRAGMENTS_LENGTH {
+					if frag_queue.len() < config.max_fragments_length {
 						frag_queue.extend_from_slice(packet.content());
 						None
 					} else {
@@ -421,9 +549,9 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 					// Decompress
 					let decompressed = if flags.contains(Flags::COMPRESSED) {
 						//debug!(logger, "Compressed"; "data" => ?::utils::HexSlice(packet.content()));
-						::quicklz::decompress(
-							&mut Cursor::new(packet.content()),
-							crate::MAX_DECOMPRESSED_SIZE,
+						decompress(
+							packet.content(),
+							config.max_decompressed_size,
 						)?
 					} else {
 						packet.take_content()
@@ -457,7 +585,8 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 			// Out of order
 			warn!(logger, "Out of order command packet"; "got" => id,
 				"expected" => cur_next);
-			let (limit, next_gen) = cur_next.overflowing_add(MAX_QUEUE_LEN);
+			let (limit, next_gen) =
+				cur_next.overflowing_add(config.max_queue_len);
 			if (!next_gen && id >= cur_next && id < limit)
 				|| (next_gen && (id >= cur_next || id < limit))
 			{
@@ -523,6 +652,29 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 	}*/
 }
 
+/// Decompress the content of a command packet.
+///
+/// Fails with `MaxLengthExceeded` if the decompressed size, which is stored in
+/// the QuickLZ header, is larger than `max_size`.
+fn decompress(data: &[u8], max_size: u32) -> Result<Vec<u8>> {
+	// Bit 1 of the first byte is set for the 9 byte header
+	let size = match data.first() {
+		Some(flags) if flags & 2 != 0 && data.len() >= 9 => {
+			Some(LittleEndian::read_u32(&data[5..9]))
+		}
+		Some(flags) if flags & 2 == 0 && data.len() >= 3 => {
+			Some(u32::from(data[2]))
+		}
+		_ => None,
+	};
+	if size.map_or(false, |s| s > max_size) {
+		return Err(Error::MaxLengthExceeded(String::from(
+			"decompressed packet",
+		)));
+	}
+	Ok(::quicklz::decompress(&mut Cursor::new(data), max_size)?)
+}
+
 /// Encodes outgoing packets.
 ///
 /// This part does the compression, encryption and fragmentation.
@@ -536,8 +688,26 @@ impl PacketCodecSender {
 	pub fn encode_packet(
 		&self,
 		con: &mut Connection,
-		mut packet: OutPacket,
+		packet: OutPacket,
 	) -> Result<Vec<(u32, u16, Bytes)>>
+	{
+		let mut res = Vec::new();
+		self.encode_packet_into(con, packet, &mut res)?;
+		Ok(res)
+	}
+
+	/// Like [`encode_packet`], but appends the udp packets to `res`.
+	///
+	/// Packets are encrypted in place. Fragments are written into one buffer,
+	/// which is shared by the returned `Bytes`.
+	///
+	/// [`encode_packet`]: #method.encode_packet
+	pub fn encode_packet_into(
+		&self,
+		con: &mut Connection,
+		mut packet: OutPacket,
+		res: &mut Vec<(u32, u16, Bytes)>,
+	) -> Result<()>
 	{
 		let p_type = packet.header().packet_type();
 		let type_i = p_type.to_usize().unwrap();
@@ -601,75 +771,243 @@ impl PacketCodecSender {
 		}
 
 		// Compress and split packet
-		let packet_id;
-		let packets = if p_type == PacketType::Command
-			|| p_type == PacketType::CommandLow
-		{
-			packet_id = None;
-			algs::compress_and_split(self.is_client, packet)
-		} else {
-			// Set the inner packet id for voice packets
-			if p_type == PacketType::Voice || p_type == PacketType::VoiceWhisper
+		if p_type == PacketType::Command || p_type == PacketType::CommandLow {
+			// Fragments are written into one buffer and encrypted in place
+			let mut buf = BytesMut::new();
+			if let Some(len) =
+				algs::compress_and_split_into(self.is_client, &packet, &mut buf)
 			{
-				(&mut packet.content_mut()[..2])
-					.write_u16::<NetworkEndian>(con.outgoing_p_ids[type_i].1)
-					.unwrap();
-			}
-
-			// Identify init packets by their number
-			if p_type == PacketType::Init {
-				if packet.direction() == Direction::S2C {
-					packet_id = Some(u16::from(packet.content()[0]));
-				} else {
-					packet_id = Some(u16::from(packet.content()[4]));
+				res.reserve((buf.len() + len - 1) / len);
+				while !buf.is_empty() {
+					let len = cmp::min(len, buf.len());
+					let mut data = buf.split_to(len);
+					let (gen, p_id) = Self::finish_packet(
+						con,
+						p_type,
+						packet.direction(),
+						&mut data,
+						None,
+						fake_encrypt,
+						should_encrypt,
+					)?;
+					res.push((gen, p_id, data.freeze()));
 				}
-			} else {
-				packet_id = None;
+				return Ok(());
 			}
+			let (gen, p_id) = Self::finish_packet(
+				con,
+				p_type,
+				packet.direction(),
+				packet.data_mut(),
+				None,
+				fake_encrypt,
+				should_encrypt,
+			)?;
+			res.push((gen, p_id, packet.into_vec().into()));
+			return Ok(());
+		}
 
-			vec![packet]
+		// Set the inner packet id for voice packets
+		if p_type == PacketType::Voice || p_type == PacketType::VoiceWhisper {
+			(&mut packet.content_mut()[..2])
+				.write_u16::<NetworkEndian>(con.outgoing_p_ids[type_i].1)
+				.unwrap();
+		}
+
+		// Identify init packets by their number
+		let init_id = if p_type != PacketType::Init {
+			None
+		} else if packet.direction() == Direction::S2C {
+			Some(u16::from(packet.content()[0]))
+		} else {
+			Some(u16::from(packet.content()[4]))
 		};
 
-		let packets = packets
-			.into_iter()
-			.map(|mut packet| -> Result<_> {
-				// Get packet id
-				let (gen, mut p_id) = if p_type == PacketType::Init {
-					(0, 0)
-				} else {
-					con.outgoing_p_ids[type_i]
-				};
-				let packet_id = if p_type != PacketType::Init {
-					packet.packet_id(p_id);
-					p_id
-				} else {
-					// Identify init packets by their number
-					packet_id.unwrap()
-				};
+		let (gen, p_id) = Self::finish_packet(
+			con,
+			p_type,
+			packet.direction(),
+			packet.data_mut(),
+			init_id,
+			fake_encrypt,
+			should_encrypt,
+		)?;
+		res.push((gen, p_id, packet.into_vec().into()));
+		Ok(())
+	}
 
-				// Encrypt if necessary
-				if fake_encrypt {
-					algs::encrypt_fake(&mut packet)?;
-				} else if should_encrypt {
-					// The params are set
-					let params = con.params.as_mut().unwrap();
-					algs::encrypt(
-						&mut packet,
-						gen,
-						&params.shared_iv,
-						&mut params.key_cache,
-					)?;
-				}
+	/// Set the packet id of a udp packet, encrypt it and update the counters
+	/// of the connection.
+	///
+	/// Init packets are identified by `init_id` instead of their packet id.
+	/// Returns the generation and packet id.
+	fn finish_packet(
+		con: &mut Connection,
+		p_type: PacketType,
+		dir: Direction,
+		data: &mut [u8],
+		init_id: Option<u16>,
+		fake_encrypt: bool,
+		should_encrypt: bool,
+	) -> Result<(u32, u16)>
+	{
+		let type_i = p_type.to_usize().unwrap();
+		// Get packet id
+		let (gen, mut p_id) = if p_type == PacketType::Init {
+			(0, 0)
+		} else {
+			con.outgoing_p_ids[type_i]
+		};
+		let packet_id = if let Some(id) = init_id {
+			id
+		} else {
+			NetworkEndian::write_u16(&mut data[8..10], p_id);
+			p_id
+		};
 
-				// Increment outgoing_p_ids
-				p_id = p_id.wrapping_add(1);
-				let new_gen = if p_id == 0 { gen.wrapping_add(1) } else { gen };
-				if p_type != PacketType::Init {
-					con.outgoing_p_ids[type_i] = (new_gen, p_id);
-				}
-				Ok((gen, packet_id, packet.into_vec().into()))
-			})
-			.collect::<Result<Vec<_>>>()?;
-		Ok(packets)
+		if p_type == PacketType::Ping {
+			con.ping_sent(p_id);
+		}
+
+		// Encrypt if necessary
+		if fake_encrypt {
+			algs::encrypt_fake_udp(data, dir)?;
+		} else if should_encrypt {
+			// The params are set
+			let params = con.params.as_mut().unwrap();
+			algs::encrypt_udp(
+				data,
+				dir,
+				gen,
+				&params.shared_iv,
+				&mut params.key_cache,
+			)?;
+		}
+
+		// Increment outgoing_p_ids
+		p_id = p_id.wrapping_add(1);
+		let new_gen = if p_id == 0 { gen.wrapping_add(1) } else { gen };
+		if p_type != PacketType::Init {
+			con.outgoing_p_ids[type_i] = (new_gen, p_id);
+		}
+		con.stats.add_sent(p_type, data.len());
+		Ok((gen, packet_id))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::connectionmanager::SocketConnectionManager;
+	use crate::handler_data::PacketHandler;
+
+	use futures::Stream;
+	use quicklz::CompressionLevel;
+
+	struct NoHandler;
+
+	impl PacketHandler<()> for NoHandler {
+		fn new_connection<S1, S2, S3, S4>(
+			&mut self,
+			_: &ConnectionValue<()>,
+			_: S1,
+			_: S2,
+			_: S3,
+			_: S4,
+		) where
+			S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+			S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+			S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+			S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+		{
+		}
+	}
+
+	type Receiver =
+		PacketCodecReceiver<SocketConnectionManager<NoHandler, ()>>;
+
+	/// The receive state of a command packet type.
+	#[derive(Default)]
+	struct Queues {
+		r_queue: [Vec<InPacket>; 2],
+		frag_queue: [Option<(InPacket, Vec<u8>)>; 2],
+		in_ids: [(u32, u16); 8],
+	}
+
+	impl Queues {
+		fn handle(
+			&mut self,
+			config: &ProtocolConfig,
+			id: u16,
+			flags: Flags,
+			content: &[u8],
+		) -> Result<Vec<InCommand>>
+		{
+			let logger = Logger::root(slog::Discard, o!());
+			let mut packet = OutPacket::new_with_dir(
+				Direction::S2C,
+				flags,
+				PacketType::Command,
+			);
+			packet.packet_id(id);
+			packet.data_mut().extend_from_slice(content);
+			let packet =
+				InPacket::new(packet.into_vec().into(), Direction::S2C);
+			Receiver::handle_command_packet(
+				&logger,
+				config,
+				&mut self.r_queue,
+				&mut self.frag_queue,
+				&mut self.in_ids,
+				packet,
+			)
+		}
+	}
+
+	fn assert_exceeded(res: Result<Vec<InCommand>>) {
+		if let Err(Error::MaxLengthExceeded(_)) = res {
+		} else {
+			panic!("Expected MaxLengthExceeded");
+		}
+	}
+
+	#[test]
+	fn max_queue_len() {
+		let config = ProtocolConfig { max_queue_len: 5, ..Default::default() };
+		let mut queues = Queues::default();
+		// Queue an out of order packet
+		let res = queues.handle(&config, 4, Flags::empty(), b"a").unwrap();
+		assert!(res.is_empty());
+		assert_exceeded(queues.handle(&config, 5, Flags::empty(), b"a"));
+	}
+
+	#[test]
+	fn max_fragments_length() {
+		let config =
+			ProtocolConfig { max_fragments_length: 10, ..Default::default() };
+		let mut queues = Queues::default();
+		let content = [b'a'; 8];
+		let res = queues.handle(&config, 0, Flags::FRAGMENTED, &content);
+		assert!(res.unwrap().is_empty());
+		let res = queues.handle(&config, 1, Flags::empty(), &content);
+		assert!(res.unwrap().is_empty());
+		assert_exceeded(queues.handle(&config, 2, Flags::empty(), &content));
+	}
+
+	#[test]
+	fn max_decompressed_size() {
+		let content = ::quicklz::compress(
+			b"clientupdate client_nickname=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+			CompressionLevel::Lvl1,
+		);
+		let config = ProtocolConfig::default();
+		let mut queues = Queues::default();
+		let res = queues.handle(&config, 0, Flags::COMPRESSED, &content);
+		assert_eq!(res.unwrap().len(), 1);
+
+		let config =
+			ProtocolConfig { max_decompressed_size: 20, ..Default::default() };
+		let mut queues = Queues::default();
+		assert_exceeded(queues.handle(&config, 0, Flags::COMPRESSED, &content));
 	}
 }
@@ -0,0 +1,445 @@
+//! Write packets into [pcapng] files, which can be opened with e.g. Wireshark.
+//!
+//! Udp packets are written with a synthesized ip and udp header, so they can
+//! be dissected like a normal capture. Commands are written as they are seen
+//! by the application, that means decrypted, decompressed and reassembled.
+//!
+//! [pcapng]: https://github.com/pcapng/pcapng
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, NetworkEndian, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use slog::{error, Logger};
+use tsproto_packets::packets::{InCommand, InPacket, OutPacket};
+
+use crate::connection::Connection;
+use crate::connectionmanager::ConnectionManager;
+use crate::handler_data::{
+	Data, InCommandObserver, InUdpPacketObserver, OutPacketObserver,
+	OutUdpPacketObserver,
+};
+use crate::Result;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPTION_END: u16 = 0;
+const OPTION_COMMENT: u16 = 1;
+
+/// Raw ip packets, the version is taken from the ip header.
+pub const LINKTYPE_RAW: u16 = 101;
+/// Reserved for private use, we write commands with this link type.
+pub const LINKTYPE_USER0: u16 = 147;
+
+/// The observer key which is used for packet captures.
+const OBSERVER_KEY: &str = "pcap";
+
+/// Writes a pcapng file with a single interface.
+pub struct PcapWriter<W: Write> {
+	writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+	/// Write the section header and the interface description.
+	///
+	/// All packets in the file are of the given link type.
+	pub fn new(writer: W, link_type: u16) -> Result<Self> {
+		let mut res = Self { writer };
+
+		let mut body = Vec::new();
+		body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+		// Version 1.0
+		body.write_u16::<LittleEndian>(1)?;
+		body.write_u16::<LittleEndian>(0)?;
+		// Unknown section length
+		body.write_i64::<LittleEndian>(-1)?;
+		res.write_block(BLOCK_SECTION_HEADER, &body)?;
+
+		let mut body = Vec::new();
+		body.write_u16::<LittleEndian>(link_type)?;
+		// Reserved
+		body.write_u16::<LittleEndian>(0)?;
+		// No snap length
+		body.write_u32::<LittleEndian>(0)?;
+		res.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)?;
+
+		res.writer.flush()?;
+		Ok(res)
+	}
+
+	/// Write a packet, optionally with a comment.
+	///
+	/// The timestamp is stored with a resolution of microseconds.
+	pub fn write_packet(
+		&mut self,
+		time: DateTime<Utc>,
+		data: &[u8],
+		comment: Option<&str>,
+	) -> Result<()>
+	{
+		let micros = time.timestamp() as u64 * 1_000_000
+			+ u64::from(time.timestamp_subsec_micros());
+
+		let mut body = Vec::with_capacity(32 + data.len());
+		// Interface id
+		body.write_u32::<LittleEndian>(0)?;
+		body.write_u32::<LittleEndian>((micros >> 32) as u32)?;
+		body.write_u32::<LittleEndian>(micros as u32)?;
+		// Captured and original length
+		body.write_u32::<LittleEndian>(data.len() as u32)?;
+		body.write_u32::<LittleEndian>(data.len() as u32)?;
+		body.extend_from_slice(data);
+		pad(&mut body);
+
+		if let Some(comment) = comment {
+			body.write_u16::<LittleEndian>(OPTION_COMMENT)?;
+			body.write_u16::<LittleEndian>(comment.len() as u16)?;
+			body.extend_from_slice(comment.as_bytes());
+			pad(&mut body);
+			body.write_u16::<LittleEndian>(OPTION_END)?;
+			body.write_u16::<LittleEndian>(0)?;
+		}
+		self.write_block(BLOCK_ENHANCED_PACKET, &body)?;
+		// Flush, so the file is usable if the process crashes
+		self.writer.flush()?;
+		Ok(())
+	}
+
+	fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<()> {
+		// Block type and two times the length
+		let len = body.len() as u32 + 12;
+		self.writer.write_u32::<LittleEndian>(block_type)?;
+		self.writer.write_u32::<LittleEndian>(len)?;
+		self.writer.write_all(body)?;
+		self.writer.write_u32::<LittleEndian>(len)?;
+		Ok(())
+	}
+}
+
+/// Pad to a multiple of 4 bytes.
+fn pad(data: &mut Vec<u8>) {
+	let len = (data.len() + 3) / 4 * 4;
+	data.resize(len, 0);
+}
+
+/// Add up 16 bit words for the internet checksum.
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+	for c in data.chunks(2) {
+		let word = if c.len() == 2 {
+			u16::from(c[0]) << 8 | u16::from(c[1])
+		} else {
+			u16::from(c[0]) << 8
+		};
+		sum += u32::from(word);
+	}
+	sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+	while sum > 0xffff {
+		sum = (sum & 0xffff) + (sum >> 16);
+	}
+	!(sum as u16)
+}
+
+/// Wrap a udp payload into an ip and udp header.
+///
+/// If only one of the addresses is an IPv6 address, the other one is
+/// converted into an IPv4-mapped IPv6 address.
+pub fn ip_udp_packet(
+	src: SocketAddr,
+	dst: SocketAddr,
+	payload: &[u8],
+) -> Vec<u8>
+{
+	let udp_len = payload.len() + 8;
+	let mut udp = Vec::with_capacity(udp_len);
+	udp.write_u16::<NetworkEndian>(src.port()).unwrap();
+	udp.write_u16::<NetworkEndian>(dst.port()).unwrap();
+	udp.write_u16::<NetworkEndian>(udp_len as u16).unwrap();
+	// Checksum, filled in later
+	udp.write_u16::<NetworkEndian>(0).unwrap();
+	udp.extend_from_slice(payload);
+
+	let mut res = Vec::with_capacity(40 + udp_len);
+	let pseudo_sum;
+	match (src.ip(), dst.ip()) {
+		(IpAddr::V4(src), IpAddr::V4(dst)) => {
+			res.push(0x45);
+			// Type of service
+			res.push(0);
+			res.write_u16::<NetworkEndian>(20 + udp_len as u16).unwrap();
+			// Identification
+			res.write_u16::<NetworkEndian>(0).unwrap();
+			// Don't fragment
+			res.write_u16::<NetworkEndian>(0x4000).unwrap();
+			// Time to live
+			res.push(64);
+			// Udp
+			res.push(17);
+			// Header checksum, filled in later
+			res.write_u16::<NetworkEndian>(0).unwrap();
+			res.extend_from_slice(&src.octets());
+			res.extend_from_slice(&dst.octets());
+			let sum = checksum_finish(checksum_add(0, &res));
+			(&mut res[10..12]).write_u16::<NetworkEndian>(sum).unwrap();
+
+			pseudo_sum = checksum_add(
+				checksum_add(checksum_add(0, &src.octets()), &dst.octets()),
+				&[0, 17, (udp_len >> 8) as u8, udp_len as u8],
+			);
+		}
+		(src, dst) => {
+			let to_v6 = |ip: IpAddr| match ip {
+				IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+				IpAddr::V6(ip) => ip,
+			};
+			let (src, dst) = (to_v6(src), to_v6(dst));
+			res.extend_from_slice(&[0x60, 0, 0, 0]);
+			res.write_u16::<NetworkEndian>(udp_len as u16).unwrap();
+			// Next header is udp
+			res.push(17);
+			// Hop limit
+			res.push(64);
+			res.extend_from_slice(&src.octets());
+			res.extend_from_slice(&dst.octets());
+
+			let mut len = [0; 4];
+			(&mut len[..])
+				.write_u32::<NetworkEndian>(udp_len as u32)
+				.unwrap();
+			pseudo_sum = checksum_add(
+				checksum_add(
+					checksum_add(checksum_add(0, &src.octets()), &dst.octets()),
+					&len,
+				),
+				&[0, 0, 0, 17],
+			);
+		}
+	}
+
+	let mut sum = checksum_finish(checksum_add(pseudo_sum, &udp));
+	if sum == 0 {
+		// Zero means no checksum
+		sum = 0xffff;
+	}
+	(&mut udp[6..8]).write_u16::<NetworkEndian>(sum).unwrap();
+	res.extend_from_slice(&udp);
+	res
+}
+
+/// A pcapng writer which can be shared by multiple `Data` objects.
+pub type SharedWriter = Arc<Mutex<PcapWriter<Box<Write + Send>>>>;
+
+/// Create a shared writer for udp packets.
+pub fn new_udp_writer<W: Write + Send + 'static>(
+	writer: W,
+) -> Result<SharedWriter> {
+	let writer: Box<Write + Send> = Box::new(writer);
+	Ok(Arc::new(Mutex::new(PcapWriter::new(writer, LINKTYPE_RAW)?)))
+}
+
+/// Create a shared writer for commands.
+pub fn new_command_writer<W: Write + Send + 'static>(
+	writer: W,
+) -> Result<SharedWriter> {
+	let writer: Box<Write + Send> = Box::new(writer);
+	Ok(Arc::new(Mutex::new(PcapWriter::new(writer, LINKTYPE_USER0)?)))
+}
+
+struct UdpPcapObserver {
+	writer: SharedWriter,
+	local_addr: SocketAddr,
+	logger: Logger,
+}
+
+impl UdpPcapObserver {
+	fn write(&self, src: SocketAddr, dst: SocketAddr, udp_packet: &[u8]) {
+		let packet = ip_udp_packet(src, dst, udp_packet);
+		let res = self.writer.lock().write_packet(Utc::now(), &packet, None);
+		if let Err(e) = res {
+			error!(self.logger, "Failed to write packet capture";
+				"error" => ?e);
+		}
+	}
+}
+
+impl InUdpPacketObserver for UdpPcapObserver {
+	fn observe(&self, addr: SocketAddr, udp_packet: &InPacket) {
+		self.write(addr, self.local_addr, udp_packet.udp_data());
+	}
+}
+
+impl OutUdpPacketObserver for UdpPcapObserver {
+	fn observe(&self, addr: SocketAddr, udp_packet: &[u8]) {
+		self.write(self.local_addr, addr, udp_packet);
+	}
+}
+
+struct CommandPcapObserver {
+	writer: SharedWriter,
+	logger: Logger,
+}
+
+impl CommandPcapObserver {
+	fn write(&self, con: &Connection, incoming: bool, content: &[u8]) {
+		// Store the direction and address in the comment
+		let comment =
+			format!("{} {}", if incoming { "IN" } else { "OUT" }, con.address);
+		let res = self.writer.lock().write_packet(
+			Utc::now(),
+			content,
+			Some(&comment),
+		);
+		if let Err(e) = res {
+			error!(self.logger, "Failed to write packet capture";
+				"error" => ?e);
+		}
+	}
+}
+
+impl<T: Send> InCommandObserver<T> for CommandPcapObserver {
+	fn observe(&self, con: &mut (T, Connection), cmd: &InCommand) {
+		self.write(&con.1, true, cmd.content());
+	}
+}
+
+impl<T: Send> OutPacketObserver<T> for CommandPcapObserver {
+	fn observe(&self, con: &mut (T, Connection), packet: &mut OutPacket) {
+		if packet.header().packet_type().is_command() {
+			self.write(&con.1, false, packet.content());
+		}
+	}
+}
+
+/// Write all incoming and outgoing udp packets into a pcapng file.
+pub fn add_udp_pcap_writer<
+	CM: ConnectionManager + 'static,
+	W: Write + Send + 'static,
+>(
+	data: &mut Data<CM>,
+	writer: W,
+) -> Result<()>
+{
+	add_shared_udp_pcap_writer(data, new_udp_writer(writer)?);
+	Ok(())
+}
+
+/// Like [`add_udp_pcap_writer`], but write into a writer which was created
+/// with [`new_udp_writer`].
+///
+/// Multiple `Data` objects can write into the same file this way.
+///
+/// [`add_udp_pcap_writer`]: fn.add_udp_pcap_writer.html
+/// [`new_udp_writer`]: fn.new_udp_writer.html
+pub fn add_shared_udp_pcap_writer<CM: ConnectionManager + 'static>(
+	data: &mut Data<CM>,
+	writer: SharedWriter,
+) {
+	data.add_in_udp_packet_observer(
+		OBSERVER_KEY.into(),
+		Box::new(UdpPcapObserver {
+			writer: writer.clone(),
+			local_addr: data.local_addr,
+			logger: data.logger.clone(),
+		}),
+	);
+	data.add_out_udp_packet_observer(
+		OBSERVER_KEY.into(),
+		Box::new(UdpPcapObserver {
+			writer,
+			local_addr: data.local_addr,
+			logger: data.logger.clone(),
+		}),
+	);
+}
+
+/// Write the content of all incoming and outgoing commands into a pcapng
+/// file.
+///
+/// The commands are written after they are decrypted, decompressed and
+/// reassembled. Every packet has a comment with the direction and the address
+/// of the other side.
+pub fn add_command_pcap_writer<
+	CM: ConnectionManager + 'static,
+	W: Write + Send + 'static,
+>(
+	data: &mut Data<CM>,
+	writer: W,
+) -> Result<()>
+{
+	add_shared_command_pcap_writer(data, new_command_writer(writer)?);
+	Ok(())
+}
+
+/// Like [`add_command_pcap_writer`], but write into a writer which was
+/// created with [`new_command_writer`].
+///
+/// [`add_command_pcap_writer`]: fn.add_command_pcap_writer.html
+/// [`new_command_writer`]: fn.new_command_writer.html
+pub fn add_shared_command_pcap_writer<CM: ConnectionManager + 'static>(
+	data: &mut Data<CM>,
+	writer: SharedWriter,
+) {
+	data.add_in_command_observer(
+		OBSERVER_KEY.into(),
+		Box::new(CommandPcapObserver {
+			writer: writer.clone(),
+			logger: data.logger.clone(),
+		}),
+	);
+	data.add_out_packet_observer(
+		OBSERVER_KEY.into(),
+		Box::new(CommandPcapObserver {
+			writer,
+			logger: data.logger.clone(),
+		}),
+	);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ipv4_checksum() {
+		let packet = ip_udp_packet(
+			"127.0.0.1:1234".parse().unwrap(),
+			"127.0.0.1:9987".parse().unwrap(),
+			b"TS3INIT1",
+		);
+		assert_eq!(packet.len(), 20 + 8 + 8);
+		// A correct checksum sums up to zero
+		assert_eq!(checksum_finish(checksum_add(0, &packet[..20])), 0);
+	}
+
+	#[test]
+	fn write_blocks() {
+		let mut buf = Vec::new();
+		{
+			let mut writer = PcapWriter::new(&mut buf, LINKTYPE_USER0).unwrap();
+			writer
+				.write_packet(Utc::now(), b"clientinit", Some("OUT"))
+				.unwrap();
+		}
+		// Section header, interface description and packet
+		let mut pos = 0;
+		let mut blocks = Vec::new();
+		while pos < buf.len() {
+			let len = u32::from(buf[pos + 4])
+				| u32::from(buf[pos + 5]) << 8
+				| u32::from(buf[pos + 6]) << 16
+				| u32::from(buf[pos + 7]) << 24;
+			assert_eq!(len % 4, 0);
+			blocks.push(buf[pos]);
+			pos += len as usize;
+		}
+		assert_eq!(pos, buf.len());
+		assert_eq!(blocks, vec![0x0A, 1, 6]);
+	}
+}
@@ -1,26 +1,29 @@
 use std::cmp::{Ord, Ordering};
-use std::collections::{binary_heap, BinaryHeap};
+use std::collections::{binary_heap, BinaryHeap, HashMap, VecDeque};
 use std::convert::From;
 use std::hash::{Hash, Hasher};
 use std::mem;
-use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
-use std::sync::Weak;
+use std::sync::{Arc, Weak};
 use std::time::Instant;
 
 use bytes::Bytes;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use failure::format_err;
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
 use futures::sync::mpsc;
-use futures::task::{self, Task};
+use futures::task::{self, AtomicTask, Task};
 use futures::{self, Async, Future, Sink};
 use parking_lot::Mutex;
-use slog::{info, warn, Logger};
+use slog::{error, info, warn, Logger};
 use tokio::timer::Delay;
 use tsproto_packets::packets::*;
 
+use crate::clock;
 use crate::connectionmanager::{ConnectionManager, Resender, ResenderEvent};
 use crate::handler_data::{ConnectionValue, ConnectionValueWeak, Data};
+use crate::send_queue::SendQueueSink;
+use crate::timer_wheel::TimerWheel;
 use crate::{Error, LockedHashMap};
 
 /// Identify a packet with type, generation id and packet id.
@@ -36,6 +39,17 @@ struct SendRecord {
 	pub last: DateTime<Utc>,
 	/// How often the packet was already resent.
 	pub tries: usize,
+	/// How often the packet was sent in total.
+	///
+	/// In contrast to `tries`, this is not reset when the state changes.
+	pub transmissions: usize,
+	/// The retransmission timeout of this packet, it doubles with every
+	/// retransmission.
+	///
+	/// This is only used by [`ResendAlgorithm::Rfc6298`].
+	///
+	/// [`ResendAlgorithm::Rfc6298`]: enum.ResendAlgorithm.html
+	pub rto: Duration,
 	pub id: PacketId,
 	/// The packet of this record.
 	pub packet: Bytes,
@@ -69,7 +83,9 @@ impl Ord for SendRecord {
 			Ordering::Less
 		} else {
 			// The smallest time is the most important time
-			self.last.cmp(&other.last).reverse().then_with(||
+			let deadline = self.last + self.rto;
+			let other_deadline = other.last + other.rto;
+			deadline.cmp(&other_deadline).reverse().then_with(||
 				// Else, the lower packet id is more important
 				self.id.1.cmp(&other.id.1).reverse().then_with(||
 					self.id.2.cmp(&other.id.2).reverse()))
@@ -95,6 +111,17 @@ pub struct DefaultResender {
 	srtt: Duration,
 	/// Deviation of the srtt.
 	srtt_dev: Duration,
+	/// If `srtt` was initialized from a measured round trip time.
+	rtt_measured: bool,
+
+	/// The number of packets which can be sent without waiting for an ack.
+	send_window: usize,
+	/// The window grows exponentially below this threshold and linearly above.
+	slow_start_threshold: usize,
+	/// The number of acks since the window grew the last time.
+	window_acks: usize,
+	/// Packets which do not fit into the send window.
+	waiting: VecDeque<SendRecord>,
 
 	/// The task of the sink, which is used to put new packets into the queue.
 	///
@@ -152,15 +179,23 @@ impl DefaultResender {
 	pub fn new(config: ResendConfig, logger: Logger) -> Self {
 		let srtt = config.srtt;
 		let srtt_dev = config.srtt_dev;
+		let send_window = config.initial_send_window.max(1);
+		let slow_start_threshold = config.max_send_queue_len;
 		Self {
 			logger,
 			state: ResendStates::Connecting {
 				to_send: Default::default(),
-				start_time: Utc::now(),
+				start_time: clock::now(),
 			},
 			config,
 			srtt,
 			srtt_dev,
+			rtt_measured: false,
+
+			send_window,
+			slow_start_threshold,
+			window_acks: 0,
+			waiting: VecDeque::new(),
 
 			resender_task: Vec::new(),
 			resender_future_task: None,
@@ -178,6 +213,80 @@ impl DefaultResender {
 		self.srtt = self.srtt * 7 / 8 + rtt / 8;
 	}
 
+	/// Add a round trip time sample according to RFC 6298.
+	fn update_rto(&mut self, rtt: Duration) {
+		if self.rtt_measured {
+			self.update_srtt(rtt);
+		} else {
+			self.srtt = rtt;
+			self.srtt_dev = rtt / 2;
+			self.rtt_measured = true;
+		}
+	}
+
+	/// The current retransmission timeout, computed from the smoothed rtt.
+	pub fn retransmission_timeout(&self) -> Duration {
+		let rto = self.srtt + self.srtt_dev * 4;
+		match self.config.algorithm {
+			ResendAlgorithm::Legacy => rto,
+			ResendAlgorithm::Rfc6298 => {
+				rto.max(self.config.min_rto).min(self.config.max_rto)
+			}
+		}
+	}
+
+	/// The number of packets which can be sent without waiting for an ack.
+	pub fn send_window(&self) -> usize {
+		match self.config.algorithm {
+			ResendAlgorithm::Legacy => self.config.max_send_queue_len,
+			ResendAlgorithm::Rfc6298 => self.send_window,
+		}
+	}
+
+	/// Grow the send window for an acknowledged packet.
+	fn grow_window(&mut self) {
+		if self.send_window < self.slow_start_threshold {
+			self.send_window += 1;
+		} else {
+			self.window_acks += 1;
+			if self.window_acks >= self.send_window {
+				self.send_window += 1;
+				self.window_acks = 0;
+			}
+		}
+		self.send_window = self.send_window.min(self.config.max_send_queue_len);
+	}
+
+	/// Shrink the send window because a packet was lost.
+	fn shrink_window(&mut self, first_retransmission: bool) {
+		// The threshold is not reduced again if the same packet is lost
+		// repeatedly (RFC 5681).
+		if first_retransmission {
+			self.slow_start_threshold = (self.state.len() / 2).max(2);
+		}
+		self.send_window = 1;
+		self.window_acks = 0;
+	}
+
+	/// Move waiting packets into the send queue while they fit into the
+	/// send window.
+	fn fill_window(&mut self) {
+		let mut moved = false;
+		while self.state.len() < self.send_window() {
+			if let Some(rec) = self.waiting.pop_front() {
+				self.state.push(rec);
+				moved = true;
+			} else {
+				break;
+			}
+		}
+		if moved {
+			if let Some(ref task) = self.resender_future_task {
+				task.notify();
+			}
+		}
+	}
+
 	/// Replaces the current state by a new state and return the old state.
 	fn set_state(&mut self, state: ResendStates) -> ResendStates {
 		info!(self.logger, "Changed state"; "old" => self.state.get_name(),
@@ -246,12 +355,29 @@ impl Resender for DefaultResender {
 		};
 
 		if let Some(rec) = rec {
-			// Update srtt only if the packet was not resent
-			if rec.tries == 1 {
-				let now = Utc::now();
-				let diff =
-					now.naive_utc().signed_duration_since(rec.sent.naive_utc());
-				self.update_srtt(diff);
+			let now = clock::now();
+			match self.config.algorithm {
+				ResendAlgorithm::Legacy => {
+					// Update srtt only if the packet was not resent
+					if rec.tries == 1 {
+						let diff = now
+							.naive_utc()
+							.signed_duration_since(rec.sent.naive_utc());
+						self.update_srtt(diff);
+					}
+				}
+				ResendAlgorithm::Rfc6298 => {
+					// Karn's algorithm: The ack of a retransmitted packet
+					// could belong to any of the transmissions.
+					// A rebound packet has no valid send time.
+					if rec.transmissions == 1 && rec.last >= rec.sent {
+						let diff = now
+							.naive_utc()
+							.signed_duration_since(rec.last.naive_utc());
+						self.update_rto(diff);
+					}
+					self.grow_window();
+				}
 			}
 		}
 
@@ -266,9 +392,11 @@ impl Resender for DefaultResender {
 				}
 				let to_send = mem::replace(to_send, Vec::new()).into();
 
-				// Reset srtt, this will reset to stalling mode after 3 packets
-				// are lost again.
-				self.srtt = self.config.normal_timeout / 4;
+				if self.config.algorithm == ResendAlgorithm::Legacy {
+					// Reset srtt, this will reset to stalling mode after 3
+					// packets are lost again.
+					self.srtt = self.config.normal_timeout / 4;
+				}
 
 				Some(ResendStates::Normal { to_send })
 			}
@@ -282,6 +410,8 @@ impl Resender for DefaultResender {
 			}
 		}
 
+		self.fill_window();
+
 		// Notify, that a packet was removed from the queue
 		for t in self.resender_task.drain(..) {
 			t.notify()
@@ -299,13 +429,7 @@ impl Resender for DefaultResender {
 	}
 
 	fn is_empty(&self) -> bool {
-		match self.state {
-			ResendStates::Connecting { ref to_send, .. }
-			| ResendStates::Disconnecting { ref to_send, .. }
-			| ResendStates::Normal { ref to_send, .. } => to_send.is_empty(),
-			ResendStates::Stalling { ref to_send, .. }
-			| ResendStates::Dead { ref to_send, .. } => to_send.is_empty(),
-		}
+		self.state.len() == 0 && self.waiting.is_empty()
 	}
 
 	fn handle_event(&mut self, event: ResenderEvent) {
@@ -331,11 +455,11 @@ impl Resender for DefaultResender {
 		let next_state = match event {
 			ResenderEvent::Connecting => ResendStates::Connecting {
 				to_send,
-				start_time: Utc::now(),
+				start_time: clock::now(),
 			},
 			ResenderEvent::Disconnecting => ResendStates::Disconnecting {
 				to_send,
-				start_time: Utc::now(),
+				start_time: clock::now(),
 			},
 			ResenderEvent::Connected => ResendStates::Normal { to_send },
 		};
@@ -357,7 +481,7 @@ impl Resender for DefaultResender {
 				// Switch to Stalling if the connection was dead
 				Some(ResendStates::Stalling {
 					to_send,
-					start_time: Utc::now(),
+					start_time: clock::now(),
 				})
 			}
 			// We will switch to Normal from stalling after we received an ack
@@ -380,6 +504,36 @@ impl Resender for DefaultResender {
 			false
 		}
 	}
+
+	fn rebound(&mut self) {
+		// Send all packets again as soon as possible. The state and its
+		// timeouts are kept, so a connection over a broken network still dies.
+		let due = Utc.timestamp(0, 0);
+		let make_due = |rec: &mut SendRecord| {
+			if rec.tries != 0 {
+				rec.last = due;
+			}
+		};
+		match &mut self.state {
+			ResendStates::Stalling { to_send, .. }
+			| ResendStates::Dead { to_send, .. } => {
+				to_send.iter_mut().for_each(make_due);
+			}
+			ResendStates::Connecting { to_send, .. }
+			| ResendStates::Normal { to_send }
+			| ResendStates::Disconnecting { to_send, .. } => {
+				// The order depends on the send time
+				let mut v = mem::replace(to_send, BinaryHeap::new()).into_vec();
+				v.iter_mut().for_each(make_due);
+				*to_send = v.into();
+			}
+		}
+
+		// Notify the resender future that packets should be sent
+		if let Some(ref task) = self.resender_future_task {
+			task.notify();
+		}
+	}
 }
 
 impl Sink for DefaultResender {
@@ -392,59 +546,30 @@ impl Sink for DefaultResender {
 		(p_type, p_gen, p_id, packet): Self::SinkItem,
 	) -> futures::StartSend<Self::SinkItem, Self::SinkError>
 	{
-		let rec = SendRecord {
-			sent: Utc::now(),
-			last: Utc::now(),
-			tries: 0,
-			id: PacketId(p_type, p_gen, p_id),
-			packet,
-		};
-
 		// Put the packet into the queue if there is space left
-		// otherwise, put it into rec_res.
-		let mut rec_res = None;
-		match &mut self.state {
-			ResendStates::Connecting {
-				to_send,
-				start_time,
-			}
-			| ResendStates::Disconnecting {
-				to_send,
-				start_time,
-			} => {
-				if to_send.len() >= self.config.max_send_queue_len {
-					rec_res = Some(rec);
-				} else {
-					to_send.push(rec);
-					// Update start time
-					*start_time = Utc::now();
-				}
-			}
-			ResendStates::Stalling { to_send, .. }
-			| ResendStates::Dead { to_send, .. } => {
-				if to_send.len() >= self.config.max_send_queue_len {
-					rec_res = Some(rec);
-				} else {
-					to_send.push(rec);
-				}
-			}
-			ResendStates::Normal { to_send } => {
-				if to_send.len() >= self.config.max_send_queue_len {
-					rec_res = Some(rec);
-				} else {
-					to_send.push(rec);
-				}
-			}
-		}
-
-		if let Some(rec) = rec_res {
+		if self.state.len() + self.waiting.len()
+			>= self.config.max_send_queue_len
+		{
 			// Set the task, so we get woken up if a place in the queue gets
 			// free.
 			self.resender_task.push(task::current());
-			Ok(futures::AsyncSink::NotReady((
-				rec.id.0, rec.id.1, rec.id.2, rec.packet,
-			)))
+			Ok(futures::AsyncSink::NotReady((p_type, p_gen, p_id, packet)))
 		} else {
+			let rec = SendRecord {
+				sent: clock::now(),
+				last: clock::now(),
+				tries: 0,
+				transmissions: 0,
+				rto: Duration::zero(),
+				id: PacketId(p_type, p_gen, p_id),
+				packet,
+			};
+			if self.state.len() >= self.send_window() {
+				self.waiting.push_back(rec);
+			} else {
+				self.state.push(rec);
+			}
+
 			// Notify the resender future that a new packet is available
 			if let Some(ref task) = self.resender_future_task {
 				task.notify();
@@ -494,6 +619,38 @@ impl<'a, T: Ord + 'a> From<binary_heap::PeekMut<'a, T>> for PeekMut<'a, T> {
 }
 
 impl ResendStates {
+	/// The number of packets in the queue.
+	fn len(&self) -> usize {
+		match *self {
+			ResendStates::Connecting { ref to_send, .. }
+			| ResendStates::Disconnecting { ref to_send, .. }
+			| ResendStates::Normal { ref to_send, .. } => to_send.len(),
+			ResendStates::Stalling { ref to_send, .. }
+			| ResendStates::Dead { ref to_send, .. } => to_send.len(),
+		}
+	}
+
+	/// Add a packet to the queue.
+	fn push(&mut self, rec: SendRecord) {
+		match self {
+			ResendStates::Connecting {
+				to_send,
+				start_time,
+			}
+			| ResendStates::Disconnecting {
+				to_send,
+				start_time,
+			} => {
+				to_send.push(rec);
+				// Update start time
+				*start_time = clock::now();
+			}
+			ResendStates::Stalling { to_send, .. }
+			| ResendStates::Dead { to_send, .. } => to_send.push(rec),
+			ResendStates::Normal { to_send } => to_send.push(rec),
+		}
+	}
+
 	/// Returns the next record which should be sent, if there is one.
 	fn peek_mut_next_record(&mut self) -> Option<PeekMut<SendRecord>> {
 		match *self {
@@ -533,6 +690,49 @@ impl ResendStates {
 	}
 }
 
+/// The algorithm which computes retransmission timeouts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResendAlgorithm {
+	/// The original algorithm of tsproto.
+	///
+	/// A lost packet doubles the smoothed rtt and packets are resent in
+	/// fixed intervals while stalling. All packets up to
+	/// `max_send_queue_len` are sent without waiting for acks.
+	Legacy,
+	/// Compute the retransmission timeout according to RFC 6298.
+	///
+	/// The rtt is only sampled from packets which were not retransmitted
+	/// (Karn's algorithm). The timeout of a packet doubles with every
+	/// retransmission up to `max_rto`, in states with a fixed resend interval
+	/// the interval is used as lower bound. The number of unacknowledged
+	/// packets is limited by a send window, which grows with acks and shrinks
+	/// when packets are lost.
+	Rfc6298,
+}
+
+/// The next retransmission timeout of a packet for
+/// [`ResendAlgorithm::Rfc6298`].
+///
+/// `rto` is used for the first transmission, afterwards the timeout doubles
+/// up to `max_rto`.
+///
+/// [`ResendAlgorithm::Rfc6298`]: enum.ResendAlgorithm.html
+fn rfc6298_backoff(
+	last_rto: Duration,
+	tries: usize,
+	rto: Duration,
+	packet_interval: Option<Duration>,
+	max_rto: Duration,
+) -> Duration
+{
+	if tries == 0 {
+		return rto;
+	}
+	// Do not resend faster than the interval of the current state
+	let last_rto = packet_interval.map_or(last_rto, |i| last_rto.max(i));
+	(last_rto * 2).min(max_rto)
+}
+
 /// Configure the length of timeouts.
 #[derive(Clone, Debug)]
 pub struct ResendConfig {
@@ -564,6 +764,32 @@ pub struct ResendConfig {
 
 	/// The maximum number of not acknowledged packets which are stored.
 	pub max_send_queue_len: usize,
+
+	/// The algorithm which computes retransmission timeouts.
+	///
+	/// # Default
+	/// `ResendAlgorithm::Legacy`
+	pub algorithm: ResendAlgorithm,
+	/// The lower bound for the retransmission timeout.
+	///
+	/// This is only used by [`ResendAlgorithm::Rfc6298`].
+	///
+	/// [`ResendAlgorithm::Rfc6298`]: enum.ResendAlgorithm.html
+	pub min_rto: Duration,
+	/// The upper bound for the retransmission timeout and the exponential
+	/// backoff.
+	///
+	/// This is only used by [`ResendAlgorithm::Rfc6298`].
+	///
+	/// [`ResendAlgorithm::Rfc6298`]: enum.ResendAlgorithm.html
+	pub max_rto: Duration,
+	/// The number of packets which can be sent before the first ack is
+	/// received.
+	///
+	/// This is only used by [`ResendAlgorithm::Rfc6298`].
+	///
+	/// [`ResendAlgorithm::Rfc6298`]: enum.ResendAlgorithm.html
+	pub initial_send_window: usize,
 }
 
 impl Default for ResendConfig {
@@ -582,12 +808,23 @@ impl Default for ResendConfig {
 			srtt_dev: Duration::milliseconds(0),
 
 			max_send_queue_len: 50,
+
+			algorithm: ResendAlgorithm::Legacy,
+			min_rto: Duration::seconds(1),
+			max_rto: Duration::seconds(60),
+			initial_send_window: 4,
 		}
 	}
 }
 
 /// This future is running in parallel to the rest and is responsible for
 /// sending all command packets.
+///
+/// The future does not start timers on its own, it has to be driven by a
+/// [`ResendScheduler`], which polls it again at [`next_timeout`].
+///
+/// [`ResendScheduler`]: struct.ResendScheduler.html
+/// [`next_timeout`]: #method.next_timeout
 pub struct ResendFuture<CM: ConnectionManager + 'static> {
 	data: Weak<Mutex<Data<CM>>>,
 	is_client: bool,
@@ -595,13 +832,9 @@ pub struct ResendFuture<CM: ConnectionManager + 'static> {
 	connections: LockedHashMap<CM::Key, ConnectionValue<CM::AssociatedData>>,
 	connection_key: CM::Key,
 	connection: ConnectionValueWeak<CM::AssociatedData>,
-	sink: mpsc::Sender<(SocketAddr, Bytes)>,
-	/// The future to wake us up when the next packet should be resent.
-	///
-	/// This is only used while stalling.
-	timeout: Delay,
-	/// The future to wake us up when the current state times out.
-	state_timeout: Delay,
+	sink: SendQueueSink,
+	/// When the next packet should be resent or the current state times out.
+	next_timeout: Option<Instant>,
 	/// If we are sending and should poll the sink.
 	is_sending: bool,
 }
@@ -625,11 +858,20 @@ impl<CM: ConnectionManager + 'static> ResendFuture<CM> {
 			connection_key,
 			connection,
 			sink: data.udp_packet_sink.clone(),
-			timeout: Delay::new(Instant::now()),
-			state_timeout: Delay::new(Instant::now()),
+			next_timeout: None,
 			is_sending: false,
 		}
 	}
+
+	/// The time when this future wants to be polled again.
+	pub fn next_timeout(&self) -> Option<Instant> { self.next_timeout }
+
+	/// Wake up the future at `at` at the latest.
+	fn schedule(&mut self, at: Instant) {
+		if self.next_timeout.map(|t| at < t).unwrap_or(true) {
+			self.next_timeout = Some(at);
+		}
+	}
 }
 
 impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
@@ -637,6 +879,7 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 	type Error = Error;
 
 	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		self.next_timeout = None;
 		if !self.connections.read().contains_key(&self.connection_key) {
 			// Quit if the connection does not exist anymore
 			return Ok(futures::Async::Ready(()));
@@ -666,7 +909,7 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 		// Set task
 		con.resender.resender_future_task = Some(task::current());
 
-		let now = Utc::now();
+		let now = clock::now();
 		let now_naive = now.naive_utc();
 
 		// Check if we are over time in the current state
@@ -690,13 +933,9 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 							+ resender.config.connecting_timeout)
 							.naive_utc()
 							.signed_duration_since(now_naive);
-						let next = Instant::now() + dur.to_std().unwrap();
-						self.state_timeout.reset(next);
-						if let futures::Async::Ready(()) =
-							self.state_timeout.poll()?
-						{
-							task::current().notify();
-						}
+						self.schedule(
+							tokio::clock::now() + dur.to_std().unwrap(),
+						);
 						StateChange::Nothing
 					}
 				}
@@ -710,7 +949,7 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 					{
 						StateChange::NewState(ResendStates::Dead {
 							to_send: mem::replace(to_send, Vec::new()),
-							start_time: Utc::now(),
+							start_time: clock::now(),
 						})
 					} else {
 						// Schedule timeout
@@ -718,13 +957,9 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 							+ resender.config.stalling_timeout)
 							.naive_utc()
 							.signed_duration_since(now_naive);
-						let next = Instant::now() + dur.to_std().unwrap();
-						self.state_timeout.reset(next);
-						if let futures::Async::Ready(()) =
-							self.state_timeout.poll()?
-						{
-							task::current().notify();
-						}
+						self.schedule(
+							tokio::clock::now() + dur.to_std().unwrap(),
+						);
 						StateChange::Nothing
 					}
 				}
@@ -738,13 +973,9 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 						let dur = (*start_time + resender.config.dead_timeout)
 							.naive_utc()
 							.signed_duration_since(now_naive);
-						let next = Instant::now() + dur.to_std().unwrap();
-						self.state_timeout.reset(next);
-						if let futures::Async::Ready(()) =
-							self.state_timeout.poll()?
-						{
-							task::current().notify();
-						}
+						self.schedule(
+							tokio::clock::now() + dur.to_std().unwrap(),
+						);
 						StateChange::Nothing
 					}
 				}
@@ -759,13 +990,9 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 							+ resender.config.disconnect_timeout)
 							.naive_utc()
 							.signed_duration_since(now_naive);
-						let next = Instant::now() + dur.to_std().unwrap();
-						self.state_timeout.reset(next);
-						if let futures::Async::Ready(()) =
-							self.state_timeout.poll()?
-						{
-							task::current().notify();
-						}
+						self.schedule(
+							tokio::clock::now() + dur.to_std().unwrap(),
+						);
 						StateChange::Nothing
 					}
 				}
@@ -798,17 +1025,16 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 		let packet_interval =
 			con.resender.state.get_packet_interval(&con.resender.config);
 
+		let algorithm = con.resender.config.algorithm;
 		let mut rto;
-		let mut last_threshold;
 		#[allow(clippy::let_and_return)]
-		while let Some(packet) = {
+		while let Some((p_type, packet)) = {
 			// Retransmission timeout
 			rto = if let Some(interval) = packet_interval {
 				interval
 			} else {
-				con.resender.srtt + con.resender.srtt_dev * 4
+				con.resender.retransmission_timeout()
 			};
-			last_threshold = now - rto;
 
 			let packet = if let Some(rec) =
 				&mut con.resender.state.peek_mut_next_record()
@@ -821,20 +1047,18 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 				//);
 
 				// Check if we should resend this packet or not
-				if rec.tries != 0 && rec.last > last_threshold {
+				let next = match algorithm {
+					ResendAlgorithm::Legacy => rec.last + rto,
+					ResendAlgorithm::Rfc6298 => rec.last + rec.rto,
+				};
+				if rec.tries != 0 && next > now {
 					// Schedule next send
-					let dur = rec
-						.last
-						.naive_utc()
-						.signed_duration_since(last_threshold.naive_utc());
-					let next = Instant::now() + dur.to_std().unwrap();
-					self.timeout.reset(next);
-					if let futures::Async::Ready(()) = self.timeout.poll()? {
-						task::current().notify();
-					}
+					let dur =
+						next.naive_utc().signed_duration_since(now.naive_utc());
+					self.schedule(tokio::clock::now() + dur.to_std().unwrap());
 					return Ok(futures::Async::NotReady);
 				}
-				Some(rec.packet.clone())
+				Some((rec.id.0, rec.packet.clone()))
 			} else {
 				//info!(con.logger, "No packet in send queue");
 				None
@@ -842,8 +1066,10 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 			packet
 		} {
 			// Try to send this packet
-			if let futures::AsyncSink::NotReady(_) =
-				self.sink.start_send((con.address, packet)).map_err(|e| {
+			if let futures::AsyncSink::NotReady(_) = self
+				.sink
+				.start_send((p_type, con.address, packet))
+				.map_err(|e| {
 					format_err!(
 						"Failed to poll_complete udp packet sink ({:?})",
 						e
@@ -868,6 +1094,9 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 
 				let is_normal_state;
 				let p_id;
+				let first_send;
+				let first_retransmission;
+				let timeout;
 				{
 					is_normal_state = if let ResendStates::Normal { .. } =
 						con.resender.state
@@ -877,22 +1106,42 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 						false
 					};
 
+					let max_rto = con.resender.config.max_rto;
 					let mut rec =
 						con.resender.state.peek_mut_next_record().unwrap();
 					p_id = rec.id.1;
-					// Double srtt on packet loss
-					if rec.tries != 0
-						&& con.resender.srtt
-							< con.resender.config.normal_timeout
-					{
-						con.resender.srtt = con.resender.srtt * 2;
-					}
+					first_send = rec.tries == 0;
+					first_retransmission = rec.tries == 1;
+					timeout = match algorithm {
+						ResendAlgorithm::Legacy => {
+							// Double srtt on packet loss
+							if rec.tries != 0
+								&& con.resender.srtt
+									< con.resender.config.normal_timeout
+							{
+								con.resender.srtt = con.resender.srtt * 2;
+							}
+							rto
+						}
+						ResendAlgorithm::Rfc6298 => {
+							rec.rto = rfc6298_backoff(
+								rec.rto,
+								rec.tries,
+								rto,
+								packet_interval,
+								max_rto,
+							);
+							rec.rto
+						}
+					};
 
 					// Update record
 					rec.last = now;
 					rec.tries += 1;
+					rec.transmissions += 1;
 
 					if rec.tries != 1 {
+						con.stats.add_resent(rec.id.0, rec.packet.len());
 						let to_s = if self.is_client { "S" } else { "C" };
 						warn!(self.logger, "Resend";
 							"p_type" => ?rec.id.0,
@@ -902,19 +1151,20 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 							"to" => to_s,
 							"srtt" => %con.resender.srtt,
 							"srtt_dev" => %con.resender.srtt_dev,
-							"rto" => %rto,
-							"threshold" => %last_threshold,
+							"rto" => %timeout,
 						);
 					}
 				}
 
-				let next = now + rto;
-				let dur =
-					next.naive_utc().signed_duration_since(now.naive_utc());
+				if algorithm == ResendAlgorithm::Rfc6298 && !first_send {
+					con.resender.shrink_window(first_retransmission);
+				}
 
-				if is_normal_state && dur > con.resender.config.normal_timeout {
+				if is_normal_state
+					&& timeout > con.resender.config.normal_timeout
+				{
 					warn!(self.logger, "Max resend timeout exceeded";
-						"p_id" => p_id, "dur" => %dur);
+						"p_id" => p_id, "dur" => %timeout);
 					// Switch connection to stalling state
 					switch_to_stalling = true;
 					break;
@@ -943,3 +1193,314 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 		Ok(futures::Async::NotReady)
 	}
 }
+
+/// Wakes up the scheduler and remembers which connections should be polled.
+struct Wakeup {
+	ready: Mutex<Vec<usize>>,
+	task: AtomicTask,
+}
+
+impl Notify for Wakeup {
+	fn notify(&self, id: usize) {
+		self.ready.lock().push(id);
+		self.task.notify();
+	}
+}
+
+struct ScheduledConnection<CM: ConnectionManager + 'static> {
+	future: Spawn<ResendFuture<CM>>,
+	/// The timeout of this connection in the timer wheel.
+	timeout: Option<Instant>,
+}
+
+/// Drives the [`ResendFuture`]s of all connections of a [`Data`] object.
+///
+/// Instead of a tokio timer per connection, the timeouts of all connections
+/// are stored in a [`TimerWheel`] and only the next expiration of the wheel
+/// is registered as tokio timer. The future finishes when all handles are
+/// dropped and all connections are gone.
+///
+/// [`ResendFuture`]: struct.ResendFuture.html
+/// [`Data`]: ../handler_data/struct.Data.html
+/// [`TimerWheel`]: ../timer_wheel/struct.TimerWheel.html
+pub struct ResendScheduler<CM: ConnectionManager + 'static> {
+	logger: Logger,
+	recv: Option<mpsc::UnboundedReceiver<ResendFuture<CM>>>,
+	connections: HashMap<usize, ScheduledConnection<CM>>,
+	next_id: usize,
+	wakeup: Arc<Wakeup>,
+	wheel: TimerWheel<(usize, Instant)>,
+	timer: Delay,
+}
+
+/// Add connections to a [`ResendScheduler`].
+///
+/// [`ResendScheduler`]: struct.ResendScheduler.html
+pub struct ResendSchedulerHandle<CM: ConnectionManager + 'static>(
+	mpsc::UnboundedSender<ResendFuture<CM>>,
+);
+
+impl<CM: ConnectionManager + 'static> ResendScheduler<CM> {
+	pub fn new(logger: Logger) -> (Self, ResendSchedulerHandle<CM>) {
+		let (send, recv) = mpsc::unbounded();
+		let now = tokio::clock::now();
+		let scheduler = Self {
+			logger,
+			recv: Some(recv),
+			connections: HashMap::new(),
+			next_id: 0,
+			wakeup: Arc::new(Wakeup {
+				ready: Mutex::new(Vec::new()),
+				task: AtomicTask::new(),
+			}),
+			wheel: TimerWheel::new(now, std::time::Duration::from_millis(1)),
+			timer: Delay::new(now),
+		};
+		(scheduler, ResendSchedulerHandle(send))
+	}
+}
+
+impl<CM: ConnectionManager + 'static> ResendSchedulerHandle<CM> {
+	/// Drive the resend future of a connection by the scheduler.
+	pub fn add(&self, future: ResendFuture<CM>) {
+		// If the scheduler is gone, the future is dropped
+		let _ = self.0.unbounded_send(future);
+	}
+}
+
+impl<CM: ConnectionManager + 'static> Clone for ResendSchedulerHandle<CM> {
+	fn clone(&self) -> Self { ResendSchedulerHandle(self.0.clone()) }
+}
+
+impl<CM: ConnectionManager + 'static> Future for ResendScheduler<CM> {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		self.wakeup.task.register();
+
+		// Add new connections
+		while let Some(recv) = &mut self.recv {
+			match recv.poll()? {
+				Async::Ready(Some(future)) => {
+					let id = self.next_id;
+					self.next_id = self.next_id.wrapping_add(1);
+					let con = ScheduledConnection {
+						future: executor::spawn(future),
+						timeout: None,
+					};
+					self.connections.insert(id, con);
+					self.wakeup.ready.lock().push(id);
+				}
+				Async::Ready(None) => self.recv = None,
+				Async::NotReady => break,
+			}
+		}
+
+		// Collect notified connections and connections with expired timeouts
+		let mut ready =
+			mem::replace(&mut *self.wakeup.ready.lock(), Vec::new());
+		let mut expired = Vec::new();
+		self.wheel.poll_expired(tokio::clock::now(), &mut expired);
+		for (id, timeout) in expired {
+			if let Some(con) = self.connections.get_mut(&id) {
+				// Ignore timeouts which were replaced by a newer one
+				if con.timeout == Some(timeout) {
+					con.timeout = None;
+					ready.push(id);
+				}
+			}
+		}
+		ready.sort_unstable();
+		ready.dedup();
+
+		let notify = NotifyHandle::from(self.wakeup.clone());
+		for id in ready {
+			let con = match self.connections.get_mut(&id) {
+				Some(c) => c,
+				None => continue,
+			};
+			match con.future.poll_future_notify(&notify, id) {
+				Ok(Async::NotReady) => {
+					let timeout = con.future.get_ref().next_timeout();
+					if let Some(t) = timeout {
+						if con.timeout != timeout {
+							self.wheel.insert(t, (id, t));
+						}
+					}
+					con.timeout = timeout;
+				}
+				Ok(Async::Ready(())) => {
+					self.connections.remove(&id);
+				}
+				Err(e) => {
+					error!(self.logger, "Resend future failed"; "error" => ?e);
+					self.connections.remove(&id);
+				}
+			}
+		}
+
+		if self.recv.is_none() && self.connections.is_empty() {
+			return Ok(Async::Ready(()));
+		}
+
+		// Wake up when the next timeout expires
+		if let Some(next) = self.wheel.next_expiration() {
+			self.timer.reset(next);
+			match self.timer.poll() {
+				Ok(Async::NotReady) => {}
+				Ok(Async::Ready(())) => task::current().notify(),
+				Err(ref e) if e.is_shutdown() => {
+					// Without a timer, no connection would be resent anymore
+					error!(self.logger, "Resend timer shut down, stopping \
+						resends"; "error" => ?e);
+					return Err(());
+				}
+				Err(e) => {
+					// The timer is at capacity, try again with a new timer
+					warn!(self.logger, "Resend timer failed"; "error" => ?e);
+					self.timer = Delay::new(next);
+					task::current().notify();
+				}
+			}
+		}
+		Ok(Async::NotReady)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use slog::o;
+
+	use super::*;
+
+	fn resender(config: ResendConfig) -> DefaultResender {
+		let logger = Logger::root(slog::Discard, o!());
+		let mut resender = DefaultResender::new(config, logger);
+		resender.handle_event(ResenderEvent::Connected);
+		resender
+	}
+
+	#[test]
+	fn rfc6298_rto() {
+		let mut resender = resender(ResendConfig {
+			algorithm: ResendAlgorithm::Rfc6298,
+			..Default::default()
+		});
+		// The first sample initializes the srtt
+		resender.update_rto(Duration::milliseconds(400));
+		assert_eq!(resender.srtt, Duration::milliseconds(400));
+		assert_eq!(resender.srtt_dev, Duration::milliseconds(200));
+		assert_eq!(
+			resender.retransmission_timeout(),
+			Duration::milliseconds(1200)
+		);
+
+		// The timeout is clamped to min_rto
+		for _ in 0..20 {
+			resender.update_rto(Duration::milliseconds(50));
+		}
+		assert_eq!(resender.retransmission_timeout(), Duration::seconds(1));
+	}
+
+	#[test]
+	fn rfc6298_backoff_in_all_states() {
+		let max_rto = Duration::seconds(60);
+		let interval = Some(Duration::seconds(5));
+		// The first transmission uses the given timeout
+		let mut rto = rfc6298_backoff(
+			Duration::zero(),
+			0,
+			Duration::seconds(5),
+			interval,
+			max_rto,
+		);
+		assert_eq!(rto, Duration::seconds(5));
+
+		// Retransmissions double the timeout even with a fixed interval
+		let mut rtos = Vec::new();
+		for tries in 1..6 {
+			rto = rfc6298_backoff(rto, tries, rto, interval, max_rto);
+			rtos.push(rto.num_seconds());
+		}
+		assert_eq!(rtos, [10, 20, 40, 60, 60]);
+
+		// The interval of the state is the lower bound
+		let rto = rfc6298_backoff(
+			Duration::seconds(1),
+			1,
+			Duration::seconds(5),
+			interval,
+			max_rto,
+		);
+		assert_eq!(rto, Duration::seconds(10));
+		let rto = rfc6298_backoff(
+			Duration::seconds(1),
+			1,
+			Duration::seconds(1),
+			None,
+			max_rto,
+		);
+		assert_eq!(rto, Duration::seconds(2));
+	}
+
+	#[test]
+	fn send_window() {
+		let mut resender = resender(ResendConfig {
+			algorithm: ResendAlgorithm::Rfc6298,
+			initial_send_window: 2,
+			..Default::default()
+		});
+		for i in 0..4 {
+			let packet = (PacketType::Command, 0, i, Bytes::new());
+			assert!(resender.start_send(packet).unwrap().is_ready());
+		}
+		assert_eq!(resender.state.len(), 2);
+		assert_eq!(resender.waiting.len(), 2);
+		assert!(!resender.is_empty());
+
+		// Acks grow the window
+		resender.ack_packet(PacketType::Command, 0);
+		assert_eq!(resender.send_window(), 3);
+		assert_eq!(resender.state.len(), 3);
+		assert!(resender.waiting.is_empty());
+
+		// Losses shrink the window
+		resender.shrink_window(true);
+		assert_eq!(resender.send_window(), 1);
+		assert_eq!(resender.slow_start_threshold, 2);
+		resender.start_send((PacketType::Command, 0, 4, Bytes::new())).unwrap();
+		assert_eq!(resender.waiting.len(), 1);
+	}
+
+	#[test]
+	fn rebound() {
+		let mut resender = resender(ResendConfig::default());
+		let packet = (PacketType::Command, 0, 0, Bytes::new());
+		assert!(resender.start_send(packet).unwrap().is_ready());
+		let mut to_send = match &mut resender.state {
+			ResendStates::Normal { to_send } => {
+				mem::replace(to_send, BinaryHeap::new()).into_vec()
+			}
+			_ => unreachable!("Resender is not connected"),
+		};
+		to_send[0].tries = 3;
+		resender.set_state(ResendStates::Stalling {
+			to_send,
+			start_time: clock::now(),
+		});
+
+		// Packets are sent again immediately over the new socket, but the
+		// state does not change
+		resender.rebound();
+		assert!(!resender.is_disconnecting());
+		assert!(!resender.send_voice_packets(PacketType::Voice));
+		match resender.state {
+			ResendStates::Stalling { .. } => {}
+			_ => panic!("Rebinding should keep the state"),
+		}
+		let rec = resender.state.peek_mut_next_record().unwrap();
+		assert_eq!(rec.tries, 3);
+		assert!(rec.last + rec.rto < clock::now());
+	}
+}
@@ -0,0 +1,321 @@
+//! The queue for outgoing udp packets of a [`Data`] object.
+//!
+//! All connections of a socket share this queue. Packets are sent by
+//! priority: acks first, then commands, pings and init packets and voice
+//! packets last. The queues for acks and commands have a fixed capacity and
+//! apply backpressure when they are full. Voice packets never block, if the
+//! voice queue is full, the oldest voice packet is dropped. Voice packets which
+//! waited longer than the voice deadline are dropped instead of sent.
+//!
+//! [`Data`]: ../handler_data/struct.Data.html
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use failure::format_err;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use parking_lot::Mutex;
+use tsproto_packets::packets::PacketType;
+
+use crate::Error;
+
+/// The number of priority classes.
+const CLASSES: usize = 3;
+
+/// The priority class of an outgoing packet, lower values are sent first.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Class {
+	Ack = 0,
+	Command = 1,
+	Voice = 2,
+}
+
+/// The current state of the send queue.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SendQueueStats {
+	/// The number of queued acks.
+	pub acks: usize,
+	/// The number of queued command, ping, pong and init packets.
+	pub commands: usize,
+	/// The number of queued voice packets.
+	pub voice: usize,
+	/// The number of voice packets which were dropped because the queue was
+	/// full or they exceeded the deadline.
+	pub dropped_voice: u64,
+}
+
+struct Shared {
+	queues: [VecDeque<(Instant, SocketAddr, Bytes)>; CLASSES],
+	capacity: usize,
+	voice_deadline: Duration,
+	dropped_voice: u64,
+	/// The number of existing sinks.
+	senders: usize,
+	/// If the stream was dropped.
+	closed: bool,
+	/// The task which polls the stream.
+	receiver_task: Option<Task>,
+	/// Tasks which wait until the queue has space again.
+	///
+	/// Every task is only stored once, even if it polls a full queue
+	/// repeatedly.
+	sender_tasks: Vec<Task>,
+}
+
+/// Put packets into the send queue.
+pub struct SendQueueSink {
+	shared: Arc<Mutex<Shared>>,
+}
+
+/// Take packets out of the send queue in the order in which they should be
+/// sent.
+pub struct SendQueueStream {
+	shared: Arc<Mutex<Shared>>,
+}
+
+/// Create a new send queue.
+///
+/// `capacity` is the capacity for each priority class. Voice packets which
+/// are older than `voice_deadline` are dropped.
+pub fn new(
+	capacity: usize,
+	voice_deadline: Duration,
+) -> (SendQueueSink, SendQueueStream)
+{
+	let shared = Arc::new(Mutex::new(Shared {
+		queues: Default::default(),
+		capacity: capacity.max(1),
+		voice_deadline,
+		dropped_voice: 0,
+		senders: 1,
+		closed: false,
+		receiver_task: None,
+		sender_tasks: Vec::new(),
+	}));
+	(
+		SendQueueSink {
+			shared: shared.clone(),
+		},
+		SendQueueStream { shared },
+	)
+}
+
+impl Class {
+	fn from_type(p_type: PacketType) -> Self {
+		match p_type {
+			PacketType::Ack | PacketType::AckLow => Class::Ack,
+			PacketType::Voice | PacketType::VoiceWhisper => Class::Voice,
+			PacketType::Command
+			| PacketType::CommandLow
+			| PacketType::Ping
+			| PacketType::Pong
+			| PacketType::Init => Class::Command,
+		}
+	}
+}
+
+impl Shared {
+	fn notify_receiver(&mut self) {
+		if let Some(task) = self.receiver_task.take() {
+			task.notify();
+		}
+	}
+
+	fn notify_senders(&mut self) {
+		for task in self.sender_tasks.drain(..) {
+			task.notify();
+		}
+	}
+}
+
+impl SendQueueSink {
+	/// The current length of the queues.
+	pub fn stats(&self) -> SendQueueStats {
+		let shared = self.shared.lock();
+		SendQueueStats {
+			acks: shared.queues[Class::Ack as usize].len(),
+			commands: shared.queues[Class::Command as usize].len(),
+			voice: shared.queues[Class::Voice as usize].len(),
+			dropped_voice: shared.dropped_voice,
+		}
+	}
+}
+
+impl Clone for SendQueueSink {
+	fn clone(&self) -> Self {
+		self.shared.lock().senders += 1;
+		Self {
+			shared: self.shared.clone(),
+		}
+	}
+}
+
+impl Drop for SendQueueSink {
+	fn drop(&mut self) {
+		let mut shared = self.shared.lock();
+		shared.senders -= 1;
+		if shared.senders == 0 {
+			// Let the stream finish
+			shared.notify_receiver();
+		}
+	}
+}
+
+impl fmt::Debug for SendQueueSink {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "SendQueueSink({:?})", self.stats())
+	}
+}
+
+impl Sink for SendQueueSink {
+	type SinkItem = (PacketType, SocketAddr, Bytes);
+	type SinkError = Error;
+
+	fn start_send(
+		&mut self,
+		(p_type, addr, packet): Self::SinkItem,
+	) -> StartSend<Self::SinkItem, Self::SinkError>
+	{
+		let mut shared = self.shared.lock();
+		if shared.closed {
+			return Err(format_err!("The send queue is closed").into());
+		}
+		let class = Class::from_type(p_type);
+		let capacity = shared.capacity;
+		if shared.queues[class as usize].len() >= capacity {
+			if class == Class::Voice {
+				// Drop the oldest voice packet
+				shared.queues[class as usize].pop_front();
+				shared.dropped_voice += 1;
+			} else {
+				if !shared.sender_tasks.iter().any(Task::will_notify_current) {
+					shared.sender_tasks.push(task::current());
+				}
+				return Ok(AsyncSink::NotReady((p_type, addr, packet)));
+			}
+		}
+		shared.queues[class as usize].push_back((
+			tokio::clock::now(),
+			addr,
+			packet,
+		));
+		shared.notify_receiver();
+		Ok(AsyncSink::Ready)
+	}
+
+	fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+		// The packets are sent by the stream
+		Ok(Async::Ready(()))
+	}
+}
+
+impl Stream for SendQueueStream {
+	type Item = (SocketAddr, Bytes);
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		let mut shared = self.shared.lock();
+		let now = tokio::clock::now();
+		let deadline = shared.voice_deadline;
+		for class in &[Class::Ack, Class::Command, Class::Voice] {
+			while let Some((time, addr, packet)) =
+				shared.queues[*class as usize].pop_front()
+			{
+				// Use a saturating difference, the clock can go backwards
+				if *class == Class::Voice
+					&& now.saturating_duration_since(time) > deadline
+				{
+					shared.dropped_voice += 1;
+					continue;
+				}
+				shared.notify_senders();
+				return Ok(Async::Ready(Some((addr, packet))));
+			}
+		}
+
+		if shared.senders == 0 {
+			return Ok(Async::Ready(None));
+		}
+		shared.receiver_task = Some(task::current());
+		Ok(Async::NotReady)
+	}
+}
+
+impl Drop for SendQueueStream {
+	fn drop(&mut self) {
+		let mut shared = self.shared.lock();
+		shared.closed = true;
+		shared.notify_senders();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::{future, Future};
+	use tokio::runtime::current_thread::Runtime;
+
+	use super::*;
+
+	#[test]
+	fn send_queue_priorities() {
+		let mut rt = Runtime::new().unwrap();
+		let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+		let (mut sink, stream) = new(2, Duration::from_secs(60));
+
+		let received = rt
+			.block_on(future::lazy(move || {
+				for (t, b) in &[
+					(PacketType::Voice, 0),
+					(PacketType::Command, 1),
+					(PacketType::Voice, 2),
+					(PacketType::Ack, 3),
+					(PacketType::Voice, 4),
+					(PacketType::Ping, 5),
+				] {
+					let packet = Bytes::from(vec![*b]);
+					assert!(sink.start_send((*t, addr, packet))?.is_ready());
+				}
+				// The command queue is full
+				assert!(!sink
+					.start_send((PacketType::Command, addr, Bytes::new()))?
+					.is_ready());
+				let stats = sink.stats();
+				assert_eq!(stats.commands, 2);
+				assert_eq!(stats.voice, 2);
+				assert_eq!(stats.dropped_voice, 1);
+
+				drop(sink);
+				Ok::<_, Error>(stream.map(|(_, p)| p[0]).collect())
+			}))
+			.unwrap()
+			.wait()
+			.unwrap();
+		assert_eq!(received, [3, 1, 5, 2, 4]);
+	}
+
+	/// A sender which polls a full queue repeatedly is only notified once.
+	#[test]
+	fn send_queue_full_repeated() {
+		let mut rt = Runtime::new().unwrap();
+		let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+		let (mut sink, _stream) = new(1, Duration::from_secs(60));
+
+		rt.block_on(future::lazy(move || {
+			assert!(sink
+				.start_send((PacketType::Command, addr, Bytes::new()))?
+				.is_ready());
+			for _ in 0..10 {
+				assert!(!sink
+					.start_send((PacketType::Command, addr, Bytes::new()))?
+					.is_ready());
+			}
+			assert_eq!(sink.shared.lock().sender_tasks.len(), 1);
+			Ok::<_, Error>(())
+		}))
+		.unwrap();
+	}
+}
@@ -0,0 +1,1339 @@
+//! The server side of the TeamSpeak protocol.
+//!
+//! This module is the counterpart of the [`client`] module. It accepts new
+//! connections, answers the `Init` packets, checks the RSA puzzle and the hash
+//! cash level of clients and does the key exchange.
+//!
+//! [`client`]: ../client/index.html
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::{Arc, Weak};
+
+use arrayref::array_ref;
+use bytes::{Bytes, BytesMut};
+use chrono::{Duration, Utc};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use failure::format_err;
+use futures::sync::mpsc;
+use futures::{future, Future, Sink, Stream};
+use num_bigint::BigUint;
+use num_traits::One;
+use parking_lot::Mutex;
+use rand::{self, Rng};
+use slog::{error, info, warn, Logger};
+use tsproto_packets::commands::parse_command;
+use tsproto_packets::packets::*;
+
+use crate::algorithms as algs;
+use crate::connection::*;
+use crate::connectionmanager::{
+	Resender, ResenderEvent, SocketConnectionManager,
+};
+use crate::crypto::{EccKeyPrivEd25519, EccKeyPrivP256, EccKeyPubP256};
+use crate::handler_data::{
+	ConnectionValue, ConnectionValueWeak, Data, DataM, InCommandObserver,
+	OutPacketObserver, PacketHandler,
+};
+use crate::license::{InnerLicense, License, LicenseKey, Licenses};
+use crate::{Error, Result};
+
+/// The error id which is sent to clients with a too low hash cash level.
+///
+/// This is `client_could_not_validate_identity`, the `extra_msg` contains the
+/// required level.
+const CLIENT_COULD_NOT_VALIDATE_IDENTITY: u32 = 518;
+
+pub type CM<PH> =
+	SocketConnectionManager<DefaultPacketHandler<PH>, ClientConnectionData>;
+/// The data of our server.
+pub type ServerData<PH> = Data<CM<PH>>;
+pub type ServerDataM<PH> = DataM<CM<PH>>;
+/// Connections from a server to a client.
+pub type ServerConnection = Connection;
+pub type ServerConVal = ConnectionValueWeak<ClientConnectionData>;
+
+pub struct ClientConnectionData {
+	/// Every function in this list is called when the state of the connection
+	/// changes.
+	///
+	/// Return `false` to remain in the list of listeners.
+	/// If `true` is returned, this listener will be removed.
+	pub state_change_listener:
+		Vec<Box<FnMut(&ClientConnectionState) -> bool + Send>>,
+	pub state: ClientConnectionState,
+	/// The public key of the client.
+	///
+	/// It is known after the `Init4` packet was received.
+	pub client_key: Option<EccKeyPubP256>,
+	/// Values from the handshake which are needed for the next steps.
+	handshake: HandshakeData,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientConnectionState {
+	/// The connection was created because of an `Init0` packet.
+	Init0,
+	/// After `Init1` was sent.
+	Init1,
+	/// After `Init3` was sent.
+	Init3,
+	/// After `initivexpand2` was sent, the next packet has to be `clientek`.
+	ClientEk,
+	/// The key exchange is done and the next packet has to be `clientinit`.
+	Connecting,
+	/// Fully connected, `initserver` was sent.
+	Connected,
+	/// The connection is finishing, no more packets should be sent.
+	/// We are only waiting until the last ack is sent.
+	Disconnecting,
+}
+
+/// Data of the handshake, which has to be remembered until the next packet of
+/// the client arrives.
+struct HandshakeData {
+	random1: [u8; 16],
+	random2: [u8; 100],
+	alpha: [u8; 10],
+	beta: [u8; 54],
+	/// The derived private key of the license with our ephemeral key.
+	ek: Option<EccKeyPrivEd25519>,
+}
+
+/// Configure the handshake of a server.
+#[derive(Clone, Debug)]
+pub struct HandshakeConfig {
+	/// The level of the RSA puzzle, which clients have to solve.
+	///
+	/// The time needed to solve the puzzle grows linearly with the level.
+	pub puzzle_level: u32,
+	/// The minimum hash cash level of client identities.
+	pub hash_cash_level: u8,
+	/// The license of the server, together with the derived private key of
+	/// the license chain.
+	///
+	/// An ephemeral license block is appended for each connection. If no
+	/// license is set, the protocol before TeamSpeak 3.1 (`initivexpand`) is
+	/// used.
+	pub license: Option<(Licenses, EccKeyPrivEd25519)>,
+}
+
+impl Default for HandshakeConfig {
+	fn default() -> Self {
+		Self {
+			puzzle_level: 10_000,
+			hash_cash_level: 8,
+			license: None,
+		}
+	}
+}
+
+/// The RSA puzzle, which has to be solved by the clients.
+///
+/// y = x ^ (2 ^ level) % n
+///
+/// The puzzle is created once per server, so the solution has not to be
+/// computed for every client.
+struct RsaPuzzle {
+	x: [u8; 64],
+	n: [u8; 64],
+	level: u32,
+	y: [u8; 64],
+}
+
+impl RsaPuzzle {
+	fn new(level: u32) -> Self {
+		let mut rng = rand::thread_rng();
+		let mut x = [0; 64];
+		let mut n = [0; 64];
+		rng.fill(&mut x[..]);
+		rng.fill(&mut n[..]);
+		// Use the full 512 bit for n and make sure that x is smaller than n
+		n[0] |= 0x80;
+		n[63] |= 1;
+		x[0] &= 0x7f;
+
+		let xi = algs::array_to_biguint(&x);
+		let ni = algs::array_to_biguint(&n);
+		let mut e = BigUint::one();
+		e <<= level as usize;
+		let y = algs::biguint_to_array(&xi.modpow(&e, &ni));
+		Self { x, n, level, y }
+	}
+}
+
+impl ClientConnectionData {
+	fn new() -> Self {
+		Self {
+			state_change_listener: Vec::new(),
+			state: ClientConnectionState::Init0,
+			client_key: None,
+			handshake: HandshakeData {
+				random1: [0; 16],
+				random2: [0; 100],
+				alpha: [0; 10],
+				beta: [0; 54],
+				ek: None,
+			},
+		}
+	}
+
+	/// Call all state change listeners with the current state.
+	fn notify_state_changed(&mut self) {
+		let mut i = 0;
+		while i < self.state_change_listener.len() {
+			if (&mut self.state_change_listener[i])(&self.state) {
+				self.state_change_listener.remove(i);
+			} else {
+				i += 1;
+			}
+		}
+	}
+}
+
+/// Wait until a client reaches a certain state.
+///
+/// `is_state` should return `true`, if the state is reached and `false` if this
+/// function should continue waiting.
+pub fn wait_for_state<
+	F: Fn(&ClientConnectionState) -> bool + Send + 'static,
+>(
+	connection: &ServerConVal,
+	f: F,
+) -> Box<Future<Item = (), Error = Error> + Send>
+{
+	let (send, recv) = mpsc::channel(0);
+	let con = match connection.mutex.upgrade() {
+		Some(c) => c,
+		None => {
+			return Box::new(future::err(
+				format_err!("Connection is gone").into(),
+			));
+		}
+	};
+	let mut con = con.lock();
+	con.0.state_change_listener.push(Box::new(move |s| {
+		// Check if it is the right state
+		if f(s) {
+			let send = send.clone();
+			// Ignore errors
+			tokio::spawn(send.send(()).then(|_| Ok(())));
+			true
+		} else {
+			false
+		}
+	}));
+	Box::new(
+		recv.into_future()
+			.map_err(|e| {
+				format_err!(
+					"Failed to receive while waiting for state ({:?})",
+					e
+				)
+				.into()
+			})
+			.and_then(|(r, _)| match r {
+				Some(()) => Ok(()),
+				None => Err(format_err!("Connection is gone").into()),
+			}),
+	)
+}
+
+pub fn wait_until_connected(
+	connection: &ServerConVal,
+) -> impl Future<Item = (), Error = Error> {
+	wait_for_state(connection, |state| {
+		*state == ClientConnectionState::Connected
+	})
+}
+
+/// Create a new server, which listens on `local_addr`.
+///
+/// New connections are accepted, when an `Init0` packet is received from an
+/// unknown address. The `packet_handler` gets notified about every new
+/// connection. After the handshake, the `clientinit` command is forwarded to
+/// it and it has to answer with `initserver`.
+pub fn new<
+	PH: PacketHandler<ClientConnectionData> + 'static,
+	L: Into<Option<slog::Logger>>,
+>(
+	local_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+	packet_handler: PH,
+	config: HandshakeConfig,
+	protocol_config: ProtocolConfig,
+	logger: L,
+) -> Result<Arc<Mutex<ServerData<PH>>>>
+{
+	let (unknown_send, unknown_recv) =
+		mpsc::channel(protocol_config.udp_sink_capacity);
+	let s = ServerData::new(
+		local_addr,
+		private_key,
+		false,
+		Some(unknown_send),
+		DefaultPacketHandler::new(packet_handler, config),
+		SocketConnectionManager::new(),
+		protocol_config,
+		logger,
+	)?;
+	init(&s, unknown_recv);
+	Ok(s)
+}
+
+/// Create a new server without creating a socket.
+///
+/// The stream and sink of the socket have to be provided, see
+/// [`Data::new_with_socket`].
+///
+/// [`Data::new_with_socket`]: ../handler_data/struct.Data.html#method.new_with_socket
+pub fn new_with_socket<
+	PH: PacketHandler<ClientConnectionData> + 'static,
+	E1: Debug,
+	E2: Debug,
+>(
+	local_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+	packet_handler: PH,
+	config: HandshakeConfig,
+	protocol_config: ProtocolConfig,
+	sink: impl Sink<SinkItem = (Bytes, SocketAddr), SinkError = E1>
+		+ Send
+		+ 'static,
+	stream: impl Stream<Item = (BytesMut, SocketAddr), Error = E2>
+		+ Send
+		+ 'static,
+	logger: slog::Logger,
+) -> Result<Arc<Mutex<ServerData<PH>>>>
+{
+	let (unknown_send, unknown_recv) =
+		mpsc::channel(protocol_config.udp_sink_capacity);
+	let s = ServerData::new_with_socket(
+		local_addr,
+		private_key,
+		false,
+		Some(unknown_send),
+		DefaultPacketHandler::new(packet_handler, config),
+		SocketConnectionManager::new(),
+		protocol_config,
+		sink,
+		stream,
+		logger,
+	)?;
+	init(&s, unknown_recv);
+	Ok(s)
+}
+
+/// Set the data reference, add the observers of the server and start to
+/// accept new connections.
+fn init<PH: PacketHandler<ClientConnectionData> + 'static>(
+	s: &Arc<Mutex<ServerData<PH>>>,
+	unknown_recv: mpsc::Receiver<(SocketAddr, InPacket)>,
+) {
+	let s2 = Arc::downgrade(s);
+	let logger = {
+		let mut s = s.lock();
+		let s = &mut *s;
+		// Set the data reference
+		s.packet_handler.complete(s2.clone());
+
+		// Handle the clientek synchronously
+		s.add_in_command_observer(
+			"tsproto::server".into(),
+			Box::new(ServerInCommandObserver),
+		);
+		// Change state on initserver
+		s.add_out_packet_observer(
+			"tsproto::server".into(),
+			Box::new(ServerOutPacketObserver),
+		);
+		s.logger.clone()
+	};
+
+	// Accept new connections
+	tokio::spawn(unknown_recv.for_each(move |(addr, packet)| {
+		if s2.upgrade().is_none() {
+			// The server is gone
+			return Err(());
+		}
+		if let Err(e) = accept_connection(&s2, addr, packet) {
+			warn!(logger, "Failed to accept connection"; "addr" => %addr,
+				"error" => ?e);
+		}
+		Ok(())
+	}));
+}
+
+/// Create a connection for a packet from an unknown address.
+///
+/// The packet has to be an `Init0`, all other packets are dropped.
+fn accept_connection<PH: PacketHandler<ClientConnectionData> + 'static>(
+	datam: &Weak<Mutex<ServerData<PH>>>,
+	addr: SocketAddr,
+	packet: InPacket,
+) -> Result<()>
+{
+	let init = packet.into_c2sinit().map_err(|(_, e)| e)?;
+	let is_init0 = init.with_data(|init| {
+		if let C2SInitData::Init0 { .. } = init {
+			true
+		} else {
+			false
+		}
+	});
+	if !is_init0 {
+		return Err(format_err!("Expected Init0 from new connection").into());
+	}
+
+	let con = {
+		let data = datam
+			.upgrade()
+			.ok_or_else(|| format_err!("Server does not exist anymore"))?;
+		let mut data = data.lock();
+		if let Some(con) = data.get_connection(&addr) {
+			// The connection was created in the meantime
+			con
+		} else {
+			let key = data.add_connection(
+				datam.clone(),
+				ClientConnectionData::new(),
+				addr,
+			);
+			data.get_connection(&key).unwrap()
+		}
+	};
+
+	let con = con.mutex.lock();
+	con.1.c2s_init_sink.unbounded_send(init).map_err(|e| {
+		format_err!("Failed to send packet to handler ({:?})", e)
+	})?;
+	Ok(())
+}
+
+/// Send a packet, then set the new state and notify the listeners.
+fn send_and_notify(
+	con_val: ServerConVal,
+	packet: OutPacket,
+	logger: Logger,
+) -> impl Future<Item = (), Error = ()>
+{
+	let con_val2 = con_val.clone();
+	con_val
+		.as_packet_sink()
+		.send(packet)
+		.and_then(move |_| -> Result<()> {
+			let mutex = con_val2
+				.upgrade()
+				.ok_or_else(|| format_err!("Connection is gone"))?
+				.mutex;
+			mutex.lock().0.notify_state_changed();
+			Ok(())
+		})
+		.map_err(move |e| {
+			error!(logger, "Error sending response packet"; "error" => ?e)
+		})
+}
+
+/// Finishes the key exchange for the protocol since TeamSpeak 3.1.
+///
+/// This has to be done synchronously, when the `clientek` is received, so the
+/// next packet of the client can already be decrypted with the new keys.
+struct ServerInCommandObserver;
+impl InCommandObserver<ClientConnectionData> for ServerInCommandObserver {
+	fn observe(
+		&self,
+		(data, con): &mut (ClientConnectionData, Connection),
+		cmd: &InCommand,
+	)
+	{
+		if data.state != ClientConnectionState::ClientEk
+			|| cmd.name() != "clientek"
+		{
+			return;
+		}
+
+		match Self::handle_clientek(data, con, cmd) {
+			Ok(()) => {
+				data.state = ClientConnectionState::Connecting;
+				data.notify_state_changed();
+			}
+			Err(e) => {
+				warn!(con.logger, "Failed to handle clientek"; "error" => ?e)
+			}
+		}
+	}
+}
+
+impl ServerInCommandObserver {
+	fn handle_clientek(
+		data: &mut ClientConnectionData,
+		con: &mut Connection,
+		cmd: &InCommand,
+	) -> Result<()>
+	{
+		let args = cmd.data();
+		let ek = base64::decode(
+			args.static_arg("ek")
+				.ok_or_else(|| format_err!("clientek has no ek"))?,
+		)?;
+		let proof = base64::decode(
+			args.static_arg("proof")
+				.ok_or_else(|| format_err!("clientek has no proof"))?,
+		)?;
+		if ek.len() != 32 {
+			return Err(format_err!("Incorrect ek length").into());
+		}
+
+		let client_key = data
+			.client_key
+			.clone()
+			.ok_or_else(|| format_err!("Client key is unknown"))?;
+		// Proof: ECDSA signature of ek || beta
+		let mut all = Vec::with_capacity(32 + 54);
+		all.extend_from_slice(&ek);
+		all.extend_from_slice(&data.handshake.beta);
+		client_key.clone().verify(&all, &proof)?;
+
+		let client_ek = CompressedEdwardsY(*array_ref!(ek, 0, 32))
+			.decompress()
+			.ok_or_else(|| format_err!("Cannot uncompress ek"))?;
+		let own_ek = data
+			.handshake
+			.ek
+			.take()
+			.ok_or_else(|| format_err!("No ephemeral key exists"))?;
+		let (iv, mac) = algs::compute_iv_mac31(
+			&data.handshake.alpha,
+			&data.handshake.beta,
+			&own_ek,
+			&client_ek,
+		)?;
+		con.params = Some(ConnectedParams::new(
+			client_key,
+			SharedIv::Protocol31(iv),
+			mac,
+		));
+		Ok(())
+	}
+}
+
+struct ServerOutPacketObserver;
+impl OutPacketObserver<ClientConnectionData> for ServerOutPacketObserver {
+	fn observe(
+		&self,
+		(data, con): &mut (ClientConnectionData, Connection),
+		packet: &mut OutPacket,
+	)
+	{
+		let p_type = packet.header().packet_type();
+		if p_type != PacketType::Command
+			|| data.state != ClientConnectionState::Connecting
+		{
+			return;
+		}
+		let s = b"initserver";
+		if packet.content().len() < s.len()
+			|| packet.content()[..s.len()] != s[..]
+		{
+			return;
+		}
+
+		// Remember the client id
+		if let Some(params) = &mut con.params {
+			if let Ok(cmd) = str::from_utf8(packet.content()) {
+				if let Ok(cmd) = parse_command(cmd) {
+					if let Some(Ok(c_id)) =
+						cmd.static_arg("aclid").map(|s| s.parse::<u16>())
+					{
+						params.c_id = c_id;
+					}
+				}
+			}
+		}
+
+		// Notify the resender that we are connected
+		con.resender.handle_event(ResenderEvent::Connected);
+		data.state = ClientConnectionState::Connected;
+		data.notify_state_changed();
+	}
+}
+
+pub struct DefaultPacketHandler<
+	IPH: PacketHandler<ClientConnectionData> + 'static,
+> {
+	pub inner: IPH,
+	config: Arc<HandshakeConfig>,
+	puzzle: Arc<RsaPuzzle>,
+	/// The data instance is created after the packet handler so this has to be
+	/// an option.
+	data: Option<Weak<Mutex<ServerData<IPH>>>>,
+}
+
+impl<IPH: PacketHandler<ClientConnectionData> + 'static>
+	PacketHandler<ClientConnectionData> for DefaultPacketHandler<IPH>
+{
+	fn new_connection<S1, S2, S3, S4>(
+		&mut self,
+		con_val: &ConnectionValue<ClientConnectionData>,
+		s2c_init_stream: S1,
+		c2s_init_stream: S2,
+		command_stream: S3,
+		audio_stream: S4,
+	) where
+		S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+		S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+		S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+		S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+	{
+		let con_val2 = con_val.downgrade();
+		let data = self.data.as_ref().unwrap().clone();
+		let config = self.config.clone();
+		let puzzle = self.puzzle.clone();
+		let c2s_init_stream = c2s_init_stream
+			.and_then(move |p| -> Result<Option<InC2SInit>> {
+				// Get private key
+				let key = {
+					let d = if let Some(d) = data.upgrade() {
+						d
+					} else {
+						// Connection doesn't exist anymore
+						return Err(format_err!(
+							"Connection does not exist while handling packet"
+						)
+						.into());
+					};
+					let d = d.lock();
+					d.private_key.clone()
+				};
+
+				let con_val = con_val2
+					.upgrade()
+					.ok_or_else(|| format_err!("Connection is gone"))?;
+				let mut con = con_val.mutex.lock();
+				let logger = con.1.logger.clone();
+				let mut ignore_packet = true;
+				let handle_res = match Self::handle_init(
+					&mut *con,
+					&p,
+					&mut ignore_packet,
+					&key,
+					&config,
+					&puzzle,
+				) {
+					Ok(r) => r,
+					Err(e) => {
+						error!(logger, "Error handling init packet";
+							"error" => ?e);
+						// Ignore packet, it is probably malformed
+						return Ok(None);
+					}
+				};
+
+				if let Some((s, packet)) = handle_res {
+					con.0.state = s;
+					if let Some(packet) = packet {
+						drop(con);
+						tokio::spawn(send_and_notify(
+							con_val2.clone(),
+							packet,
+							logger,
+						));
+					} else {
+						con.0.notify_state_changed();
+					}
+				}
+
+				if ignore_packet {
+					Ok(None)
+				} else {
+					Ok(Some(p))
+				}
+			})
+			.filter_map(|p| p);
+
+		let con_val2 = con_val.downgrade();
+		let config = self.config.clone();
+		let command_stream = command_stream
+			.and_then(move |cmd| -> Result<Option<InCommand>> {
+				// Check if we handle this packet
+				let name = cmd.name();
+				if name != "clientek"
+					&& name != "clientinit"
+					&& name != "clientdisconnect"
+				{
+					// Forward packet
+					return Ok(Some(cmd));
+				}
+
+				let con_val = con_val2
+					.upgrade()
+					.ok_or_else(|| format_err!("Connection is gone"))?;
+				let mut con = con_val.mutex.lock();
+				let logger = con.1.logger.clone();
+				let mut ignore_packet = true;
+				let handle_res = match Self::handle_command(
+					&mut *con,
+					&cmd,
+					&mut ignore_packet,
+					&config,
+				) {
+					Ok(r) => r,
+					Err(e) => {
+						error!(logger, "Error handling client command";
+							"error" => ?e);
+						// Ignore packet, it is probably malformed
+						return Ok(None);
+					}
+				};
+
+				if let Some((s, packet)) = handle_res {
+					if s == ClientConnectionState::Disconnecting {
+						con.1
+							.resender
+							.handle_event(ResenderEvent::Disconnecting);
+					}
+					con.0.state = s;
+					if let Some(packet) = packet {
+						drop(con);
+						tokio::spawn(send_and_notify(
+							con_val2.clone(),
+							packet,
+							logger,
+						));
+					} else {
+						con.0.notify_state_changed();
+					}
+				}
+
+				if ignore_packet {
+					Ok(None)
+				} else {
+					Ok(Some(cmd))
+				}
+			})
+			.filter_map(|p| p);
+
+		self.inner.new_connection(
+			con_val,
+			s2c_init_stream,
+			c2s_init_stream,
+			command_stream,
+			audio_stream,
+		);
+	}
+}
+
+impl<IPH: PacketHandler<ClientConnectionData> + 'static>
+	DefaultPacketHandler<IPH>
+{
+	/// Do not forget to call [`complete`] afterwards.
+	///
+	/// This creates the RSA puzzle for the server, which can take some time
+	/// for high levels.
+	///
+	/// [`complete`]: #method.complete
+	pub fn new(inner: IPH, config: HandshakeConfig) -> Self {
+		let puzzle = RsaPuzzle::new(config.puzzle_level);
+		Self {
+			inner,
+			config: Arc::new(config),
+			puzzle: Arc::new(puzzle),
+			data: None,
+		}
+	}
+
+	/// Needs to be called to complete the initialization of this packet handler.
+	pub fn complete(&mut self, data: Weak<Mutex<ServerData<IPH>>>) {
+		self.data = Some(data);
+	}
+
+	fn handle_init(
+		(data, con): &mut (ClientConnectionData, Connection),
+		packet: &InC2SInit,
+		ignore_packet: &mut bool,
+		private_key: &EccKeyPrivP256,
+		config: &HandshakeConfig,
+		puzzle: &RsaPuzzle,
+	) -> Result<Option<(ClientConnectionState, Option<OutPacket>)>>
+	{
+		let mut rng = rand::thread_rng();
+		packet.with_data(|init| -> Result<_> {
+			match (data.state, init) {
+				(
+					ClientConnectionState::Init0,
+					C2SInitData::Init0 { random0, .. },
+				) => {
+					data.handshake.random1 = rng.gen();
+					let mut random0_r = **random0;
+					random0_r.reverse();
+					let packet =
+						OutS2CInit1::new(&data.handshake.random1, random0_r);
+					Ok(Some((ClientConnectionState::Init1, Some(packet))))
+				}
+				(
+					ClientConnectionState::Init1,
+					C2SInitData::Init2 { random1, .. },
+				) => {
+					if **random1 != data.handshake.random1 {
+						return Err(
+							format_err!("Init2 has a wrong random1").into()
+						);
+					}
+					con.resender.ack_packet(PacketType::Init, 1);
+
+					rng.fill(&mut data.handshake.random2[..]);
+					let packet = OutS2CInit3::new(
+						&puzzle.x,
+						&puzzle.n,
+						puzzle.level,
+						&data.handshake.random2,
+					);
+					Ok(Some((ClientConnectionState::Init3, Some(packet))))
+				}
+				(
+					ClientConnectionState::Init3,
+					C2SInitData::Init4 {
+						x,
+						n,
+						level,
+						random2,
+						y,
+						command,
+						..
+					},
+				) => {
+					// Check the solution of the RSA puzzle
+					if x[..] != puzzle.x[..]
+						|| n[..] != puzzle.n[..]
+						|| *level != puzzle.level
+						|| random2[..] != data.handshake.random2[..]
+					{
+						return Err(format_err!("Init4 contains a wrong puzzle")
+							.into());
+					}
+					if y[..] != puzzle.y[..] {
+						return Err(
+							format_err!("Wrong solution for RSA puzzle").into()
+						);
+					}
+					con.resender.ack_packet(PacketType::Init, 3);
+
+					if command.name != "clientinitiv" {
+						return Err(format_err!(
+							"Expected clientinitiv but got {}",
+							command.name
+						)
+						.into());
+					}
+					let alpha = base64::decode(
+						command.static_arg("alpha").ok_or_else(|| {
+							format_err!("clientinitiv has no alpha")
+						})?,
+					)?;
+					if alpha.len() != 10 {
+						return Err(
+							format_err!("Incorrect alpha length").into()
+						);
+					}
+					data.handshake.alpha.copy_from_slice(&alpha);
+					let client_key = EccKeyPubP256::from_ts(
+						command.static_arg("omega").ok_or_else(|| {
+							format_err!("clientinitiv has no omega")
+						})?,
+					)?;
+					data.client_key = Some(client_key.clone());
+					let omega = private_key.to_pub().to_ts()?;
+
+					if let Some((licenses, license_key)) = &config.license {
+						rng.fill(&mut data.handshake.beta[..]);
+
+						// Append an ephemeral license block
+						let ek = EccKeyPrivEd25519::create()?;
+						let mut licenses = licenses.clone();
+						let (not_valid_before, not_valid_after) =
+							if let Some(l) = licenses.blocks.last() {
+								(l.not_valid_before, l.not_valid_after)
+							} else {
+								let now = Utc::now();
+								(
+									now - Duration::days(1),
+									now + Duration::days(1),
+								)
+							};
+						let mut license = License {
+							key: LicenseKey::Private(ek),
+							not_valid_before,
+							not_valid_after,
+							hash: [0; 32],
+							inner: InnerLicense::Ephemeral,
+						};
+						license.fill_hash();
+						licenses.blocks.push(license);
+						let own_ek = licenses.derive_private_key(
+							licenses.blocks.len() - 1,
+							license_key.clone(),
+						)?;
+						data.handshake.ek = Some(own_ek);
+
+						let mut l = Vec::new();
+						licenses.write(&mut l)?;
+						// Sign the license with our identity
+						let proof = private_key.clone().sign(&l)?;
+
+						let packet = OutCommand::new::<
+							_,
+							_,
+							String,
+							String,
+							_,
+							_,
+							std::iter::Empty<_>,
+						>(
+							Direction::S2C,
+							PacketType::Command,
+							"initivexpand2",
+							vec![
+								("l", base64::encode(&l)),
+								(
+									"beta",
+									base64::encode(&data.handshake.beta[..]),
+								),
+								("omega", omega),
+								("ot", "1".into()),
+								("proof", base64::encode(&proof)),
+								("tvd", String::new()),
+								("time", Utc::now().timestamp().to_string()),
+							]
+							.into_iter(),
+							std::iter::empty(),
+						);
+						Ok(Some((
+							ClientConnectionState::ClientEk,
+							Some(packet),
+						)))
+					} else {
+						let beta = rng.gen::<[u8; 10]>();
+						let (iv, mac) = algs::compute_iv_mac(
+							&data.handshake.alpha,
+							&beta,
+							private_key.clone(),
+							client_key.clone(),
+						)?;
+						con.params = Some(ConnectedParams::new(
+							client_key,
+							SharedIv::ProtocolOrig(iv),
+							mac,
+						));
+
+						let packet = OutCommand::new::<
+							_,
+							_,
+							String,
+							String,
+							_,
+							_,
+							std::iter::Empty<_>,
+						>(
+							Direction::S2C,
+							PacketType::Command,
+							"initivexpand",
+							vec![
+								(
+									"alpha",
+									base64::encode(&data.handshake.alpha),
+								),
+								("beta", base64::encode(&beta)),
+								("omega", omega),
+							]
+							.into_iter(),
+							std::iter::empty(),
+						);
+						Ok(Some((
+							ClientConnectionState::Connecting,
+							Some(packet),
+						)))
+					}
+				}
+				(ClientConnectionState::Init1, C2SInitData::Init0 { .. })
+				| (ClientConnectionState::Init3, C2SInitData::Init2 { .. }) => {
+					// The client did not yet receive our answer, the
+					// resender will send it again.
+					Ok(None)
+				}
+				_ => {
+					*ignore_packet = false;
+					Ok(None)
+				}
+			}
+		})
+	}
+
+	fn handle_command(
+		(data, con): &mut (ClientConnectionData, Connection),
+		command: &InCommand,
+		ignore_packet: &mut bool,
+		config: &HandshakeConfig,
+	) -> Result<Option<(ClientConnectionState, Option<OutPacket>)>>
+	{
+		let args = command.data();
+		let res = match (data.state, command.name()) {
+			// The clientek is handled by the ServerInCommandObserver
+			(_, "clientek") => None,
+			(ClientConnectionState::Connecting, "clientinit") => {
+				// Check the hash cash level of the client
+				let omega = data
+					.client_key
+					.as_ref()
+					.ok_or_else(|| format_err!("Client key is unknown"))?
+					.to_ts()?;
+				let offset = args
+					.static_arg("client_key_offset")
+					.and_then(|o| o.parse().ok())
+					.unwrap_or(0);
+				let level = algs::get_hash_cash_level(&omega, offset);
+				if level < config.hash_cash_level {
+					info!(con.logger, "Client security level too low";
+						"level" => level,
+						"required" => config.hash_cash_level);
+					let packet = OutCommand::new::<
+						_,
+						_,
+						String,
+						String,
+						_,
+						_,
+						std::iter::Empty<_>,
+					>(
+						Direction::S2C,
+						PacketType::Command,
+						"error",
+						vec![
+							(
+								"id",
+								CLIENT_COULD_NOT_VALIDATE_IDENTITY.to_string(),
+							),
+							(
+								"msg",
+								"client could not validate identity".into(),
+							),
+							("extra_msg", config.hash_cash_level.to_string()),
+						]
+						.into_iter(),
+						std::iter::empty(),
+					);
+					Some((ClientConnectionState::Disconnecting, Some(packet)))
+				} else {
+					// Let the packet handler answer with initserver
+					*ignore_packet = false;
+					None
+				}
+			}
+			(ClientConnectionState::Connected, "clientdisconnect")
+			| (ClientConnectionState::Connecting, "clientdisconnect") => {
+				*ignore_packet = false;
+				let c_id = con.params.as_ref().map(|p| p.c_id).unwrap_or(0);
+				let reason_id = args.static_arg("reasonid").unwrap_or("8");
+				let reason_msg = args.static_arg("reasonmsg").unwrap_or("");
+				let packet = OutCommand::new::<
+					_,
+					_,
+					String,
+					String,
+					_,
+					_,
+					std::iter::Empty<_>,
+				>(
+					Direction::S2C,
+					PacketType::Command,
+					"notifyclientleftview",
+					vec![
+						("reasonid", reason_id),
+						("reasonmsg", reason_msg),
+						("clid", &c_id.to_string()),
+					]
+					.into_iter(),
+					std::iter::empty(),
+				);
+				Some((ClientConnectionState::Disconnecting, Some(packet)))
+			}
+			_ => {
+				*ignore_packet = false;
+				None
+			}
+		};
+		Ok(res)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::license::{LicenseBuilder, LicenseType};
+	use crate::{client, memory};
+
+	use std::time::Duration;
+
+	use slog::{o, Drain};
+	use tokio::runtime::current_thread::Runtime;
+	use tokio::util::FutureExt;
+
+	/// Answers a `clientinit` with `initserver` and ignores everything else.
+	struct TestPacketHandler;
+
+	impl<T: Send + 'static> PacketHandler<T> for TestPacketHandler {
+		fn new_connection<S1, S2, S3, S4>(
+			&mut self,
+			con_val: &ConnectionValue<T>,
+			s2c_init_stream: S1,
+			c2s_init_stream: S2,
+			command_stream: S3,
+			audio_stream: S4,
+		) where
+			S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+			S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+			S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+			S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+		{
+			tokio::spawn(
+				s2c_init_stream
+					.for_each(|_| Ok(()))
+					.map_err(|e| panic!("s2c_init_stream errored: {:?}", e)),
+			);
+			tokio::spawn(
+				c2s_init_stream
+					.for_each(|_| Ok(()))
+					.map_err(|e| panic!("c2s_init_stream errored: {:?}", e)),
+			);
+			let con_val = con_val.downgrade();
+			tokio::spawn(
+				command_stream
+					.for_each(
+						move |cmd| -> Box<Future<Item = _, Error = _> + Send> {
+							if cmd.name() != "clientinit" {
+								return Box::new(future::ok(()));
+							}
+							let packet = OutCommand::new::<
+								_,
+								_,
+								String,
+								String,
+								_,
+								_,
+								std::iter::Empty<_>,
+							>(
+								Direction::S2C,
+								PacketType::Command,
+								"initserver",
+								vec![("aclid", "2")].into_iter(),
+								std::iter::empty(),
+							);
+							Box::new(
+								con_val
+									.as_packet_sink()
+									.send(packet)
+									.map(|_| ()),
+							)
+						},
+					)
+					.map_err(|e| panic!("command_stream errored: {:?}", e)),
+			);
+			tokio::spawn(
+				audio_stream
+					.for_each(|_| Ok(()))
+					.map_err(|e| panic!("audio_stream errored: {:?}", e)),
+			);
+		}
+	}
+
+	fn create_logger() -> Logger {
+		let decorator = slog_term::TermDecorator::new().stdout().build();
+		let drain = slog_term::FullFormat::new(decorator).build().fuse();
+		let drain = slog_async::Async::new(drain).build().fuse();
+
+		slog::Logger::root(drain, o!())
+	}
+
+	/// Connect a client and send the `clientinit`.
+	fn handshake(
+		client: &Arc<Mutex<client::ClientData<TestPacketHandler>>>,
+		server_addr: SocketAddr,
+	) -> impl Future<Item = (), Error = Error>
+	{
+		let cw = Arc::downgrade(client);
+		client::connect(cw, &mut *client.lock(), server_addr)
+			.and_then(|con| {
+				let packet = OutCommand::new::<
+					_,
+					_,
+					String,
+					String,
+					_,
+					_,
+					std::iter::Empty<_>,
+				>(
+					Direction::C2S,
+					PacketType::Command,
+					"clientinit",
+					vec![("client_key_offset", "0")].into_iter(),
+					std::iter::empty(),
+				);
+				con.as_packet_sink().send(packet).map(move |_| con)
+			})
+			.and_then(|con| client::wait_until_connected(&con))
+	}
+
+	fn handshake_config() -> HandshakeConfig {
+		HandshakeConfig {
+			puzzle_level: 100,
+			hash_cash_level: 0,
+			license: None,
+		}
+	}
+
+	#[test]
+	fn test_handshake() {
+		let mut runtime = Runtime::new().unwrap();
+		let logger = create_logger();
+
+		runtime
+			.block_on(future::lazy(move || {
+				let server = new(
+					"127.0.0.1:0".parse().unwrap(),
+					EccKeyPrivP256::create().unwrap(),
+					TestPacketHandler,
+					handshake_config(),
+					Default::default(),
+					logger.new(o!("server" => true)),
+				)
+				.unwrap();
+				let server_addr = server.lock().local_addr;
+
+				let client = client::new(
+					"127.0.0.1:0".parse().unwrap(),
+					EccKeyPrivP256::create().unwrap(),
+					TestPacketHandler,
+					Default::default(),
+					logger,
+				)
+				.unwrap();
+
+				handshake(&client, server_addr)
+					.timeout(Duration::from_secs(10))
+					.map(move |_| {
+						drop(client);
+						drop(server);
+					})
+					.map_err(|e| panic!("Failed to connect: {:?}", e))
+			}))
+			.unwrap();
+	}
+
+	/// Run the handshake over an in-memory transport.
+	#[test]
+	fn test_handshake_in_memory() {
+		let mut runtime = Runtime::new().unwrap();
+		let logger = create_logger();
+		let (c, s) = memory::pair(
+			"127.0.0.1:1".parse().unwrap(),
+			"127.0.0.1:2".parse().unwrap(),
+		);
+
+		runtime
+			.block_on(future::lazy(move || {
+				let server_addr = s.addr;
+				let server = new_with_socket(
+					s.addr,
+					EccKeyPrivP256::create().unwrap(),
+					TestPacketHandler,
+					handshake_config(),
+					Default::default(),
+					s.sink,
+					s.stream,
+					logger.new(o!("server" => true)),
+				)
+				.unwrap();
+
+				let client = client::new_with_socket(
+					c.addr,
+					EccKeyPrivP256::create().unwrap(),
+					TestPacketHandler,
+					Default::default(),
+					c.sink,
+					c.stream,
+					logger,
+				)
+				.unwrap();
+
+				handshake(&client, server_addr)
+					.timeout(Duration::from_secs(10))
+					.map(move |_| {
+						drop(client);
+						drop(server);
+					})
+					.map_err(|e| panic!("Failed to connect: {:?}", e))
+			}))
+			.unwrap();
+	}
+
+	/// Run the handshake of TeamSpeak 3.1 with a license chain of an own root
+	/// key.
+	#[test]
+	fn test_handshake_license() {
+		let mut runtime = Runtime::new().unwrap();
+		let logger = create_logger();
+		let (c, s) = memory::pair(
+			"127.0.0.1:1".parse().unwrap(),
+			"127.0.0.1:2".parse().unwrap(),
+		);
+
+		let root = EccKeyPrivEd25519::create().unwrap();
+		let protocol_config = ProtocolConfig {
+			license_root_key: root.to_pub(),
+			..Default::default()
+		};
+		let license = LicenseBuilder::new(root)
+			.intermediate("Test")
+			.server("Test server", LicenseType::Npl)
+			.build()
+			.unwrap();
+
+		runtime
+			.block_on(future::lazy(move || {
+				let server_addr = s.addr;
+				let server = new_with_socket(
+					s.addr,
+					EccKeyPrivP256::create().unwrap(),
+					TestPacketHandler,
+					HandshakeConfig {
+						license: Some(license),
+						..handshake_config()
+					},
+					Default::default(),
+					s.sink,
+					s.stream,
+					logger.new(o!("server" => true)),
+				)
+				.unwrap();
+
+				let client = client::new_with_socket(
+					c.addr,
+					EccKeyPrivP256::create().unwrap(),
+					TestPacketHandler,
+					protocol_config,
+					c.sink,
+					c.stream,
+					logger,
+				)
+				.unwrap();
+
+				handshake(&client, server_addr)
+					.timeout(Duration::from_secs(10))
+					.map(move |_| {
+						drop(client);
+						drop(server);
+					})
+					.map_err(|e| panic!("Failed to connect: {:?}", e))
+			}))
+			.unwrap();
+	}
+}
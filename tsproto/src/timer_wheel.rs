@@ -0,0 +1,271 @@
+//! A hierarchical timer wheel.
+//!
+//! The wheel has several levels with 64 slots each. A slot of the first level
+//! covers one tick, a slot of the next level covers all 64 slots of the level
+//! below and so on. Timers are put into the lowest level which can contain
+//! them and move down when their slot is reached. Inserting a timer and
+//! expiring a timer are `O(1)`.
+//!
+//! Timers cannot be removed, the resend scheduler ignores timers which are not
+//! current anymore instead.
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// The number of bits of a tick which are handled by one level.
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+/// Timers which are further away than `2^TOTAL_BITS` ticks are stored in an
+/// overflow list.
+const TOTAL_BITS: usize = SLOT_BITS * LEVELS;
+
+struct Level<T> {
+	/// A bit is set if the slot contains timers.
+	occupied: u64,
+	/// The timers of each slot with the tick when they expire.
+	slots: Vec<Vec<(u64, T)>>,
+}
+
+/// A timer wheel which stores values of type `T` until their deadline is
+/// reached.
+pub struct TimerWheel<T> {
+	start: Instant,
+	/// The length of a tick in nanoseconds.
+	resolution: u128,
+	/// The number of ticks which passed since `start`.
+	elapsed: u64,
+	levels: Vec<Level<T>>,
+	/// Timers which are too far in the future for the wheel.
+	overflow: Vec<(u64, T)>,
+	/// Timers which were already expired when they were inserted.
+	expired: Vec<T>,
+	len: usize,
+}
+
+fn nanos(dur: Duration) -> u128 {
+	u128::from(dur.as_secs()) * 1_000_000_000 + u128::from(dur.subsec_nanos())
+}
+
+impl<T> Level<T> {
+	fn new() -> Self {
+		Self {
+			occupied: 0,
+			slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+		}
+	}
+}
+
+impl<T> TimerWheel<T> {
+	/// Create a new wheel which starts at `start`.
+	///
+	/// Deadlines are rounded up to the next multiple of `resolution`.
+	pub fn new(start: Instant, resolution: Duration) -> Self {
+		Self {
+			start,
+			resolution: nanos(resolution).max(1),
+			elapsed: 0,
+			levels: (0..LEVELS).map(|_| Level::new()).collect(),
+			overflow: Vec::new(),
+			expired: Vec::new(),
+			len: 0,
+		}
+	}
+
+	/// The number of stored timers.
+	pub fn len(&self) -> usize { self.len }
+
+	pub fn is_empty(&self) -> bool { self.len == 0 }
+
+	/// Insert a timer which expires at `deadline`.
+	pub fn insert(&mut self, deadline: Instant, value: T) {
+		let offset = if deadline > self.start {
+			nanos(deadline - self.start)
+		} else {
+			0
+		};
+		// Round up so timers never expire too early
+		let tick = ((offset + self.resolution - 1) / self.resolution) as u64;
+		self.len += 1;
+		if tick <= self.elapsed {
+			self.expired.push(value);
+		} else {
+			self.insert_tick(tick, value);
+		}
+	}
+
+	/// The time when the next timer expires.
+	///
+	/// This can be earlier than the real deadline of the timer, if the timer
+	/// has to move down to a lower level at this time.
+	pub fn next_expiration(&self) -> Option<Instant> {
+		let tick = if !self.expired.is_empty() {
+			self.elapsed
+		} else {
+			self.next_tick()?.0
+		};
+		let offset = u128::from(tick) * self.resolution;
+		Some(self.start + Duration::from_nanos(offset as u64))
+	}
+
+	/// Append all timers which expired until `now` to `expired`.
+	pub fn poll_expired(&mut self, now: Instant, expired: &mut Vec<T>) {
+		let now_tick = if now > self.start {
+			(nanos(now - self.start) / self.resolution) as u64
+		} else {
+			0
+		};
+
+		self.len -= self.expired.len();
+		expired.append(&mut self.expired);
+
+		while let Some((tick, level)) = self.next_tick() {
+			if tick > now_tick {
+				break;
+			}
+			self.elapsed = tick;
+			let timers = if level == LEVELS {
+				mem::replace(&mut self.overflow, Vec::new())
+			} else {
+				let slot = Self::slot(tick, level);
+				let level = &mut self.levels[level];
+				level.occupied &= !(1 << slot);
+				mem::replace(&mut level.slots[slot], Vec::new())
+			};
+
+			// Expire timers or move them to a lower level
+			for (t, value) in timers {
+				if t <= self.elapsed {
+					self.len -= 1;
+					expired.push(value);
+				} else {
+					self.insert_tick(t, value);
+				}
+			}
+		}
+
+		if now_tick > self.elapsed {
+			self.elapsed = now_tick;
+		}
+	}
+
+	fn slot(tick: u64, level: usize) -> usize {
+		(tick >> (level * SLOT_BITS)) as usize & (SLOTS - 1)
+	}
+
+	/// Insert a timer which expires after the current tick.
+	fn insert_tick(&mut self, tick: u64, value: T) {
+		// The highest bit which differs from the current tick decides the
+		// level.
+		let masked = (tick ^ self.elapsed) | (SLOTS as u64 - 1);
+		let level = (63 - masked.leading_zeros() as usize) / SLOT_BITS;
+		if level >= LEVELS {
+			self.overflow.push((tick, value));
+			return;
+		}
+
+		let slot = Self::slot(tick, level);
+		let level = &mut self.levels[level];
+		level.occupied |= 1 << slot;
+		level.slots[slot].push((tick, value));
+	}
+
+	/// The next tick where a slot has to be processed and the level of the
+	/// slot.
+	///
+	/// The level is `LEVELS` if the overflow list has to be processed.
+	fn next_tick(&self) -> Option<(u64, usize)> {
+		let mut res: Option<(u64, usize)> = None;
+		for (i, level) in self.levels.iter().enumerate() {
+			let shift = i * SLOT_BITS;
+			let current = Self::slot(self.elapsed, i);
+			// All timers of a level are in the current rotation and not before
+			// the current slot.
+			let occupied = level.occupied & (!0 << current);
+			if occupied == 0 {
+				continue;
+			}
+			let rotation_start =
+				self.elapsed & !((1 << (shift + SLOT_BITS)) - 1);
+			let tick = (rotation_start
+				+ (u64::from(occupied.trailing_zeros()) << shift))
+				.max(self.elapsed);
+			if res.map(|(t, _)| tick < t).unwrap_or(true) {
+				res = Some((tick, i));
+			}
+		}
+
+		if !self.overflow.is_empty() {
+			// Move the overflowing timers into the wheel when the highest
+			// level starts a new rotation.
+			let tick = ((self.elapsed >> TOTAL_BITS) + 1) << TOTAL_BITS;
+			if res.map(|(t, _)| tick < t).unwrap_or(true) {
+				res = Some((tick, LEVELS));
+			}
+		}
+		res
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn expire(
+		wheel: &mut TimerWheel<u32>,
+		start: Instant,
+		millis: u64,
+	) -> Vec<u32>
+	{
+		let mut res = Vec::new();
+		wheel.poll_expired(start + Duration::from_millis(millis), &mut res);
+		res
+	}
+
+	#[test]
+	fn timer_wheel_expire() {
+		let start = Instant::now();
+		let mut wheel = TimerWheel::new(start, Duration::from_millis(1));
+		for (i, ms) in [5, 100, 5000, 70_000, 100].iter().enumerate() {
+			wheel.insert(start + Duration::from_millis(*ms), i as u32);
+		}
+		assert_eq!(wheel.len(), 5);
+		assert_eq!(
+			wheel.next_expiration(),
+			Some(start + Duration::from_millis(5))
+		);
+
+		assert!(expire(&mut wheel, start, 4).is_empty());
+		assert_eq!(expire(&mut wheel, start, 5), [0]);
+		assert!(expire(&mut wheel, start, 99).is_empty());
+		let mut res = expire(&mut wheel, start, 100);
+		res.sort();
+		assert_eq!(res, [1, 4]);
+		assert!(expire(&mut wheel, start, 4999).is_empty());
+		assert_eq!(expire(&mut wheel, start, 60_000), [2]);
+
+		// Timers in the past expire immediately
+		wheel.insert(start, 5);
+		assert_eq!(expire(&mut wheel, start, 60_000), [5]);
+		assert!(expire(&mut wheel, start, 69_999).is_empty());
+		assert_eq!(expire(&mut wheel, start, 70_000), [3]);
+		assert!(wheel.is_empty());
+		assert_eq!(wheel.next_expiration(), None);
+	}
+
+	#[test]
+	fn timer_wheel_overflow() {
+		let start = Instant::now();
+		// 2^36 ns are about 69 s
+		let mut wheel = TimerWheel::new(start, Duration::from_nanos(1));
+		wheel.insert(start + Duration::from_secs(100), 0);
+		wheel.insert(start + Duration::from_secs(300), 1);
+
+		let mut res = Vec::new();
+		wheel.poll_expired(start + Duration::from_millis(99_999), &mut res);
+		assert!(res.is_empty());
+		wheel.poll_expired(start + Duration::from_secs(100), &mut res);
+		assert_eq!(res, [0]);
+		wheel.poll_expired(start + Duration::from_secs(300), &mut res);
+		assert_eq!(res, [0, 1]);
+	}
+}
@@ -22,19 +22,22 @@ include!(concat!(env!("OUT_DIR"), "/m2bdecls.rs"));
 include!(concat!(env!("OUT_DIR"), "/structs.rs"));
 include!(concat!(env!("OUT_DIR"), "/properties.rs"));
 
+macro_rules! limited_max_clients {
+	($cmd:ident, $arg:expr) => {{
+		$cmd.get($arg)
+			.and_then(|s| s.parse::<i32>().ok())
+			.filter(|i| *i >= 0 && *i <= i32::from(u16::MAX))
+			.map(|i| MaxClients::Limited(i as u16))
+	}};
+}
+
 macro_rules! max_clients {
 	($cmd:ident) => {{
 		if $cmd.get("channel_flag_maxclients_unlimited") == Some("1") {
 			Some(MaxClients::Unlimited)
-		} else if $cmd.get("channel_maxclients")
-			.and_then(|s| s.parse().ok())
-			.map(|i: i32| i >= 0 && i <= u16::MAX as i32)
-			.unwrap_or(false)
-		{
-			Some(MaxClients::Limited($cmd.get("channel_maxclients").unwrap().parse().unwrap()))
 		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
+			// Ignore max clients if it is less than zero or too high
+			limited_max_clients!($cmd, "channel_maxclients")
 		}
 	}};
 }
@@ -49,7 +52,17 @@ macro_rules! copy_attrs {
 }
 
 impl Connection {
-	pub fn new(server_uid: Uid, msg: &InMessage) -> Self {
+	/// Create the connection from the `initserver` message.
+	///
+	/// For newer servers, the `license` should be taken from the license
+	/// chain of the handshake, the `initserver` message does not contain it
+	/// anymore.
+	pub fn new(
+		server_uid: Uid,
+		license: Option<LicenseType>,
+		msg: &InMessage,
+	) -> Self
+	{
 		let packet = if let InMessages::InitServer(p) = msg.msg() {
 			p
 		} else {
@@ -88,8 +101,9 @@ impl Connection {
 				created: packet.server_created,
 				ips: packet.server_ip.iter().map(|s| s.to_string()).collect(),
 				ask_for_privilegekey: packet.ask_for_privilegekey,
-				// TODO Or get from license struct for newer servers
-				license: packet.license_type.unwrap_or(LicenseType::NoLicense),
+				license: license
+					.or(packet.license_type)
+					.unwrap_or(LicenseType::NoLicense),
 
 				optional_data: None,
 				connection_data: None,
@@ -347,15 +361,9 @@ impl Connection {
 			Some(MaxClients::Unlimited)
 		} else if cmd.get("channel_flag_maxfamilyclients_inherited") == Some("1") {
 			Some(MaxClients::Inherited)
-		} else if cmd.get("channel_maxfamilyclients")
-			.and_then(|s| s.parse().ok())
-			.map(|i: i32| i >= 0 && i <= u16::MAX as i32)
-			.unwrap_or(false)
-		{
-			Some(MaxClients::Limited(cmd.get("channel_maxfamilyclients").unwrap().parse().unwrap()))
 		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
+			// Ignore max clients if it is less than zero or too high
+			limited_max_clients!(cmd, "channel_maxfamilyclients")
 		};
 		Ok((ch, ch_fam))
 	}
@@ -384,15 +392,9 @@ impl Connection {
 				Some(MaxClients::Unlimited)
 			} else if cmd.get("channel_flag_maxfamilyclients_inherited") == Some("1") {
 				Some(MaxClients::Inherited)
-			} else if cmd.get("channel_maxfamilyclients")
-				.and_then(|s| s.parse().ok())
-				.map(|i: i32| i >= 0 && i <= u16::MAX as i32)
-				.unwrap_or(false)
-			{
-				Some(MaxClients::Limited(cmd.get("channel_maxfamilyclients").unwrap().parse().unwrap()))
 			} else {
-				// Max clients is less than zero or too high so ignore it
-				None
+				// Ignore max clients if it is less than zero or too high
+				limited_max_clients!(cmd, "channel_maxfamilyclients")
 			};
 			if let Some(ch_fam) = ch_fam {
 				events.push(Event::PropertyChanged {
@@ -764,6 +766,20 @@ impl<'a> ChannelOptions<'a> {
 }
 
 impl Server {
+	/// Request the connection information of the server.
+	///
+	/// The server answers with a `notifyserverconnectioninfo`, which fills the
+	/// `connection_data` of the server.
+	pub fn get_connection_info(&self) -> OutPacket {
+		OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"serverrequestconnectioninfo",
+			std::iter::empty(),
+			std::iter::empty(),
+		)
+	}
+
 	pub fn add_channel(
 		&self,
 		options: ChannelOptions,
@@ -1006,6 +1022,20 @@ impl Client {
 		)
 	}
 
+	/// Request the connection information of this client.
+	///
+	/// The server answers with a `notifyconnectioninfo`, which fills the
+	/// `connection_data` of this client.
+	pub fn get_connection_info(&self) -> OutPacket {
+		c2s::OutGetClientConnectionInfoMessage::new(
+			vec![c2s::GetClientConnectionInfoPart {
+				client_id: self.id,
+				phantom: PhantomData,
+			}]
+			.into_iter(),
+		)
+	}
+
 	pub fn poke(&self, message: &str) -> OutPacket {
 		c2s::OutClientPokeRequestMessage::new(
 			vec![c2s::ClientPokeRequestPart {
@@ -312,6 +312,12 @@ impl InPacket {
 	#[inline]
 	fn header_data(&self) -> &[u8] { self.inner.head() }
 
+	/// The raw data of the udp packet, as it was received.
+	///
+	/// Setting the content of the packet does not change this data.
+	#[inline]
+	pub fn udp_data(&self) -> &[u8] { self.inner.head() }
+
 	#[inline]
 	pub fn set_content(&mut self, content: Vec<u8>) {
 		self.inner.rent_mut(|c| *c = Cow::Owned(content));
@@ -492,6 +498,10 @@ impl fmt::Debug for InPacket {
 }
 
 impl<'a> InHeader<'a> {
+	/// Read the header at the start of the udp packet `data`.
+	#[inline]
+	pub fn new(data: &'a [u8], dir: Direction) -> Self { InHeader(data, dir) }
+
 	/// The offset to the packet type.
 	#[inline]
 	fn get_off(&self) -> usize {
@@ -529,16 +539,14 @@ impl<'a> InHeader<'a> {
 		PacketType::from_u8(self.0[self.get_off()] & 0xf).unwrap()
 	}
 
-	pub fn get_meta(&self) -> Vec<u8> {
-		let mut res = Vec::with_capacity(5);
-		res.write_u16::<NetworkEndian>(self.packet_id()).unwrap();
-		if let Some(c_id) = self.client_id() {
-			res.write_u16::<NetworkEndian>(c_id).unwrap();
-		}
-		// Packet type and flags
-		res.write_u8(self.0[self.get_off()]).unwrap();
-		res
-	}
+	pub fn get_meta(&self) -> Vec<u8> { self.meta().to_vec() }
+
+	/// The meta data of the packet, which is authenticated by the mac.
+	///
+	/// This is the packet id, the client id (if it exists) and the packet
+	/// type and flags.
+	#[inline]
+	pub fn meta(&self) -> &'a [u8] { &self.0[8..=self.get_off()] }
 }
 
 impl<'a> fmt::Debug for C2SInitData<'a> {
@@ -713,7 +721,7 @@ impl<'a> AudioData<'a> {
 				}
 				let channel_count = content[3] as usize;
 				let client_count = content[4] as usize;
-				let channel_off = 4;
+				let channel_off = 5;
 				let client_off = channel_off + channel_count * 8;
 				let off = client_off + client_count * 2;
 				if content.len() < off {
@@ -883,6 +891,19 @@ impl OutPacket {
 
 	#[inline]
 	pub fn mac(&mut self) -> &mut [u8; 8] { array_mut_ref!(self.data, 0, 8) }
+	/// Split the packet into the mac, the meta data and the content.
+	///
+	/// This allows to encrypt the content in place.
+	#[inline]
+	pub fn split_mac_meta_content_mut(
+		&mut self,
+	) -> (&mut [u8; 8], &[u8], &mut [u8])
+	{
+		let off = self.content_offset();
+		let (header, content) = self.data.split_at_mut(off);
+		let (mac, meta) = header.split_at_mut(8);
+		(array_mut_ref!(mac, 0, 8), meta, content)
+	}
 	#[inline]
 	pub fn packet_id(&mut self, packet_id: u16) {
 		(&mut self.data[8..10])
@@ -1278,3 +1299,33 @@ impl OutAudio {
 		res
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_whisper() {
+		let content = [0, 1, 4, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 5, 0xaa, 0xbb];
+		let audio = AudioData::parse(
+			PacketType::VoiceWhisper,
+			false,
+			Direction::C2S,
+			&content,
+		)
+		.unwrap();
+		if let AudioData::C2SWhisper {
+			channels,
+			clients,
+			data,
+			..
+		} = audio
+		{
+			assert_eq!(channels, vec![2]);
+			assert_eq!(clients, vec![5]);
+			assert_eq!(data, &[0xaa, 0xbb]);
+		} else {
+			panic!("Expected a whisper packet");
+		}
+	}
+}